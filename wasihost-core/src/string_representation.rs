@@ -1,9 +1,9 @@
 //! Defines string representations for the high level WASI APIs.
 
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 #[cfg(unix)]
 use std::{
-    ffi::OsString,
+    ffi::{OsStr, OsString},
     ops::Deref,
     os::unix::ffi::{OsStrExt, OsStringExt},
 };
@@ -16,6 +16,9 @@ pub trait StringRepresentation: Deref + Sized + Send + Sync + 'static {
 
     /// Converts a string into a byte slice.
     fn as_bytes(&self) -> &[u8];
+
+    /// Converts a borrowed string into a byte slice.
+    fn target_as_bytes(target: &Self::Target) -> &[u8];
 }
 
 impl StringRepresentation for String {
@@ -26,6 +29,10 @@ impl StringRepresentation for String {
     fn as_bytes(&self) -> &[u8] {
         self.deref().as_bytes()
     }
+
+    fn target_as_bytes(target: &str) -> &[u8] {
+        target.as_bytes()
+    }
 }
 
 impl StringRepresentation for Vec<u8> {
@@ -36,6 +43,10 @@ impl StringRepresentation for Vec<u8> {
     fn as_bytes(&self) -> &[u8] {
         self
     }
+
+    fn target_as_bytes(target: &[u8]) -> &[u8] {
+        target
+    }
 }
 
 #[cfg(unix)]
@@ -47,6 +58,10 @@ impl StringRepresentation for OsString {
     fn as_bytes(&self) -> &[u8] {
         self.deref().as_bytes()
     }
+
+    fn target_as_bytes(target: &OsStr) -> &[u8] {
+        target.as_bytes()
+    }
 }
 
 impl StringRepresentation for CString {
@@ -57,4 +72,8 @@ impl StringRepresentation for CString {
     fn as_bytes(&self) -> &[u8] {
         self.to_bytes()
     }
+
+    fn target_as_bytes(target: &CStr) -> &[u8] {
+        target.to_bytes()
+    }
 }
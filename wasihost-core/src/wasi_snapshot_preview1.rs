@@ -1,6 +1,8 @@
 //! Implementation of types and interfaces for WASI snapshot preview 1.
 
-use self::native::{NativeWasiImports, NativeWasiImportsExt};
+use self::native::{
+    NativeLayered, NativeWasiImports, NativeWasiImportsExt, NativeWasiImportsLayer,
+};
 use super::string_representation::StringRepresentation;
 use std::{
     cell::Cell,
@@ -361,6 +363,25 @@ impl<T: WasiImports> WasiImportsExt for Arc<T> {
     }
 }
 
+/// Extension methods to generate the imports for a [`WasiImports`](trait.WasiImports.html)
+/// object with a [`NativeWasiImportsLayer`](native/trait.NativeWasiImportsLayer.html).
+pub trait WasiImportsLayerExt: WasiImportsExt {
+    /// Generates the imports for this object, passing every call through the hooks of `layer`.
+    fn into_imports_with_layer(self, layer: impl NativeWasiImportsLayer) -> ImportObject;
+}
+
+impl<T: WasiImports> WasiImportsLayerExt for T {
+    fn into_imports_with_layer(self, layer: impl NativeWasiImportsLayer) -> ImportObject {
+        Arc::new(self).into_imports_with_layer(layer)
+    }
+}
+
+impl<T: WasiImports> WasiImportsLayerExt for Arc<T> {
+    fn into_imports_with_layer(self, layer: impl NativeWasiImportsLayer) -> ImportObject {
+        NativeLayered::new(layer, NativeWasiAdapter(self)).into_imports()
+    }
+}
+
 struct NativeWasiAdapter<T>(Arc<T>);
 
 impl<T> Clone for NativeWasiAdapter<T> {
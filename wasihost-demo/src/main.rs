@@ -10,20 +10,41 @@
     variant_size_differences
 )]
 
-use std::env;
-use wasihost::wasi_snapshot_preview1::{DefaultWasiFdInitializer, WasiHost};
+use std::{env, fs::File, io};
+use wasihost::wasi_snapshot_preview1::{RunOutcome, TraceWriter, WasiHost};
 
 fn main() {
-    let wasm_filename = env::args().skip(1).next().unwrap();
+    // `--trace` prints every system call to standard error, `--trace=FILE` writes them to FILE.
+    let mut arguments = env::args().skip(1).peekable();
+    let trace =
+        arguments.next_if(|argument| argument == "--trace" || argument.starts_with("--trace="));
 
-    let arguments = env::args().skip(1);
+    let arguments: Vec<_> = arguments.collect();
+    let wasm_filename = arguments.first().cloned().unwrap();
     let environment = env::vars().map(|(key, value)| format!("{}={}", key, value));
 
-    let wasi_host = WasiHost::<String>::new(arguments, environment, DefaultWasiFdInitializer);
+    let mut builder = WasiHost::<String>::builder()
+        .args(arguments)
+        .env_entries(environment);
 
-    let code = wasi_host
+    match trace.as_deref() {
+        Some("--trace") => builder = builder.trace_sink(TraceWriter::new(io::stderr())),
+        Some(option) => {
+            let trace_file =
+                File::create(&option["--trace=".len()..]).expect("Unable to create the trace file");
+            builder = builder.trace_sink(TraceWriter::new(trace_file));
+        }
+        None => {}
+    }
+
+    let wasi_host = builder.build().expect("Unable to create the WASI host");
+
+    let outcome = wasi_host
         .run_file(&wasm_filename)
         .expect("Unable to run WASM binary");
 
-    eprintln!("WASI program exited with exit code {}.", code);
+    match outcome {
+        RunOutcome::Exited(code) => eprintln!("WASI program exited with exit code {}.", code),
+        outcome => eprintln!("WASI program did not exit: {:?}", outcome),
+    }
 }
@@ -12,3 +12,13 @@ pub(crate) use windows::*;
 mod unknown;
 #[cfg(not(any(unix, windows)))]
 pub(crate) use unknown::*;
+
+/// How [`DirHandle::open_file_at`] opens a file. Files are only created if they do not exist.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct FileOptions {
+    pub(crate) read: bool,
+    pub(crate) write: bool,
+    pub(crate) append: bool,
+    pub(crate) create_new: bool,
+    pub(crate) truncate: bool,
+}
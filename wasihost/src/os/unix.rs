@@ -1,8 +1,30 @@
+use super::FileOptions;
 use libc::{
-    clock_getres, clock_gettime, clockid_t, timespec, CLOCK_MONOTONIC, CLOCK_PROCESS_CPUTIME_ID,
-    CLOCK_REALTIME, CLOCK_THREAD_CPUTIME_ID,
+    c_char, c_int, c_long, c_short, clock_getres, clock_gettime, clockid_t, close, closedir, fcntl,
+    fdopendir, fstatat, futimens, ioctl, iovec, mkdirat, msghdr, openat, poll, pollfd, readdir,
+    readlinkat, recvmsg, time_t, timespec, unlinkat, AT_REMOVEDIR, AT_SYMLINK_NOFOLLOW,
+    CLOCK_MONOTONIC, CLOCK_PROCESS_CPUTIME_ID, CLOCK_REALTIME, CLOCK_THREAD_CPUTIME_ID, DT_BLK,
+    DT_CHR, DT_DIR, DT_LNK, DT_REG, DT_SOCK, DT_UNKNOWN, EBUSY, EISDIR, ELOOP, ENAMETOOLONG,
+    ENOSPC, ENOTDIR, ENOTEMPTY, EPERM, EROFS, EXDEV, FIONREAD, F_GETFL, F_SETFL, MSG_PEEK,
+    MSG_TRUNC, MSG_WAITALL, O_APPEND, O_CLOEXEC, O_CREAT, O_DIRECTORY, O_EXCL, O_NOFOLLOW,
+    O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, POLLHUP, POLLIN, POLLOUT, STDIN_FILENO, S_IFBLK, S_IFCHR,
+    S_IFDIR, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK, UTIME_NOW, UTIME_OMIT,
+};
+use std::{
+    ffi::{CStr, CString},
+    fs::{File, FileType, Metadata, OpenOptions},
+    io::{self, IoSliceMut, Read},
+    mem::{self, ManuallyDrop},
+    os::unix::{
+        fs::{FileExt, FileTypeExt, MetadataExt, OpenOptionsExt},
+        io::{AsRawFd, FromRawFd, IntoRawFd},
+    },
+    path::Path,
+};
+use wasihost_core::wasi_snapshot_preview1::{
+    Clockid, Device, Errno, EventFdReadwrite, Eventrwflags, Filesize, Filestat, Filetype, Fstflags,
+    Inode, Linkcount, Riflags, Roflags, Size, Timestamp, WasiResult,
 };
-use wasihost_core::wasi_snapshot_preview1::{Clockid, Errno, Timestamp, WasiResult};
 
 fn get_unix_clockid(id: Clockid) -> Option<clockid_t> {
     match id {
@@ -17,7 +39,7 @@ fn get_unix_clockid(id: Clockid) -> Option<clockid_t> {
 fn clock_error_from_errno() -> Errno {
     match errno::errno().0 {
         libc::EINVAL => Errno::Inval,
-        libc::EPERM => Errno::Perm,
+        EPERM => Errno::Perm,
         _ => Errno::Inval,
     }
 }
@@ -26,9 +48,23 @@ fn clock_timestamp_from_timespce(timespec: timespec) -> Timestamp {
     Timestamp((timespec.tv_sec as u64 * 1_000_000_000).wrapping_add(timespec.tv_nsec as u64))
 }
 
-pub(crate) fn preview1_clock_res_get(id: Clockid) -> WasiResult<Timestamp> {
-    let unix_clock_id = get_unix_clockid(id).ok_or(Errno::Inval)?;
+/// Returns the coarse variant of `clock`, which is cheaper to read but only as precise as its
+/// resolution.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn get_coarse_clockid(clock: clockid_t) -> Option<clockid_t> {
+    match clock {
+        CLOCK_REALTIME => Some(libc::CLOCK_REALTIME_COARSE),
+        CLOCK_MONOTONIC => Some(libc::CLOCK_MONOTONIC_COARSE),
+        _ => None,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn get_coarse_clockid(_clock: clockid_t) -> Option<clockid_t> {
+    None
+}
 
+fn clock_res_get(unix_clock_id: clockid_t) -> WasiResult<Timestamp> {
     let mut timespec = timespec {
         tv_sec: 0,
         tv_nsec: 0,
@@ -41,8 +77,19 @@ pub(crate) fn preview1_clock_res_get(id: Clockid) -> WasiResult<Timestamp> {
     }
 }
 
-pub(crate) fn preview1_clock_time_get(id: Clockid, _precision: Timestamp) -> WasiResult<Timestamp> {
-    let unix_clock_id = get_unix_clockid(id).ok_or(Errno::Inval)?;
+pub(crate) fn preview1_clock_res_get(id: Clockid) -> WasiResult<Timestamp> {
+    clock_res_get(get_unix_clockid(id).ok_or(Errno::Inval)?)
+}
+
+pub(crate) fn preview1_clock_time_get(id: Clockid, precision: Timestamp) -> WasiResult<Timestamp> {
+    let mut unix_clock_id = get_unix_clockid(id).ok_or(Errno::Inval)?;
+
+    // Callers that accept some lag get the cheaper coarse clock if it is precise enough.
+    if let Some(coarse_clock_id) = get_coarse_clockid(unix_clock_id) {
+        if matches!(clock_res_get(coarse_clock_id), Ok(res) if res <= precision) {
+            unix_clock_id = coarse_clock_id;
+        }
+    }
 
     let mut timespec: timespec = timespec {
         tv_sec: 0,
@@ -55,3 +102,481 @@ pub(crate) fn preview1_clock_time_get(id: Clockid, _precision: Timestamp) -> Was
         Err(clock_error_from_errno())
     }
 }
+
+fn timestamp_from_parts(seconds: i64, nanoseconds: i64) -> Timestamp {
+    Timestamp(
+        (seconds as u64)
+            .wrapping_mul(1_000_000_000)
+            .wrapping_add(nanoseconds as u64),
+    )
+}
+
+pub(crate) fn filetype_from_file_type(file_type: FileType) -> Filetype {
+    if file_type.is_dir() {
+        Filetype::Directory
+    } else if file_type.is_file() {
+        Filetype::RegularFile
+    } else if file_type.is_symlink() {
+        Filetype::SymbolicLink
+    } else if file_type.is_block_device() {
+        Filetype::BlockDevice
+    } else if file_type.is_char_device() {
+        Filetype::CharacterDevice
+    } else if file_type.is_socket() {
+        Filetype::SocketStream
+    } else {
+        Filetype::Unknown
+    }
+}
+
+pub(crate) fn filestat_from_metadata(metadata: &Metadata) -> Filestat {
+    Filestat {
+        dev: Device(metadata.dev()),
+        ino: Inode(metadata.ino()),
+        filetype: filetype_from_file_type(metadata.file_type()),
+        nlink: Linkcount(metadata.nlink()),
+        size: Filesize(metadata.size()),
+        atim: timestamp_from_parts(metadata.atime(), metadata.atime_nsec()),
+        mtim: timestamp_from_parts(metadata.mtime(), metadata.mtime_nsec()),
+        ctim: timestamp_from_parts(metadata.ctime(), metadata.ctime_nsec()),
+    }
+}
+
+/// Maps the error of the last failed call, including the codes that `std::io::ErrorKind` does
+/// not distinguish.
+fn last_error() -> Errno {
+    let error = io::Error::last_os_error();
+
+    match error.raw_os_error() {
+        Some(ELOOP) => Errno::Loop,
+        Some(ENOTDIR) => Errno::Notdir,
+        Some(EISDIR) => Errno::Isdir,
+        Some(ENOTEMPTY) => Errno::Notempty,
+        Some(EBUSY) => Errno::Busy,
+        Some(ENAMETOOLONG) => Errno::Nametoolong,
+        Some(EPERM) => Errno::Perm,
+        Some(EXDEV) => Errno::Xdev,
+        Some(EROFS) => Errno::Rofs,
+        Some(ENOSPC) => Errno::Nospc,
+        _ => error.into(),
+    }
+}
+
+fn c_name(name: &[u8]) -> WasiResult<CString> {
+    CString::new(name).map_err(|_| Errno::Inval)
+}
+
+// The field types of `stat` differ between platforms.
+#[allow(trivial_numeric_casts, clippy::unnecessary_cast)]
+fn filestat_from_stat(stat: &libc::stat) -> Filestat {
+    let filetype = match stat.st_mode & S_IFMT {
+        S_IFDIR => Filetype::Directory,
+        S_IFREG => Filetype::RegularFile,
+        S_IFLNK => Filetype::SymbolicLink,
+        S_IFBLK => Filetype::BlockDevice,
+        S_IFCHR => Filetype::CharacterDevice,
+        S_IFSOCK => Filetype::SocketStream,
+        _ => Filetype::Unknown,
+    };
+
+    Filestat {
+        dev: Device(stat.st_dev as u64),
+        ino: Inode(stat.st_ino as u64),
+        filetype,
+        nlink: Linkcount(stat.st_nlink as u64),
+        size: Filesize(stat.st_size as u64),
+        atim: timestamp_from_parts(stat.st_atime as i64, stat.st_atime_nsec as i64),
+        mtim: timestamp_from_parts(stat.st_mtime as i64, stat.st_mtime_nsec as i64),
+        ctim: timestamp_from_parts(stat.st_ctime as i64, stat.st_ctime_nsec as i64),
+    }
+}
+
+/// A directory on the host that paths are resolved relative to one component at a time. All
+/// names passed to it are single path components and symbolic links in them are never
+/// followed.
+#[derive(Debug)]
+pub(crate) struct DirHandle {
+    file: File,
+}
+
+impl DirHandle {
+    /// Opens the directory `path`.
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(O_DIRECTORY)
+            .open(path)?;
+
+        Ok(DirHandle { file })
+    }
+
+    fn open_at(&self, name: &[u8], flags: c_int) -> WasiResult<File> {
+        let name = c_name(name)?;
+        let fd = unsafe {
+            openat(
+                self.file.as_raw_fd(),
+                name.as_ptr(),
+                flags | O_CLOEXEC | O_NOFOLLOW,
+                0o666,
+            )
+        };
+
+        if fd < 0 {
+            return Err(last_error());
+        }
+
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Returns the attributes of the directory.
+    pub(crate) fn filestat(&self) -> WasiResult<Filestat> {
+        Ok(filestat_from_metadata(&self.file.metadata()?))
+    }
+
+    /// Returns the name, type and inode of every entry but `.` and `..`.
+    pub(crate) fn entries(&self) -> WasiResult<Vec<(Vec<u8>, Filetype, Inode)>> {
+        // The stream takes ownership of a new file descriptor, so its position is not shared.
+        let fd = self.open_at(b".", O_RDONLY | O_DIRECTORY)?.into_raw_fd();
+        let dir = unsafe { fdopendir(fd) };
+        if dir.is_null() {
+            let errno = last_error();
+            unsafe { close(fd) };
+            return Err(errno);
+        }
+
+        let mut entries = Vec::new();
+        let result = loop {
+            errno::set_errno(errno::Errno(0));
+
+            let entry = unsafe { readdir(dir) };
+            if entry.is_null() {
+                match errno::errno().0 {
+                    0 => break Ok(entries),
+                    _ => break Err(last_error()),
+                }
+            }
+
+            let entry = unsafe { &*entry };
+            let name = unsafe { CStr::from_ptr(entry.d_name.as_ptr()) }.to_bytes();
+            if name == b"." || name == b".." {
+                continue;
+            }
+
+            let filetype = match entry.d_type {
+                DT_DIR => Filetype::Directory,
+                DT_REG => Filetype::RegularFile,
+                DT_LNK => Filetype::SymbolicLink,
+                DT_BLK => Filetype::BlockDevice,
+                DT_CHR => Filetype::CharacterDevice,
+                DT_SOCK => Filetype::SocketStream,
+                DT_UNKNOWN => match self.stat_at(name) {
+                    Ok(stat) => stat.filetype,
+                    // The entry was removed while reading the directory.
+                    Err(Errno::Noent) => continue,
+                    Err(errno) => break Err(errno),
+                },
+                _ => Filetype::Unknown,
+            };
+
+            // `d_ino` is not a `u64` on every platform.
+            #[allow(trivial_numeric_casts, clippy::unnecessary_cast)]
+            let inode = Inode(entry.d_ino as u64);
+
+            entries.push((name.to_vec(), filetype, inode));
+        };
+
+        unsafe { closedir(dir) };
+        result
+    }
+
+    /// Returns the attributes of `name`.
+    pub(crate) fn stat_at(&self, name: &[u8]) -> WasiResult<Filestat> {
+        let name = c_name(name)?;
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+
+        if unsafe {
+            fstatat(
+                self.file.as_raw_fd(),
+                name.as_ptr(),
+                &mut stat,
+                AT_SYMLINK_NOFOLLOW,
+            )
+        } < 0
+        {
+            return Err(last_error());
+        }
+
+        Ok(filestat_from_stat(&stat))
+    }
+
+    /// Returns the target of the symbolic link `name`.
+    pub(crate) fn read_link_at(&self, name: &[u8]) -> WasiResult<Vec<u8>> {
+        let name = c_name(name)?;
+        let mut buf = vec![0; 256];
+
+        loop {
+            let len = unsafe {
+                readlinkat(
+                    self.file.as_raw_fd(),
+                    name.as_ptr(),
+                    buf.as_mut_ptr() as *mut c_char,
+                    buf.len(),
+                )
+            };
+
+            if len < 0 {
+                return Err(last_error());
+            }
+            if (len as usize) < buf.len() {
+                buf.truncate(len as usize);
+                return Ok(buf);
+            }
+
+            // The target may have been truncated.
+            let len = buf.len() * 2;
+            buf.resize(len, 0);
+        }
+    }
+
+    /// Opens the directory `name`.
+    pub(crate) fn open_dir_at(&self, name: &[u8]) -> WasiResult<DirHandle> {
+        let file = self.open_at(name, O_RDONLY | O_DIRECTORY)?;
+
+        Ok(DirHandle { file })
+    }
+
+    /// Opens the file `name` with `options`.
+    pub(crate) fn open_file_at(&self, name: &[u8], options: &FileOptions) -> WasiResult<File> {
+        let mut flags = match (options.read, options.write) {
+            (true, true) => O_RDWR,
+            (false, true) => O_WRONLY,
+            _ => O_RDONLY,
+        };
+        if options.append {
+            flags |= O_APPEND;
+        }
+        if options.create_new {
+            flags |= O_CREAT | O_EXCL;
+        }
+        if options.truncate {
+            flags |= O_TRUNC;
+        }
+
+        self.open_at(name, flags)
+    }
+
+    /// Creates the directory `name`.
+    pub(crate) fn create_dir_at(&self, name: &[u8]) -> WasiResult<()> {
+        let name = c_name(name)?;
+
+        if unsafe { mkdirat(self.file.as_raw_fd(), name.as_ptr(), 0o777) } < 0 {
+            return Err(last_error());
+        }
+
+        Ok(())
+    }
+
+    /// Removes the empty directory `name`.
+    pub(crate) fn remove_dir_at(&self, name: &[u8]) -> WasiResult<()> {
+        let name = c_name(name)?;
+
+        if unsafe { unlinkat(self.file.as_raw_fd(), name.as_ptr(), AT_REMOVEDIR) } < 0 {
+            // Some systems report a directory that is not empty as existing.
+            return match last_error() {
+                Errno::Exist => Err(Errno::Notempty),
+                errno => Err(errno),
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Removes the file or symbolic link `name`.
+    pub(crate) fn unlink_at(&self, name: &[u8]) -> WasiResult<()> {
+        let name = c_name(name)?;
+
+        if unsafe { unlinkat(self.file.as_raw_fd(), name.as_ptr(), 0) } < 0 {
+            return Err(last_error());
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn read_at(file: &File, buf: &mut [u8], offset: u64) -> WasiResult<usize> {
+    Ok(file.read_at(buf, offset)?)
+}
+
+pub(crate) fn write_at(file: &File, buf: &[u8], offset: u64) -> WasiResult<usize> {
+    Ok(file.write_at(buf, offset)?)
+}
+
+/// Whether [`set_append`](fn.set_append.html) makes the host append on its own.
+pub(crate) const SETS_APPEND: bool = true;
+
+/// Makes every write to `file` append to it, or stops doing so.
+pub(crate) fn set_append(file: &File, append: bool) -> WasiResult<()> {
+    let fd = file.as_raw_fd();
+    let flags = unsafe { fcntl(fd, F_GETFL) };
+    if flags < 0 {
+        return Err(last_error());
+    }
+
+    let new_flags = if append {
+        flags | O_APPEND
+    } else {
+        flags & !O_APPEND
+    };
+
+    if new_flags != flags && unsafe { fcntl(fd, F_SETFL, new_flags) } < 0 {
+        return Err(last_error());
+    }
+
+    Ok(())
+}
+
+fn timespec_from_fstflags(
+    time: Timestamp,
+    fst_flags: Fstflags,
+    set: Fstflags,
+    now: Fstflags,
+) -> timespec {
+    if fst_flags.contains(set) {
+        timespec {
+            tv_sec: (time.0 / 1_000_000_000) as time_t,
+            tv_nsec: (time.0 % 1_000_000_000) as c_long,
+        }
+    } else if fst_flags.contains(now) {
+        timespec {
+            tv_sec: 0,
+            tv_nsec: UTIME_NOW,
+        }
+    } else {
+        timespec {
+            tv_sec: 0,
+            tv_nsec: UTIME_OMIT,
+        }
+    }
+}
+
+pub(crate) fn set_file_times(
+    file: &File,
+    atim: Timestamp,
+    mtim: Timestamp,
+    fst_flags: Fstflags,
+) -> WasiResult<()> {
+    let times = [
+        timespec_from_fstflags(atim, fst_flags, Fstflags::ATIM, Fstflags::ATIM_NOW),
+        timespec_from_fstflags(mtim, fst_flags, Fstflags::MTIM, Fstflags::MTIM_NOW),
+    ];
+
+    if unsafe { futimens(file.as_raw_fd(), times.as_ptr()) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error().into())
+    }
+}
+
+/// Polls `fd` for `events` without blocking, returning the events that occurred.
+fn poll_fd(fd: c_int, events: c_short, timeout: c_int) -> WasiResult<c_short> {
+    let mut fd = pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+
+    if unsafe { poll(&mut fd, 1, timeout) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(fd.revents)
+}
+
+fn readiness(revents: c_short, nbytes: c_int) -> Option<EventFdReadwrite> {
+    if revents == 0 {
+        return None;
+    }
+
+    let flags = if revents & POLLHUP != 0 {
+        Eventrwflags::FD_READWRITE_HANGUP
+    } else {
+        Eventrwflags::empty()
+    };
+
+    Some(EventFdReadwrite {
+        nbytes: Filesize(nbytes.max(0) as u64),
+        flags,
+    })
+}
+
+/// Reads from the standard input of the process without the buffer of `std::io::Stdin`, which
+/// would hold data that `read_readiness` can not see. Calls `interruption_point` regularly
+/// while no data arrives.
+pub(crate) fn read_stdin(
+    iovs: &mut [IoSliceMut<'_>],
+    interruption_point: impl Fn(),
+) -> WasiResult<usize> {
+    loop {
+        match poll_fd(STDIN_FILENO, POLLIN, 10) {
+            Ok(0) | Err(Errno::Intr) => interruption_point(),
+            Ok(_) => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    // The file descriptor belongs to the process and must not be closed.
+    let stdin = ManuallyDrop::new(unsafe { File::from_raw_fd(STDIN_FILENO) });
+
+    Ok((&*stdin).read_vectored(iovs)?)
+}
+
+pub(crate) fn read_readiness(fd: &impl AsRawFd) -> WasiResult<Option<EventFdReadwrite>> {
+    let fd = fd.as_raw_fd();
+    let revents = poll_fd(fd, POLLIN, 0)?;
+
+    let mut nbytes: c_int = 0;
+    if revents != 0 && unsafe { ioctl(fd, FIONREAD, &mut nbytes) } < 0 {
+        nbytes = 0;
+    }
+
+    Ok(readiness(revents, nbytes))
+}
+
+pub(crate) fn write_readiness(fd: &impl AsRawFd) -> WasiResult<Option<EventFdReadwrite>> {
+    Ok(readiness(poll_fd(fd.as_raw_fd(), POLLOUT, 0)?, 0))
+}
+
+pub(crate) fn sock_recv(
+    socket: &impl AsRawFd,
+    iovs: &mut [IoSliceMut<'_>],
+    flags: Riflags,
+) -> WasiResult<(Size, Roflags)> {
+    let mut recv_flags = 0;
+    if flags.contains(Riflags::RECV_PEEK) {
+        recv_flags |= MSG_PEEK;
+    }
+    if flags.contains(Riflags::RECV_WAITALL) {
+        recv_flags |= MSG_WAITALL;
+    }
+
+    #[allow(trivial_numeric_casts)] // `msg_iovlen` is not a `usize` on every platform.
+    let iovlen = iovs.len() as _;
+
+    // `IoSliceMut` is guaranteed to be ABI compatible with `iovec` on Unix.
+    let mut message: msghdr = unsafe { mem::zeroed() };
+    message.msg_iov = iovs.as_mut_ptr() as *mut iovec;
+    message.msg_iovlen = iovlen;
+
+    let received = unsafe { recvmsg(socket.as_raw_fd(), &mut message, recv_flags) };
+    if received < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    let roflags = if message.msg_flags & MSG_TRUNC != 0 {
+        Roflags::RECV_DATA_TRUNCATED
+    } else {
+        Roflags::empty()
+    };
+
+    Ok((Size(received as u32), roflags))
+}
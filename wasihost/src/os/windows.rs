@@ -1,9 +1,263 @@
-use wasihost_core::wasi_snapshot_preview1::{Clockid, Errno, Timestamp, WasiResult};
+use super::FileOptions;
+use std::{
+    fs::{self, File, FileType, Metadata, OpenOptions},
+    io::{self, stdin, IoSliceMut, Read, Seek, SeekFrom},
+    os::windows::fs::{FileExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    str,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use wasihost_core::wasi_snapshot_preview1::{
+    Clockid, Device, Errno, EventFdReadwrite, Eventrwflags, Filesize, Filestat, Filetype, Fstflags,
+    Inode, Linkcount, Riflags, Roflags, Size, Timestamp, WasiResult,
+};
 
-pub(crate) fn preview1_clock_res_get(id: Clockid) -> WasiResult<Timestamp> {
+pub(crate) fn preview1_clock_res_get(_id: Clockid) -> WasiResult<Timestamp> {
     Err(Errno::Nosys)
 }
 
-pub(crate) fn preview1_clock_time_get(id: Clockid, precision: Timestamp) -> WasiResult<Timestamp> {
+pub(crate) fn preview1_clock_time_get(
+    _id: Clockid,
+    _precision: Timestamp,
+) -> WasiResult<Timestamp> {
+    Err(Errno::Nosys)
+}
+
+fn timestamp_from_system_time(time: io::Result<SystemTime>) -> Timestamp {
+    time.ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| Timestamp(duration.as_nanos() as u64))
+        .unwrap_or(Timestamp(0))
+}
+
+pub(crate) fn filetype_from_file_type(file_type: FileType) -> Filetype {
+    if file_type.is_dir() {
+        Filetype::Directory
+    } else if file_type.is_file() {
+        Filetype::RegularFile
+    } else if file_type.is_symlink() {
+        Filetype::SymbolicLink
+    } else {
+        Filetype::Unknown
+    }
+}
+
+pub(crate) fn filestat_from_metadata(metadata: &Metadata) -> Filestat {
+    Filestat {
+        dev: Device(0),
+        ino: Inode(0),
+        filetype: filetype_from_file_type(metadata.file_type()),
+        nlink: Linkcount(1),
+        size: Filesize(metadata.len()),
+        atim: timestamp_from_system_time(metadata.accessed()),
+        mtim: timestamp_from_system_time(metadata.modified()),
+        ctim: timestamp_from_system_time(metadata.created()),
+    }
+}
+
+/// `FILE_FLAG_OPEN_REPARSE_POINT`, which opens a symbolic link itself instead of its target.
+const FILE_FLAG_OPEN_REPARSE_POINT: u32 = 0x0020_0000;
+/// `FILE_FLAG_BACKUP_SEMANTICS`, which is required to open a directory.
+const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+/// `FILE_SHARE_READ | FILE_SHARE_WRITE`, which lets others use an opened directory but not
+/// move or delete it.
+const FILE_SHARE_READ_WRITE: u32 = 0x0000_0003;
+/// `ERROR_DIR_NOT_EMPTY`.
+const ERROR_DIR_NOT_EMPTY: i32 = 145;
+
+/// A directory on the host that paths are resolved relative to one component at a time. All
+/// names passed to it are single path components and symbolic links in them are never
+/// followed.
+///
+/// The directory is kept open without sharing the right to delete it, so it can not be moved
+/// or replaced while the handle exists and its path keeps leading to it.
+#[derive(Debug)]
+pub(crate) struct DirHandle {
+    path: PathBuf,
+    file: File,
+}
+
+impl DirHandle {
+    /// Opens the directory `path`.
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let path = path.canonicalize()?;
+        let file = OpenOptions::new()
+            .read(true)
+            .share_mode(FILE_SHARE_READ_WRITE)
+            .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+            .open(&path)?;
+
+        Ok(DirHandle { path, file })
+    }
+
+    /// Returns the path of the entry `name`, which must be a single path component.
+    fn child(&self, name: &[u8]) -> WasiResult<PathBuf> {
+        let name = str::from_utf8(name).map_err(|_| Errno::Ilseq)?;
+
+        if name.is_empty() || name.contains(|c| c == '/' || c == '\\' || c == ':') {
+            return Err(Errno::Notcapable);
+        }
+
+        Ok(self.path.join(name))
+    }
+
+    /// Returns the attributes of the directory.
+    pub(crate) fn filestat(&self) -> WasiResult<Filestat> {
+        Ok(filestat_from_metadata(&self.file.metadata()?))
+    }
+
+    /// Returns the name, type and inode of every entry but `.` and `..`.
+    pub(crate) fn entries(&self) -> WasiResult<Vec<(Vec<u8>, Filetype, Inode)>> {
+        let mut entries = Vec::new();
+
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let name = entry.file_name().into_string().map_err(|_| Errno::Ilseq)?;
+
+            entries.push((
+                name.into_bytes(),
+                filetype_from_file_type(entry.file_type()?),
+                Inode(0),
+            ));
+        }
+
+        Ok(entries)
+    }
+
+    /// Returns the attributes of `name`.
+    pub(crate) fn stat_at(&self, name: &[u8]) -> WasiResult<Filestat> {
+        Ok(filestat_from_metadata(&fs::symlink_metadata(
+            self.child(name)?,
+        )?))
+    }
+
+    /// Returns the target of the symbolic link `name`, with `/` as the separator.
+    pub(crate) fn read_link_at(&self, name: &[u8]) -> WasiResult<Vec<u8>> {
+        let target = fs::read_link(self.child(name)?)?;
+        let target = target.to_str().ok_or(Errno::Ilseq)?;
+
+        Ok(target.replace('\\', "/").into_bytes())
+    }
+
+    /// Opens the directory `name`.
+    pub(crate) fn open_dir_at(&self, name: &[u8]) -> WasiResult<DirHandle> {
+        let path = self.child(name)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .share_mode(FILE_SHARE_READ_WRITE)
+            .custom_flags(FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OPEN_REPARSE_POINT)
+            .open(&path)?;
+
+        let file_type = file.metadata()?.file_type();
+        if file_type.is_symlink() {
+            return Err(Errno::Loop);
+        }
+        if !file_type.is_dir() {
+            return Err(Errno::Notdir);
+        }
+
+        Ok(DirHandle { path, file })
+    }
+
+    /// Opens the file `name` with `options`.
+    pub(crate) fn open_file_at(&self, name: &[u8], options: &FileOptions) -> WasiResult<File> {
+        let file = OpenOptions::new()
+            .read(options.read)
+            .write(options.write)
+            .append(options.append)
+            .create_new(options.create_new)
+            .truncate(options.truncate)
+            .custom_flags(FILE_FLAG_OPEN_REPARSE_POINT)
+            .open(self.child(name)?)?;
+
+        if file.metadata()?.file_type().is_symlink() {
+            return Err(Errno::Loop);
+        }
+
+        Ok(file)
+    }
+
+    /// Creates the directory `name`.
+    pub(crate) fn create_dir_at(&self, name: &[u8]) -> WasiResult<()> {
+        Ok(fs::create_dir(self.child(name)?)?)
+    }
+
+    /// Removes the empty directory `name`.
+    pub(crate) fn remove_dir_at(&self, name: &[u8]) -> WasiResult<()> {
+        fs::remove_dir(self.child(name)?).map_err(|error| match error.raw_os_error() {
+            Some(ERROR_DIR_NOT_EMPTY) => Errno::Notempty,
+            _ => error.into(),
+        })
+    }
+
+    /// Removes the file or symbolic link `name`.
+    pub(crate) fn unlink_at(&self, name: &[u8]) -> WasiResult<()> {
+        Ok(fs::remove_file(self.child(name)?)?)
+    }
+}
+
+/// Runs `f`, which moves the cursor of `file`, and moves the cursor back.
+fn keep_cursor<T>(file: &File, f: impl FnOnce() -> io::Result<T>) -> WasiResult<T> {
+    let cursor = Seek::seek(&mut &*file, SeekFrom::Current(0))?;
+    let result = f();
+    Seek::seek(&mut &*file, SeekFrom::Start(cursor))?;
+
+    Ok(result?)
+}
+
+// Positional reads and writes move the cursor on Windows.
+pub(crate) fn read_at(file: &File, buf: &mut [u8], offset: u64) -> WasiResult<usize> {
+    keep_cursor(file, || file.seek_read(buf, offset))
+}
+
+pub(crate) fn write_at(file: &File, buf: &[u8], offset: u64) -> WasiResult<usize> {
+    keep_cursor(file, || file.seek_write(buf, offset))
+}
+
+/// Whether [`set_append`](fn.set_append.html) makes the host append on its own.
+pub(crate) const SETS_APPEND: bool = false;
+
+/// Does nothing, as the append mode of a file can not be changed after opening it on this
+/// platform.
+pub(crate) fn set_append(_file: &File, _append: bool) -> WasiResult<()> {
+    Ok(())
+}
+
+pub(crate) fn set_file_times(
+    _file: &File,
+    _atim: Timestamp,
+    _mtim: Timestamp,
+    _fst_flags: Fstflags,
+) -> WasiResult<()> {
+    Err(Errno::Nosys)
+}
+
+/// Standard input is always reported as ready, so it can be read through its buffer. Reads
+/// block without calling `interruption_point`, as there is no portable way to wait for input.
+pub(crate) fn read_stdin(
+    iovs: &mut [IoSliceMut<'_>],
+    _interruption_point: impl Fn(),
+) -> WasiResult<usize> {
+    Ok(stdin().lock().read_vectored(iovs)?)
+}
+
+/// File descriptors are always reported as ready, as there is no portable way to check.
+pub(crate) fn read_readiness<T: ?Sized>(_fd: &T) -> WasiResult<Option<EventFdReadwrite>> {
+    Ok(Some(EventFdReadwrite {
+        nbytes: Filesize(0),
+        flags: Eventrwflags::empty(),
+    }))
+}
+
+/// File descriptors are always reported as ready, as there is no portable way to check.
+pub(crate) fn write_readiness<T: ?Sized>(fd: &T) -> WasiResult<Option<EventFdReadwrite>> {
+    read_readiness(fd)
+}
+
+pub(crate) fn sock_recv<T: ?Sized>(
+    _socket: &T,
+    _iovs: &mut [IoSliceMut<'_>],
+    _flags: Riflags,
+) -> WasiResult<(Size, Roflags)> {
     Err(Errno::Nosys)
 }
@@ -0,0 +1,473 @@
+use super::{
+    layers::Layers, run::LimitKind, trace::Tracer, CharacterDevice, ClockProvider,
+    FdAllocationPolicy, MemoryFs, OsRandom, PreopenWasiFdInitializer, RandomSource, Recorder,
+    ResourceLimits, SeededRandom, SystemClock, TraceSink, VirtualClock, WasiDirectory, WasiFd,
+    WasiHost, WasiSocket,
+};
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use std::{
+    error::Error,
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use wasihost_core::{
+    wasi_snapshot_preview1::{native::NativeWasiImportsLayer, Rights},
+    StringRepresentation,
+};
+
+/// The reasons a WASI host could not be built.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BuildError {
+    /// An argument contains a NUL byte or can not be represented by the string
+    /// representation.
+    InvalidArgument(Vec<u8>),
+    /// An environment variable is not of the form `key=value`, contains a NUL byte or can not
+    /// be represented by the string representation.
+    InvalidEnvironmentVariable(Vec<u8>),
+    /// A host directory could not be preopened.
+    Preopen(PathBuf, io::Error),
+    /// The arguments and environment variables exceed the resource limits.
+    LimitExceeded(LimitKind),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::InvalidArgument(argument) => write!(
+                f,
+                "invalid argument {:?}",
+                String::from_utf8_lossy(argument)
+            ),
+            BuildError::InvalidEnvironmentVariable(variable) => write!(
+                f,
+                "invalid environment variable {:?}",
+                String::from_utf8_lossy(variable)
+            ),
+            BuildError::Preopen(path, error) => {
+                write!(f, "unable to preopen {}: {}", path.display(), error)
+            }
+            BuildError::LimitExceeded(kind) => write!(f, "resource limit exceeded: {:?}", kind),
+        }
+    }
+}
+
+impl Error for BuildError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BuildError::Preopen(_, error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Configures a new [`WasiHost`](struct.WasiHost.html).
+///
+/// Invalid arguments, environment variables and preopens are reported by
+/// [`build`](#method.build), which reports the first error.
+#[derive(Debug)]
+pub struct WasiHostBuilder<S> {
+    arguments: Vec<S>,
+    environment: Vec<S>,
+    fds: PreopenWasiFdInitializer<S>,
+    error: Option<BuildError>,
+    seed: Option<u64>,
+    clock: Option<Box<dyn ClockProvider>>,
+    random: Option<Box<dyn RandomSource>>,
+    fd_allocation_policy: FdAllocationPolicy,
+    limits: ResourceLimits,
+    timeout: Option<Duration>,
+    trace_sink: Option<Tracer>,
+    layers: Layers,
+    recorder: Option<Recorder>,
+    metrics: bool,
+}
+
+impl<S: StringRepresentation> WasiHostBuilder<S> {
+    /// Creates a builder for a WASI host without arguments, environment variables and
+    /// preopened directories, that uses the standard streams of the host.
+    pub fn new() -> Self {
+        WasiHostBuilder {
+            arguments: Vec::new(),
+            environment: Vec::new(),
+            fds: PreopenWasiFdInitializer::new(),
+            error: None,
+            seed: None,
+            clock: None,
+            random: None,
+            fd_allocation_policy: FdAllocationPolicy::default(),
+            limits: ResourceLimits::default(),
+            timeout: None,
+            trace_sink: None,
+            layers: Layers::default(),
+            recorder: None,
+            metrics: false,
+        }
+    }
+
+    fn fail(&mut self, error: BuildError) {
+        self.error.get_or_insert(error);
+    }
+
+    fn string(bytes: &[u8]) -> Option<S> {
+        if bytes.contains(&0) {
+            None
+        } else {
+            S::from_bytes(bytes.to_vec()).ok()
+        }
+    }
+
+    /// Appends an argument. The first argument is usually the name of the program.
+    pub fn arg(mut self, argument: impl Into<Vec<u8>>) -> Self {
+        let argument = argument.into();
+
+        match Self::string(&argument) {
+            Some(argument) => self.arguments.push(argument),
+            None => self.fail(BuildError::InvalidArgument(argument)),
+        }
+        self
+    }
+
+    /// Appends several arguments.
+    pub fn args(self, arguments: impl IntoIterator<Item = impl Into<Vec<u8>>>) -> Self {
+        arguments.into_iter().fold(self, Self::arg)
+    }
+
+    /// Appends the environment variable `key` with `value`. The key must not be empty or
+    /// contain `=`.
+    pub fn env(mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Self {
+        let mut variable = key.into();
+        let valid_key = !variable.is_empty() && !variable.contains(&b'=');
+
+        variable.push(b'=');
+        variable.extend(value.into());
+
+        if !valid_key {
+            self.fail(BuildError::InvalidEnvironmentVariable(variable));
+            return self;
+        }
+        self.env_entry(variable)
+    }
+
+    /// Appends an environment variable of the form `key=value`.
+    pub fn env_entry(mut self, variable: impl Into<Vec<u8>>) -> Self {
+        let variable = variable.into();
+        let has_key = matches!(variable.iter().position(|&b| b == b'='), Some(i) if i > 0);
+
+        match Self::string(&variable).filter(|_| has_key) {
+            Some(variable) => self.environment.push(variable),
+            None => self.fail(BuildError::InvalidEnvironmentVariable(variable)),
+        }
+        self
+    }
+
+    /// Appends several environment variables of the form `key=value`.
+    pub fn env_entries(self, variables: impl IntoIterator<Item = impl Into<Vec<u8>>>) -> Self {
+        variables.into_iter().fold(self, Self::env_entry)
+    }
+
+    /// Uses `device` as standard input instead of the host's standard input.
+    pub fn stdin(mut self, device: impl CharacterDevice) -> Self {
+        self.fds = self.fds.stdin(device);
+        self
+    }
+
+    /// Uses `device` as standard output instead of the host's standard output.
+    pub fn stdout(mut self, device: impl CharacterDevice) -> Self {
+        self.fds = self.fds.stdout(device);
+        self
+    }
+
+    /// Uses `device` as standard error instead of the host's standard error.
+    pub fn stderr(mut self, device: impl CharacterDevice) -> Self {
+        self.fds = self.fds.stderr(device);
+        self
+    }
+
+    /// Preopens the host directory `path`, which is presented to the WASI binary as `name`.
+    pub fn host_directory(mut self, path: impl AsRef<Path>, name: impl Into<Vec<u8>>) -> Self {
+        let rights = Rights::all();
+
+        match WasiFd::from_host_directory(&path, name, rights, rights) {
+            Ok(fd) => self.fds.preopens.push(fd),
+            Err(error) => self.fail(BuildError::Preopen(path.as_ref().to_owned(), error)),
+        }
+        self
+    }
+
+    /// Preopens `directory`, which is presented to the WASI binary as `name`.
+    pub fn directory(mut self, directory: impl WasiDirectory, name: impl Into<Vec<u8>>) -> Self {
+        self.fds = self.fds.directory(directory, name);
+        self
+    }
+
+    /// Preopens the root directory of the in-memory file system `fs`, which is presented to
+    /// the WASI binary as `name`.
+    pub fn memory_fs(mut self, fs: MemoryFs, name: impl Into<Vec<u8>>) -> Self {
+        self.fds = self.fds.memory_fs(fs, name);
+        self
+    }
+
+    /// Hands the connected or listening socket `socket` to the WASI binary.
+    pub fn socket(mut self, socket: impl WasiSocket) -> Self {
+        self.fds = self.fds.socket(socket);
+        self
+    }
+
+    /// Makes runs reproducible like
+    /// [`WasiHost::new_deterministic`](struct.WasiHost.html#method.new_deterministic). Clocks
+    /// and random sources that are set explicitly are used instead of the virtual ones.
+    pub fn deterministic(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Sets the clocks observed by the WASI binary.
+    pub fn clock_provider(mut self, clock: impl ClockProvider) -> Self {
+        self.clock = Some(Box::new(clock));
+        self
+    }
+
+    /// Sets the source of the random data returned to the WASI binary.
+    pub fn random_source(mut self, random: impl RandomSource) -> Self {
+        self.random = Some(Box::new(random));
+        self
+    }
+
+    /// Sets how the numbers of newly opened file descriptors are chosen.
+    pub fn fd_allocation_policy(mut self, policy: FdAllocationPolicy) -> Self {
+        self.fd_allocation_policy = policy;
+        self
+    }
+
+    /// Limits the resources the WASI binary may use.
+    pub fn resource_limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Limits the wall-clock time of each run.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Reports every system call of the WASI binary to `sink`.
+    pub fn trace_sink(mut self, sink: impl TraceSink) -> Self {
+        self.trace_sink = Some(Tracer(Arc::new(sink)));
+        self
+    }
+
+    /// Passes every system call of the WASI binary through the hooks of `layer`, see
+    /// [`WasiHost::add_layer`](struct.WasiHost.html#method.add_layer).
+    pub fn layer(mut self, layer: impl NativeWasiImportsLayer) -> Self {
+        self.layers.push(Arc::new(layer));
+        self
+    }
+
+    /// Records the system calls of the first run with `recorder`, see
+    /// [`WasiHost::record_next_run`](struct.WasiHost.html#method.record_next_run).
+    pub fn recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Collects the metrics of the system calls of the WASI binary, see
+    /// [`WasiHost::collect_metrics`](struct.WasiHost.html#method.collect_metrics).
+    pub fn collect_metrics(mut self) -> Self {
+        self.metrics = true;
+        self
+    }
+
+    /// Creates the WASI host.
+    pub fn build(self) -> Result<Arc<WasiHost<S>>, BuildError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+
+        self.limits
+            .check_args_environ(&self.arguments, &self.environment)
+            .map_err(BuildError::LimitExceeded)?;
+
+        let seed = self.seed;
+        let fd_rng = seed.map_or_else(ChaCha20Rng::from_entropy, ChaCha20Rng::seed_from_u64);
+        let clock = self.clock.unwrap_or_else(|| match seed {
+            Some(_) => Box::new(VirtualClock::new()),
+            None => Box::new(SystemClock::new()),
+        });
+        let random = self.random.unwrap_or_else(|| match seed {
+            Some(seed) => Box::new(SeededRandom::new(seed)),
+            None => Box::new(OsRandom),
+        });
+
+        let host = WasiHost::with_sources(
+            self.arguments,
+            self.environment,
+            self.fds,
+            fd_rng,
+            clock,
+            random,
+        );
+
+        host.set_fd_allocation_policy(self.fd_allocation_policy);
+        host.set_resource_limits(self.limits);
+        host.set_timeout(self.timeout);
+        *host.trace_sink.write() = self.trace_sink;
+        *host.layers.write() = self.layers;
+        *host.recorder.lock() = self.recorder;
+        if self.metrics {
+            host.collect_metrics();
+        }
+
+        Ok(host)
+    }
+}
+
+impl<S: StringRepresentation> Default for WasiHostBuilder<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasi_snapshot_preview1::{CaptureDevice, FixedClock};
+    use std::io::IoSlice;
+    use wasihost_core::wasi_snapshot_preview1::{Clockid, Fd, Size, Timestamp, WasiImports};
+
+    fn builder() -> WasiHostBuilder<String> {
+        WasiHostBuilder::new()
+    }
+
+    #[test]
+    fn passes_arguments_and_environment() {
+        let host = builder()
+            .arg("prog")
+            .args(vec!["-v", "file"])
+            .env("HOME", "/home/user")
+            .env_entries(vec!["EMPTY="])
+            .build()
+            .unwrap();
+
+        assert_eq!(host.args_get().unwrap(), ["prog", "-v", "file"]);
+        assert_eq!(host.environ_get().unwrap(), ["HOME=/home/user", "EMPTY="]);
+    }
+
+    #[test]
+    fn reports_the_first_invalid_argument_or_variable() {
+        assert!(matches!(
+            builder().arg("a\0b").env("=", "x").build(),
+            Err(BuildError::InvalidArgument(ref argument)) if argument == b"a\0b"
+        ));
+        assert!(matches!(
+            builder().env("A=B", "x").build(),
+            Err(BuildError::InvalidEnvironmentVariable(ref variable)) if variable == b"A=B=x"
+        ));
+        assert!(matches!(
+            builder().env_entry("NOVALUE").build(),
+            Err(BuildError::InvalidEnvironmentVariable(_))
+        ));
+        assert!(matches!(
+            builder().env_entry("=value").build(),
+            Err(BuildError::InvalidEnvironmentVariable(_))
+        ));
+    }
+
+    #[test]
+    fn reports_missing_host_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+
+        match builder().host_directory(&missing, "/data").build() {
+            Err(BuildError::Preopen(path, _)) => assert_eq!(path, missing),
+            result => panic!("unexpected result {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn checks_the_size_of_arguments_and_environment() {
+        let limits = ResourceLimits {
+            max_args_environ_size: Some(8),
+            ..ResourceLimits::default()
+        };
+
+        assert!(builder()
+            .arg("1234567")
+            .resource_limits(limits.clone())
+            .build()
+            .is_ok());
+        assert!(matches!(
+            builder().arg("12345678").resource_limits(limits).build(),
+            Err(BuildError::LimitExceeded(LimitKind::ArgsEnviron))
+        ));
+    }
+
+    #[test]
+    fn preopens_directories_after_the_standard_streams() {
+        let stdout = CaptureDevice::new();
+        let host = builder()
+            .stdout(stdout.clone())
+            .memory_fs(MemoryFs::new(), "/data")
+            .build()
+            .unwrap();
+
+        assert_eq!(host.fd_prestat_dir_name(Fd(3)).unwrap(), "/data");
+        assert_eq!(
+            host.fd_write(Fd(1), &[IoSlice::new(b"hello")]).unwrap(),
+            Size(5)
+        );
+        assert_eq!(stdout.contents(), b"hello");
+    }
+
+    #[test]
+    fn deterministic_hosts_repeat_clocks_and_random_data() {
+        let sample = |host: Arc<WasiHost<String>>| {
+            let mut random = [0; 16];
+            host.random_get(&mut random).unwrap();
+            let time = host
+                .clock_time_get(Clockid::Realtime, Timestamp(0))
+                .unwrap();
+
+            (random, time)
+        };
+
+        let first = sample(builder().deterministic(7).build().unwrap());
+        assert_eq!(first, sample(builder().deterministic(7).build().unwrap()));
+        assert_ne!(
+            first.0,
+            sample(builder().deterministic(8).build().unwrap()).0
+        );
+
+        let host = builder()
+            .deterministic(7)
+            .clock_provider(FixedClock::new(Timestamp(42)))
+            .build()
+            .unwrap();
+        assert_eq!(
+            host.clock_time_get(Clockid::Realtime, Timestamp(0))
+                .unwrap(),
+            Timestamp(42)
+        );
+    }
+
+    #[test]
+    fn memory_file_systems_take_timestamps_from_the_host_clocks() {
+        let fs = MemoryFs::new();
+        let host = builder()
+            .memory_fs(fs.clone(), "/data")
+            .clock_provider(FixedClock::new(Timestamp(42)))
+            .build()
+            .unwrap();
+
+        fs.write("file", "contents").unwrap();
+        assert_eq!(fs.metadata("file").unwrap().mtim, Timestamp(42));
+
+        host.set_clock_provider(FixedClock::new(Timestamp(43)));
+        fs.write("file", "changed").unwrap();
+        assert_eq!(fs.metadata("file").unwrap().mtim, Timestamp(43));
+    }
+}
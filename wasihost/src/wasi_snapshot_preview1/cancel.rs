@@ -0,0 +1,617 @@
+use super::{leb128, run::RunError};
+use parking_lot::{const_mutex, Condvar, Mutex, MutexGuard, RwLock};
+use std::{
+    cell::RefCell,
+    fmt, panic,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Once,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use wasmer_runtime::{Export, Global, Instance, Value};
+use wasmparser::{
+    CodeSectionReader, GlobalSectionReader, ImportSectionEntryType, ImportSectionReader, Operator,
+};
+
+/// The reason a run was stopped before the WASI binary exited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Interrupted {
+    Cancelled,
+    TimedOut,
+}
+
+impl Interrupted {
+    /// The value of the interrupt global that makes instrumented WASM code trap.
+    fn code(self) -> i32 {
+        match self {
+            Interrupted::Cancelled => 1,
+            Interrupted::TimedOut => 2,
+        }
+    }
+
+    fn from_code(code: i32) -> Option<Self> {
+        match code {
+            1 => Some(Interrupted::Cancelled),
+            2 => Some(Interrupted::TimedOut),
+            _ => None,
+        }
+    }
+}
+
+/// Cancels the runs of a WASI host from another thread.
+///
+/// Clones cancel the same WASI host. Once cancelled, all further runs of the WASI host are
+/// cancelled as well, until the cancellation is [reset](#method.reset).
+#[derive(Clone, Debug)]
+pub struct CancellationHandle {
+    state: Arc<State>,
+}
+
+impl CancellationHandle {
+    /// Cancels the WASI host.
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::SeqCst);
+        self.state.run.lock().interrupt(Interrupted::Cancelled);
+    }
+
+    /// Returns whether the WASI host has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Withdraws the cancellation, so that the WASI host can be run again. Runs that are
+    /// already being stopped are not resumed.
+    pub fn reset(&self) {
+        self.state.cancelled.store(false, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    cancelled: AtomicBool,
+    timeout: RwLock<Option<Duration>>,
+    run: Mutex<Run>,
+}
+
+/// The run in progress.
+#[derive(Default)]
+struct Run {
+    /// Counts the runs, so that the watchdog can tell whether a run has ended.
+    generation: u64,
+    deadline: Option<Instant>,
+    /// The interrupt global of the instance that runs, see [`instrument`](fn.instrument.html).
+    global: Option<Global>,
+}
+
+impl Run {
+    fn interrupt(&self, interrupted: Interrupted) {
+        if let Some(ref global) = self.global {
+            global.set(Value::I32(interrupted.code()));
+        }
+    }
+}
+
+impl fmt::Debug for Run {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Run")
+            .field("generation", &self.generation)
+            .field("deadline", &self.deadline)
+            .field("instrumented", &self.global.is_some())
+            .finish()
+    }
+}
+
+thread_local! {
+    /// The interrupt of the run whose WASM code executes on this thread.
+    static CURRENT: RefCell<Option<Arc<State>>> = const { RefCell::new(None) };
+}
+
+/// How often blocking waits check whether the run on their thread was interrupted.
+const WAIT_STEP: Duration = Duration::from_millis(10);
+
+/// Decides whether the running WASI binary has to be stopped.
+#[derive(Debug, Default)]
+pub(super) struct Interrupt {
+    state: Arc<State>,
+}
+
+impl Interrupt {
+    pub(super) fn handle(&self) -> CancellationHandle {
+        CancellationHandle {
+            state: self.state.clone(),
+        }
+    }
+
+    pub(super) fn set_timeout(&self, timeout: Option<Duration>) {
+        *self.state.timeout.write() = timeout;
+    }
+
+    /// Starts measuring the timeout for a new run.
+    pub(super) fn start(&self) {
+        let timeout = *self.state.timeout.read();
+        let mut run = self.state.run.lock();
+
+        run.deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    }
+
+    pub(super) fn check(&self) -> Option<Interrupted> {
+        self.state.check()
+    }
+
+    /// Traps the running WASI binary if it has to be stopped.
+    ///
+    /// Must only be called from host functions, where the runtime turns the unwinding into a
+    /// trap carrying the reason.
+    pub(super) fn checkpoint(&self) {
+        if let Some(interrupted) = self.check() {
+            panic::resume_unwind(Box::new(interrupted));
+        }
+    }
+
+    /// Calls `f`, which executes WASM code of `instance` on this thread, so that the run can
+    /// be stopped while the code computes or the host blocks. Also returns the reason if the
+    /// instrumented code of `instance` trapped because the run was interrupted.
+    pub(super) fn run<R>(
+        &self,
+        instance: &Instance,
+        f: impl FnOnce() -> R,
+    ) -> (R, Option<Interrupted>) {
+        let global = instance.exports().find_map(|(name, export)| match export {
+            Export::Global(global) if name == INTERRUPT_EXPORT => Some(global),
+            _ => None,
+        });
+        if let Some(ref global) = global {
+            global.set(Value::I32(self.check().map_or(0, Interrupted::code)));
+        }
+
+        let (generation, deadline) = {
+            let mut run = self.state.run.lock();
+
+            run.generation += 1;
+            run.global = global.clone();
+            (run.generation, run.deadline.filter(|_| global.is_some()))
+        };
+        if let Some(deadline) = deadline {
+            WATCHDOG.watch(&self.state, generation, deadline);
+        }
+
+        let previous = CURRENT.with(|current| current.replace(Some(self.state.clone())));
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(f));
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+
+        {
+            let mut run = self.state.run.lock();
+
+            if run.generation == generation {
+                run.generation += 1;
+                run.global = None;
+            }
+        }
+        if deadline.is_some() {
+            WATCHDOG.unwatch(&self.state, generation);
+        }
+
+        let interrupted = match global.map(|global| global.get()) {
+            Some(Value::I32(code)) => Interrupted::from_code(code),
+            _ => None,
+        };
+
+        match result {
+            Ok(result) => (result, interrupted),
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+}
+
+impl State {
+    fn check(&self) -> Option<Interrupted> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            Some(Interrupted::Cancelled)
+        } else if matches!(self.run.lock().deadline, Some(deadline) if Instant::now() >= deadline) {
+            Some(Interrupted::TimedOut)
+        } else {
+            None
+        }
+    }
+}
+
+/// A run to interrupt when its timeout expires.
+struct Watch {
+    state: Arc<State>,
+    generation: u64,
+    deadline: Instant,
+}
+
+/// Interrupts runs when their timeout expires. A single thread, started with the first
+/// timeout, watches the runs of all WASI hosts.
+struct Watchdog {
+    watches: Mutex<Vec<Watch>>,
+    /// Signalled when a run is watched, as its deadline may be the earliest one.
+    changed: Condvar,
+}
+
+static WATCHDOG: Watchdog = Watchdog {
+    watches: const_mutex(Vec::new()),
+    changed: Condvar::new(),
+};
+
+static WATCHDOG_STARTED: Once = Once::new();
+
+impl Watchdog {
+    /// Interrupts the run `generation` of `state` at `deadline`, unless it is unwatched
+    /// before.
+    fn watch(&'static self, state: &Arc<State>, generation: u64, deadline: Instant) {
+        WATCHDOG_STARTED.call_once(|| {
+            thread::Builder::new()
+                .name("wasihost-watchdog".to_string())
+                .spawn(move || self.run())
+                .expect("failed to spawn the watchdog thread");
+        });
+
+        self.watches.lock().push(Watch {
+            state: state.clone(),
+            generation,
+            deadline,
+        });
+        self.changed.notify_one();
+    }
+
+    /// Stops watching the run `generation` of `state`, which has ended.
+    fn unwatch(&self, state: &Arc<State>, generation: u64) {
+        self.watches
+            .lock()
+            .retain(|watch| !(Arc::ptr_eq(&watch.state, state) && watch.generation == generation));
+    }
+
+    fn run(&self) {
+        let mut watches = self.watches.lock();
+
+        loop {
+            let now = Instant::now();
+            let mut expired = Vec::new();
+            let mut i = 0;
+            while i < watches.len() {
+                if watches[i].deadline <= now {
+                    expired.push(watches.swap_remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+
+            // The runs are interrupted without holding the lock, which runs that start take
+            // after their own one.
+            if !expired.is_empty() {
+                MutexGuard::unlocked(&mut watches, || {
+                    for watch in expired {
+                        let run = watch.state.run.lock();
+
+                        if run.generation == watch.generation {
+                            run.interrupt(Interrupted::TimedOut);
+                        }
+                    }
+                });
+                continue;
+            }
+
+            match watches.iter().map(|watch| watch.deadline).min() {
+                Some(deadline) => {
+                    self.changed.wait_until(&mut watches, deadline);
+                }
+                None => self.changed.wait(&mut watches),
+            }
+        }
+    }
+}
+
+fn current() -> Option<Arc<State>> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Traps the WASI binary running on this thread if it has to be stopped.
+///
+/// Like [`Interrupt::checkpoint`](struct.Interrupt.html#method.checkpoint), but for code that
+/// does not know the WASI host, like devices. Does nothing outside of runs.
+pub(super) fn interruption_point() {
+    if let Some(interrupted) = current().and_then(|state| state.check()) {
+        panic::resume_unwind(Box::new(interrupted));
+    }
+}
+
+/// Waits for `condvar` like `Condvar::wait`, but wakes up regularly to stop the WASI binary
+/// running on this thread if it has to be stopped. May return spuriously.
+pub(super) fn wait<T>(condvar: &Condvar, guard: &mut MutexGuard<'_, T>) {
+    if current().is_none() {
+        return condvar.wait(guard);
+    }
+
+    interruption_point();
+    condvar.wait_for(guard, WAIT_STEP);
+    interruption_point();
+}
+
+/// Parks the current thread like `thread::park`, but wakes up regularly to stop the WASI
+/// binary running on this thread if it has to be stopped. May return spuriously.
+pub(super) fn park() {
+    if current().is_none() {
+        return thread::park();
+    }
+
+    interruption_point();
+    thread::park_timeout(WAIT_STEP);
+    interruption_point();
+}
+
+/// Sleeps like `thread::sleep`, but wakes up regularly to stop the WASI binary running on this
+/// thread if it has to be stopped.
+pub(super) fn sleep(duration: Duration) {
+    let end = match Instant::now().checked_add(duration) {
+        Some(end) if current().is_some() => end,
+        _ => return thread::sleep(duration),
+    };
+
+    loop {
+        interruption_point();
+
+        let now = Instant::now();
+        if now >= end {
+            return;
+        }
+        thread::sleep((end - now).min(WAIT_STEP));
+    }
+}
+
+/// The exported global that interrupts WASM code instrumented by
+/// [`instrument`](fn.instrument.html).
+const INTERRUPT_EXPORT: &str = "wasihost:interrupt";
+
+const CUSTOM_SECTION: u8 = 0;
+const IMPORT_SECTION: u8 = 2;
+const GLOBAL_SECTION: u8 = 6;
+const EXPORT_SECTION: u8 = 7;
+const CODE_SECTION: u8 = 10;
+const DATA_COUNT_SECTION: u8 = 12;
+
+/// Returns the position of a known section in the order the sections have to appear in.
+fn section_order(id: u8) -> u8 {
+    match id {
+        DATA_COUNT_SECTION => CODE_SECTION,
+        CODE_SECTION | 11 => id + 1,
+        _ => id,
+    }
+}
+
+/// Prepends a vector of entries with its length, after adding `entry`.
+fn append_entry(entries: &[u8], entry: &[u8]) -> Option<Vec<u8>> {
+    let mut position = 0;
+    let count = leb128::read_u32(entries, &mut position)?.checked_add(1)?;
+    let mut appended = Vec::with_capacity(entries.len() + entry.len() + 1);
+
+    leb128::write_u32(&mut appended, count);
+    appended.extend_from_slice(&entries[position..]);
+    appended.extend_from_slice(entry);
+    Some(appended)
+}
+
+/// Inserts `check` at the start of every function and loop of the code section `content`,
+/// which starts at `offset` in the binary.
+fn instrument_code(content: &[u8], offset: usize, check: &[u8]) -> Option<Vec<u8>> {
+    let mut reader = CodeSectionReader::new(content, offset).ok()?;
+    let mut section = Vec::with_capacity(content.len());
+
+    leb128::write_u32(&mut section, reader.get_count());
+    for _ in 0..reader.get_count() {
+        let body = reader.read().ok()?;
+        let end = body.range().end - offset;
+        let mut operators = body.get_operators_reader().ok()?;
+        let mut copied = body.range().start - offset;
+        let mut code = Vec::new();
+        let mut insert = |code: &mut Vec<u8>, at: usize| {
+            code.extend_from_slice(&content[copied..at]);
+            code.extend_from_slice(check);
+            copied = at;
+        };
+
+        insert(&mut code, operators.original_position() - offset);
+        while !operators.eof() {
+            if let Operator::Loop { .. } = operators.read().ok()? {
+                insert(&mut code, operators.original_position() - offset);
+            }
+        }
+        code.extend_from_slice(&content[copied..end]);
+
+        leb128::write_u32(&mut section, code.len() as u32);
+        section.extend_from_slice(&code);
+    }
+
+    Some(section)
+}
+
+/// The version of the code [`instrument`](fn.instrument.html) emits. Bump it with every
+/// change to the instrumentation, so that modules cached before are compiled again.
+pub(super) const INSTRUMENTATION_VERSION: u32 = 1;
+
+/// Instruments `binary` so that its functions and loops trap while a new exported global is
+/// set, which lets runs be stopped while the WASI binary computes without making system
+/// calls. Fails if the binary is invalid, so no binary runs without the check.
+pub(super) fn instrument(binary: &[u8]) -> Result<Vec<u8>, RunError> {
+    instrument_sections(binary).ok_or_else(|| {
+        RunError::Compile("invalid WebAssembly binary, it can not be instrumented".to_string())
+    })
+}
+
+fn instrument_sections(binary: &[u8]) -> Option<Vec<u8>> {
+    if binary.len() < 8 || !binary.starts_with(b"\0asm") {
+        return None;
+    }
+
+    let mut sections = Vec::new();
+    let mut position = 8;
+    while position < binary.len() {
+        let id = binary[position];
+        position += 1;
+
+        let size = leb128::read_u32(binary, &mut position)? as usize;
+        let end = position.checked_add(size)?;
+        sections.push((id, binary.get(position..end)?, position));
+        position = end;
+    }
+
+    // The new global comes after the imported and defined ones, so no indices change.
+    let mut index = 0u32;
+    for &(id, content, offset) in &sections {
+        match id {
+            IMPORT_SECTION => {
+                for import in ImportSectionReader::new(content, offset).ok()? {
+                    if let ImportSectionEntryType::Global(_) = import.ok()?.ty {
+                        index += 1;
+                    }
+                }
+            }
+            GLOBAL_SECTION => index += GlobalSectionReader::new(content, offset).ok()?.get_count(),
+            _ => {}
+        }
+    }
+
+    // A mutable i32 initialized to 0.
+    let global = [0x7f, 0x01, 0x41, 0x00, 0x0b];
+    let mut export = Vec::new();
+    leb128::write_u32(&mut export, INTERRUPT_EXPORT.len() as u32);
+    export.extend_from_slice(INTERRUPT_EXPORT.as_bytes());
+    export.push(0x03);
+    leb128::write_u32(&mut export, index);
+    // global.get, if, unreachable, end
+    let mut check = vec![0x23];
+    leb128::write_u32(&mut check, index);
+    check.extend_from_slice(&[0x04, 0x40, 0x00, 0x0b]);
+
+    let mut output = binary[..8].to_vec();
+    let mut missing = vec![(GLOBAL_SECTION, global.to_vec()), (EXPORT_SECTION, export)];
+    let push_section = |output: &mut Vec<u8>, id: u8, content: &[u8]| {
+        output.push(id);
+        leb128::write_u32(output, content.len() as u32);
+        output.extend_from_slice(content);
+    };
+
+    for &(id, content, offset) in &sections {
+        if id != CUSTOM_SECTION {
+            while !missing.is_empty() && section_order(missing[0].0) < section_order(id) {
+                let (id, entry) = missing.remove(0);
+                push_section(&mut output, id, &append_entry(&[0], &entry)?);
+            }
+        }
+
+        let content = match missing.iter().position(|&(missing, _)| missing == id) {
+            Some(i) => append_entry(content, &missing.remove(i).1)?,
+            None if id == CODE_SECTION => instrument_code(content, offset, &check)?,
+            None => content.to_vec(),
+        };
+        push_section(&mut output, id, &content);
+    }
+    for (id, entry) in missing {
+        push_section(&mut output, id, &append_entry(&[0], &entry)?);
+    }
+
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasi_snapshot_preview1::{
+        pipe,
+        test_binaries::{calling_with, looping, returning},
+        LimitKind, RunOutcome, WasiHost, WasiHostBuilder,
+    };
+
+    /// A binary that reads a byte from standard input.
+    fn reading() -> Vec<u8> {
+        // An iovec at 16 pointing to a byte at 32.
+        let mut data = vec![0; 16];
+        data.extend_from_slice(&[32, 0, 0, 0, 1, 0, 0, 0]);
+
+        calling_with("fd_read", &[0, 16, 1, 8], &data)
+    }
+
+    fn cancel_soon(host: &WasiHost<String>) -> thread::JoinHandle<()> {
+        let handle = host.cancellation_handle();
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            handle.cancel();
+        })
+    }
+
+    #[test]
+    fn instruments_valid_binaries() {
+        for binary in &[looping(), reading(), returning()] {
+            let instrumented = instrument(binary).unwrap();
+
+            assert!(wasmer_runtime::validate(&instrumented));
+            assert!(instrumented.len() > binary.len());
+        }
+
+        assert!(matches!(
+            instrument(b"\0asm\x01\0\0\0\x01\x05\x01"),
+            Err(RunError::Compile(_))
+        ));
+        assert!(matches!(instrument(b"not wasm"), Err(RunError::Compile(_))));
+    }
+
+    #[test]
+    fn stops_computing_binaries_when_cancelled() {
+        let host = WasiHostBuilder::<String>::new().build().unwrap();
+        let canceller = cancel_soon(&host);
+
+        assert_eq!(host.run_binary(&looping()).unwrap(), RunOutcome::Cancelled);
+        canceller.join().unwrap();
+    }
+
+    #[test]
+    fn stops_computing_binaries_at_the_timeout() {
+        let host = WasiHostBuilder::<String>::new()
+            .timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            host.run_binary(&looping()).unwrap(),
+            RunOutcome::LimitExceeded(LimitKind::Time)
+        );
+    }
+
+    #[test]
+    fn stops_waiting_for_pipes_when_cancelled() {
+        let (reader, _writer) = pipe(16);
+        let host = WasiHostBuilder::<String>::new()
+            .stdin(reader)
+            .build()
+            .unwrap();
+        let canceller = cancel_soon(&host);
+
+        assert_eq!(host.run_binary(&reading()).unwrap(), RunOutcome::Cancelled);
+        canceller.join().unwrap();
+    }
+
+    #[test]
+    fn cancellation_lasts_until_reset() {
+        let host = WasiHostBuilder::<String>::new().build().unwrap();
+        let handle = host.cancellation_handle();
+        let returning = returning();
+
+        handle.cancel();
+        assert_eq!(
+            host.clone().run_binary(&returning).unwrap(),
+            RunOutcome::Cancelled
+        );
+        assert_eq!(
+            host.clone().run_binary(&returning).unwrap(),
+            RunOutcome::Cancelled
+        );
+
+        handle.reset();
+        assert!(!handle.is_cancelled());
+        assert_eq!(host.run_binary(&returning).unwrap(), RunOutcome::Exited(0));
+    }
+}
@@ -0,0 +1,479 @@
+use super::cancel;
+use crate::os;
+use parking_lot::{Condvar, Mutex, RwLock};
+use std::{
+    convert::TryFrom,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use wasihost_core::wasi_snapshot_preview1::{Clockid, Errno, Timestamp, WasiResult};
+
+/// Provides the clocks observed by a WASI binary.
+pub trait ClockProvider: Debug + Send + Sync + 'static {
+    /// Returns the resolution of the clock `id`.
+    fn res_get(&self, id: Clockid) -> WasiResult<Timestamp>;
+
+    /// Returns the time of the clock `id`. `precision` is the maximum lag the caller accepts.
+    fn time_get(&self, id: Clockid, precision: Timestamp) -> WasiResult<Timestamp>;
+
+    /// Waits until `duration` nanoseconds have passed on the monotonic clock.
+    ///
+    /// Defaults to putting the current thread to sleep, waking up regularly so that the run
+    /// can be cancelled or time out in between.
+    fn sleep(&self, duration: u64) {
+        cancel::sleep(Duration::from_nanos(duration));
+    }
+}
+
+/// The clocks of a host, shared with the file systems that take timestamps from them.
+pub(super) type SharedClock = Arc<RwLock<Box<dyn ClockProvider>>>;
+
+fn check_clockid(id: Clockid) -> WasiResult<()> {
+    match id {
+        Clockid::Realtime
+        | Clockid::Monotonic
+        | Clockid::ProcessCputimeId
+        | Clockid::ThreadCputimeId => Ok(()),
+        _ => Err(Errno::Inval),
+    }
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(duration.subsec_nanos().into())
+}
+
+/// The clocks of the host.
+///
+/// Where the operating system does not provide clocks, the realtime and monotonic clocks are
+/// emulated with the standard library.
+#[derive(Debug)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    /// Creates a provider for the clocks of the host.
+    pub fn new() -> Self {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockProvider for SystemClock {
+    fn res_get(&self, id: Clockid) -> WasiResult<Timestamp> {
+        match os::preview1_clock_res_get(id) {
+            Err(Errno::Nosys) if id == Clockid::Realtime || id == Clockid::Monotonic => {
+                Ok(Timestamp(1))
+            }
+            result => result,
+        }
+    }
+
+    fn time_get(&self, id: Clockid, precision: Timestamp) -> WasiResult<Timestamp> {
+        match os::preview1_clock_time_get(id, precision) {
+            Err(Errno::Nosys) if id == Clockid::Realtime => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| Timestamp(nanos(time)))
+                .map_err(|_| Errno::Overflow),
+            Err(Errno::Nosys) if id == Clockid::Monotonic => {
+                Ok(Timestamp(nanos(self.start.elapsed())))
+            }
+            result => result,
+        }
+    }
+}
+
+/// Clocks that are stopped at a fixed point in time.
+///
+/// All clocks report the same time and waiting returns immediately.
+#[derive(Debug)]
+pub struct FixedClock {
+    time: Timestamp,
+}
+
+impl FixedClock {
+    /// Creates clocks that always report `time`.
+    pub fn new(time: Timestamp) -> Self {
+        FixedClock { time }
+    }
+}
+
+impl ClockProvider for FixedClock {
+    fn res_get(&self, id: Clockid) -> WasiResult<Timestamp> {
+        check_clockid(id)?;
+        Ok(Timestamp(1))
+    }
+
+    fn time_get(&self, id: Clockid, _precision: Timestamp) -> WasiResult<Timestamp> {
+        check_clockid(id)?;
+        Ok(self.time)
+    }
+
+    fn sleep(&self, _duration: u64) {}
+}
+
+/// Shifts the realtime clock of another provider, for example to make a WASI binary believe
+/// it runs at a different date. The other clocks are passed through unchanged.
+#[derive(Debug)]
+pub struct OffsetClock<C> {
+    inner: C,
+    offset: i128,
+}
+
+impl<C: ClockProvider> OffsetClock<C> {
+    /// Shifts the realtime clock of `inner` by `offset` nanoseconds.
+    pub fn new(inner: C, offset: i64) -> Self {
+        OffsetClock {
+            inner,
+            offset: offset.into(),
+        }
+    }
+
+    /// Shifts the realtime clock of `inner` so that it currently reports `time`.
+    pub fn starting_at(inner: C, time: Timestamp) -> WasiResult<Self> {
+        let now = inner.time_get(Clockid::Realtime, Timestamp(0))?;
+        let offset = i128::from(time.0) - i128::from(now.0);
+
+        Ok(OffsetClock { inner, offset })
+    }
+}
+
+impl<C: ClockProvider> ClockProvider for OffsetClock<C> {
+    fn res_get(&self, id: Clockid) -> WasiResult<Timestamp> {
+        self.inner.res_get(id)
+    }
+
+    fn time_get(&self, id: Clockid, precision: Timestamp) -> WasiResult<Timestamp> {
+        let time = self.inner.time_get(id, precision)?;
+
+        if id == Clockid::Realtime {
+            u64::try_from(i128::from(time.0) + self.offset)
+                .map(Timestamp)
+                .map_err(|_| Errno::Overflow)
+        } else {
+            Ok(time)
+        }
+    }
+
+    fn sleep(&self, duration: u64) {
+        self.inner.sleep(duration)
+    }
+}
+
+/// Makes the clocks of another provider run faster or slower.
+///
+/// Time passes `factor` times as fast as for the other provider, starting from the moment the
+/// scaled clocks were created.
+#[derive(Debug)]
+pub struct ScaledClock<C> {
+    inner: C,
+    factor: f64,
+    realtime_origin: u64,
+    monotonic_origin: u64,
+}
+
+impl<C: ClockProvider> ScaledClock<C> {
+    /// Scales the clocks of `inner` by `factor`, which has to be positive and finite.
+    pub fn new(inner: C, factor: f64) -> WasiResult<Self> {
+        if !(factor > 0.0 && factor.is_finite()) {
+            return Err(Errno::Inval);
+        }
+
+        let origin = |id| inner.time_get(id, Timestamp(0)).map_or(0, |time| time.0);
+        let realtime_origin = origin(Clockid::Realtime);
+        let monotonic_origin = origin(Clockid::Monotonic);
+
+        Ok(ScaledClock {
+            inner,
+            factor,
+            realtime_origin,
+            monotonic_origin,
+        })
+    }
+
+    fn scale(&self, duration: u64) -> u64 {
+        (duration as f64 * self.factor) as u64
+    }
+}
+
+impl<C: ClockProvider> ClockProvider for ScaledClock<C> {
+    fn res_get(&self, id: Clockid) -> WasiResult<Timestamp> {
+        let resolution = self.inner.res_get(id)?;
+
+        Ok(Timestamp(self.scale(resolution.0).max(1)))
+    }
+
+    fn time_get(&self, id: Clockid, precision: Timestamp) -> WasiResult<Timestamp> {
+        let time = self.inner.time_get(id, precision)?.0;
+        let origin = match id {
+            Clockid::Realtime => self.realtime_origin,
+            Clockid::Monotonic => self.monotonic_origin,
+            _ => 0,
+        };
+        let elapsed = self.scale(time.saturating_sub(origin));
+
+        Ok(Timestamp(origin.saturating_add(elapsed)))
+    }
+
+    fn sleep(&self, duration: u64) {
+        self.inner.sleep((duration as f64 / self.factor) as u64)
+    }
+}
+
+#[derive(Debug)]
+struct ManualClockState {
+    elapsed: Mutex<u64>,
+    advanced: Condvar,
+}
+
+/// Clocks that only move when they are advanced explicitly, for tests.
+///
+/// Clones share the same time, so a clone can be kept to advance the clocks while a WASI
+/// binary runs. Waiting blocks until the clocks have been advanced far enough by another
+/// thread.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    start: Timestamp,
+    state: Arc<ManualClockState>,
+}
+
+impl ManualClock {
+    /// Creates clocks whose realtime clock starts at `start`.
+    pub fn new(start: Timestamp) -> Self {
+        ManualClock {
+            start,
+            state: Arc::new(ManualClockState {
+                elapsed: Mutex::new(0),
+                advanced: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Advances all clocks by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        let mut elapsed = self.state.elapsed.lock();
+
+        *elapsed = elapsed.saturating_add(nanos(duration));
+        self.state.advanced.notify_all();
+    }
+
+    /// Returns the time that has passed since the clocks were created.
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(*self.state.elapsed.lock())
+    }
+}
+
+impl ClockProvider for ManualClock {
+    fn res_get(&self, id: Clockid) -> WasiResult<Timestamp> {
+        check_clockid(id)?;
+        Ok(Timestamp(1))
+    }
+
+    fn time_get(&self, id: Clockid, _precision: Timestamp) -> WasiResult<Timestamp> {
+        check_clockid(id)?;
+
+        let elapsed = *self.state.elapsed.lock();
+
+        match id {
+            Clockid::Realtime => Ok(Timestamp(self.start.0.saturating_add(elapsed))),
+            _ => Ok(Timestamp(elapsed)),
+        }
+    }
+
+    fn sleep(&self, duration: u64) {
+        let mut elapsed = self.state.elapsed.lock();
+        let target = elapsed.saturating_add(duration);
+
+        while *elapsed < target {
+            cancel::wait(&self.state.advanced, &mut elapsed);
+        }
+    }
+}
+
+/// Clocks that only advance deterministically, used by
+/// [`WasiHost::new_deterministic`](struct.WasiHost.html#method.new_deterministic).
+///
+/// All clocks start at a fixed point in time and advance by a fixed tick whenever they are
+/// read. Waiting advances them by exactly the time waited, without actually sleeping.
+#[derive(Debug, Default)]
+pub struct VirtualClock {
+    elapsed: AtomicU64,
+}
+
+impl VirtualClock {
+    /// The time the realtime clock starts at, 2020-01-01T00:00:00Z.
+    const EPOCH: u64 = 1_577_836_800_000_000_000;
+    /// The time that passes whenever a clock is read.
+    const TICK: u64 = 1_000;
+
+    /// Creates virtual clocks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ClockProvider for VirtualClock {
+    fn res_get(&self, id: Clockid) -> WasiResult<Timestamp> {
+        check_clockid(id)?;
+        Ok(Timestamp(Self::TICK))
+    }
+
+    fn time_get(&self, id: Clockid, _precision: Timestamp) -> WasiResult<Timestamp> {
+        check_clockid(id)?;
+
+        let elapsed = self.elapsed.fetch_add(Self::TICK, Ordering::SeqCst) + Self::TICK;
+
+        match id {
+            Clockid::Realtime => Ok(Timestamp(Self::EPOCH + elapsed)),
+            _ => Ok(Timestamp(elapsed)),
+        }
+    }
+
+    fn sleep(&self, duration: u64) {
+        self.elapsed.fetch_add(duration, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn virtual_clocks_advance_when_read_or_slept() {
+        let clock = VirtualClock::new();
+
+        let first = clock.time_get(Clockid::Monotonic, Timestamp(0)).unwrap();
+        let second = clock.time_get(Clockid::Monotonic, Timestamp(0)).unwrap();
+        clock.sleep(1_000_000);
+        let third = clock.time_get(Clockid::Realtime, Timestamp(0)).unwrap();
+
+        assert_eq!(first, Timestamp(VirtualClock::TICK));
+        assert_eq!(second.0 - first.0, VirtualClock::TICK);
+        assert_eq!(
+            third.0,
+            VirtualClock::EPOCH + second.0 + 1_000_000 + VirtualClock::TICK
+        );
+        assert_eq!(
+            clock.res_get(Clockid::Realtime).unwrap(),
+            Timestamp(VirtualClock::TICK)
+        );
+    }
+
+    #[test]
+    fn system_clocks_honour_the_precision() {
+        let clock = SystemClock::new();
+
+        for &id in &[Clockid::Realtime, Clockid::Monotonic] {
+            let before = clock.time_get(id, Timestamp(0)).unwrap();
+            let coarse = clock.time_get(id, Timestamp(1_000_000_000)).unwrap();
+            let after = clock.time_get(id, Timestamp(0)).unwrap();
+
+            assert!(before <= after);
+            assert!(coarse <= after, "{:?}", id);
+            assert!(coarse.0 + 1_000_000_000 >= before.0, "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn fixed_clocks_stand_still() {
+        let clock = FixedClock::new(Timestamp(42));
+
+        clock.sleep(1_000_000_000_000);
+
+        assert_eq!(
+            clock.time_get(Clockid::Realtime, Timestamp(0)),
+            Ok(Timestamp(42))
+        );
+        assert_eq!(
+            clock.time_get(Clockid::Monotonic, Timestamp(0)),
+            Ok(Timestamp(42))
+        );
+    }
+
+    #[test]
+    fn offset_clocks_only_shift_the_realtime_clock() {
+        let clock = OffsetClock::new(FixedClock::new(Timestamp(1_000)), -400);
+
+        assert_eq!(
+            clock.time_get(Clockid::Realtime, Timestamp(0)),
+            Ok(Timestamp(600))
+        );
+        assert_eq!(
+            clock.time_get(Clockid::Monotonic, Timestamp(0)),
+            Ok(Timestamp(1_000))
+        );
+
+        let clock =
+            OffsetClock::starting_at(FixedClock::new(Timestamp(1_000)), Timestamp(5)).unwrap();
+        assert_eq!(
+            clock.time_get(Clockid::Realtime, Timestamp(0)),
+            Ok(Timestamp(5))
+        );
+
+        let clock = OffsetClock::new(FixedClock::new(Timestamp(1_000)), -1_001);
+        assert_eq!(
+            clock.time_get(Clockid::Realtime, Timestamp(0)),
+            Err(Errno::Overflow)
+        );
+    }
+
+    #[test]
+    fn scaled_clocks_run_faster_from_their_creation() {
+        let manual = ManualClock::new(Timestamp(1_000));
+        manual.advance(Duration::from_nanos(500));
+        let clock = ScaledClock::new(manual.clone(), 2.0).unwrap();
+
+        manual.advance(Duration::from_nanos(100));
+
+        assert_eq!(
+            clock.time_get(Clockid::Monotonic, Timestamp(0)),
+            Ok(Timestamp(700))
+        );
+        assert_eq!(
+            clock.time_get(Clockid::Realtime, Timestamp(0)),
+            Ok(Timestamp(1_700))
+        );
+        assert_eq!(clock.res_get(Clockid::Monotonic), Ok(Timestamp(2)));
+
+        for &factor in &[0.0, -1.0, f64::INFINITY, f64::NAN] {
+            assert_eq!(
+                ScaledClock::new(SystemClock::new(), factor).unwrap_err(),
+                Errno::Inval
+            );
+        }
+    }
+
+    #[test]
+    fn manual_clocks_wait_until_advanced() {
+        let clock = ManualClock::new(Timestamp(0));
+        let advancer = clock.clone();
+        let thread = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            advancer.advance(Duration::from_secs(1));
+        });
+
+        clock.sleep(1_000_000_000);
+        thread.join().unwrap();
+
+        assert_eq!(clock.elapsed(), Duration::from_secs(1));
+        assert_eq!(
+            clock.time_get(Clockid::Monotonic, Timestamp(0)),
+            Ok(Timestamp(1_000_000_000))
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use super::{
+    cancel, limits,
+    run::{self, RunError, RunOutcome},
+};
+use parking_lot::Mutex;
+use std::{fmt, io, path::PathBuf};
+use wasihost_core::wasi_snapshot_preview1::WasiImportsExt;
+use wasmer_runtime::{
+    cache::{Cache, FileSystemCache, WasmHash},
+    compile, Module,
+};
+
+/// A compiled WASM binary that can be run many times, on the same or on different WASI
+/// hosts, without compiling it again.
+#[derive(Clone)]
+pub struct CompiledModule {
+    module: Module,
+}
+
+impl CompiledModule {
+    /// Compiles `wasm_binary`.
+    pub fn compile(wasm_binary: &[u8]) -> Result<Self, RunError> {
+        // Instrumented so that cancellation and timeouts stop binaries that only compute.
+        let instrumented = cancel::instrument(wasm_binary)?;
+
+        compile(&instrumented)
+            .map(|module| CompiledModule { module })
+            .map_err(|error| RunError::Compile(error.to_string()))
+    }
+
+    /// Compiles `wasm_binary` so that its memories can not grow beyond `max_pages` pages.
+    ///
+    /// Only modules compiled with a memory limit can be run on WASI hosts that limit
+    /// [`max_memory_pages`](struct.ResourceLimits.html#structfield.max_memory_pages), and only
+    /// if the limit of the module is not higher.
+    pub fn compile_with_memory_limit(wasm_binary: &[u8], max_pages: u32) -> Result<Self, RunError> {
+        match limits::limit_memory(wasm_binary, max_pages)? {
+            Some(limited_binary) => Self::compile(&limited_binary),
+            // Compiled as is, running it on a host with the limit reports that it is exceeded.
+            None => Self::compile(wasm_binary),
+        }
+    }
+
+    /// Runs the module with `imports` instead of a WASI host, for example to replay a
+    /// recorded run with [`ReplayImports`](struct.ReplayImports.html).
+    ///
+    /// The resource limits, timeout and cancellation of WASI hosts do not apply.
+    pub fn run_with_imports(&self, imports: impl WasiImportsExt) -> Result<RunOutcome, RunError> {
+        let instance = self
+            .module
+            .instantiate(&imports.into_imports())
+            .map_err(RunError::from_instantiate_error)?;
+
+        run::start(&instance)
+    }
+
+    pub(super) fn module(&self) -> &Module {
+        &self.module
+    }
+
+    /// Returns whether all memories defined by the module fit into `max_pages` pages, or an
+    /// error if they can grow beyond that.
+    pub(super) fn check_memory(&self, max_pages: u32) -> Result<bool, RunError> {
+        let mut fits = true;
+
+        for (_, memory) in self.module.info().memories.iter() {
+            match memory.maximum {
+                _ if memory.minimum.0 > max_pages => fits = false,
+                Some(maximum) if maximum.0 <= max_pages => {}
+                _ => return Err(RunError::UnlimitedMemory),
+            }
+        }
+
+        Ok(fits)
+    }
+}
+
+impl fmt::Debug for CompiledModule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompiledModule")
+            .field("memories", &self.module.info().memories)
+            .finish()
+    }
+}
+
+/// Stores compiled modules in a directory, keyed by a hash of the WASM binary, so they only
+/// have to be compiled once across processes.
+pub struct ModuleCache {
+    path: PathBuf,
+    cache: Mutex<FileSystemCache>,
+}
+
+impl ModuleCache {
+    /// Uses the directory `path` as cache, creating it if it does not exist. Modules are kept
+    /// in a subdirectory per version of the instrumentation that is applied before compiling.
+    ///
+    /// # Safety
+    ///
+    /// Cached modules are loaded as native code without any validation, so the directory must
+    /// only be writable by trusted users.
+    pub unsafe fn new(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let versioned = path.join(format!("instrumented-v{}", cancel::INSTRUMENTATION_VERSION));
+        let cache = FileSystemCache::new(versioned)?;
+
+        Ok(ModuleCache {
+            path,
+            cache: Mutex::new(cache),
+        })
+    }
+
+    /// Loads the compiled `wasm_binary` from the cache, or compiles and stores it. Failing to
+    /// store the module is not an error, it is simply compiled again the next time.
+    pub fn compile(&self, wasm_binary: &[u8]) -> Result<CompiledModule, RunError> {
+        let key = WasmHash::generate(wasm_binary);
+
+        if let Ok(module) = self.cache.lock().load(key) {
+            return Ok(CompiledModule { module });
+        }
+
+        let compiled = CompiledModule::compile(wasm_binary)?;
+        let _ = self.cache.lock().store(key, compiled.module.clone());
+
+        Ok(compiled)
+    }
+
+    /// Like [`compile`](#method.compile), but limits the memories like
+    /// [`CompiledModule::compile_with_memory_limit`](struct.CompiledModule.html#method.compile_with_memory_limit).
+    pub fn compile_with_memory_limit(
+        &self,
+        wasm_binary: &[u8],
+        max_pages: u32,
+    ) -> Result<CompiledModule, RunError> {
+        match limits::limit_memory(wasm_binary, max_pages)? {
+            Some(limited_binary) => self.compile(&limited_binary),
+            None => self.compile(wasm_binary),
+        }
+    }
+}
+
+impl fmt::Debug for ModuleCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ModuleCache")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasi_snapshot_preview1::{
+        run::{LimitKind, RunOutcome},
+        test_binaries::{module, returning},
+        ResourceLimits, WasiHostBuilder,
+    };
+    use std::path::Path;
+
+    /// A binary that returns right away and defines a memory of `minimum` pages.
+    fn with_memory(minimum: u8) -> Vec<u8> {
+        module(&[
+            (1, &[1, 0x60, 0, 0]),
+            (3, &[1, 0]),
+            (5, &[1, 0, minimum]),
+            (7, b"\x01\x06_start\x00\x00"),
+            (10, &[1, 2, 0, 0x0b]),
+        ])
+    }
+
+    fn run_limited(module: &CompiledModule, max_pages: u32) -> Result<RunOutcome, RunError> {
+        let host = WasiHostBuilder::<String>::new()
+            .resource_limits(ResourceLimits {
+                max_memory_pages: Some(max_pages),
+                ..ResourceLimits::default()
+            })
+            .build()
+            .unwrap();
+
+        host.run_module(module)
+    }
+
+    #[test]
+    fn compiled_modules_run_many_times() {
+        let module = CompiledModule::compile(&returning()).unwrap();
+
+        for _ in 0..2 {
+            let host = WasiHostBuilder::<String>::new().build().unwrap();
+            assert_eq!(host.run_module(&module).unwrap(), RunOutcome::Exited(0));
+        }
+        assert!(matches!(
+            CompiledModule::compile(b"\0asm"),
+            Err(RunError::Compile(_))
+        ));
+    }
+
+    #[test]
+    fn memory_limits_require_limited_modules() {
+        let unlimited = CompiledModule::compile(&with_memory(1)).unwrap();
+        assert!(matches!(
+            run_limited(&unlimited, 2),
+            Err(RunError::UnlimitedMemory)
+        ));
+
+        let limited = CompiledModule::compile_with_memory_limit(&with_memory(1), 2).unwrap();
+        assert_eq!(run_limited(&limited, 2).unwrap(), RunOutcome::Exited(0));
+        assert!(limited.check_memory(3).unwrap());
+        assert!(matches!(
+            run_limited(&limited, 1),
+            Err(RunError::UnlimitedMemory)
+        ));
+
+        let too_large = CompiledModule::compile_with_memory_limit(&with_memory(3), 2).unwrap();
+        assert_eq!(
+            run_limited(&too_large, 2).unwrap(),
+            RunOutcome::LimitExceeded(LimitKind::Memory)
+        );
+    }
+
+    /// Counts the modules in the cache directory, which keeps them in a directory per
+    /// instrumentation and runtime version.
+    fn cached(dir: &Path) -> usize {
+        let versioned = dir.join(format!("instrumented-v{}", cancel::INSTRUMENTATION_VERSION));
+
+        versioned
+            .read_dir()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().read_dir().unwrap().count())
+            .sum()
+    }
+
+    #[test]
+    fn caches_compiled_modules_across_instances() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = returning();
+
+        let cache = unsafe { ModuleCache::new(dir.path()) }.unwrap();
+        cache.compile(&binary).unwrap();
+        assert_eq!(cached(dir.path()), 1);
+
+        let cache = unsafe { ModuleCache::new(dir.path()) }.unwrap();
+        let module = cache.compile(&binary).unwrap();
+        let host = WasiHostBuilder::<String>::new().build().unwrap();
+        assert_eq!(host.run_module(&module).unwrap(), RunOutcome::Exited(0));
+        assert_eq!(cached(dir.path()), 1);
+
+        let limited = cache.compile_with_memory_limit(&with_memory(1), 2).unwrap();
+        assert_eq!(run_limited(&limited, 2).unwrap(), RunOutcome::Exited(0));
+        assert_eq!(cached(dir.path()), 2);
+    }
+}
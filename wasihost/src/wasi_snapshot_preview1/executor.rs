@@ -0,0 +1,255 @@
+use super::run::{RunError, RunOutcome};
+use parking_lot::Mutex;
+use std::{
+    any::Any,
+    fmt,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{mpsc, Arc},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+/// A task handed to an [`Executor`](trait.Executor.html).
+pub type BlockingTask = Box<dyn FnOnce() + Send>;
+
+/// Runs tasks that block the thread they run on, such as the runs started by
+/// [`WasiHost::run_module_on`](struct.WasiHost.html#method.run_module_on).
+///
+/// This is usually the blocking thread pool of an async runtime, for example
+/// `tokio::task::spawn_blocking`, or a [`ThreadPool`](struct.ThreadPool.html).
+pub trait Executor: Send + Sync {
+    /// Runs `task` to completion on some thread.
+    fn spawn_blocking(&self, task: BlockingTask);
+}
+
+impl<E: Executor + ?Sized> Executor for Arc<E> {
+    fn spawn_blocking(&self, task: BlockingTask) {
+        (**self).spawn_blocking(task)
+    }
+}
+
+/// An executor with a fixed number of threads that run tasks in the order they were spawned.
+///
+/// Every run occupies a thread until it ends, and further tasks wait until a thread is free, so
+/// at most that many WASI binaries run at once. The
+/// threads exit once the pool has been dropped and all tasks have run.
+pub struct ThreadPool {
+    sender: Mutex<mpsc::Sender<BlockingTask>>,
+    threads: usize,
+}
+
+impl ThreadPool {
+    /// Starts a pool with `threads` threads, at least one.
+    pub fn new(threads: usize) -> Self {
+        let threads = threads.max(1);
+        let (sender, receiver) = mpsc::channel::<BlockingTask>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+
+            thread::spawn(move || loop {
+                let task = receiver.lock().recv();
+
+                match task {
+                    // A panicking task must not take the thread down with it.
+                    Ok(task) => drop(panic::catch_unwind(AssertUnwindSafe(task))),
+                    Err(_) => break,
+                }
+            });
+        }
+
+        ThreadPool {
+            sender: Mutex::new(sender),
+            threads,
+        }
+    }
+}
+
+impl Executor for ThreadPool {
+    fn spawn_blocking(&self, task: BlockingTask) {
+        // The threads only exit once the sender is dropped, so sending can not fail.
+        let _ = self.sender.lock().send(task);
+    }
+}
+
+impl fmt::Debug for ThreadPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThreadPool")
+            .field("threads", &self.threads)
+            .finish()
+    }
+}
+
+type RunResult = Result<RunOutcome, RunError>;
+
+#[derive(Default)]
+struct Completion {
+    result: Option<thread::Result<RunResult>>,
+    waker: Option<Waker>,
+}
+
+/// A run handed to an executor, which completes with the result of the run once it has ended
+/// on a thread of the executor. The run blocks that thread throughout, see
+/// [`WasiHost::run_module_on`](struct.WasiHost.html#method.run_module_on).
+///
+/// If the run panics, or the executor drops it without running it, polling panics.
+pub struct BlockingRun {
+    completion: Arc<Mutex<Completion>>,
+}
+
+impl BlockingRun {
+    /// Hands `run` to `executor` and returns a future for its result.
+    pub(super) fn spawn(
+        executor: &dyn Executor,
+        run: impl FnOnce() -> RunResult + Send + 'static,
+    ) -> Self {
+        let completion = Arc::new(Mutex::new(Completion::default()));
+        let completer = Completer(Some(completion.clone()));
+
+        executor.spawn_blocking(Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(run));
+            completer.complete(result);
+        }));
+
+        BlockingRun { completion }
+    }
+}
+
+impl Future for BlockingRun {
+    type Output = RunResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut completion = self.completion.lock();
+
+        match completion.result.take() {
+            Some(Ok(result)) => Poll::Ready(result),
+            Some(Err(payload)) => panic::resume_unwind(payload),
+            None => {
+                completion.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl fmt::Debug for BlockingRun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockingRun")
+            .field("completed", &self.completion.lock().result.is_some())
+            .finish()
+    }
+}
+
+/// Completes a [`BlockingRun`](struct.BlockingRun.html), with a panic if it is dropped before.
+struct Completer(Option<Arc<Mutex<Completion>>>);
+
+impl Completer {
+    fn complete(mut self, result: thread::Result<RunResult>) {
+        self.set(result);
+    }
+
+    fn set(&mut self, result: thread::Result<RunResult>) {
+        if let Some(completion) = self.0.take() {
+            let waker = {
+                let mut completion = completion.lock();
+                completion.result = Some(result);
+                completion.waker.take()
+            };
+
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl Drop for Completer {
+    fn drop(&mut self) {
+        let payload: Box<dyn Any + Send> = Box::new("the executor dropped the run");
+        self.set(Err(payload));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasi_snapshot_preview1::{
+        test_binaries::returning, test_util::block_on, WasiHostBuilder,
+    };
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    /// Drops every task without running it.
+    struct Dropping;
+
+    impl Executor for Dropping {
+        fn spawn_blocking(&self, _task: BlockingTask) {}
+    }
+
+    #[test]
+    fn thread_pools_bound_the_tasks_running_at_once() {
+        let pool = ThreadPool::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let (sender, receiver) = mpsc::channel();
+
+        for _ in 0..6 {
+            let (running, most, sender) = (running.clone(), most.clone(), sender.clone());
+
+            pool.spawn_blocking(Box::new(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+                sender.send(()).unwrap();
+            }));
+        }
+
+        for _ in 0..6 {
+            receiver.recv().unwrap();
+        }
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            format!("{:?}", ThreadPool::new(0)),
+            "ThreadPool { threads: 1 }"
+        );
+    }
+
+    #[test]
+    fn thread_pools_survive_panicking_tasks() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel();
+
+        pool.spawn_blocking(Box::new(|| panic!("task failed")));
+        pool.spawn_blocking(Box::new(move || sender.send(42).unwrap()));
+        assert_eq!(receiver.recv().unwrap(), 42);
+    }
+
+    #[test]
+    fn spawned_runs_complete_their_futures() {
+        let pool = ThreadPool::new(2);
+        let futures: Vec<_> = (0..4)
+            .map(|_| {
+                let host = WasiHostBuilder::<String>::new().build().unwrap();
+                host.run_binary_on(returning(), &pool)
+            })
+            .collect();
+
+        for future in futures {
+            assert_eq!(block_on(future).unwrap(), RunOutcome::Exited(0));
+        }
+    }
+
+    #[test]
+    fn runs_dropped_by_the_executor_panic_when_polled() {
+        let host = WasiHostBuilder::<String>::new().build().unwrap();
+        let future = host.run_binary_on(returning(), &Dropping);
+
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| block_on(future))).is_err());
+    }
+}
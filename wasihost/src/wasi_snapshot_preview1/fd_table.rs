@@ -0,0 +1,279 @@
+use super::WasiFd;
+use rand::RngCore;
+use rand_chacha::ChaCha20Rng;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+use wasihost_core::wasi_snapshot_preview1::{Errno, Fd, WasiResult};
+
+/// The number of file descriptors that can be allocated.
+const MAX_FDS: u32 = 1 << 31;
+
+/// Strategies for choosing the number of a newly opened file descriptor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FdAllocationPolicy {
+    /// The lowest unused number, like POSIX requires.
+    LowestFree,
+    /// The lowest unused number after the previously allocated one, wrapping around once
+    /// all higher numbers are used.
+    Sequential,
+    /// A random unused number, which makes guessing file descriptors harder.
+    Random,
+}
+
+// `#[default]` on variants requires Rust 1.62.
+#[allow(clippy::derivable_impls)]
+impl Default for FdAllocationPolicy {
+    fn default() -> Self {
+        FdAllocationPolicy::Random
+    }
+}
+
+/// The unused file descriptor numbers, stored as disjoint ranges from their start to their
+/// exclusive end, so all operations take logarithmic time in the number of ranges.
+#[derive(Debug)]
+struct FreeFds {
+    ranges: BTreeMap<u32, u32>,
+}
+
+impl FreeFds {
+    fn new() -> Self {
+        let mut ranges = BTreeMap::new();
+        ranges.insert(0, MAX_FDS);
+
+        FreeFds { ranges }
+    }
+
+    /// Returns the lowest unused number that is at least `from`.
+    fn first_from(&self, from: u32) -> Option<u32> {
+        match self.ranges.range(..=from).next_back() {
+            Some((_, &end)) if end > from => Some(from),
+            _ => self.ranges.range(from..).next().map(|(&start, _)| start),
+        }
+    }
+
+    /// Marks `fd` as used.
+    fn take(&mut self, fd: u32) {
+        let (start, end) = match self.ranges.range(..=fd).next_back() {
+            Some((&start, &end)) if end > fd => (start, end),
+            _ => return,
+        };
+
+        self.ranges.remove(&start);
+        if start < fd {
+            self.ranges.insert(start, fd);
+        }
+        if fd + 1 < end {
+            self.ranges.insert(fd + 1, end);
+        }
+    }
+
+    /// Marks `fd` as unused.
+    fn release(&mut self, fd: u32) {
+        if fd >= MAX_FDS || self.first_from(fd) == Some(fd) {
+            return;
+        }
+
+        let mut start = fd;
+        let mut end = fd + 1;
+
+        if let Some((&before, &before_end)) = self.ranges.range(..fd).next_back() {
+            if before_end == fd {
+                start = before;
+            }
+        }
+        if let Some(after_end) = self.ranges.remove(&end) {
+            end = after_end;
+        }
+
+        self.ranges.insert(start, end);
+    }
+}
+
+/// The open file descriptors of a WASI host.
+#[derive(Debug)]
+pub(super) struct FdTable<S> {
+    fds: HashMap<Fd, Arc<WasiFd<S>>>,
+    free: FreeFds,
+    policy: FdAllocationPolicy,
+    next: u32,
+    rng: ChaCha20Rng,
+}
+
+impl<S> FdTable<S> {
+    pub(super) fn new(fds: HashMap<Fd, WasiFd<S>>, rng: ChaCha20Rng) -> Self {
+        let mut free = FreeFds::new();
+
+        for fd in fds.keys() {
+            free.take(fd.0);
+        }
+
+        FdTable {
+            fds: fds.into_iter().map(|(k, v)| (k, Arc::new(v))).collect(),
+            free,
+            policy: FdAllocationPolicy::default(),
+            next: 0,
+            rng,
+        }
+    }
+
+    pub(super) fn set_policy(&mut self, policy: FdAllocationPolicy) {
+        self.policy = policy;
+    }
+
+    pub(super) fn get(&self, fd: Fd) -> Option<&Arc<WasiFd<S>>> {
+        self.fds.get(&fd)
+    }
+
+    pub(super) fn values(&self) -> impl Iterator<Item = &Arc<WasiFd<S>>> {
+        self.fds.values()
+    }
+
+    pub(super) fn remove(&mut self, fd: Fd) -> Option<Arc<WasiFd<S>>> {
+        let removed = self.fds.remove(&fd);
+
+        if removed.is_some() {
+            self.free.release(fd.0);
+        }
+        removed
+    }
+
+    /// Moves the file descriptor `from` to the number `to`, which must be different and in
+    /// use. Returns the file descriptor that was replaced, so it can be closed outside of the
+    /// lock of the table.
+    pub(super) fn renumber(&mut self, from: Fd, to: Fd) -> WasiResult<Arc<WasiFd<S>>> {
+        if !self.fds.contains_key(&to) {
+            return Err(Errno::Badf);
+        }
+
+        let fd = self.remove(from).ok_or(Errno::Badf)?;
+
+        Ok(self.fds.insert(to, fd).expect("the target is in use"))
+    }
+
+    /// Inserts `fd` under a number chosen by the allocation policy, unless `max_open` file
+    /// descriptors are open already.
+    pub(super) fn allocate(&mut self, fd: WasiFd<S>, max_open: Option<usize>) -> WasiResult<Fd> {
+        if let Some(max_open) = max_open {
+            if self.fds.len() >= max_open {
+                return Err(Errno::Mfile);
+            }
+        }
+
+        let from = match self.policy {
+            FdAllocationPolicy::LowestFree => 0,
+            FdAllocationPolicy::Sequential => self.next,
+            // `MAX_FDS` is a power of two, so the numbers stay uniform and only depend on the
+            // ChaCha20 keystream, not on how `rand` samples ranges.
+            FdAllocationPolicy::Random => self.rng.next_u32() % MAX_FDS,
+        };
+        let number = self
+            .free
+            .first_from(from)
+            .or_else(|| self.free.first_from(0))
+            .ok_or(Errno::Nfile)?;
+
+        self.free.take(number);
+        self.next = (number + 1) % MAX_FDS;
+        self.fds.insert(Fd(number), Arc::new(fd));
+
+        Ok(Fd(number))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasi_snapshot_preview1::CaptureDevice;
+    use rand::SeedableRng;
+    use wasihost_core::wasi_snapshot_preview1::{Fdflags, Rights};
+
+    fn device() -> WasiFd<String> {
+        WasiFd::from_character_device(CaptureDevice::new(), Fdflags::empty(), Rights::all())
+    }
+
+    /// Creates a table with the standard streams open.
+    fn table(policy: FdAllocationPolicy) -> FdTable<String> {
+        let fds = (0..3).map(|fd| (Fd(fd), device())).collect();
+        let mut table = FdTable::new(fds, ChaCha20Rng::seed_from_u64(0));
+        table.set_policy(policy);
+
+        table
+    }
+
+    #[test]
+    fn free_fds_merge_released_ranges() {
+        let mut free = FreeFds::new();
+
+        for fd in 0..4 {
+            free.take(fd);
+        }
+        assert_eq!(free.first_from(0), Some(4));
+
+        free.release(1);
+        free.release(2);
+        assert_eq!(free.first_from(0), Some(1));
+        assert_eq!(free.first_from(3), Some(4));
+
+        free.release(0);
+        free.release(3);
+        free.release(3);
+        assert_eq!(free.ranges.len(), 1);
+        assert_eq!(free.ranges[&0], MAX_FDS);
+    }
+
+    #[test]
+    fn allocates_the_lowest_free_fd() {
+        let mut table = table(FdAllocationPolicy::LowestFree);
+
+        assert_eq!(table.allocate(device(), None), Ok(Fd(3)));
+        assert_eq!(table.allocate(device(), None), Ok(Fd(4)));
+        table.remove(Fd(1)).unwrap();
+        assert_eq!(table.allocate(device(), None), Ok(Fd(1)));
+    }
+
+    #[test]
+    fn allocates_fds_sequentially() {
+        let mut table = table(FdAllocationPolicy::Sequential);
+
+        assert_eq!(table.allocate(device(), None), Ok(Fd(3)));
+        table.remove(Fd(1)).unwrap();
+        assert_eq!(table.allocate(device(), None), Ok(Fd(4)));
+    }
+
+    #[test]
+    fn allocates_random_unused_fds() {
+        let mut table = table(FdAllocationPolicy::Random);
+        let mut allocated = Vec::new();
+
+        for _ in 0..16 {
+            let fd = table.allocate(device(), None).unwrap();
+            assert!(fd.0 < MAX_FDS && !allocated.contains(&fd));
+            allocated.push(fd);
+        }
+        assert!(table.get(Fd(0)).is_some());
+    }
+
+    #[test]
+    fn limits_the_number_of_open_fds() {
+        let mut table = table(FdAllocationPolicy::LowestFree);
+
+        assert_eq!(table.allocate(device(), Some(4)), Ok(Fd(3)));
+        assert_eq!(table.allocate(device(), Some(4)), Err(Errno::Mfile));
+    }
+
+    #[test]
+    fn renumbering_frees_the_source() {
+        let mut table = table(FdAllocationPolicy::LowestFree);
+        let source = table.get(Fd(1)).unwrap().clone();
+
+        table.renumber(Fd(1), Fd(2)).unwrap();
+
+        assert!(table.get(Fd(1)).is_none());
+        assert!(Arc::ptr_eq(table.get(Fd(2)).unwrap(), &source));
+        assert_eq!(table.renumber(Fd(1), Fd(2)).unwrap_err(), Errno::Badf);
+        assert_eq!(table.renumber(Fd(2), Fd(1)).unwrap_err(), Errno::Badf);
+        assert_eq!(table.allocate(device(), None), Ok(Fd(1)));
+    }
+}
@@ -0,0 +1,187 @@
+//! The signatures of the functions of `WasiImports`, from which the imports that wrap or replace
+//! other imports generate their implementations.
+
+/// Calls the macro `$implement` with the signature of every function of `WasiImports`.
+///
+/// Every parameter is given with its kind, see [`wasi_argument!`](macro.wasi_argument.html).
+/// The result is given by kind as well: `unit`, `value(T)`, `strings`, `string`, `dirent`,
+/// `events` and `exit` for the values these functions return, and `read`, `received`,
+/// `written` and `random` with the parameters that describe the data the call transferred.
+/// The types of WASI values resolve where `$implement` is expanded.
+macro_rules! wasi_functions {
+    ($implement:ident) => {
+        $implement! {
+            fn args_get() -> strings;
+            fn environ_get() -> strings;
+            fn clock_res_get(id: value(Clockid)) -> value(Timestamp);
+            fn clock_time_get(id: value(Clockid), precision: value(Timestamp)) -> value(Timestamp);
+            fn fd_advise(
+                fd: value(Fd),
+                offset: value(Filesize),
+                len: value(Filesize),
+                advice: value(Advice)
+            ) -> unit;
+            fn fd_allocate(fd: value(Fd), offset: value(Filesize), len: value(Filesize)) -> unit;
+            fn fd_close(fd: value(Fd)) -> unit;
+            fn fd_datasync(fd: value(Fd)) -> unit;
+            fn fd_fdstat_get(fd: value(Fd)) -> value(Fdstat);
+            fn fd_fdstat_set_flags(fd: value(Fd), flags: value(Fdflags)) -> unit;
+            fn fd_fdstat_set_rights(
+                fd: value(Fd),
+                fs_rights_base: value(Rights),
+                fs_rights_inheriting: value(Rights)
+            ) -> unit;
+            fn fd_filestat_get(fd: value(Fd)) -> value(Filestat);
+            fn fd_filestat_set_size(fd: value(Fd), size: value(Filesize)) -> unit;
+            fn fd_filestat_set_times(
+                fd: value(Fd),
+                atim: value(Timestamp),
+                mtim: value(Timestamp),
+                fst_flags: value(Fstflags)
+            ) -> unit;
+            fn fd_pread(fd: value(Fd), iovs: buffers, offset: value(Filesize)) -> read(fd, iovs);
+            fn fd_prestat_get(fd: value(Fd)) -> value(Prestat);
+            fn fd_prestat_dir_name(fd: value(Fd)) -> string;
+            fn fd_pwrite(fd: value(Fd), bufs: data, offset: value(Filesize)) -> written(fd);
+            fn fd_read(fd: value(Fd), iovs: buffers) -> read(fd, iovs);
+            fn fd_readdir(fd: value(Fd), cookie: value(Dircookie)) -> dirent;
+            fn fd_renumber(fd: value(Fd), to: value(Fd)) -> unit;
+            fn fd_seek(fd: value(Fd), offset: value(Filedelta), whence: value(Whence))
+                -> value(Filesize);
+            fn fd_sync(fd: value(Fd)) -> unit;
+            fn fd_tell(fd: value(Fd)) -> value(Filesize);
+            fn fd_write(fd: value(Fd), bufs: data) -> written(fd);
+            fn path_create_directory(fd: value(Fd), path: string) -> unit;
+            fn path_filestat_get(fd: value(Fd), flags: value(Lookupflags), path: string)
+                -> value(Filestat);
+            fn path_filestat_set_times(
+                fd: value(Fd),
+                flags: value(Lookupflags),
+                path: string,
+                atim: value(Timestamp),
+                mtim: value(Timestamp),
+                fst_flags: value(Fstflags)
+            ) -> unit;
+            fn path_link(
+                old_fd: value(Fd),
+                old_flags: value(Lookupflags),
+                old_path: string,
+                new_fd: value(Fd),
+                new_path: string
+            ) -> unit;
+            fn path_open(
+                fd: value(Fd),
+                dirflags: value(Lookupflags),
+                path: string,
+                oflags: value(Oflags),
+                fs_rights_base: value(Rights),
+                fs_rights_inheriting: value(Rights),
+                fdflags: value(Fdflags)
+            ) -> value(Fd);
+            fn path_readlink(fd: value(Fd), path: string) -> string;
+            fn path_remove_directory(fd: value(Fd), path: string) -> unit;
+            fn path_rename(
+                fd: value(Fd),
+                old_path: string,
+                new_fd: value(Fd),
+                new_path: string
+            ) -> unit;
+            fn path_symlink(old_path: string, fd: value(Fd), new_path: string) -> unit;
+            fn path_unlink_file(fd: value(Fd), path: string) -> unit;
+            fn poll_oneoff(subscriptions: subscriptions) -> events;
+            fn proc_exit(rval: value(Exitcode)) -> exit;
+            fn proc_raise(sig: value(Signal)) -> unit;
+            fn random_get(buf: buffer) -> random(buf);
+            fn sched_yield() -> unit;
+            fn sock_recv(fd: value(Fd), ri_data: buffers, ri_flags: value(Riflags))
+                -> received(fd, ri_data);
+            fn sock_send(fd: value(Fd), si_data: data, si_flags: value(Siflags)) -> written(fd);
+            fn sock_shutdown(fd: value(Fd), how: value(Sdflags)) -> unit;
+        }
+    };
+}
+
+/// The type of a parameter of the kind given to [`wasi_functions!`](macro.wasi_functions.html).
+macro_rules! wasi_parameter {
+    (value($type:ty)) => { $type };
+    (data) => { &[std::io::IoSlice<'_>] };
+    (buffers) => { &mut [std::io::IoSliceMut<'_>] };
+    (buffer) => { &mut [u8] };
+    (string) => { &<Self::StringRepresentation as std::ops::Deref>::Target };
+    (subscriptions) => { &[wasihost_core::wasi_snapshot_preview1::Subscription] };
+}
+
+/// The return type of a function whose result is of the kind given to
+/// [`wasi_functions!`](macro.wasi_functions.html).
+macro_rules! wasi_result {
+    (unit) => { wasihost_core::wasi_snapshot_preview1::WasiResult<()> };
+    (value($type:ty)) => { wasihost_core::wasi_snapshot_preview1::WasiResult<$type> };
+    (strings) => {
+        wasihost_core::wasi_snapshot_preview1::WasiResult<&[Self::StringRepresentation]>
+    };
+    (string) => { wasihost_core::wasi_snapshot_preview1::WasiResult<Self::StringRepresentation> };
+    (dirent) => {
+        wasihost_core::wasi_snapshot_preview1::WasiResult<
+            Option<(
+                wasihost_core::wasi_snapshot_preview1::Dirent,
+                Self::StringRepresentation,
+            )>,
+        >
+    };
+    (events) => {
+        wasihost_core::wasi_snapshot_preview1::WasiResult<
+            Vec<wasihost_core::wasi_snapshot_preview1::Event>,
+        >
+    };
+    (read($($parameters:tt)*)) => {
+        wasihost_core::wasi_snapshot_preview1::WasiResult<
+            wasihost_core::wasi_snapshot_preview1::Size,
+        >
+    };
+    (received($($parameters:tt)*)) => {
+        wasihost_core::wasi_snapshot_preview1::WasiResult<(
+            wasihost_core::wasi_snapshot_preview1::Size,
+            wasihost_core::wasi_snapshot_preview1::Roflags,
+        )>
+    };
+    (written($($parameters:tt)*)) => {
+        wasihost_core::wasi_snapshot_preview1::WasiResult<
+            wasihost_core::wasi_snapshot_preview1::Size,
+        >
+    };
+    (random($($parameters:tt)*)) => { wasihost_core::wasi_snapshot_preview1::WasiResult<()> };
+    (exit) => {
+        Result<std::convert::Infallible, wasihost_core::wasi_snapshot_preview1::Exitcode>
+    };
+}
+
+/// Describes the parameter `$name` of the given kind as an
+/// [`Argument`](../observe/enum.Argument.html): `value(T)` is a WASI value, `data` the data a
+/// call writes, `buffers` the buffers it reads into, `buffer` the buffer `random_get` fills,
+/// `string` a path and `subscriptions` the subscriptions of `poll_oneoff`.
+macro_rules! wasi_argument {
+    (value($type:ty) $name:ident) => {
+        $crate::wasi_snapshot_preview1::observe::Argument::Value(&$name)
+    };
+    (data $name:ident) => {
+        $crate::wasi_snapshot_preview1::observe::Argument::Data($name)
+    };
+    (buffers $name:ident) => {
+        $crate::wasi_snapshot_preview1::observe::Argument::Buffers($name)
+    };
+    (buffer $name:ident) => {
+        $crate::wasi_snapshot_preview1::observe::Argument::Buffer($name.len())
+    };
+    (string $name:ident) => {
+        $crate::wasi_snapshot_preview1::observe::Argument::String(
+            <Self::StringRepresentation as wasihost_core::StringRepresentation>::target_as_bytes(
+                $name,
+            ),
+        )
+    };
+    (subscriptions $name:ident) => {
+        $crate::wasi_snapshot_preview1::observe::Argument::Subscriptions($name)
+    };
+}
+
+pub(super) use {wasi_argument, wasi_functions, wasi_parameter, wasi_result};
@@ -0,0 +1,189 @@
+use std::{cell::Cell, fmt, sync::Arc};
+use wasihost_core::wasi_snapshot_preview1::{
+    native::{errno, NativeCall, NativeCallResults, NativeWasiImportsLayer},
+    WasiImportsLayerExt,
+};
+use wasmer_runtime::{Ctx, ImportObject};
+
+thread_local! {
+    /// The number of layers that saw the current call before it was forwarded or denied. Calls
+    /// on a thread never overlap, because the imports do not call back into WASM.
+    static SEEN: Cell<usize> = const { Cell::new(0) };
+}
+
+/// The layers added to a WASI host, which see calls in the order they were added.
+#[derive(Clone, Default)]
+pub(super) struct Layers {
+    layers: Vec<Arc<dyn NativeWasiImportsLayer>>,
+}
+
+impl Layers {
+    pub(super) fn push(&mut self, layer: Arc<dyn NativeWasiImportsLayer>) {
+        self.layers.push(layer);
+    }
+
+    /// Adds `layer` in front of the other layers, so it sees every call before them.
+    pub(super) fn push_front(&mut self, layer: Arc<dyn NativeWasiImportsLayer>) {
+        self.layers.insert(0, layer);
+    }
+
+    /// Generates the imports for `imports`, passing every call through the layers.
+    pub(super) fn into_imports(self, imports: impl WasiImportsLayerExt) -> ImportObject {
+        if self.layers.is_empty() {
+            imports.into_imports()
+        } else {
+            imports.into_imports_with_layer(self)
+        }
+    }
+}
+
+impl NativeWasiImportsLayer for Layers {
+    fn before(&self, ctx: &mut Ctx, call: &NativeCall) -> Result<(), errno> {
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Err(errno) = layer.before(ctx, call) {
+                // Only the layers up to the one that denied the call see that it was denied.
+                SEEN.with(|seen| seen.set(i + 1));
+                return Err(errno);
+            }
+        }
+
+        SEEN.with(|seen| seen.set(self.layers.len()));
+        Ok(())
+    }
+
+    fn after(&self, ctx: &mut Ctx, call: &NativeCall, results: &NativeCallResults) {
+        let seen = SEEN.with(Cell::get);
+
+        for layer in self.layers[..seen].iter().rev() {
+            layer.after(ctx, call, results);
+        }
+    }
+}
+
+impl fmt::Debug for Layers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Layers")
+            .field("len", &self.layers.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasi_snapshot_preview1::{
+        test_binaries::{calling_with, exiting},
+        RunOutcome, WasiHostBuilder,
+    };
+    use parking_lot::Mutex;
+    use wasihost_core::wasi_snapshot_preview1::{native, Signal};
+
+    /// A layer that logs the calls it sees and denies the calls of `denied`.
+    struct Logging {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+        denied: Option<&'static str>,
+    }
+
+    impl NativeWasiImportsLayer for Logging {
+        fn before(&self, _ctx: &mut Ctx, call: &NativeCall) -> Result<(), errno> {
+            self.log
+                .lock()
+                .push(format!("{} before {}", self.name, call.name()));
+
+            if self.denied == Some(call.name()) || self.denied == Some("*") {
+                Err(native::errno_perm)
+            } else {
+                Ok(())
+            }
+        }
+
+        fn after(&self, _ctx: &mut Ctx, call: &NativeCall, results: &NativeCallResults) {
+            self.log.lock().push(format!(
+                "{} after {} {:?}",
+                self.name,
+                call.name(),
+                results.errno()
+            ));
+        }
+    }
+
+    fn logging(
+        name: &'static str,
+        log: &Arc<Mutex<Vec<String>>>,
+        denied: Option<&'static str>,
+    ) -> Logging {
+        Logging {
+            name,
+            log: log.clone(),
+            denied,
+        }
+    }
+
+    #[test]
+    fn layers_see_calls_in_the_order_they_were_added() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let host = WasiHostBuilder::<String>::new()
+            .layer(logging("a", &log, None))
+            .build()
+            .unwrap();
+        host.add_layer(logging("b", &log, None));
+
+        host.run_binary(&calling_with("fd_close", &[99], &[]))
+            .unwrap();
+
+        let badf = Some(native::errno_badf);
+        assert_eq!(
+            *log.lock(),
+            [
+                "a before fd_close".to_string(),
+                "b before fd_close".to_string(),
+                format!("b after fd_close {:?}", badf),
+                format!("a after fd_close {:?}", badf),
+            ]
+        );
+    }
+
+    #[test]
+    fn denied_calls_are_not_forwarded() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let host = WasiHostBuilder::<String>::new()
+            .layer(logging("a", &log, None))
+            .layer(logging("b", &log, Some("proc_raise")))
+            .layer(logging("c", &log, None))
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            host.run_binary(&calling_with("proc_raise", &[Signal::Term as i32], &[]))
+                .unwrap(),
+            RunOutcome::Exited(0)
+        );
+
+        let perm = Some(native::errno_perm);
+        assert_eq!(
+            *log.lock(),
+            [
+                "a before proc_raise".to_string(),
+                "b before proc_raise".to_string(),
+                format!("b after proc_raise {:?}", perm),
+                format!("a after proc_raise {:?}", perm),
+            ]
+        );
+    }
+
+    #[test]
+    fn exiting_can_not_be_denied() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let host = WasiHostBuilder::<String>::new()
+            .layer(logging("a", &log, Some("*")))
+            .build()
+            .unwrap();
+
+        assert_eq!(host.run_binary(&exiting(3)).unwrap(), RunOutcome::Exited(3));
+        assert_eq!(
+            *log.lock(),
+            ["a before proc_exit", "a after proc_exit None"]
+        );
+    }
+}
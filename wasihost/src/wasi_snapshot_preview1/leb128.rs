@@ -0,0 +1,61 @@
+//! The variable-length integers used by the WebAssembly binary format.
+
+/// Reads an unsigned LEB128 number at `position` and advances it past the number. Returns
+/// `None` if the number is truncated or does not fit into 32 bits.
+pub(super) fn read_u32(binary: &[u8], position: &mut usize) -> Option<u32> {
+    let mut result = 0u32;
+
+    for shift in (0..35).step_by(7) {
+        let byte = *binary.get(*position)?;
+        *position += 1;
+
+        // Only the lowest four bits of the fifth byte fit into 32 bits.
+        if shift == 28 && byte & 0x70 != 0 {
+            return None;
+        }
+        result |= u32::from(byte & 0x7f) << shift;
+
+        if byte & 0x80 == 0 {
+            return Some(result);
+        }
+    }
+
+    None
+}
+
+/// Appends `value` as an unsigned LEB128 number.
+pub(super) fn write_u32(buffer: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            buffer.push(byte);
+            return;
+        }
+        buffer.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_numbers() {
+        for &value in &[0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX] {
+            let mut buffer = Vec::new();
+            write_u32(&mut buffer, value);
+
+            let mut position = 0;
+            assert_eq!(read_u32(&buffer, &mut position), Some(value));
+            assert_eq!(position, buffer.len());
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_and_oversized_numbers() {
+        assert_eq!(read_u32(&[0x80], &mut 0), None);
+        assert_eq!(read_u32(&[0xff, 0xff, 0xff, 0xff, 0x7f], &mut 0), None);
+    }
+}
@@ -0,0 +1,265 @@
+use super::{
+    leb128,
+    run::{LimitKind, RunError},
+};
+use std::{
+    cmp,
+    io::{IoSlice, IoSliceMut},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use wasihost_core::StringRepresentation;
+
+/// Limits on the resources a WASI binary may use. Every limit is disabled by default.
+///
+/// The byte limits count from the start of each run, a reactor counts from its instantiation.
+/// Growing a regular file, by writing past its end or changing its size, counts the bytes
+/// it grows by as written.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// The maximum number of open file descriptors. Opening more fails with `Errno::Mfile`.
+    pub max_open_fds: Option<usize>,
+    /// The maximum number of bytes written to a single file descriptor. Writing more fails
+    /// with `Errno::Fbig`.
+    pub max_bytes_written_per_fd: Option<u64>,
+    /// The maximum number of bytes written to all file descriptors. Writing more fails with
+    /// `Errno::Dquot`.
+    pub max_bytes_written: Option<u64>,
+    /// The maximum number of bytes read from all file descriptors. Reading more fails with
+    /// `Errno::Dquot`.
+    pub max_bytes_read: Option<u64>,
+    /// The maximum number of 64 KiB pages of linear memory. Growing the memory further fails
+    /// inside the WASI binary, binaries that require more memory are not instantiated.
+    pub max_memory_pages: Option<u32>,
+    /// The maximum size of the arguments and environment variables in bytes, including
+    /// their terminating NUL bytes. Binaries are not instantiated if it is exceeded.
+    pub max_args_environ_size: Option<usize>,
+}
+
+impl ResourceLimits {
+    pub(super) fn check_args_environ<S: StringRepresentation>(
+        &self,
+        arguments: &[S],
+        environment: &[S],
+    ) -> Result<(), LimitKind> {
+        if let Some(max) = self.max_args_environ_size {
+            let size: usize = arguments
+                .iter()
+                .chain(environment)
+                .map(|s| s.as_bytes().len() + 1)
+                .sum();
+
+            if size > max {
+                return Err(LimitKind::ArgsEnviron);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reserves up to `requested` units from `counter` without exceeding `limit` and returns the
+/// number of units reserved.
+pub(super) fn reserve(counter: &AtomicU64, limit: Option<u64>, requested: u64) -> u64 {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => {
+            counter.fetch_add(requested, Ordering::SeqCst);
+            return requested;
+        }
+    };
+    let mut used = counter.load(Ordering::SeqCst);
+
+    loop {
+        let reserved = cmp::min(requested, limit.saturating_sub(used));
+
+        match counter.compare_exchange(used, used + reserved, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return reserved,
+            Err(actual) => used = actual,
+        }
+    }
+}
+
+/// Returns `amount` units that were reserved but not used to `counter`.
+pub(super) fn release(counter: &AtomicU64, amount: u64) {
+    counter.fetch_sub(amount, Ordering::SeqCst);
+}
+
+pub(super) fn total_len(bufs: &[IoSlice<'_>]) -> u64 {
+    bufs.iter().map(|buf| buf.len() as u64).sum()
+}
+
+pub(super) fn total_len_mut(iovs: &[IoSliceMut<'_>]) -> u64 {
+    iovs.iter().map(|iov| iov.len() as u64).sum()
+}
+
+/// Returns the first `len` bytes of `bufs`.
+pub(super) fn truncate<'a>(bufs: &'a [IoSlice<'_>], mut len: u64) -> Vec<IoSlice<'a>> {
+    let mut truncated = Vec::new();
+
+    for buf in bufs {
+        let n = cmp::min(buf.len() as u64, len) as usize;
+        truncated.push(IoSlice::new(&buf[..n]));
+        len -= n as u64;
+    }
+
+    truncated
+}
+
+/// Returns the first `len` bytes of `iovs`.
+pub(super) fn truncate_mut<'a>(
+    iovs: &'a mut [IoSliceMut<'_>],
+    mut len: u64,
+) -> Vec<IoSliceMut<'a>> {
+    let mut truncated = Vec::new();
+
+    for iov in iovs {
+        let n = cmp::min(iov.len() as u64, len) as usize;
+        truncated.push(IoSliceMut::new(&mut iov[..n]));
+        len -= n as u64;
+    }
+
+    truncated
+}
+
+const MEMORY_SECTION: u8 = 5;
+
+/// Rewrites the memories defined by `binary` so they can not grow beyond `max_pages` pages.
+/// Returns `None` if a memory requires more than `max_pages` pages initially.
+pub(super) fn limit_memory(binary: &[u8], max_pages: u32) -> Result<Option<Vec<u8>>, RunError> {
+    let invalid = || RunError::Compile("invalid WebAssembly binary".to_string());
+
+    if binary.len() < 8 {
+        return Err(invalid());
+    }
+
+    let mut output = binary[..8].to_vec();
+    let mut position = 8;
+
+    while position < binary.len() {
+        let id = binary[position];
+        position += 1;
+
+        let size = leb128::read_u32(binary, &mut position).ok_or_else(invalid)? as usize;
+        let end = position.checked_add(size).ok_or_else(invalid)?;
+        let content = binary.get(position..end).ok_or_else(invalid)?;
+        position = end;
+
+        if id != MEMORY_SECTION {
+            output.push(id);
+            leb128::write_u32(&mut output, size as u32);
+            output.extend_from_slice(content);
+            continue;
+        }
+
+        let mut section = Vec::new();
+        let mut cursor = 0;
+        let count = leb128::read_u32(content, &mut cursor).ok_or_else(invalid)?;
+        leb128::write_u32(&mut section, count);
+
+        for _ in 0..count {
+            let flags = *content.get(cursor).ok_or_else(invalid)?;
+            cursor += 1;
+
+            // Only 32 bit memories are supported, the lowest bit signals a maximum.
+            if flags & !0x03 != 0 {
+                return Err(invalid());
+            }
+
+            let minimum = leb128::read_u32(content, &mut cursor).ok_or_else(invalid)?;
+            let maximum = if flags & 0x01 != 0 {
+                leb128::read_u32(content, &mut cursor).ok_or_else(invalid)?
+            } else {
+                max_pages
+            };
+
+            if minimum > max_pages {
+                return Ok(None);
+            }
+
+            section.push(flags | 0x01);
+            leb128::write_u32(&mut section, minimum);
+            leb128::write_u32(&mut section, cmp::min(maximum, max_pages));
+        }
+
+        if cursor != content.len() {
+            return Err(invalid());
+        }
+
+        output.push(id);
+        leb128::write_u32(&mut output, section.len() as u32);
+        output.extend_from_slice(&section);
+    }
+
+    Ok(Some(output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserves_up_to_the_limit() {
+        let counter = AtomicU64::new(0);
+
+        assert_eq!(reserve(&counter, Some(10), 4), 4);
+        assert_eq!(reserve(&counter, Some(10), 8), 6);
+        assert_eq!(reserve(&counter, Some(10), 1), 0);
+        release(&counter, 3);
+        assert_eq!(counter.load(Ordering::SeqCst), 7);
+        assert_eq!(reserve(&counter, None, 100), 100);
+        assert_eq!(counter.load(Ordering::SeqCst), 107);
+    }
+
+    #[test]
+    fn reserves_nothing_past_a_lowered_limit() {
+        let counter = AtomicU64::new(20);
+
+        assert_eq!(reserve(&counter, Some(10), 5), 0);
+        assert_eq!(counter.load(Ordering::SeqCst), 20);
+    }
+
+    #[test]
+    fn truncates_buffers() {
+        let (a, b) = (*b"abc", *b"defg");
+        let bufs = [IoSlice::new(&a), IoSlice::new(&b)];
+
+        assert_eq!(total_len(&bufs), 7);
+        let truncated = truncate(&bufs, 5);
+        assert_eq!(total_len(&truncated), 5);
+        assert_eq!(&*truncated[1], b"de");
+
+        let (mut c, mut d) = ([0; 2], [0; 3]);
+        let mut iovs = [IoSliceMut::new(&mut c), IoSliceMut::new(&mut d)];
+        let truncated = truncate_mut(&mut iovs, 1);
+        assert_eq!(total_len_mut(&truncated), 1);
+        assert!(truncated[1].is_empty());
+    }
+
+    /// A binary with a single memory section defining `memory`.
+    fn binary(memory: &[u8]) -> Vec<u8> {
+        let mut binary = b"\0asm\x01\0\0\0".to_vec();
+        binary.push(MEMORY_SECTION);
+        binary.push(memory.len() as u8 + 1);
+        binary.push(1);
+        binary.extend_from_slice(memory);
+        binary
+    }
+
+    #[test]
+    fn limits_the_maximum_memory() {
+        assert_eq!(
+            limit_memory(&binary(&[0, 2]), 4).unwrap(),
+            Some(binary(&[1, 2, 4]))
+        );
+        assert_eq!(
+            limit_memory(&binary(&[1, 1, 8]), 4).unwrap(),
+            Some(binary(&[1, 1, 4]))
+        );
+        assert_eq!(
+            limit_memory(&binary(&[1, 1, 2]), 4).unwrap(),
+            Some(binary(&[1, 1, 2]))
+        );
+        assert_eq!(limit_memory(&binary(&[0, 5]), 4).unwrap(), None);
+        assert!(limit_memory(&binary(&[0, 0x80]), 4).is_err());
+    }
+}
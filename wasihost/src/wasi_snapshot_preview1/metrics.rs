@@ -0,0 +1,329 @@
+use parking_lot::Mutex;
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+use wasihost_core::wasi_snapshot_preview1::{
+    native::{errno, NativeCall, NativeCallResults, NativeWasiImportsLayer},
+    Errno, Fd,
+};
+use wasmer_runtime::Ctx;
+use witx_gen::WasiValue;
+
+thread_local! {
+    /// When the metrics saw the current call. Calls on a thread never overlap.
+    static STARTED: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// The metrics of the calls of one WASI function.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FunctionMetrics {
+    /// The number of calls.
+    pub calls: u64,
+    /// The time spent in all calls together, including the time spent in the other layers of
+    /// the WASI host. Calls that terminate the run on a signal or are cancelled add no time.
+    pub latency: Duration,
+    /// The number of calls that failed or were denied by a layer, by error. Successful calls
+    /// are not included.
+    pub errors: BTreeMap<Errno, u64>,
+}
+
+/// The data transferred through one file descriptor number.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FdMetrics {
+    /// The bytes read with `fd_read`, `fd_pread` and `sock_recv`.
+    pub bytes_read: u64,
+    /// The bytes written with `fd_write`, `fd_pwrite` and `sock_send`.
+    pub bytes_written: u64,
+}
+
+/// The metrics of a WASI host at one point in time, see
+/// [`Metrics::snapshot`](struct.Metrics.html#method.snapshot).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MetricsSnapshot {
+    /// The metrics of each WASI function that was called, by name.
+    pub functions: BTreeMap<&'static str, FunctionMetrics>,
+    /// The data transferred through each file descriptor number that was read or written.
+    /// File descriptors that are closed and opened again share their number.
+    pub fds: BTreeMap<Fd, FdMetrics>,
+}
+
+impl MetricsSnapshot {
+    /// Returns the number of calls of all functions.
+    pub fn calls(&self) -> u64 {
+        self.functions.values().map(|function| function.calls).sum()
+    }
+
+    /// Returns the time spent in the calls of all functions.
+    pub fn latency(&self) -> Duration {
+        self.functions
+            .values()
+            .map(|function| function.latency)
+            .sum()
+    }
+
+    /// Returns the number of failed calls of all functions, by error.
+    pub fn errors(&self) -> BTreeMap<Errno, u64> {
+        let mut errors = BTreeMap::new();
+
+        for function in self.functions.values() {
+            for (&errno, &count) in &function.errors {
+                *errors.entry(errno).or_insert(0) += count;
+            }
+        }
+
+        errors
+    }
+
+    /// Returns the bytes read from all file descriptors.
+    pub fn bytes_read(&self) -> u64 {
+        self.fds.values().map(|fd| fd.bytes_read).sum()
+    }
+
+    /// Returns the bytes written to all file descriptors.
+    pub fn bytes_written(&self) -> u64 {
+        self.fds.values().map(|fd| fd.bytes_written).sum()
+    }
+}
+
+/// The live metrics of the system calls handled by a WASI host, returned by
+/// [`WasiHost::metrics`](struct.WasiHost.html#method.metrics) when they are collected.
+///
+/// The counters are updated after every call, so they can be read from another thread while
+/// a WASI binary runs, and accumulate over all runs of the WASI host until they are
+/// [reset](#method.reset). Calls that end the run are counted as well. The metrics see every
+/// call before the [layers](struct.WasiHost.html#method.add_layer) of the WASI host, so calls
+/// denied by a layer are counted with the error they were denied with.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    current: Mutex<MetricsSnapshot>,
+}
+
+impl Metrics {
+    /// Returns a copy of the current metrics.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.current.lock().clone()
+    }
+
+    /// Returns the number of calls of `function` so far.
+    pub fn calls(&self, function: &str) -> u64 {
+        self.current
+            .lock()
+            .functions
+            .get(function)
+            .map_or(0, |function| function.calls)
+    }
+
+    /// Returns the bytes read from `fd` so far.
+    pub fn bytes_read(&self, fd: Fd) -> u64 {
+        self.current
+            .lock()
+            .fds
+            .get(&fd)
+            .map_or(0, |fd| fd.bytes_read)
+    }
+
+    /// Returns the bytes written to `fd` so far.
+    pub fn bytes_written(&self, fd: Fd) -> u64 {
+        self.current
+            .lock()
+            .fds
+            .get(&fd)
+            .map_or(0, |fd| fd.bytes_written)
+    }
+
+    /// Returns the metrics collected so far and starts over, for example to account for each
+    /// run separately.
+    pub fn reset(&self) -> MetricsSnapshot {
+        std::mem::take(&mut *self.current.lock())
+    }
+
+    fn count(&self, function: &'static str) {
+        self.current
+            .lock()
+            .functions
+            .entry(function)
+            .or_default()
+            .calls += 1;
+    }
+
+    fn record(
+        &self,
+        function: &'static str,
+        errno: Option<Errno>,
+        transferred: Option<(Fd, u64, u64)>,
+        latency: Duration,
+    ) {
+        let mut current = self.current.lock();
+        let metrics = current.functions.entry(function).or_default();
+
+        metrics.latency += latency;
+        if let Some(errno) = errno {
+            *metrics.errors.entry(errno).or_insert(0) += 1;
+        }
+
+        if let Some((fd, read, written)) = transferred {
+            let fd = current.fds.entry(fd).or_default();
+
+            fd.bytes_read += read;
+            fd.bytes_written += written;
+        }
+    }
+}
+
+impl NativeWasiImportsLayer for Metrics {
+    fn before(&self, _ctx: &mut Ctx, call: &NativeCall) -> Result<(), errno> {
+        // Counted before the call, as calls that terminate the run on a signal never return.
+        self.count(call.name());
+        STARTED.with(|started| started.set(Some(Instant::now())));
+        Ok(())
+    }
+
+    fn after(&self, _ctx: &mut Ctx, call: &NativeCall, results: &NativeCallResults) {
+        let latency = STARTED
+            .with(Cell::take)
+            .map_or_else(Duration::default, |started| started.elapsed());
+        let errno = results
+            .errno()
+            .and_then(|errno| Errno::from_native(errno).ok())
+            .filter(|&errno| errno != Errno::Success);
+        let transferred = match (*call, *results) {
+            (NativeCall::FdRead { fd, .. }, NativeCallResults::FdRead(_, size))
+            | (NativeCall::FdPread { fd, .. }, NativeCallResults::FdPread(_, size))
+            | (NativeCall::SockRecv { fd, .. }, NativeCallResults::SockRecv(_, size, _)) => {
+                Some((Fd(fd), u64::from(size), 0))
+            }
+            (NativeCall::FdWrite { fd, .. }, NativeCallResults::FdWrite(_, size))
+            | (NativeCall::FdPwrite { fd, .. }, NativeCallResults::FdPwrite(_, size))
+            | (NativeCall::SockSend { fd, .. }, NativeCallResults::SockSend(_, size)) => {
+                Some((Fd(fd), 0, u64::from(size)))
+            }
+            _ => None,
+        };
+
+        self.record(call.name(), errno, transferred, latency);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasi_snapshot_preview1::{
+        test_binaries::{calling_with, exiting},
+        CaptureDevice, RunOutcome, WasiHostBuilder,
+    };
+    use std::sync::Arc;
+    use wasihost_core::wasi_snapshot_preview1::{native, Signal};
+
+    /// A layer that denies every call.
+    struct Denying;
+
+    impl NativeWasiImportsLayer for Denying {
+        fn before(&self, _ctx: &mut Ctx, _call: &NativeCall) -> Result<(), errno> {
+            Err(native::errno_perm)
+        }
+    }
+
+    #[test]
+    fn metrics_are_collected_on_request() {
+        let host = WasiHostBuilder::<String>::new().build().unwrap();
+        let binary = calling_with("sched_yield", &[], &[]);
+
+        host.clone().run_binary(&binary).unwrap();
+        assert!(host.metrics().is_none());
+
+        let metrics = host.collect_metrics();
+        host.clone().run_binary(&binary).unwrap();
+        assert!(Arc::ptr_eq(&host.metrics().unwrap(), &metrics));
+        assert!(Arc::ptr_eq(&host.collect_metrics(), &metrics));
+        assert_eq!(metrics.calls("sched_yield"), 1);
+    }
+
+    #[test]
+    fn calls_errors_and_bytes_are_counted() {
+        let stdout = CaptureDevice::new();
+        let host = WasiHostBuilder::<String>::new()
+            .stdout(stdout.clone())
+            .collect_metrics()
+            .build()
+            .unwrap();
+        let mut data = vec![0; 8];
+        // One iovec of 5 bytes at address 16, the number of bytes written goes to address 4.
+        data.extend_from_slice(&[16, 0, 0, 0, 5, 0, 0, 0]);
+        data.extend_from_slice(b"hello");
+
+        host.clone()
+            .run_binary(&calling_with("fd_write", &[1, 8, 1, 4], &data))
+            .unwrap();
+        for _ in 0..2 {
+            host.clone()
+                .run_binary(&calling_with("fd_close", &[99], &[]))
+                .unwrap();
+        }
+
+        let metrics = host.metrics().unwrap();
+        let snapshot = metrics.snapshot();
+        assert_eq!(stdout.contents(), b"hello");
+        assert_eq!(metrics.calls("fd_write"), 1);
+        assert_eq!(metrics.calls("fd_close"), 2);
+        assert_eq!(metrics.bytes_written(Fd(1)), 5);
+        assert_eq!(metrics.bytes_read(Fd(1)), 0);
+        assert_eq!(snapshot.calls(), 3);
+        assert_eq!(snapshot.bytes_written(), 5);
+        assert_eq!(snapshot.functions["fd_write"].errors, BTreeMap::new());
+        assert_eq!(
+            snapshot.errors(),
+            vec![(Errno::Badf, 2)].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn calls_ending_the_run_are_counted() {
+        let host = WasiHostBuilder::<String>::new()
+            .collect_metrics()
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            host.clone().run_binary(&exiting(3)).unwrap(),
+            RunOutcome::Exited(3)
+        );
+        assert_eq!(
+            host.clone()
+                .run_binary(&calling_with("proc_raise", &[Signal::Term as i32], &[]))
+                .unwrap(),
+            RunOutcome::Signaled(Signal::Term)
+        );
+
+        let metrics = host.metrics().unwrap();
+        let snapshot = metrics.reset();
+        assert_eq!(snapshot.functions["proc_exit"].calls, 1);
+        assert_eq!(snapshot.functions["proc_raise"].calls, 1);
+        assert!(snapshot.errors().is_empty());
+        assert_eq!(metrics.snapshot(), MetricsSnapshot::default());
+    }
+
+    #[test]
+    fn calls_denied_by_layers_are_counted() {
+        let host = WasiHostBuilder::<String>::new()
+            .layer(Denying)
+            .build()
+            .unwrap();
+        let metrics = host.collect_metrics();
+
+        host.clone()
+            .run_binary(&calling_with("sched_yield", &[], &[]))
+            .unwrap();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.functions["sched_yield"].calls, 1);
+        assert_eq!(
+            snapshot.errors(),
+            vec![(Errno::Perm, 1)].into_iter().collect()
+        );
+    }
+}
@@ -1,65 +1,291 @@
 //! High-level abstraction for executing binaries conforming to WASI snapshot preview 1.
 
 mod atomic;
+mod builder;
+mod cancel;
+mod clock;
+mod compiled;
+mod executor;
+mod fd_table;
+mod functions;
+mod layers;
+mod leb128;
+mod limits;
+mod metrics;
+mod observe;
+mod poll;
+mod random;
+mod reactor;
+mod record;
+mod replay;
+mod run;
+mod signal;
+#[cfg(test)]
+mod test_binaries;
+#[cfg(test)]
+mod test_util;
+mod trace;
 mod wasi_fd;
 
-use parking_lot::Mutex;
-use rand::{distributions::Uniform, thread_rng, Rng};
+use self::{
+    cancel::Interrupt,
+    fd_table::FdTable,
+    layers::Layers,
+    observe::ObservedImports,
+    signal::{Killed, SignalHandlers},
+    trace::Tracer,
+};
+use parking_lot::{Mutex, RwLock};
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
 use std::{
-    collections::hash_map::{Entry, HashMap},
-    error::Error,
+    collections::HashMap,
     fs::File,
-    io::{IoSlice, IoSliceMut, Read},
-    mem,
+    io::{self, IoSlice, IoSliceMut, Read},
     ops::Deref,
+    panic,
     path::Path,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use wasihost_core::{
+    wasi_snapshot_preview1::{native::NativeWasiImportsLayer, *},
+    StringRepresentation,
 };
-use wasihost_core::{wasi_snapshot_preview1::*, StringRepresentation};
-use wasmer_runtime::{instantiate, Func};
+use wasmer_runtime::{ImportObject, Instance};
 
-pub use self::wasi_fd::{CharacterDevice, Stderr, Stdin, Stdout, WasiFd};
+pub use self::builder::{BuildError, WasiHostBuilder};
+pub use self::cancel::CancellationHandle;
+use self::clock::SharedClock;
+pub use self::clock::{
+    ClockProvider, FixedClock, ManualClock, OffsetClock, ScaledClock, SystemClock, VirtualClock,
+};
+pub use self::compiled::{CompiledModule, ModuleCache};
+pub use self::executor::{BlockingRun, BlockingTask, Executor, ThreadPool};
+pub use self::fd_table::FdAllocationPolicy;
+pub use self::limits::ResourceLimits;
+pub use self::metrics::{FdMetrics, FunctionMetrics, Metrics, MetricsSnapshot};
+pub use self::random::{OsRandom, RandomSource, ReplayRandom, SeededRandom};
+pub use self::reactor::{Reactor, ReactorError};
+pub use self::record::{Recorder, RecordingImports};
+pub use self::replay::{Divergence, ReplayImports};
+pub use self::run::{LimitKind, RunError, RunOutcome};
+pub use self::signal::SignalAction;
+pub use self::trace::{TraceEvent, TraceSink, TraceWriter, TracingImports};
+pub use self::wasi_fd::{
+    pipe, BlockingDevice, CaptureDevice, CharacterDevice, MemoryFs, Opened, PipeReader, PipeWriter,
+    Pollable, PollingCharacterDevice, ReaderDevice, Stderr, Stdin, Stdout, WasiDirectory, WasiFd,
+    WasiFile, WasiSocket,
+};
+pub use wasmer_runtime::{Ctx, Value};
 
 /// Host functions for WASI.
 #[derive(Debug)]
 pub struct WasiHost<S: StringRepresentation> {
     arguments: Vec<S>,
     environment: Vec<S>,
-    fds: Mutex<HashMap<Fd, Arc<WasiFd<S>>>>,
-    fd_distribution: Uniform<u32>,
+    fds: Mutex<FdTable<S>>,
+    clock: SharedClock,
+    random: RwLock<Box<dyn RandomSource>>,
+    limits: RwLock<ResourceLimits>,
+    bytes_written: AtomicU64,
+    bytes_read: AtomicU64,
+    interrupt: Interrupt,
+    signals: RwLock<SignalHandlers>,
+    trace_sink: RwLock<Option<Tracer>>,
+    layers: RwLock<Layers>,
+    recorder: Mutex<Option<Recorder>>,
+    metrics: RwLock<Option<Arc<Metrics>>>,
 }
 
 impl<S: StringRepresentation> WasiHost<S> {
+    /// Returns a builder that configures a new WASI host.
+    pub fn builder() -> WasiHostBuilder<S> {
+        WasiHostBuilder::new()
+    }
+
     /// Creates a new WASI host.
     pub fn new(
         arguments: impl IntoIterator<Item = impl Into<S>>,
         environment: impl IntoIterator<Item = impl Into<S>>,
         fd_initialzer: impl WasiFdInitializer<S>,
+    ) -> Arc<Self> {
+        Self::with_sources(
+            arguments,
+            environment,
+            fd_initialzer,
+            ChaCha20Rng::from_entropy(),
+            Box::new(SystemClock::new()),
+            Box::new(OsRandom),
+        )
+    }
+
+    /// Creates a new WASI host whose runs are reproducible.
+    ///
+    /// The WASI binary observes virtual clocks that start at a fixed time and only advance
+    /// when they are read or waited on, random data generated from `seed`, and file
+    /// descriptor numbers chosen from a sequence seeded the same way. Running the same binary
+    /// with the same inputs and `seed` results in the same system calls and outputs.
+    pub fn new_deterministic(
+        arguments: impl IntoIterator<Item = impl Into<S>>,
+        environment: impl IntoIterator<Item = impl Into<S>>,
+        fd_initialzer: impl WasiFdInitializer<S>,
+        seed: u64,
+    ) -> Arc<Self> {
+        Self::with_sources(
+            arguments,
+            environment,
+            fd_initialzer,
+            ChaCha20Rng::seed_from_u64(seed),
+            Box::new(VirtualClock::new()),
+            Box::new(SeededRandom::new(seed)),
+        )
+    }
+
+    fn with_sources(
+        arguments: impl IntoIterator<Item = impl Into<S>>,
+        environment: impl IntoIterator<Item = impl Into<S>>,
+        fd_initialzer: impl WasiFdInitializer<S>,
+        fd_rng: ChaCha20Rng,
+        clock: Box<dyn ClockProvider>,
+        random: Box<dyn RandomSource>,
     ) -> Arc<Self> {
         let arguments = arguments.into_iter().map(|s| s.into()).collect();
         let environment = environment.into_iter().map(|s| s.into()).collect();
-        let fds = Mutex::new(
-            fd_initialzer
-                .initialize()
-                .into_iter()
-                .map(|(k, v)| (k, Arc::new(v)))
-                .collect(),
-        );
-        let fd_distribution = (0..2u32.pow(31)).into();
+        let clock: SharedClock = Arc::new(RwLock::new(clock));
+        let fds = FdTable::new(fd_initialzer.initialize(), fd_rng);
+
+        for fd in fds.values() {
+            fd.share_clock(&clock);
+        }
 
         Arc::new(WasiHost {
             arguments,
             environment,
-            fds,
-            fd_distribution,
+            fds: Mutex::new(fds),
+            clock,
+            random: RwLock::new(random),
+            limits: RwLock::new(ResourceLimits::default()),
+            bytes_written: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            interrupt: Interrupt::default(),
+            signals: RwLock::new(SignalHandlers::default()),
+            trace_sink: RwLock::new(None),
+            layers: RwLock::new(Layers::default()),
+            recorder: Mutex::new(None),
+            metrics: RwLock::new(None),
         })
     }
 
+    /// Replaces the clocks observed by the WASI binary.
+    pub fn set_clock_provider(&self, clock: impl ClockProvider) {
+        *self.clock.write() = Box::new(clock);
+    }
+
+    /// Sets how the numbers of newly opened file descriptors are chosen. Defaults to
+    /// [`FdAllocationPolicy::Random`](enum.FdAllocationPolicy.html#variant.Random).
+    pub fn set_fd_allocation_policy(&self, policy: FdAllocationPolicy) {
+        self.fds.lock().set_policy(policy);
+    }
+
+    /// Replaces the source of the random data returned to the WASI binary.
+    pub fn set_random_source(&self, random: impl RandomSource) {
+        *self.random.write() = Box::new(random);
+    }
+
+    /// Limits the resources the WASI binary may use. Limits on memory and on the size of the
+    /// arguments and environment variables only apply to binaries that are run afterwards.
+    pub fn set_resource_limits(&self, limits: ResourceLimits) {
+        *self.limits.write() = limits;
+    }
+
+    /// Decides with `handler` what happens when the WASI binary raises `signal`, instead of
+    /// the [default action](enum.SignalAction.html#method.default_for).
+    pub fn set_signal_handler(
+        &self,
+        signal: Signal,
+        handler: impl Fn(Signal) -> SignalAction + Send + Sync + 'static,
+    ) {
+        self.signals.write().set(signal, Box::new(handler));
+    }
+
+    /// Restores the default action for `signal`.
+    pub fn reset_signal_handler(&self, signal: Signal) {
+        self.signals.write().remove(signal);
+    }
+
+    /// Reports every system call of the WASI binaries run afterwards to `sink`, see
+    /// [`TracingImports`](type.TracingImports.html).
+    pub fn set_trace_sink(&self, sink: impl TraceSink) {
+        *self.trace_sink.write() = Some(Tracer(Arc::new(sink)));
+    }
+
+    /// Stops tracing the system calls of the WASI binaries run afterwards.
+    pub fn remove_trace_sink(&self) {
+        *self.trace_sink.write() = None;
+    }
+
+    /// Passes every system call of the WASI binaries run afterwards through the hooks of
+    /// `layer`, for example to deny calls. Layers see the calls in the
+    /// order they were added, and after the calls in reverse order.
+    pub fn add_layer(&self, layer: impl NativeWasiImportsLayer) {
+        self.layers.write().push(Arc::new(layer));
+    }
+
+    /// Records the system calls of the next run, or of the next reactor and all calls into it,
+    /// with `recorder`. The recording can be replayed with
+    /// [`ReplayImports`](struct.ReplayImports.html).
+    ///
+    /// Calls are recorded as this WASI host handles them, so calls denied by a layer are not
+    /// recorded.
+    pub fn record_next_run(&self, recorder: Recorder) {
+        *self.recorder.lock() = Some(recorder);
+    }
+
+    /// Collects the metrics of the system calls of the WASI binaries run afterwards: the number
+    /// and duration of the calls of each function, their errors and the bytes read and written
+    /// through each file descriptor. Returns the metrics, which keep their counts if they were
+    /// already collected.
+    pub fn collect_metrics(&self) -> Arc<Metrics> {
+        self.metrics
+            .write()
+            .get_or_insert_with(|| Arc::new(Metrics::default()))
+            .clone()
+    }
+
+    /// Returns the metrics of the system calls handled by this WASI host, or `None` if they are
+    /// not [collected](#method.collect_metrics).
+    ///
+    /// The metrics are updated while WASI binaries run. Take a
+    /// [snapshot](struct.Metrics.html#method.snapshot) once a run has finished to account for
+    /// it.
+    pub fn metrics(&self) -> Option<Arc<Metrics>> {
+        self.metrics.read().clone()
+    }
+
+    /// Returns a handle that cancels the runs of this WASI host from another thread.
+    ///
+    /// Cancelled runs end with [`RunOutcome::Cancelled`](enum.RunOutcome.html#variant.Cancelled). The WASI
+    /// binary is stopped at its next system call, while it waits in `poll_oneoff`, for a pipe or
+    /// for standard input, and at the next function call or loop iteration while it computes.
+    /// Devices of the embedder that block are waited for.
+    pub fn cancellation_handle(&self) -> CancellationHandle {
+        self.interrupt.handle()
+    }
+
+    /// Limits the wall-clock time of each run and of each call into a
+    /// [`Reactor`](struct.Reactor.html), measured from its start. Runs that take longer
+    /// end with [`LimitKind::Time`](enum.LimitKind.html#variant.Time), subject to the same
+    /// restrictions as cancellation.
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        self.interrupt.set_timeout(timeout);
+    }
+
     /// Runs a WASM file on this WASI host.
-    pub fn run_file(
-        self: Arc<Self>,
-        wasm_file: impl AsRef<Path>,
-    ) -> Result<native::exitcode, Box<dyn Error>> {
+    pub fn run_file(self: Arc<Self>, wasm_file: impl AsRef<Path>) -> Result<RunOutcome, RunError> {
         let mut wasm_binary = Vec::new();
 
         {
@@ -71,30 +297,142 @@ impl<S: StringRepresentation> WasiHost<S> {
     }
 
     /// Runs a WASM binary from memory on this WASI host.
-    pub fn run_binary(
+    ///
+    /// Returns how the run ended, or an error if the binary could not be run at all.
+    pub fn run_binary(self: Arc<Self>, wasm_binary: &[u8]) -> Result<RunOutcome, RunError> {
+        let module = match self.limits.read().max_memory_pages {
+            Some(max_pages) => CompiledModule::compile_with_memory_limit(wasm_binary, max_pages)?,
+            None => CompiledModule::compile(wasm_binary)?,
+        };
+
+        self.run_module(&module)
+    }
+
+    /// Runs a compiled WASM binary on this WASI host.
+    ///
+    /// Returns how the run ended, or an error if the module could not be run at all.
+    pub fn run_module(self: Arc<Self>, module: &CompiledModule) -> Result<RunOutcome, RunError> {
+        match self.clone().instantiate(module)? {
+            Ok(instance) => {
+                let (outcome, interrupted) =
+                    self.interrupt.run(&instance, || run::start(&instance));
+                outcome.map(|outcome| outcome.interrupted_by(interrupted))
+            }
+            Err(outcome) => Ok(outcome),
+        }
+    }
+
+    /// Hands a run of a WASM binary like [`run_binary`](#method.run_binary) to `executor`,
+    /// which compiles and runs it on one of its threads, and returns a future for its outcome.
+    ///
+    /// The run occupies that thread until it ends, like [`run_module_on`](#method.run_module_on)
+    /// describes.
+    pub fn run_binary_on(
+        self: Arc<Self>,
+        wasm_binary: Vec<u8>,
+        executor: &dyn Executor,
+    ) -> BlockingRun {
+        BlockingRun::spawn(executor, move || self.run_binary(&wasm_binary))
+    }
+
+    /// Hands a run of a compiled WASM binary like [`run_module`](#method.run_module) to
+    /// `executor`, which runs it on one of its threads, and returns a future for its outcome.
+    ///
+    /// This is not an asynchronous run: wasmer 0.14 can not suspend WASM code, so system calls
+    /// can not be handed to the executor on their own. The run occupies one thread of the
+    /// executor from start to end, including while a
+    /// [`BlockingDevice`](struct.BlockingDevice.html) waits, and only waiting for the outcome
+    /// does not block. An executor with a bounded number of threads, like a
+    /// [`ThreadPool`](struct.ThreadPool.html), caps the threads many WASI hosts use, with runs
+    /// waiting for a free one.
+    pub fn run_module_on(
+        self: Arc<Self>,
+        module: CompiledModule,
+        executor: &dyn Executor,
+    ) -> BlockingRun {
+        BlockingRun::spawn(executor, move || self.run_module(&module))
+    }
+
+    /// Instantiates a compiled reactor module, which exports functions that are called
+    /// repeatedly instead of a `_start` function, and calls its `_initialize` function if it
+    /// exports one.
+    ///
+    /// All calls into the returned [`Reactor`](struct.Reactor.html) share this WASI host, so
+    /// open file descriptors and other state persist between them.
+    pub fn instantiate_reactor(
         self: Arc<Self>,
-        wasm_binary: &[u8],
-    ) -> Result<native::exitcode, Box<dyn Error>> {
-        let instance = {
-            let import_object = self.into_imports();
-            instantiate(&wasm_binary, &import_object)?
+        module: &CompiledModule,
+    ) -> Result<Reactor<S>, ReactorError> {
+        match self.clone().instantiate(module)? {
+            Ok(instance) => Reactor::initialize(self, instance),
+            Err(outcome) => Err(ReactorError::Stopped(outcome)),
+        }
+    }
+
+    /// Checks the resource limits and instantiates `module`, or returns the outcome of a run
+    /// that ended before it started.
+    fn instantiate(
+        self: Arc<Self>,
+        module: &CompiledModule,
+    ) -> Result<Result<Instance, RunOutcome>, RunError> {
+        let limits = self.limits.read().clone();
+
+        if let Err(kind) = limits.check_args_environ(&self.arguments, &self.environment) {
+            return Ok(Err(RunOutcome::LimitExceeded(kind)));
+        }
+        if let Some(max_pages) = limits.max_memory_pages {
+            if !module.check_memory(max_pages)? {
+                return Ok(Err(RunOutcome::LimitExceeded(LimitKind::Memory)));
+            }
+        }
+
+        // The byte limits apply to each run separately.
+        self.bytes_written.store(0, Ordering::SeqCst);
+        self.bytes_read.store(0, Ordering::SeqCst);
+        for fd in self.fds.lock().values() {
+            fd.bytes_written().store(0, Ordering::SeqCst);
+        }
+
+        self.interrupt.start();
+        if let Some(interrupted) = self.interrupt.check() {
+            return Ok(Err(interrupted.into()));
+        }
+
+        let observer = (self.trace_sink.read().clone(), self.recorder.lock().take());
+        let import_object = match observer {
+            (None, None) => self.imports(self.clone()),
+            observer => {
+                let observed = ObservedImports::with_observer(self.clone(), observer);
+                self.imports(Arc::new(observed))
+            }
         };
 
-        let start: Func<'_, ()> = instance.func("_start")?;
+        module
+            .module()
+            .instantiate(&import_object)
+            .map(Ok)
+            .map_err(RunError::from_instantiate_error)
+    }
 
-        Ok(match start.call() {
-            Ok(()) => 0,
-            Err(e) => match e.0.downcast_ref::<native::exitcode>() {
-                Some(&code) => code,
-                None => Err(e)?,
-            },
-        })
+    /// Generates the imports for `imports`, passed through the metrics and the layers of this
+    /// WASI host.
+    fn imports<I: WasiImports>(&self, imports: Arc<I>) -> ImportObject {
+        let mut layers = self.layers.read().clone();
+
+        // The metrics see calls first, so they also count the calls the layers deny.
+        if let Some(metrics) = self.metrics.read().clone() {
+            layers.push_front(metrics);
+        }
+
+        layers.into_imports(imports)
     }
 
     fn with_fd<R>(&self, fd: Fd, f: impl FnOnce(&WasiFd<S>) -> WasiResult<R>) -> WasiResult<R> {
+        self.interrupt.checkpoint();
+
         let fd = {
             let fds = self.fds.lock();
-            fds.get(&fd).map(|fd| fd.clone()).ok_or(Errno::Badf)
+            fds.get(fd).map(|fd| fd.clone()).ok_or(Errno::Badf)
         };
         fd.and_then(|fd| f(&fd))
     }
@@ -105,10 +443,12 @@ impl<S: StringRepresentation> WasiHost<S> {
         fd2: Fd,
         f: impl FnOnce(&WasiFd<S>, &WasiFd<S>) -> WasiResult<R>,
     ) -> WasiResult<R> {
+        self.interrupt.checkpoint();
+
         let fds = {
             let fds = self.fds.lock();
-            fds.get(&fd1).ok_or(Errno::Badf).and_then(|fd1| {
-                fds.get(&fd2)
+            fds.get(fd1).ok_or(Errno::Badf).and_then(|fd1| {
+                fds.get(fd2)
                     .map(|fd2| (fd1.clone(), fd2.clone()))
                     .ok_or(Errno::Badf)
             })
@@ -117,18 +457,110 @@ impl<S: StringRepresentation> WasiHost<S> {
     }
 
     fn allocate_fd(&self, fd: WasiFd<S>) -> WasiResult<Fd> {
-        let mut rng = thread_rng();
-        let mut fds = self.fds.lock();
+        let max_open_fds = self.limits.read().max_open_fds;
 
-        // TODO: If there are too many open file descriptors, this is likely to take long.
-        loop {
-            let new_fd = Fd(rng.sample(self.fd_distribution));
+        self.fds.lock().allocate(fd, max_open_fds)
+    }
 
-            if let Entry::Vacant(entry) = fds.entry(new_fd) {
-                entry.insert(Arc::new(fd));
-                return Ok(new_fd);
-            }
+    fn write_limits(&self) -> (Option<u64>, Option<u64>) {
+        let limits = self.limits.read();
+
+        (limits.max_bytes_written_per_fd, limits.max_bytes_written)
+    }
+
+    /// Runs `grow`, which extends the regular file `fd` to at least `end` bytes, if the bytes
+    /// it grows by fit into the write limits. They are counted as written unless `grow` fails.
+    fn limit_growth<R>(
+        &self,
+        fd: &WasiFd<S>,
+        end: u64,
+        grow: impl FnOnce() -> WasiResult<R>,
+    ) -> WasiResult<R> {
+        let growth = fd.file_size().map_or(0, |size| end.saturating_sub(size));
+        if growth == 0 {
+            return grow();
+        }
+
+        let (max_per_fd, max_total) = self.write_limits();
+        let per_fd = limits::reserve(fd.bytes_written(), max_per_fd, growth);
+        let reserved = limits::reserve(&self.bytes_written, max_total, per_fd);
+
+        let result = if reserved < growth {
+            Err(if per_fd < growth {
+                Errno::Fbig
+            } else {
+                Errno::Dquot
+            })
+        } else {
+            grow()
+        };
+
+        if result.is_err() {
+            limits::release(fd.bytes_written(), per_fd);
+            limits::release(&self.bytes_written, reserved);
+        }
+        result
+    }
+
+    /// Writes `bufs` to `fd` with `write`, shortened to what the resource limits allow.
+    fn limit_write(
+        &self,
+        fd: &WasiFd<S>,
+        bufs: &[IoSlice<'_>],
+        write: impl FnOnce(&[IoSlice<'_>]) -> WasiResult<Size>,
+    ) -> WasiResult<Size> {
+        let (max_per_fd, max_total) = self.write_limits();
+        let requested = limits::total_len(bufs);
+
+        let per_fd = limits::reserve(fd.bytes_written(), max_per_fd, requested);
+        let reserved = limits::reserve(&self.bytes_written, max_total, per_fd);
+        limits::release(fd.bytes_written(), per_fd - reserved);
+
+        if reserved == 0 && requested > 0 {
+            return Err(if per_fd == 0 {
+                Errno::Fbig
+            } else {
+                Errno::Dquot
+            });
         }
+
+        let result = if reserved < requested {
+            write(&limits::truncate(bufs, reserved))
+        } else {
+            write(bufs)
+        };
+        // Devices that report more than they were given must not make the counters wrap.
+        let unused = reserved.saturating_sub(result.as_ref().map_or(0, |size| u64::from(size.0)));
+
+        limits::release(fd.bytes_written(), unused);
+        limits::release(&self.bytes_written, unused);
+        result
+    }
+
+    /// Reads into `iovs` with `read`, shortened to what the resource limits allow.
+    fn limit_read<R>(
+        &self,
+        iovs: &mut [IoSliceMut<'_>],
+        read: impl FnOnce(&mut [IoSliceMut<'_>]) -> WasiResult<R>,
+        size: impl FnOnce(&R) -> Size,
+    ) -> WasiResult<R> {
+        let max_total = self.limits.read().max_bytes_read;
+        let requested = limits::total_len_mut(iovs);
+        let reserved = limits::reserve(&self.bytes_read, max_total, requested);
+
+        if reserved == 0 && requested > 0 {
+            return Err(Errno::Dquot);
+        }
+
+        let result = if reserved < requested {
+            read(&mut limits::truncate_mut(iovs, reserved))
+        } else {
+            read(iovs)
+        };
+        let used = result.as_ref().map_or(0, |r| u64::from(size(r).0));
+
+        limits::release(&self.bytes_read, reserved.saturating_sub(used));
+        result
     }
 }
 
@@ -136,19 +568,23 @@ impl<S: StringRepresentation> WasiImports for WasiHost<S> {
     type StringRepresentation = S;
 
     fn args_get(&self) -> WasiResult<&[S]> {
+        self.interrupt.checkpoint();
         Ok(&self.arguments[..])
     }
 
     fn environ_get(&self) -> WasiResult<&[S]> {
+        self.interrupt.checkpoint();
         Ok(&self.environment[..])
     }
 
     fn clock_res_get(&self, id: Clockid) -> WasiResult<Timestamp> {
-        crate::os::preview1_clock_res_get(id)
+        self.interrupt.checkpoint();
+        self.clock.read().res_get(id)
     }
 
     fn clock_time_get(&self, id: Clockid, precision: Timestamp) -> WasiResult<Timestamp> {
-        crate::os::preview1_clock_time_get(id, precision)
+        self.interrupt.checkpoint();
+        self.clock.read().time_get(id, precision)
     }
 
     fn fd_advise(&self, fd: Fd, offset: Filesize, len: Filesize, advice: Advice) -> WasiResult<()> {
@@ -156,14 +592,19 @@ impl<S: StringRepresentation> WasiImports for WasiHost<S> {
     }
 
     fn fd_allocate(&self, fd: Fd, offset: Filesize, len: Filesize) -> WasiResult<()> {
-        self.with_fd(fd, |fd| fd.allocate(offset, len))
+        self.with_fd(fd, |fd| {
+            self.limit_growth(fd, offset.0.saturating_add(len.0), || {
+                fd.allocate(offset, len)
+            })
+        })
     }
 
     fn fd_close(&self, fd: Fd) -> WasiResult<()> {
+        self.interrupt.checkpoint();
         let fd = {
             let mut fds = self.fds.lock();
 
-            match fds.remove(&fd) {
+            match fds.remove(fd) {
                 Some(fd) => fd,
                 None => return Err(Errno::Badf),
             }
@@ -203,7 +644,9 @@ impl<S: StringRepresentation> WasiImports for WasiHost<S> {
     }
 
     fn fd_filestat_set_size(&self, fd: Fd, size: Filesize) -> WasiResult<()> {
-        self.with_fd(fd, |fd| fd.filestat_set_size(size))
+        self.with_fd(fd, |fd| {
+            self.limit_growth(fd, size.0, || fd.filestat_set_size(size))
+        })
     }
 
     fn fd_filestat_set_times(
@@ -217,7 +660,9 @@ impl<S: StringRepresentation> WasiImports for WasiHost<S> {
     }
 
     fn fd_pread(&self, fd: Fd, iovs: &mut [IoSliceMut<'_>], offset: Filesize) -> WasiResult<Size> {
-        self.with_fd(fd, |fd| fd.pread(iovs, offset))
+        self.with_fd(fd, |fd| {
+            self.limit_read(iovs, |iovs| fd.pread(iovs, offset), |&size| size)
+        })
     }
 
     fn fd_prestat_get(&self, fd: Fd) -> WasiResult<Prestat> {
@@ -229,11 +674,18 @@ impl<S: StringRepresentation> WasiImports for WasiHost<S> {
     }
 
     fn fd_pwrite(&self, fd: Fd, bufs: &[IoSlice<'_>], offset: Filesize) -> WasiResult<Size> {
-        self.with_fd(fd, |fd| fd.pwrite(bufs, offset))
+        // Writing past the end of a file fills the gap with zeros, which counts as written.
+        self.with_fd(fd, |fd| {
+            self.limit_growth(fd, offset.0, || {
+                self.limit_write(fd, bufs, |bufs| fd.pwrite(bufs, offset))
+            })
+        })
     }
 
     fn fd_read(&self, fd: Fd, iovs: &mut [IoSliceMut<'_>]) -> WasiResult<Size> {
-        self.with_fd(fd, |fd| fd.read(iovs))
+        self.with_fd(fd, |fd| {
+            self.limit_read(iovs, |iovs| fd.read(iovs), |&size| size)
+        })
     }
 
     fn fd_readdir(&self, fd: Fd, cookie: Dircookie) -> WasiResult<Option<(Dirent, S)>> {
@@ -241,15 +693,9 @@ impl<S: StringRepresentation> WasiImports for WasiHost<S> {
     }
 
     fn fd_renumber(&self, fd: Fd, to: Fd) -> WasiResult<()> {
+        self.interrupt.checkpoint();
         if fd != to {
-            let old_to = {
-                let mut fds = self.fds.lock();
-
-                let fd = fds.get(&fd).map(|fd| fd.clone()).ok_or(Errno::Badf)?;
-                let to = fds.get_mut(&to).ok_or(Errno::Badf)?;
-
-                mem::replace(to, fd)
-            };
+            let old_to = self.fds.lock().renumber(fd, to)?;
 
             // Always drop the fd outside of the lock.
             drop(old_to);
@@ -271,7 +717,11 @@ impl<S: StringRepresentation> WasiImports for WasiHost<S> {
     }
 
     fn fd_write(&self, fd: Fd, bufs: &[IoSlice<'_>]) -> WasiResult<Size> {
-        self.with_fd(fd, |fd| fd.write(bufs))
+        self.with_fd(fd, |fd| {
+            self.limit_growth(fd, fd.write_position().unwrap_or(0), || {
+                self.limit_write(fd, bufs, |bufs| fd.write(bufs))
+            })
+        })
     }
 
     fn path_create_directory(&self, fd: Fd, path: &<S as Deref>::Target) -> WasiResult<()> {
@@ -371,23 +821,36 @@ impl<S: StringRepresentation> WasiImports for WasiHost<S> {
         self.with_fd(fd, |fd| fd.path_unlink_file(path))
     }
 
-    fn poll_oneoff(&self, _subscriptions: &[Subscription]) -> WasiResult<Vec<Event>> {
-        Err(Errno::Nosys)
+    fn poll_oneoff(&self, subscriptions: &[Subscription]) -> WasiResult<Vec<Event>> {
+        let clock = self.clock.read();
+
+        poll::poll_oneoff(subscriptions, &**clock, &self.interrupt, |fd, r#type| {
+            self.with_fd(fd, |fd| fd.poll(r#type))
+        })
     }
 
     fn proc_exit(&self, c: Exitcode) -> Result<std::convert::Infallible, Exitcode> {
         Err(c)
     }
 
-    fn proc_raise(&self, _: Signal) -> WasiResult<()> {
-        Err(Errno::Nosys)
+    fn proc_raise(&self, sig: Signal) -> WasiResult<()> {
+        self.interrupt.checkpoint();
+
+        let action = self.signals.read().action(sig);
+
+        match action {
+            SignalAction::Terminate => panic::resume_unwind(Box::new(Killed(sig))),
+            SignalAction::Ignore => Ok(()),
+        }
     }
 
     fn random_get(&self, buf: &mut [u8]) -> WasiResult<()> {
-        getrandom::getrandom(buf).map_err(|_| Errno::Io)
+        self.interrupt.checkpoint();
+        self.random.read().fill(buf)
     }
 
     fn sched_yield(&self) -> WasiResult<()> {
+        self.interrupt.checkpoint();
         std::thread::yield_now();
         Ok(())
     }
@@ -398,11 +861,19 @@ impl<S: StringRepresentation> WasiImports for WasiHost<S> {
         ri_data: &mut [IoSliceMut<'_>],
         ri_flags: Riflags,
     ) -> WasiResult<(Size, Roflags)> {
-        self.with_fd(fd, |fd| fd.sock_recv(ri_data, ri_flags))
+        self.with_fd(fd, |fd| {
+            self.limit_read(
+                ri_data,
+                |iovs| fd.sock_recv(iovs, ri_flags),
+                |&(size, _)| size,
+            )
+        })
     }
 
     fn sock_send(&self, fd: Fd, si_data: &[IoSlice<'_>], si_flags: Siflags) -> WasiResult<Size> {
-        self.with_fd(fd, |fd| fd.sock_send(si_data, si_flags))
+        self.with_fd(fd, |fd| {
+            self.limit_write(fd, si_data, |bufs| fd.sock_send(bufs, si_flags))
+        })
     }
 
     fn sock_shutdown(&self, fd: Fd, how: Sdflags) -> WasiResult<()> {
@@ -435,3 +906,120 @@ impl<S: StringRepresentation> WasiFdInitializer<S> for DefaultWasiFdInitializer
         fds
     }
 }
+
+/// Initializer for the file descriptor map that initializes standard input, output and
+/// error like [`DefaultWasiFdInitializer`](struct.DefaultWasiFdInitializer.html) and
+/// additionally preopens directories, starting at file descriptor 3.
+///
+/// Each of the standard streams can be replaced by another character device, like one end
+/// of a [`pipe`](fn.pipe.html) or a [`CaptureDevice`](struct.CaptureDevice.html). Sockets
+/// are numbered after the preopened directories, so they do not end the WASI binary's search
+/// for preopens early.
+#[derive(Debug)]
+pub struct PreopenWasiFdInitializer<S> {
+    stdio: [Option<WasiFd<S>>; 3],
+    preopens: Vec<WasiFd<S>>,
+    sockets: Vec<WasiFd<S>>,
+}
+
+impl<S: StringRepresentation> PreopenWasiFdInitializer<S> {
+    /// Creates an initializer without any preopened directories.
+    pub fn new() -> Self {
+        PreopenWasiFdInitializer {
+            stdio: [None, None, None],
+            preopens: Vec::new(),
+            sockets: Vec::new(),
+        }
+    }
+
+    fn character_device(mut self, fd: usize, device: impl CharacterDevice) -> Self {
+        self.stdio[fd] = Some(WasiFd::from_character_device(
+            device,
+            Fdflags::empty(),
+            Rights::all(),
+        ));
+        self
+    }
+
+    /// Uses `device` as standard input instead of the host's standard input.
+    pub fn stdin(self, device: impl CharacterDevice) -> Self {
+        self.character_device(0, device)
+    }
+
+    /// Uses `device` as standard output instead of the host's standard output.
+    pub fn stdout(self, device: impl CharacterDevice) -> Self {
+        self.character_device(1, device)
+    }
+
+    /// Uses `device` as standard error instead of the host's standard error.
+    pub fn stderr(self, device: impl CharacterDevice) -> Self {
+        self.character_device(2, device)
+    }
+
+    /// Preopens the host directory `path`, which is presented to the WASI binary as `name`.
+    pub fn host_directory(
+        mut self,
+        path: impl AsRef<Path>,
+        name: impl Into<Vec<u8>>,
+    ) -> io::Result<Self> {
+        let rights = Rights::all();
+
+        self.preopens
+            .push(WasiFd::from_host_directory(path, name, rights, rights)?);
+        Ok(self)
+    }
+
+    /// Preopens `directory`, which is presented to the WASI binary as `name`.
+    pub fn directory(mut self, directory: impl WasiDirectory, name: impl Into<Vec<u8>>) -> Self {
+        let rights = Rights::all();
+
+        self.preopens
+            .push(WasiFd::from_directory(directory, name, rights, rights));
+        self
+    }
+
+    /// Preopens the root directory of the in-memory file system `fs`, which is presented to
+    /// the WASI binary as `name`.
+    pub fn memory_fs(mut self, fs: MemoryFs, name: impl Into<Vec<u8>>) -> Self {
+        let rights = Rights::all();
+
+        self.preopens
+            .push(WasiFd::from_memory_fs(fs, name, rights, rights));
+        self
+    }
+
+    /// Hands the connected or listening socket `socket` to the WASI binary.
+    pub fn socket(mut self, socket: impl WasiSocket) -> Self {
+        self.sockets
+            .push(WasiFd::from_socket(socket, Fdflags::empty(), Rights::all()));
+        self
+    }
+}
+
+impl<S: StringRepresentation> Default for PreopenWasiFdInitializer<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: StringRepresentation> WasiFdInitializer<S> for PreopenWasiFdInitializer<S> {
+    fn initialize(mut self) -> HashMap<Fd, WasiFd<S>> {
+        let mut fds = DefaultWasiFdInitializer.initialize();
+
+        fds.extend(
+            self.stdio
+                .iter_mut()
+                .zip(0..)
+                .filter_map(|(fd, number)| fd.take().map(|fd| (Fd(number), fd))),
+        );
+        fds.extend(
+            self.preopens
+                .into_iter()
+                .chain(self.sockets)
+                .zip(3..)
+                .map(|(fd, number)| (Fd(number), fd)),
+        );
+
+        fds
+    }
+}
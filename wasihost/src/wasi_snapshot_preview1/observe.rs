@@ -0,0 +1,530 @@
+use super::{
+    cancel::Interrupted,
+    functions::{wasi_argument, wasi_functions, wasi_parameter, wasi_result},
+    record, run,
+    signal::Killed,
+};
+use std::{
+    any::Any,
+    fmt,
+    io::{IoSlice, IoSliceMut},
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use wasihost_core::{
+    wasi_snapshot_preview1::{
+        Advice, Clockid, Dircookie, Dirent, Errno, Event, Exitcode, Fd, Fdflags, Fdstat, Filedelta,
+        Filesize, Filestat, Fstflags, Lookupflags, Oflags, Prestat, Riflags, Rights, Roflags,
+        Sdflags, Siflags, Signal, Size, Subscription, Timestamp, WasiImports, WasiResult, Whence,
+    },
+    StringRepresentation,
+};
+use witx_gen::{WasiValue, WasmValue};
+
+/// A WASI value passed to or returned by a system call.
+pub trait Value: fmt::Debug {
+    /// Encodes the value in its native representation.
+    fn encode(&self) -> Vec<u8>;
+}
+
+impl<T: WasiValue + Copy + fmt::Debug> Value for T
+where
+    T::NativeType: WasmValue,
+{
+    fn encode(&self) -> Vec<u8> {
+        record::value(*self)
+    }
+}
+
+/// An argument of a system call.
+#[derive(Debug)]
+pub enum Argument<'a> {
+    /// A WASI value.
+    Value(&'a dyn Value),
+    /// The data the call writes.
+    Data(&'a [IoSlice<'a>]),
+    /// The buffers the call reads into.
+    Buffers(&'a [IoSliceMut<'a>]),
+    /// A buffer of this length that the call fills.
+    Buffer(usize),
+    /// A string, usually a path.
+    String(&'a [u8]),
+    /// The subscriptions of `poll_oneoff`.
+    Subscriptions(&'a [Subscription]),
+}
+
+/// What a system call returned successfully.
+#[derive(Debug)]
+pub enum Returned<'a, S> {
+    /// Nothing.
+    Unit,
+    /// A WASI value.
+    Value(&'a dyn Value),
+    /// The arguments or environment variables.
+    Strings(&'a [S]),
+    /// A string, usually a path.
+    String(&'a S),
+    /// The data read from a file descriptor.
+    Read {
+        /// The file descriptor.
+        fd: Fd,
+        /// The number of bytes read.
+        size: Size,
+        /// The flags returned by `sock_recv`.
+        flags: Option<Roflags>,
+        /// The buffers that were read into.
+        data: &'a [IoSliceMut<'a>],
+    },
+    /// The size of the data written to a file descriptor.
+    Written {
+        /// The file descriptor.
+        fd: Fd,
+        /// The number of bytes written.
+        size: Size,
+    },
+    /// A directory entry with its name, or `None` at the end of the directory.
+    Dirent(Option<(Dirent, &'a S)>),
+    /// The events of `poll_oneoff`.
+    Events(&'a [Event]),
+    /// The random data of `random_get`.
+    Random(&'a [u8]),
+}
+
+/// How a system call ended the run instead of returning.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// The run was cancelled.
+    Cancelled,
+    /// The run took longer than the timeout of the WASI host.
+    TimedOut,
+    /// A signal killed the WASI binary.
+    Killed(Signal),
+    /// The call trapped, with a description.
+    Trapped(String),
+}
+
+impl Stop {
+    fn from_payload(payload: &(dyn Any + Send)) -> Self {
+        if let Some(&interrupted) = payload.downcast_ref::<Interrupted>() {
+            return match interrupted {
+                Interrupted::Cancelled => Stop::Cancelled,
+                Interrupted::TimedOut => Stop::TimedOut,
+            };
+        }
+        if let Some(&Killed(signal)) = payload.downcast_ref::<Killed>() {
+            return Stop::Killed(signal);
+        }
+
+        Stop::Trapped(run::trap_message(payload))
+    }
+
+    /// Returns the payload that ends a run the same way.
+    pub(super) fn into_payload(self) -> Box<dyn Any + Send> {
+        match self {
+            Stop::Cancelled => Box::new(Interrupted::Cancelled),
+            Stop::TimedOut => Box::new(Interrupted::TimedOut),
+            Stop::Killed(signal) => Box::new(Killed(signal)),
+            Stop::Trapped(message) => Box::new(message),
+        }
+    }
+}
+
+/// How a system call ended.
+#[derive(Debug)]
+pub enum Outcome<'a, S> {
+    /// The call returned successfully.
+    Returned(Returned<'a, S>),
+    /// The call failed.
+    Failed(Errno),
+    /// The WASI binary called `proc_exit`.
+    Exited(Exitcode),
+    /// The call ended the run instead of returning.
+    Stopped(&'a Stop),
+}
+
+/// Observes the system calls passing through
+/// [`ObservedImports`](struct.ObservedImports.html), for tracing or recording.
+pub trait CallObserver: Send + Sync + 'static {
+    /// What the observer keeps of a call in progress.
+    type Call;
+
+    /// Called before a call is forwarded, with the names and values of its arguments.
+    fn start(
+        &self,
+        function: &'static str,
+        arguments: &[(&'static str, Argument<'_>)],
+    ) -> Self::Call;
+
+    /// Called after the call returned or ended the run, with the time it took.
+    fn finish<S: StringRepresentation>(
+        &self,
+        function: &'static str,
+        call: Self::Call,
+        outcome: &Outcome<'_, S>,
+        duration: Duration,
+    );
+}
+
+impl<O: CallObserver> CallObserver for Option<O> {
+    type Call = Option<O::Call>;
+
+    fn start(
+        &self,
+        function: &'static str,
+        arguments: &[(&'static str, Argument<'_>)],
+    ) -> Self::Call {
+        self.as_ref()
+            .map(|observer| observer.start(function, arguments))
+    }
+
+    fn finish<S: StringRepresentation>(
+        &self,
+        function: &'static str,
+        call: Self::Call,
+        outcome: &Outcome<'_, S>,
+        duration: Duration,
+    ) {
+        if let (Some(observer), Some(call)) = (self, call) {
+            observer.finish(function, call, outcome, duration);
+        }
+    }
+}
+
+impl<A: CallObserver, B: CallObserver> CallObserver for (A, B) {
+    type Call = (A::Call, B::Call);
+
+    fn start(
+        &self,
+        function: &'static str,
+        arguments: &[(&'static str, Argument<'_>)],
+    ) -> Self::Call {
+        (
+            self.0.start(function, arguments),
+            self.1.start(function, arguments),
+        )
+    }
+
+    fn finish<S: StringRepresentation>(
+        &self,
+        function: &'static str,
+        call: Self::Call,
+        outcome: &Outcome<'_, S>,
+        duration: Duration,
+    ) {
+        self.0.finish(function, call.0, outcome, duration);
+        self.1.finish(function, call.1, outcome, duration);
+    }
+}
+
+/// A system call in progress.
+struct Observation<C> {
+    function: &'static str,
+    call: C,
+    start: Instant,
+}
+
+/// Wraps WASI imports and reports every system call to a
+/// [`CallObserver`](trait.CallObserver.html), including the calls that end the run.
+pub struct ObservedImports<I, O> {
+    inner: Arc<I>,
+    observer: O,
+}
+
+impl<I, O> ObservedImports<I, O> {
+    pub(super) fn with_observer(inner: Arc<I>, observer: O) -> Self {
+        ObservedImports { inner, observer }
+    }
+
+    /// Returns the wrapped imports.
+    pub fn inner(&self) -> &Arc<I> {
+        &self.inner
+    }
+}
+
+impl<I: WasiImports, O: CallObserver> ObservedImports<I, O> {
+    fn start(
+        &self,
+        function: &'static str,
+        arguments: &[(&'static str, Argument<'_>)],
+    ) -> Observation<O::Call> {
+        Observation {
+            function,
+            call: self.observer.start(function, arguments),
+            start: Instant::now(),
+        }
+    }
+
+    /// Makes a call, which is reported before the run unwinds if the call ends it.
+    fn forward<R>(
+        &self,
+        observation: Observation<O::Call>,
+        call: impl FnOnce() -> R,
+    ) -> (Observation<O::Call>, R) {
+        match panic::catch_unwind(AssertUnwindSafe(call)) {
+            Ok(result) => (observation, result),
+            Err(payload) => {
+                let stop = Stop::from_payload(&*payload);
+
+                self.end(observation, &Outcome::Stopped(&stop));
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+
+    fn finish<'a, T>(
+        &self,
+        observation: Observation<O::Call>,
+        result: &'a WasiResult<T>,
+        returned: impl FnOnce(&'a T) -> Returned<'a, I::StringRepresentation>,
+    ) {
+        let outcome = match result {
+            Ok(value) => Outcome::Returned(returned(value)),
+            Err(errno) => Outcome::Failed(*errno),
+        };
+
+        self.end(observation, &outcome);
+    }
+
+    fn end(
+        &self,
+        observation: Observation<O::Call>,
+        outcome: &Outcome<'_, I::StringRepresentation>,
+    ) {
+        self.observer.finish(
+            observation.function,
+            observation.call,
+            outcome,
+            observation.start.elapsed(),
+        );
+    }
+}
+
+impl<I: fmt::Debug, O: fmt::Debug> fmt::Debug for ObservedImports<I, O> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ObservedImports")
+            .field("inner", &self.inner)
+            .field("observer", &self.observer)
+            .finish()
+    }
+}
+
+/// Implements `WasiImports` for `ObservedImports` from the signatures given by
+/// [`wasi_functions!`](../functions/macro.wasi_functions.html).
+macro_rules! observed_imports {
+    ($(
+        fn $function:ident($($name:ident: $kind:ident $(($type:ty))?),*)
+            -> $result:ident $(($($returned:tt)*))?;
+    )*) => {
+        impl<I: WasiImports, O: CallObserver> WasiImports for ObservedImports<I, O> {
+            type StringRepresentation = I::StringRepresentation;
+
+            $(
+                fn $function(
+                    &self,
+                    $($name: wasi_parameter!($kind $(($type))?)),*
+                ) -> wasi_result!($result $(($($returned)*))?) {
+                    let observation = self.start(
+                        stringify!($function),
+                        &[$((stringify!($name), wasi_argument!($kind $(($type))? $name))),*],
+                    );
+                    let (observation, result) = self.forward(observation, || {
+                        self.inner.$function($(forwarded!($kind $name)),*)
+                    });
+
+                    observed!(self, observation, result, $result $(($($returned)*))?)
+                }
+            )*
+        }
+    };
+}
+
+/// Passes a parameter on to the wrapped imports, reborrowing the buffers that are part of what
+/// the call returned.
+macro_rules! forwarded {
+    (buffers $name:ident) => {
+        &mut *$name
+    };
+    (buffer $name:ident) => {
+        &mut *$name
+    };
+    ($kind:ident $name:ident) => {
+        $name
+    };
+}
+
+/// Reports what a call returned, see [`wasi_functions!`](../functions/macro.wasi_functions.html)
+/// for the kinds of results.
+macro_rules! observed {
+    ($imports:ident, $observation:ident, $result:ident, exit) => {{
+        match $result {
+            Ok(never) => match never {},
+            Err(code) => $imports.end($observation, &Outcome::Exited(code)),
+        }
+        $result
+    }};
+    ($imports:ident, $observation:ident, $result:ident, $($returned:tt)*) => {{
+        $imports.finish($observation, &$result, returned!($($returned)*));
+        $result
+    }};
+}
+
+/// Describes a successful result of the given kind as [`Returned`](enum.Returned.html).
+macro_rules! returned {
+    (unit) => {
+        |()| Returned::Unit
+    };
+    (value($type:ty)) => {
+        |value| Returned::Value(value)
+    };
+    (strings) => {
+        |strings| Returned::Strings(strings)
+    };
+    (string) => {
+        |string| Returned::String(string)
+    };
+    (dirent) => {
+        |entry| Returned::Dirent(entry.as_ref().map(|(dirent, name)| (*dirent, name)))
+    };
+    (events) => {
+        |events| Returned::Events(events)
+    };
+    (read($fd:ident, $iovs:ident)) => {
+        |&size| Returned::Read {
+            fd: $fd,
+            size,
+            flags: None,
+            data: $iovs,
+        }
+    };
+    (received($fd:ident, $iovs:ident)) => {
+        |&(size, flags)| Returned::Read {
+            fd: $fd,
+            size,
+            flags: Some(flags),
+            data: $iovs,
+        }
+    };
+    (written($fd:ident)) => {
+        |&size| Returned::Written { fd: $fd, size }
+    };
+    (random($buf:ident)) => {
+        |()| Returned::Random($buf)
+    };
+}
+
+wasi_functions!(observed_imports);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasi_snapshot_preview1::{
+        test_binaries::{calling_with, exiting},
+        CompiledModule, RunOutcome, WasiHostBuilder,
+    };
+    use parking_lot::Mutex;
+
+    /// An observer that describes the calls it sees.
+    #[derive(Clone, Default)]
+    struct Collecting(Arc<Mutex<Vec<String>>>);
+
+    impl CallObserver for Collecting {
+        type Call = usize;
+
+        fn start(
+            &self,
+            _function: &'static str,
+            arguments: &[(&'static str, Argument<'_>)],
+        ) -> Self::Call {
+            arguments.len()
+        }
+
+        fn finish<S: StringRepresentation>(
+            &self,
+            function: &'static str,
+            arguments: Self::Call,
+            outcome: &Outcome<'_, S>,
+            _duration: Duration,
+        ) {
+            let outcome = match outcome {
+                Outcome::Returned(_) => "returned".to_string(),
+                Outcome::Failed(errno) => format!("failed with {:?}", errno),
+                Outcome::Exited(code) => format!("exited with {:?}", code),
+                Outcome::Stopped(stop) => format!("stopped by {:?}", stop),
+            };
+
+            self.0
+                .lock()
+                .push(format!("{}/{} {}", function, arguments, outcome));
+        }
+    }
+
+    fn observe(binary: &[u8]) -> (RunOutcome, Vec<String>) {
+        let host = WasiHostBuilder::<String>::new().build().unwrap();
+        let collecting = Collecting::default();
+        let observed =
+            ObservedImports::with_observer(host, (Some(collecting.clone()), None::<Collecting>));
+
+        let outcome = CompiledModule::compile(binary)
+            .unwrap()
+            .run_with_imports(observed)
+            .unwrap();
+        let calls = collecting.0.lock().clone();
+
+        (outcome, calls)
+    }
+
+    #[test]
+    fn observers_see_how_calls_ended() {
+        assert_eq!(
+            observe(&calling_with("fd_close", &[99], &[])),
+            (
+                RunOutcome::Exited(0),
+                vec!["fd_close/1 failed with Badf".to_string()]
+            )
+        );
+        assert_eq!(
+            observe(&calling_with("sched_yield", &[], &[])),
+            (
+                RunOutcome::Exited(0),
+                vec!["sched_yield/0 returned".to_string()]
+            )
+        );
+        assert_eq!(
+            observe(&exiting(3)),
+            (
+                RunOutcome::Exited(3),
+                vec!["proc_exit/1 exited with Exitcode(3)".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn observers_see_calls_that_end_the_run() {
+        assert_eq!(
+            observe(&calling_with("proc_raise", &[Signal::Term as i32], &[])),
+            (
+                RunOutcome::Signaled(Signal::Term),
+                vec!["proc_raise/1 stopped by Killed(Term)".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn stops_end_runs_the_way_they_were_observed() {
+        let stops = [
+            Stop::Cancelled,
+            Stop::TimedOut,
+            Stop::Killed(Signal::Kill),
+            Stop::Trapped("it broke".to_string()),
+        ];
+
+        for stop in stops.iter() {
+            assert_eq!(&Stop::from_payload(&*stop.clone().into_payload()), stop);
+        }
+        assert_eq!(
+            Stop::from_payload(&"a panic"),
+            Stop::Trapped("a panic".to_string())
+        );
+    }
+}
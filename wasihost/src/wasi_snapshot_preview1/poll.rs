@@ -0,0 +1,313 @@
+use super::{cancel::Interrupt, clock::ClockProvider};
+use std::{thread, time::Duration};
+use wasihost_core::wasi_snapshot_preview1::{
+    Clockid, Errno, Event, EventFdReadwrite, Eventrwflags, Eventtype, Fd, Filesize, Subclockflags,
+    Subscription, SubscriptionClock, SubscriptionU, Timestamp, Userdata, WasiResult,
+};
+
+fn event(userdata: Userdata, error: Errno, r#type: Eventtype) -> Event {
+    Event {
+        userdata,
+        error,
+        r#type,
+        fd_readwrite: EventFdReadwrite {
+            nbytes: Filesize(0),
+            flags: Eventrwflags::empty(),
+        },
+    }
+}
+
+/// Returns the number of nanoseconds until the clock subscription `subscription` fires.
+fn clock_timeout(clock: &dyn ClockProvider, subscription: &SubscriptionClock) -> WasiResult<u64> {
+    if subscription
+        .flags
+        .contains(Subclockflags::SUBSCRIPTION_CLOCK_ABSTIME)
+    {
+        let now = clock.time_get(subscription.id, subscription.precision)?;
+
+        Ok(subscription.timeout.0.saturating_sub(now.0))
+    } else {
+        // Relative timeouts do not need the current time, but the clock must still exist.
+        clock.res_get(subscription.id)?;
+
+        Ok(subscription.timeout.0)
+    }
+}
+
+/// The longest time to sleep between two readiness checks of file descriptors, in nanoseconds.
+const MAX_BACKOFF: u64 = 50_000_000;
+
+/// Waits until at least one of `subscriptions` fires.
+///
+/// Subscriptions that fail immediately, like clock subscriptions for unsupported clocks,
+/// are reported without waiting. File descriptor subscriptions are checked with `poll_fd`,
+/// which returns `None` if the file descriptor is not ready yet, and are checked again with
+/// an increasing delay until one becomes ready or the earliest clock subscriptions fire.
+/// Without file descriptor subscriptions, the earliest clock subscriptions are waited for
+/// directly. Timeouts are measured with the monotonic clock of `clock`. Waits for file
+/// descriptors are split up so that `interrupt` can stop the WASI binary while it waits.
+pub(super) fn poll_oneoff(
+    subscriptions: &[Subscription],
+    clock: &dyn ClockProvider,
+    interrupt: &Interrupt,
+    poll_fd: impl Fn(Fd, Eventtype) -> WasiResult<Option<EventFdReadwrite>>,
+) -> WasiResult<Vec<Event>> {
+    if subscriptions.is_empty() {
+        return Err(Errno::Inval);
+    }
+
+    let mut events = Vec::new();
+    let mut timeouts = Vec::new();
+
+    for subscription in subscriptions {
+        if let SubscriptionU::Clock(ref s) = subscription.u {
+            match clock_timeout(clock, s) {
+                Ok(timeout) => timeouts.push((subscription.userdata, timeout)),
+                Err(err) => events.push(event(subscription.userdata, err, Eventtype::Clock)),
+            }
+        }
+    }
+
+    let monotonic = || {
+        clock
+            .time_get(Clockid::Monotonic, Timestamp(0))
+            .map(|t| t.0)
+    };
+
+    // Timeouts too far in the future to be represented never fire.
+    let start = if timeouts.is_empty() { 0 } else { monotonic()? };
+    let deadlines = if timeouts.is_empty() {
+        Vec::new()
+    } else {
+        timeouts
+            .into_iter()
+            .map(|(userdata, timeout)| (userdata, start.checked_add(timeout)))
+            .collect()
+    };
+    let earliest = deadlines.iter().filter_map(|&(_, deadline)| deadline).min();
+    let clocks_only = subscriptions
+        .iter()
+        .all(|subscription| matches!(subscription.u, SubscriptionU::Clock(_)));
+    let mut backoff = 1_000_000;
+    // Providers may not advance their clocks while sleeping, so the time slept counts as well.
+    let mut slept = 0u64;
+
+    loop {
+        interrupt.checkpoint();
+
+        for subscription in subscriptions {
+            let (fd, r#type) = match subscription.u {
+                SubscriptionU::Clock(_) => continue,
+                SubscriptionU::FdRead(ref s) => (s.file_descriptor, Eventtype::FdRead),
+                SubscriptionU::FdWrite(ref s) => (s.file_descriptor, Eventtype::FdWrite),
+            };
+
+            match poll_fd(fd, r#type) {
+                Ok(Some(fd_readwrite)) => events.push(Event {
+                    fd_readwrite,
+                    ..event(subscription.userdata, Errno::Success, r#type)
+                }),
+                Ok(None) => {}
+                Err(err) => events.push(event(subscription.userdata, err, r#type)),
+            }
+        }
+
+        if !events.is_empty() {
+            return Ok(events);
+        }
+
+        match earliest {
+            Some(earliest) => {
+                let now = monotonic()?.max(start.saturating_add(slept));
+
+                if earliest <= now {
+                    return Ok(deadlines
+                        .iter()
+                        .filter(|&&(_, deadline)| deadline == Some(earliest))
+                        .map(|&(userdata, _)| event(userdata, Errno::Success, Eventtype::Clock))
+                        .collect());
+                }
+
+                let delay = if clocks_only {
+                    earliest - now
+                } else {
+                    backoff.min(earliest - now)
+                };
+
+                clock.sleep(delay);
+                slept = slept.saturating_add(delay);
+            }
+            // Waiting for file descriptors or clocks that never fire does not involve the
+            // clock.
+            None => thread::sleep(Duration::from_nanos(backoff)),
+        }
+
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasi_snapshot_preview1::clock::{FixedClock, ManualClock, VirtualClock};
+    use wasihost_core::wasi_snapshot_preview1::SubscriptionFdReadwrite;
+
+    fn clock(userdata: u64, timeout: u64, flags: Subclockflags) -> Subscription {
+        Subscription {
+            userdata: Userdata(userdata),
+            u: SubscriptionU::Clock(SubscriptionClock {
+                id: Clockid::Monotonic,
+                timeout: Timestamp(timeout),
+                precision: Timestamp(0),
+                flags,
+            }),
+        }
+    }
+
+    fn fd_read(userdata: u64, fd: u32) -> Subscription {
+        Subscription {
+            userdata: Userdata(userdata),
+            u: SubscriptionU::FdRead(SubscriptionFdReadwrite {
+                file_descriptor: Fd(fd),
+            }),
+        }
+    }
+
+    fn never_ready(_fd: Fd, _type: Eventtype) -> WasiResult<Option<EventFdReadwrite>> {
+        Ok(None)
+    }
+
+    fn userdata(events: &[Event]) -> Vec<u64> {
+        events.iter().map(|event| event.userdata.0).collect()
+    }
+
+    #[test]
+    fn rejects_empty_subscriptions() {
+        let events = poll_oneoff(
+            &[],
+            &VirtualClock::new(),
+            &Interrupt::default(),
+            never_ready,
+        );
+
+        assert_eq!(events.unwrap_err(), Errno::Inval);
+    }
+
+    #[test]
+    fn fires_the_earliest_relative_timeout() {
+        let clock_provider = VirtualClock::new();
+        let subscriptions = [
+            clock(1, 5_000_000, Subclockflags::empty()),
+            clock(2, 2_000_000, Subclockflags::empty()),
+        ];
+
+        let events = poll_oneoff(
+            &subscriptions,
+            &clock_provider,
+            &Interrupt::default(),
+            never_ready,
+        )
+        .unwrap();
+
+        assert_eq!(userdata(&events), [2]);
+        assert_eq!(events[0].r#type, Eventtype::Clock);
+        assert_eq!(events[0].error, Errno::Success);
+
+        let now = clock_provider
+            .time_get(Clockid::Monotonic, Timestamp(0))
+            .unwrap();
+        assert!(now.0 >= 2_000_000 && now.0 < 5_000_000, "{:?}", now);
+    }
+
+    #[test]
+    fn fires_absolute_timeouts_in_the_past_immediately() {
+        let subscriptions = [
+            clock(1, 50, Subclockflags::SUBSCRIPTION_CLOCK_ABSTIME),
+            clock(2, 150, Subclockflags::SUBSCRIPTION_CLOCK_ABSTIME),
+        ];
+
+        let events = poll_oneoff(
+            &subscriptions,
+            &FixedClock::new(Timestamp(100)),
+            &Interrupt::default(),
+            never_ready,
+        )
+        .unwrap();
+
+        assert_eq!(userdata(&events), [1]);
+    }
+
+    #[test]
+    fn reports_ready_and_failed_fds_before_timeouts() {
+        let subscriptions = [
+            clock(1, 0, Subclockflags::empty()),
+            fd_read(2, 3),
+            fd_read(3, 4),
+            fd_read(4, 5),
+        ];
+
+        let events = poll_oneoff(
+            &subscriptions,
+            &VirtualClock::new(),
+            &Interrupt::default(),
+            |fd, _| match fd {
+                Fd(3) => Ok(Some(EventFdReadwrite {
+                    nbytes: Filesize(7),
+                    flags: Eventrwflags::empty(),
+                })),
+                Fd(4) => Err(Errno::Badf),
+                _ => Ok(None),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(userdata(&events), [2, 3]);
+        assert_eq!(events[0].r#type, Eventtype::FdRead);
+        assert_eq!(events[0].fd_readwrite.nbytes, Filesize(7));
+        assert_eq!(events[1].error, Errno::Badf);
+    }
+
+    #[test]
+    fn waits_for_the_clock_to_reach_the_deadline() {
+        let clock_provider = ManualClock::new(Timestamp(0));
+        let advancer = clock_provider.clone();
+        let thread = thread::spawn(move || {
+            for _ in 0..10 {
+                thread::sleep(Duration::from_millis(5));
+                advancer.advance(Duration::from_millis(1));
+            }
+        });
+
+        let events = poll_oneoff(
+            &[clock(1, 10_000_000, Subclockflags::empty())],
+            &clock_provider,
+            &Interrupt::default(),
+            never_ready,
+        )
+        .unwrap();
+        thread.join().unwrap();
+
+        assert_eq!(userdata(&events), [1]);
+        assert_eq!(clock_provider.elapsed(), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn waits_for_clocks_without_polling() {
+        let clock_provider = VirtualClock::new();
+        let hour = 3_600_000_000_000;
+
+        poll_oneoff(
+            &[clock(1, hour, Subclockflags::empty())],
+            &clock_provider,
+            &Interrupt::default(),
+            never_ready,
+        )
+        .unwrap();
+
+        // Every check reads the clock, which advances it by a tick.
+        let now = clock_provider
+            .time_get(Clockid::Monotonic, Timestamp(0))
+            .unwrap();
+        assert!(now.0 >= hour && now.0 < hour + 10_000, "{:?}", now);
+    }
+}
@@ -0,0 +1,134 @@
+use parking_lot::Mutex;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::fmt::Debug;
+use wasihost_core::wasi_snapshot_preview1::{Errno, WasiResult};
+
+/// Provides the random data returned by `random_get`.
+pub trait RandomSource: Debug + Send + Sync + 'static {
+    /// Fills `buf` with random data.
+    fn fill(&self, buf: &mut [u8]) -> WasiResult<()>;
+}
+
+/// The operating system's entropy source.
+#[derive(Debug, Default)]
+pub struct OsRandom;
+
+impl RandomSource for OsRandom {
+    fn fill(&self, buf: &mut [u8]) -> WasiResult<()> {
+        getrandom::getrandom(buf).map_err(|_| Errno::Io)
+    }
+}
+
+/// A cryptographically secure pseudo random number generator with a fixed seed.
+///
+/// The data is generated with ChaCha20, so the same seed always produces the same data, on
+/// every platform and with every version of this crate.
+#[derive(Debug)]
+pub struct SeededRandom {
+    rng: Mutex<ChaCha20Rng>,
+}
+
+impl SeededRandom {
+    /// Creates a generator from `seed`.
+    pub fn new(seed: u64) -> Self {
+        SeededRandom {
+            rng: Mutex::new(ChaCha20Rng::seed_from_u64(seed)),
+        }
+    }
+
+    /// Creates a generator from a full 256 bit seed.
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        SeededRandom {
+            rng: Mutex::new(ChaCha20Rng::from_seed(seed)),
+        }
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn fill(&self, buf: &mut [u8]) -> WasiResult<()> {
+        self.rng.lock().fill_bytes(buf);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Replay {
+    data: Vec<u8>,
+    position: usize,
+}
+
+/// Returns previously recorded random data in order, for example to reproduce a run.
+///
+/// Requesting more data than is left fails with `Errno::Io` without consuming any data.
+#[derive(Debug)]
+pub struct ReplayRandom {
+    replay: Mutex<Replay>,
+}
+
+impl ReplayRandom {
+    /// Creates a source that returns `data`.
+    pub fn new(data: impl Into<Vec<u8>>) -> Self {
+        ReplayRandom {
+            replay: Mutex::new(Replay {
+                data: data.into(),
+                position: 0,
+            }),
+        }
+    }
+
+    /// Returns the number of bytes that have not been returned yet.
+    pub fn remaining(&self) -> usize {
+        let replay = self.replay.lock();
+
+        replay.data.len() - replay.position
+    }
+}
+
+impl RandomSource for ReplayRandom {
+    fn fill(&self, buf: &mut [u8]) -> WasiResult<()> {
+        let mut replay = self.replay.lock();
+        let start = replay.position;
+        let data = replay.data.get(start..start + buf.len()).ok_or(Errno::Io)?;
+
+        buf.copy_from_slice(data);
+        replay.position += buf.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_random_data_is_stable() {
+        let mut buf = [0; 8];
+
+        SeededRandom::from_seed([0; 32]).fill(&mut buf).unwrap();
+        // The first bytes of the ChaCha20 keystream for an all-zero key and nonce.
+        assert_eq!(buf, [0x76, 0xb8, 0xe0, 0xad, 0xa0, 0xf1, 0x3d, 0x90]);
+
+        let (mut a, mut b) = ([0; 16], [0; 16]);
+        SeededRandom::new(7).fill(&mut a).unwrap();
+        SeededRandom::new(7).fill(&mut b).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn replay_random_returns_the_data_in_order() {
+        let random = ReplayRandom::new(vec![1, 2, 3, 4, 5]);
+        let mut buf = [0; 2];
+
+        random.fill(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2]);
+        random.fill(&mut buf).unwrap();
+        assert_eq!(buf, [3, 4]);
+
+        assert_eq!(random.fill(&mut buf).unwrap_err(), Errno::Io);
+        assert_eq!(random.remaining(), 1);
+        random.fill(&mut buf[..1]).unwrap();
+        assert_eq!(buf[0], 5);
+        assert_eq!(random.remaining(), 0);
+    }
+}
@@ -0,0 +1,203 @@
+use super::{
+    run::{RunError, RunOutcome},
+    WasiHost,
+};
+use std::{error::Error, fmt, sync::Arc};
+use wasihost_core::StringRepresentation;
+use wasmer_runtime::{error::CallError, Instance, Value};
+
+/// The reasons a reactor could not be instantiated or a call into it failed.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ReactorError {
+    /// The module could not be instantiated.
+    Run(RunError),
+    /// The module does not export a function with this name that takes the given arguments.
+    MissingExport(String),
+    /// The module stopped instead of returning, for example because it exited or trapped.
+    Stopped(RunOutcome),
+}
+
+impl fmt::Display for ReactorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReactorError::Run(error) => error.fmt(f),
+            ReactorError::MissingExport(name) => {
+                write!(
+                    f,
+                    "the module does not export a matching function `{}`",
+                    name
+                )
+            }
+            ReactorError::Stopped(outcome) => write!(f, "the module stopped: {:?}", outcome),
+        }
+    }
+}
+
+impl Error for ReactorError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ReactorError::Run(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<RunError> for ReactorError {
+    fn from(error: RunError) -> Self {
+        ReactorError::Run(error)
+    }
+}
+
+/// A reactor module instantiated on a WASI host by
+/// [`WasiHost::instantiate_reactor`](struct.WasiHost.html#method.instantiate_reactor).
+pub struct Reactor<S: StringRepresentation> {
+    host: Arc<WasiHost<S>>,
+    instance: Instance,
+}
+
+impl<S: StringRepresentation> Reactor<S> {
+    pub(super) fn initialize(
+        host: Arc<WasiHost<S>>,
+        instance: Instance,
+    ) -> Result<Self, ReactorError> {
+        let reactor = Reactor { host, instance };
+
+        let exports = &reactor.instance.module.info.exports;
+
+        if exports.contains_key("_initialize") {
+            reactor.call("_initialize", &[])?;
+        }
+
+        Ok(reactor)
+    }
+
+    /// Calls the exported function `name` with `arguments` and returns its results.
+    pub fn call(&self, name: &str, arguments: &[Value]) -> Result<Vec<Value>, ReactorError> {
+        self.host.interrupt.start();
+        if let Some(interrupted) = self.host.interrupt.check() {
+            return Err(ReactorError::Stopped(interrupted.into()));
+        }
+
+        let (result, interrupted) = self
+            .host
+            .interrupt
+            .run(&self.instance, || self.instance.call(name, arguments));
+
+        result.map_err(|error| match error {
+            CallError::Resolve(_) => ReactorError::MissingExport(name.to_string()),
+            CallError::Runtime(error) => ReactorError::Stopped(
+                RunOutcome::from_runtime_error(error).interrupted_by(interrupted),
+            ),
+        })
+    }
+
+    /// Returns the WASI host the reactor runs on.
+    pub fn host(&self) -> &Arc<WasiHost<S>> {
+        &self.host
+    }
+}
+
+impl<S: StringRepresentation + fmt::Debug> fmt::Debug for Reactor<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reactor").field("host", &self.host).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasi_snapshot_preview1::{
+        run::LimitKind, test_binaries::module, CompiledModule, WasiHostBuilder,
+    };
+    use std::time::Duration;
+
+    /// A reactor with a counter that `_initialize` sets to 100 and `next` increments, `add`
+    /// adding two numbers and `spin` looping forever.
+    fn reactor() -> CompiledModule {
+        let mut code = vec![4];
+        for body in &[
+            &[0, 0x41, 0xe4, 0, 0x24, 0, 0x0b][..],
+            &[0, 0x20, 0, 0x20, 1, 0x6a, 0x0b],
+            &[0, 0x23, 0, 0x41, 1, 0x6a, 0x24, 0, 0x23, 0, 0x0b],
+            &[0, 0x03, 0x40, 0x0c, 0, 0x0b, 0x0b],
+        ] {
+            code.push(body.len() as u8);
+            code.extend_from_slice(body);
+        }
+
+        let binary = module(&[
+            (
+                1,
+                &[
+                    3, 0x60, 0, 0, 0x60, 2, 0x7f, 0x7f, 1, 0x7f, 0x60, 0, 1, 0x7f,
+                ],
+            ),
+            (3, &[4, 0, 1, 2, 0]),
+            (6, &[1, 0x7f, 1, 0x41, 0, 0x0b]),
+            (
+                7,
+                b"\x04\x0b_initialize\x00\x00\x03add\x00\x01\x04next\x00\x02\x04spin\x00\x03",
+            ),
+            (10, &code),
+        ]);
+
+        CompiledModule::compile(&binary).unwrap()
+    }
+
+    fn next(reactor: &Reactor<String>) -> Result<Vec<Value>, ReactorError> {
+        reactor.call("next", &[])
+    }
+
+    #[test]
+    fn calls_share_the_instance() {
+        let host = WasiHostBuilder::<String>::new().build().unwrap();
+        let reactor = host.instantiate_reactor(&reactor()).unwrap();
+
+        assert_eq!(next(&reactor).unwrap(), [Value::I32(101)]);
+        assert_eq!(next(&reactor).unwrap(), [Value::I32(102)]);
+        assert_eq!(
+            reactor
+                .call("add", &[Value::I32(2), Value::I32(3)])
+                .unwrap(),
+            [Value::I32(5)]
+        );
+        assert!(matches!(
+            reactor.call("missing", &[]),
+            Err(ReactorError::MissingExport(ref name)) if name == "missing"
+        ));
+    }
+
+    #[test]
+    fn the_timeout_applies_to_each_call() {
+        let host = WasiHostBuilder::<String>::new()
+            .timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let reactor = host.instantiate_reactor(&reactor()).unwrap();
+
+        assert!(matches!(
+            reactor.call("spin", &[]),
+            Err(ReactorError::Stopped(RunOutcome::LimitExceeded(
+                LimitKind::Time
+            )))
+        ));
+        assert_eq!(next(&reactor).unwrap(), [Value::I32(101)]);
+    }
+
+    #[test]
+    fn cancelled_reactors_resume_after_a_reset() {
+        let host = WasiHostBuilder::<String>::new().build().unwrap();
+        let handle = host.cancellation_handle();
+        let reactor = host.instantiate_reactor(&reactor()).unwrap();
+
+        handle.cancel();
+        assert!(matches!(
+            next(&reactor),
+            Err(ReactorError::Stopped(RunOutcome::Cancelled))
+        ));
+
+        handle.reset();
+        assert_eq!(next(&reactor).unwrap(), [Value::I32(101)]);
+    }
+}
@@ -0,0 +1,454 @@
+use super::observe::{Argument, CallObserver, ObservedImports, Outcome, Returned, Stop};
+use parking_lot::Mutex;
+use std::{
+    cell::Cell,
+    convert::TryFrom,
+    fmt,
+    fs::File,
+    io::{self, BufWriter, IoSlice, IoSliceMut, Read, Write},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use wasihost_core::{
+    wasi_snapshot_preview1::{Errno, Exitcode, Signal, Size, Subscription, WasiImports},
+    StringRepresentation,
+};
+use witx_gen::{WasiValue, WasmValue};
+
+/// Identifies a file written by a [`Recorder`](struct.Recorder.html).
+const MAGIC: &[u8; 8] = b"WASIREC1";
+
+/// How a recorded call ended.
+#[derive(Debug)]
+pub(super) enum Recorded {
+    /// The call returned successfully, with the encoded results and data.
+    Returned(Vec<Vec<u8>>),
+    /// The call failed.
+    Failed(Errno),
+    /// The WASI binary called `proc_exit`.
+    Exited(Exitcode),
+    /// The call ended the run instead of returning.
+    Stopped(Stop),
+}
+
+/// A recorded call with its encoded arguments.
+#[derive(Debug)]
+pub(super) struct Entry {
+    pub(super) function: String,
+    pub(super) arguments: Vec<Vec<u8>>,
+    pub(super) result: Recorded,
+}
+
+impl Entry {
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let (tag, fields) = match &self.result {
+            Recorded::Returned(fields) => (0, fields.clone()),
+            Recorded::Failed(errno) => (1, vec![value(*errno)]),
+            Recorded::Exited(code) => (2, vec![value(*code)]),
+            Recorded::Stopped(Stop::Cancelled) => (3, Vec::new()),
+            Recorded::Stopped(Stop::TimedOut) => (4, Vec::new()),
+            Recorded::Stopped(Stop::Killed(signal)) => (5, vec![value(*signal)]),
+            Recorded::Stopped(Stop::Trapped(message)) => (6, vec![message.as_bytes().to_vec()]),
+        };
+
+        write_field(writer, self.function.as_bytes())?;
+        write_fields(writer, &self.arguments)?;
+        writer.write_all(&[tag])?;
+        write_fields(writer, &fields)
+    }
+
+    /// Reads the next entry, failing with `UnexpectedEof` if the log ends before it is
+    /// complete.
+    fn read_from(reader: &mut dyn Read) -> io::Result<Self> {
+        let len = read_u32(reader)?;
+        let function = String::from_utf8(read_bytes(reader, len)?).map_err(|_| invalid_data())?;
+        let arguments = read_fields(reader)?;
+
+        let mut tag = [0];
+        reader.read_exact(&mut tag)?;
+        let fields = read_fields(reader)?;
+
+        let result = match (tag[0], fields.as_slice()) {
+            (0, _) => Recorded::Returned(fields),
+            (1, [errno]) => Recorded::Failed(from_value(errno).ok_or_else(invalid_data)?),
+            (2, [code]) => Recorded::Exited(from_value(code).ok_or_else(invalid_data)?),
+            (3, []) => Recorded::Stopped(Stop::Cancelled),
+            (4, []) => Recorded::Stopped(Stop::TimedOut),
+            (5, [signal]) => Recorded::Stopped(Stop::Killed(
+                from_value::<Signal>(signal).ok_or_else(invalid_data)?,
+            )),
+            (6, [message]) => Recorded::Stopped(Stop::Trapped(
+                String::from_utf8(message.clone()).map_err(|_| invalid_data())?,
+            )),
+            _ => return Err(invalid_data()),
+        };
+
+        Ok(Entry {
+            function,
+            arguments,
+            result,
+        })
+    }
+}
+
+/// Reads all entries of a log written by a [`Recorder`](struct.Recorder.html). A log that
+/// ends within an entry, because the recording process was killed while writing it, ends
+/// before that entry.
+pub(super) fn read_log(reader: &mut dyn Read) -> io::Result<Vec<Entry>> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+
+    if &magic != MAGIC {
+        return Err(invalid_data());
+    }
+
+    let mut entries = Vec::new();
+
+    loop {
+        match Entry::read_from(reader) {
+            Ok(entry) => entries.push(entry),
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(entries),
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+fn invalid_data() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid WASI recording")
+}
+
+fn write_field(writer: &mut dyn Write, field: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(field.len()).map_err(|_| invalid_data())?;
+
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(field)
+}
+
+fn write_fields(writer: &mut dyn Write, fields: &[Vec<u8>]) -> io::Result<()> {
+    let count = u32::try_from(fields.len()).map_err(|_| invalid_data())?;
+
+    writer.write_all(&count.to_le_bytes())?;
+    fields
+        .iter()
+        .try_for_each(|field| write_field(writer, field))
+}
+
+fn read_u32(reader: &mut dyn Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_bytes(reader: &mut dyn Read, len: u32) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.take(u64::from(len)).read_to_end(&mut bytes)?;
+
+    if bytes.len() == len as usize {
+        Ok(bytes)
+    } else {
+        Err(io::ErrorKind::UnexpectedEof.into())
+    }
+}
+
+fn read_fields(reader: &mut dyn Read) -> io::Result<Vec<Vec<u8>>> {
+    let count = read_u32(reader)?;
+
+    (0..count)
+        .map(|_| {
+            let len = read_u32(reader)?;
+            read_bytes(reader, len)
+        })
+        .collect()
+}
+
+/// Encodes a WASI value in its native representation.
+pub(super) fn value<T: WasiValue>(value: T) -> Vec<u8>
+where
+    T::NativeType: WasmValue,
+{
+    let cells = vec![Cell::new(0); <T::NativeType as WasmValue>::SIZE as usize];
+    value.to_native().write(&cells);
+    cells.into_iter().map(Cell::into_inner).collect()
+}
+
+/// Decodes a WASI value encoded by [`value`](fn.value.html).
+pub(super) fn from_value<T: WasiValue>(bytes: &[u8]) -> Option<T>
+where
+    T::NativeType: WasmValue,
+{
+    if bytes.len() != <T::NativeType as WasmValue>::SIZE as usize {
+        return None;
+    }
+
+    let cells: Vec<_> = bytes.iter().copied().map(Cell::new).collect();
+    T::from_native(<T::NativeType as WasmValue>::read(&cells)).ok()
+}
+
+/// Encodes the lengths of the buffers a call reads into.
+pub(super) fn lengths(iovs: &[IoSliceMut<'_>]) -> Vec<u8> {
+    iovs.iter()
+        .flat_map(|iov| (iov.len() as u32).to_le_bytes().to_vec())
+        .collect()
+}
+
+/// Encodes the data a call writes.
+pub(super) fn data(bufs: &[IoSlice<'_>]) -> Vec<u8> {
+    bufs.iter().flat_map(|buf| buf.iter().copied()).collect()
+}
+
+/// Encodes the first `size` bytes that a call read into `iovs`.
+fn read_data(iovs: &[IoSliceMut<'_>], size: Size) -> Vec<u8> {
+    iovs.iter()
+        .flat_map(|iov| iov.iter().copied())
+        .take(size.0 as usize)
+        .collect()
+}
+
+/// Encodes the subscriptions of `poll_oneoff`.
+pub(super) fn subscriptions(subscriptions: &[Subscription]) -> Vec<u8> {
+    subscriptions
+        .iter()
+        .flat_map(|&subscription| value(subscription))
+        .collect()
+}
+
+/// Encodes an argument of a call.
+pub(super) fn argument(argument: &Argument<'_>) -> Vec<u8> {
+    match argument {
+        Argument::Value(value) => value.encode(),
+        Argument::Data(bufs) => data(bufs),
+        Argument::Buffers(iovs) => lengths(iovs),
+        Argument::Buffer(len) => value(Size(*len as u32)),
+        Argument::String(string) => string.to_vec(),
+        Argument::Subscriptions(subscriptions) => self::subscriptions(subscriptions),
+    }
+}
+
+/// Encodes what a call returned to the WASI binary, including the data it read.
+fn returned<S: StringRepresentation>(returned: &Returned<'_, S>) -> Vec<Vec<u8>> {
+    match returned {
+        Returned::Unit => Vec::new(),
+        Returned::Value(value) => vec![value.encode()],
+        Returned::Strings(strings) => strings
+            .iter()
+            .map(|string| string.as_bytes().to_vec())
+            .collect(),
+        Returned::String(string) => vec![string.as_bytes().to_vec()],
+        Returned::Read {
+            size, flags, data, ..
+        } => {
+            let mut fields = vec![value(*size)];
+            fields.extend(flags.map(value));
+            fields.push(read_data(data, *size));
+            fields
+        }
+        Returned::Written { size, .. } => vec![value(*size)],
+        Returned::Dirent(Some((dirent, name))) => vec![value(*dirent), name.as_bytes().to_vec()],
+        Returned::Dirent(None) => Vec::new(),
+        Returned::Events(events) => events.iter().map(|&event| value(event)).collect(),
+        Returned::Random(data) => vec![data.to_vec()],
+    }
+}
+
+/// Writes the system calls of a run to a file or any other writer, so the run can be
+/// replayed with [`ReplayImports`](struct.ReplayImports.html).
+///
+/// The log contains the arguments of every call and everything it returned to the WASI
+/// binary, including the data read from file descriptors, the random data and the time of
+/// the clocks. Each call is flushed once it has been recorded, so the recording is complete
+/// while the run is still going on. Errors while writing are ignored, so they do not change
+/// the run.
+pub struct Recorder {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Recorder {
+    /// Records to `writer`, which should be buffered.
+    pub fn new(writer: impl Write + Send + 'static) -> io::Result<Self> {
+        let mut writer: Box<dyn Write + Send> = Box::new(writer);
+        writer.write_all(MAGIC)?;
+        writer.flush()?;
+
+        Ok(Recorder {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// Records to a new file at `path`, replacing an existing file.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    fn record(&self, entry: &Entry) {
+        let mut writer = self.writer.lock();
+        let _ = entry.write_to(&mut *writer).and_then(|()| writer.flush());
+    }
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder").finish()
+    }
+}
+
+impl CallObserver for Recorder {
+    type Call = Vec<Vec<u8>>;
+
+    fn start(
+        &self,
+        _function: &'static str,
+        arguments: &[(&'static str, Argument<'_>)],
+    ) -> Self::Call {
+        arguments
+            .iter()
+            .map(|(_, argument)| self::argument(argument))
+            .collect()
+    }
+
+    fn finish<S: StringRepresentation>(
+        &self,
+        function: &'static str,
+        arguments: Self::Call,
+        outcome: &Outcome<'_, S>,
+        _duration: Duration,
+    ) {
+        let result = match outcome {
+            Outcome::Returned(returned) => Recorded::Returned(self::returned(returned)),
+            Outcome::Failed(errno) => Recorded::Failed(*errno),
+            Outcome::Exited(code) => Recorded::Exited(*code),
+            Outcome::Stopped(stop) => Recorded::Stopped((*stop).clone()),
+        };
+
+        self.record(&Entry {
+            function: function.to_string(),
+            arguments,
+            result,
+        });
+    }
+}
+
+/// Wraps WASI imports and records every system call with a
+/// [`Recorder`](struct.Recorder.html), including how a call that ended the run ended it.
+pub type RecordingImports<I> = ObservedImports<I, Recorder>;
+
+impl<I: WasiImports> ObservedImports<I, Recorder> {
+    /// Records the system calls handled by `inner` with `recorder`.
+    pub fn new(inner: Arc<I>, recorder: Recorder) -> Self {
+        Self::with_observer(inner, recorder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasi_snapshot_preview1::{
+        test_binaries::{calling_with, exiting},
+        test_util::SharedBuffer,
+        RunOutcome, WasiHostBuilder,
+    };
+
+    fn entry(function: &str, result: Recorded) -> Entry {
+        Entry {
+            function: function.to_string(),
+            arguments: vec![vec![1, 2, 3], Vec::new()],
+            result,
+        }
+    }
+
+    fn log(entries: &[Entry]) -> Vec<u8> {
+        let mut log = MAGIC.to_vec();
+
+        for entry in entries {
+            entry.write_to(&mut log).unwrap();
+        }
+        log
+    }
+
+    fn results(entries: &[Entry]) -> Vec<String> {
+        entries
+            .iter()
+            .map(|entry| format!("{} {:?}", entry.function, entry.result))
+            .collect()
+    }
+
+    #[test]
+    fn entries_are_read_back() {
+        let entries = [
+            entry("fd_write", Recorded::Returned(vec![vec![4, 0, 0, 0]])),
+            entry("fd_close", Recorded::Failed(Errno::Badf)),
+            entry("proc_exit", Recorded::Exited(Exitcode(3))),
+            entry("fd_read", Recorded::Stopped(Stop::Cancelled)),
+            entry("poll_oneoff", Recorded::Stopped(Stop::TimedOut)),
+            entry("proc_raise", Recorded::Stopped(Stop::Killed(Signal::Term))),
+            entry(
+                "fd_read",
+                Recorded::Stopped(Stop::Trapped("it broke".to_string())),
+            ),
+        ];
+
+        let read = read_log(&mut &log(&entries)[..]).unwrap();
+
+        assert_eq!(results(&read), results(&entries));
+        assert!(read
+            .iter()
+            .all(|entry| entry.arguments == entries[0].arguments));
+    }
+
+    #[test]
+    fn truncated_entries_end_the_log() {
+        let first = log(&[entry("fd_close", Recorded::Failed(Errno::Badf))]);
+        let both = log(&[
+            entry("fd_close", Recorded::Failed(Errno::Badf)),
+            entry("fd_write", Recorded::Returned(vec![vec![4, 0, 0, 0]])),
+        ]);
+
+        for len in first.len()..both.len() {
+            let read = read_log(&mut &both[..len]).unwrap();
+            assert_eq!(read.len(), 1, "truncated to {} bytes", len);
+        }
+        assert_eq!(read_log(&mut &both[..]).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn logs_need_the_header() {
+        assert!(read_log(&mut &b"WASIREC0"[..]).is_err());
+        assert!(read_log(&mut &MAGIC[..4]).is_err());
+        assert!(read_log(&mut &MAGIC[..]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn runs_are_recorded_with_how_they_ended() {
+        let buffer = SharedBuffer::default();
+        let host = WasiHostBuilder::<String>::new().build().unwrap();
+
+        host.record_next_run(Recorder::new(buffer.clone()).unwrap());
+        assert_eq!(
+            host.clone()
+                .run_binary(&calling_with("proc_raise", &[Signal::Term as i32], &[]))
+                .unwrap(),
+            RunOutcome::Signaled(Signal::Term)
+        );
+        host.record_next_run(Recorder::new(buffer.clone()).unwrap());
+        host.run_binary(&exiting(3)).unwrap();
+
+        let log = buffer.0.lock().clone();
+        let second = log[MAGIC.len()..]
+            .windows(MAGIC.len())
+            .position(|window| window == MAGIC)
+            .unwrap()
+            + MAGIC.len();
+
+        let raised = read_log(&mut &log[..second]).unwrap();
+        assert_eq!(raised.len(), 1);
+        assert_eq!(raised[0].function, "proc_raise");
+        assert_eq!(raised[0].arguments, [value(Signal::Term)]);
+        assert!(matches!(
+            raised[0].result,
+            Recorded::Stopped(Stop::Killed(Signal::Term))
+        ));
+
+        let exited = read_log(&mut &log[second..]).unwrap();
+        assert_eq!(results(&exited), ["proc_exit Exited(Exitcode(3))"]);
+    }
+}
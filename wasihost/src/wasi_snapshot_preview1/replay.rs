@@ -0,0 +1,510 @@
+use super::{
+    functions::{wasi_argument, wasi_functions, wasi_parameter, wasi_result},
+    record::{self, from_value, Entry, Recorded},
+};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    error::Error,
+    fmt,
+    fs::File,
+    io::{self, BufReader, IoSliceMut, Read},
+    panic,
+    path::Path,
+};
+use wasihost_core::{
+    wasi_snapshot_preview1::{
+        Advice, Clockid, Dircookie, Dirent, Event, Exitcode, Fd, Fdflags, Fdstat, Filedelta,
+        Filesize, Filestat, Fstflags, Lookupflags, Oflags, Prestat, Riflags, Rights, Roflags,
+        Sdflags, Siflags, Signal, Size, Timestamp, WasiImports, WasiResult, Whence,
+    },
+    StringRepresentation,
+};
+use witx_gen::{WasiValue, WasmValue};
+
+/// The first point where a replayed run did not make the system call that was recorded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Divergence {
+    /// The index of the call in the recording, starting at 0.
+    pub call: usize,
+    /// The function the WASI binary called.
+    pub function: &'static str,
+    /// The function that was recorded, or `None` if the recording ended before.
+    pub expected: Option<String>,
+    /// How the call differs from the recording.
+    pub reason: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "call {} to `{}` diverged from the recording: {}",
+            self.call, self.function, self.reason
+        )
+    }
+}
+
+impl Error for Divergence {}
+
+/// WASI imports that replay a run recorded by a [`Recorder`](struct.Recorder.html) without
+/// performing any I/O.
+///
+/// Every system call is checked against the next recorded call and returns what was recorded,
+/// including the data read from file descriptors, the random data and the time of the clocks.
+/// The first call whose function or arguments differ is reported by
+/// [`divergence`](#method.divergence) and ends the run with
+/// [`RunOutcome::Trapped`](enum.RunOutcome.html#variant.Trapped). A call that ended the
+/// recorded run, because it was cancelled, timed out, killed by a signal or trapped, ends the
+/// replayed run the same way. Runs are replayed with
+/// [`CompiledModule::run_with_imports`](struct.CompiledModule.html#method.run_with_imports).
+pub struct ReplayImports<S: StringRepresentation> {
+    entries: Vec<Entry>,
+    /// The strings returned by the recorded `args_get` and `environ_get` calls, by call index.
+    strings: HashMap<usize, Vec<S>>,
+    position: Mutex<usize>,
+    divergence: Mutex<Option<Divergence>>,
+}
+
+impl<S: StringRepresentation> ReplayImports<S> {
+    /// Replays the recording in the file at `path`.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Replays the recording read from `reader`.
+    pub fn from_reader(mut reader: impl Read) -> io::Result<Self> {
+        let entries = record::read_log(&mut reader)?;
+        let mut strings = HashMap::new();
+
+        for (call, entry) in entries.iter().enumerate() {
+            if let ("args_get", Recorded::Returned(fields))
+            | ("environ_get", Recorded::Returned(fields)) = (&entry.function[..], &entry.result)
+            {
+                let decoded = fields
+                    .iter()
+                    .map(|field| S::from_bytes(field.clone()))
+                    .collect::<Result<Vec<_>, ()>>()
+                    .map_err(|()| {
+                        io::Error::new(io::ErrorKind::InvalidData, "invalid recorded string")
+                    })?;
+                strings.insert(call, decoded);
+            }
+        }
+
+        Ok(ReplayImports {
+            entries,
+            strings,
+            position: Mutex::new(0),
+            divergence: Mutex::new(None),
+        })
+    }
+
+    /// Returns where the replayed run first diverged from the recording, if it did.
+    pub fn divergence(&self) -> Option<Divergence> {
+        self.divergence.lock().clone()
+    }
+
+    /// Returns the number of recorded calls that have not been replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.entries.len() - *self.position.lock()
+    }
+
+    /// Returns whether all recorded calls have been replayed.
+    pub fn is_complete(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// Records the divergence, unless an earlier one was recorded, and ends the run.
+    fn diverge(
+        &self,
+        call: usize,
+        function: &'static str,
+        expected: Option<&Entry>,
+        reason: impl Into<String>,
+    ) -> ! {
+        let divergence = Divergence {
+            call,
+            function,
+            expected: expected.map(|entry| entry.function.clone()),
+            reason: reason.into(),
+        };
+        let message = divergence.to_string();
+
+        self.divergence.lock().get_or_insert(divergence);
+        panic::resume_unwind(Box::new(message))
+    }
+
+    /// Checks a call against the next recorded call, and returns its index and entry.
+    fn next(&self, function: &'static str, arguments: Vec<Vec<u8>>) -> (usize, &Entry) {
+        let call = {
+            let mut position = self.position.lock();
+            let call = *position;
+            *position += 1;
+            call
+        };
+
+        let entry = match self.entries.get(call) {
+            Some(entry) => entry,
+            None => self.diverge(call, function, None, "the recording ended"),
+        };
+
+        if entry.function != function {
+            self.diverge(
+                call,
+                function,
+                Some(entry),
+                format!("a call to `{}` was recorded", entry.function),
+            );
+        }
+        if entry.arguments != arguments {
+            self.diverge(call, function, Some(entry), "the arguments differ");
+        }
+        if let Recorded::Stopped(stop) = &entry.result {
+            // The run ends the way the recorded run ended.
+            panic::resume_unwind(stop.clone().into_payload());
+        }
+
+        (call, entry)
+    }
+
+    /// Replays a call, decoding what it returned with `decode`.
+    fn replay<T>(
+        &self,
+        function: &'static str,
+        arguments: Vec<Vec<u8>>,
+        decode: impl FnOnce(&[Vec<u8>]) -> Option<T>,
+    ) -> WasiResult<T> {
+        let (call, entry) = self.next(function, arguments);
+
+        match &entry.result {
+            Recorded::Returned(fields) => match decode(fields) {
+                Some(result) => Ok(result),
+                None => self.diverge(
+                    call,
+                    function,
+                    Some(entry),
+                    "the recorded result is invalid",
+                ),
+            },
+            Recorded::Failed(errno) => Err(*errno),
+            _ => self.diverge(
+                call,
+                function,
+                Some(entry),
+                "the recorded result is invalid",
+            ),
+        }
+    }
+
+    fn replay_strings(&self, function: &'static str, arguments: Vec<Vec<u8>>) -> WasiResult<&[S]> {
+        let (call, entry) = self.next(function, arguments);
+
+        match (&entry.result, self.strings.get(&call)) {
+            (Recorded::Failed(errno), _) => Err(*errno),
+            (_, Some(strings)) => Ok(strings),
+            _ => self.diverge(
+                call,
+                function,
+                Some(entry),
+                "the recorded result is invalid",
+            ),
+        }
+    }
+
+    /// Replays a call that reads into `iovs`, decoding what it returned besides the data with
+    /// `decode`.
+    fn replay_read<T>(
+        &self,
+        function: &'static str,
+        arguments: Vec<Vec<u8>>,
+        iovs: &mut [IoSliceMut<'_>],
+        decode: impl FnOnce(&[Vec<u8>]) -> Option<T>,
+    ) -> WasiResult<T> {
+        let len = total_len(iovs);
+        let (result, data) =
+            self.replay(function, arguments, |fields| match fields.split_last() {
+                Some((data, fields)) if data.len() <= len => Some((decode(fields)?, data.clone())),
+                _ => None,
+            })?;
+
+        fill(iovs, &data);
+        Ok(result)
+    }
+
+    /// Replays `random_get`, filling `buf` with the recorded data.
+    fn replay_random(
+        &self,
+        function: &'static str,
+        arguments: Vec<Vec<u8>>,
+        buf: &mut [u8],
+    ) -> WasiResult<()> {
+        let data = self.replay(function, arguments, |fields| match fields {
+            [data] if data.len() == buf.len() => Some(data.clone()),
+            _ => None,
+        })?;
+
+        buf.copy_from_slice(&data);
+        Ok(())
+    }
+
+    /// Replays `proc_exit`, which exits with the recorded exit code.
+    fn replay_exit(
+        &self,
+        function: &'static str,
+        arguments: Vec<Vec<u8>>,
+    ) -> Result<Infallible, Exitcode> {
+        let (call, entry) = self.next(function, arguments);
+
+        match entry.result {
+            Recorded::Exited(code) => Err(code),
+            _ => self.diverge(
+                call,
+                function,
+                Some(entry),
+                "the recorded result is invalid",
+            ),
+        }
+    }
+}
+
+impl<S: StringRepresentation> fmt::Debug for ReplayImports<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplayImports")
+            .field("calls", &self.entries.len())
+            .field("position", &*self.position.lock())
+            .field("divergence", &*self.divergence.lock())
+            .finish()
+    }
+}
+
+fn total_len(iovs: &[IoSliceMut<'_>]) -> usize {
+    iovs.iter().map(|iov| iov.len()).sum()
+}
+
+/// Copies `data` into consecutive buffers of `iovs`.
+fn fill(iovs: &mut [IoSliceMut<'_>], mut data: &[u8]) {
+    for iov in iovs.iter_mut() {
+        let len = iov.len().min(data.len());
+
+        iov[..len].copy_from_slice(&data[..len]);
+        data = &data[len..];
+    }
+}
+
+fn unit(fields: &[Vec<u8>]) -> Option<()> {
+    if fields.is_empty() {
+        Some(())
+    } else {
+        None
+    }
+}
+
+fn single<T: WasiValue>(fields: &[Vec<u8>]) -> Option<T>
+where
+    T::NativeType: WasmValue,
+{
+    match fields {
+        [field] => from_value(field),
+        _ => None,
+    }
+}
+
+fn owned_string<S: StringRepresentation>(fields: &[Vec<u8>]) -> Option<S> {
+    match fields {
+        [field] => S::from_bytes(field.clone()).ok(),
+        _ => None,
+    }
+}
+
+fn dirent<S: StringRepresentation>(fields: &[Vec<u8>]) -> Option<Option<(Dirent, S)>> {
+    match fields {
+        [] => Some(None),
+        [dirent, name] => Some(Some((
+            from_value(dirent)?,
+            S::from_bytes(name.clone()).ok()?,
+        ))),
+        _ => None,
+    }
+}
+
+fn events(fields: &[Vec<u8>]) -> Option<Vec<Event>> {
+    fields.iter().map(|field| from_value(field)).collect()
+}
+
+fn received(fields: &[Vec<u8>]) -> Option<(Size, Roflags)> {
+    match fields {
+        [size, roflags] => Some((from_value(size)?, from_value(roflags)?)),
+        _ => None,
+    }
+}
+
+/// Implements `WasiImports` for `ReplayImports` from the signatures given by
+/// [`wasi_functions!`](../functions/macro.wasi_functions.html).
+macro_rules! replayed_imports {
+    ($(
+        fn $function:ident($($name:ident: $kind:ident $(($type:ty))?),*)
+            -> $result:ident $(($($returned:tt)*))?;
+    )*) => {
+        impl<S: StringRepresentation> WasiImports for ReplayImports<S> {
+            type StringRepresentation = S;
+
+            $(
+                fn $function(
+                    &self,
+                    $($name: wasi_parameter!($kind $(($type))?)),*
+                ) -> wasi_result!($result $(($($returned)*))?) {
+                    let arguments =
+                        vec![$(record::argument(&wasi_argument!($kind $(($type))? $name))),*];
+
+                    replayed!(self, stringify!($function), arguments, $result $(($($returned)*))?)
+                }
+            )*
+        }
+    };
+}
+
+/// Replays a call whose result is of the given kind, see
+/// [`wasi_functions!`](../functions/macro.wasi_functions.html).
+macro_rules! replayed {
+    ($imports:ident, $function:expr, $arguments:ident, unit) => {
+        $imports.replay($function, $arguments, unit)
+    };
+    ($imports:ident, $function:expr, $arguments:ident, value($type:ty)) => {
+        $imports.replay($function, $arguments, single)
+    };
+    ($imports:ident, $function:expr, $arguments:ident, strings) => {
+        $imports.replay_strings($function, $arguments)
+    };
+    ($imports:ident, $function:expr, $arguments:ident, string) => {
+        $imports.replay($function, $arguments, owned_string)
+    };
+    ($imports:ident, $function:expr, $arguments:ident, dirent) => {
+        $imports.replay($function, $arguments, dirent)
+    };
+    ($imports:ident, $function:expr, $arguments:ident, events) => {
+        $imports.replay($function, $arguments, events)
+    };
+    ($imports:ident, $function:expr, $arguments:ident, read($fd:ident, $iovs:ident)) => {
+        $imports.replay_read($function, $arguments, $iovs, single)
+    };
+    ($imports:ident, $function:expr, $arguments:ident, received($fd:ident, $iovs:ident)) => {
+        $imports.replay_read($function, $arguments, $iovs, received)
+    };
+    ($imports:ident, $function:expr, $arguments:ident, written($fd:ident)) => {
+        $imports.replay($function, $arguments, single)
+    };
+    ($imports:ident, $function:expr, $arguments:ident, random($buf:ident)) => {
+        $imports.replay_random($function, $arguments, $buf)
+    };
+    ($imports:ident, $function:expr, $arguments:ident, exit) => {
+        $imports.replay_exit($function, $arguments)
+    };
+}
+
+wasi_functions!(replayed_imports);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasi_snapshot_preview1::{
+        test_binaries::calling_with, CancellationHandle, CompiledModule, Recorder, RunOutcome,
+        WasiHostBuilder,
+    };
+    use std::sync::Arc;
+    use wasihost_core::wasi_snapshot_preview1::native::{
+        errno, NativeCall, NativeWasiImportsLayer,
+    };
+    use wasmer_runtime::Ctx;
+
+    /// Cancels the run before every call.
+    struct Cancelling(CancellationHandle);
+
+    impl NativeWasiImportsLayer for Cancelling {
+        fn before(&self, _ctx: &mut Ctx, _call: &NativeCall) -> Result<(), errno> {
+            self.0.cancel();
+            Ok(())
+        }
+    }
+
+    /// Records a run of `binary`, which is cancelled at its first call if `cancelled` is set,
+    /// then replays the run with `replayed`. Returns both outcomes and the replay.
+    fn record_and_replay(
+        binary: &[u8],
+        replayed: &[u8],
+        cancelled: bool,
+    ) -> (RunOutcome, RunOutcome, Arc<ReplayImports<String>>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.wasirec");
+        let host = WasiHostBuilder::<String>::new().build().unwrap();
+        if cancelled {
+            host.add_layer(Cancelling(host.cancellation_handle()));
+        }
+
+        host.record_next_run(Recorder::create(&path).unwrap());
+        let recorded = host.run_binary(binary).unwrap();
+
+        let replay = Arc::new(ReplayImports::open(&path).unwrap());
+        let outcome = CompiledModule::compile(replayed)
+            .unwrap()
+            .run_with_imports(replay.clone())
+            .unwrap();
+
+        (recorded, outcome, replay)
+    }
+
+    #[test]
+    fn recorded_runs_are_replayed() {
+        let binary = calling_with("fd_close", &[99], &[]);
+        let (recorded, replayed, replay) = record_and_replay(&binary, &binary, false);
+
+        assert_eq!(recorded, RunOutcome::Exited(0));
+        assert_eq!(replayed, recorded);
+        assert!(replay.is_complete());
+        assert_eq!(replay.divergence(), None);
+    }
+
+    #[test]
+    fn runs_diverge_at_the_first_different_call() {
+        let binary = calling_with("fd_close", &[99], &[]);
+        let (_, replayed, replay) =
+            record_and_replay(&binary, &calling_with("fd_close", &[98], &[]), false);
+
+        assert!(matches!(replayed, RunOutcome::Trapped { .. }));
+        assert_eq!(
+            replay.divergence(),
+            Some(Divergence {
+                call: 0,
+                function: "fd_close",
+                expected: Some("fd_close".to_string()),
+                reason: "the arguments differ".to_string(),
+            })
+        );
+
+        let (_, _, replay) =
+            record_and_replay(&binary, &calling_with("fd_sync", &[99], &[]), false);
+        assert_eq!(
+            replay.divergence().unwrap().reason,
+            "a call to `fd_close` was recorded"
+        );
+    }
+
+    #[test]
+    fn calls_that_ended_the_run_end_the_replay() {
+        let raising = calling_with("proc_raise", &[Signal::Term as i32], &[]);
+        let (recorded, replayed, replay) = record_and_replay(&raising, &raising, false);
+
+        assert_eq!(recorded, RunOutcome::Signaled(Signal::Term));
+        assert_eq!(replayed, recorded);
+        assert!(replay.is_complete());
+
+        let closing = calling_with("fd_close", &[99], &[]);
+        let (recorded, replayed, replay) = record_and_replay(&closing, &closing, true);
+
+        assert_eq!(recorded, RunOutcome::Cancelled);
+        assert_eq!(replayed, recorded);
+        assert!(replay.is_complete());
+    }
+}
@@ -0,0 +1,283 @@
+use super::{cancel::Interrupted, signal::Killed};
+use std::{any::Any, error::Error, fmt, io};
+use wasihost_core::wasi_snapshot_preview1::{native, Signal};
+use wasmer_runtime::{
+    error::{self as wasmer_error, RuntimeError},
+    ExceptionCode, Func, Instance,
+};
+
+/// How a run of a WASI binary ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// The WASI binary returned from `_start` or called `proc_exit` with the exit code.
+    Exited(native::exitcode),
+    /// The WASI binary trapped, for example by executing `unreachable` or accessing memory
+    /// out of bounds.
+    Trapped {
+        /// A description of the trap.
+        message: String,
+        /// The WASM functions on the stack when the trap occurred, innermost first. Always
+        /// empty for now: the `RuntimeError`s of wasmer 0.14 carry no stack frames.
+        backtrace: Vec<String>,
+    },
+    /// The WASI binary raised a signal that terminated it.
+    Signaled(Signal),
+    /// The run was cancelled with a [`CancellationHandle`](struct.CancellationHandle.html).
+    Cancelled,
+    /// The run exceeded a limit of the WASI host.
+    LimitExceeded(LimitKind),
+}
+
+impl RunOutcome {
+    pub(super) fn from_runtime_error(error: RuntimeError) -> Self {
+        let payload = &error.0;
+
+        if let Some(&code) = payload.downcast_ref::<native::exitcode>() {
+            return RunOutcome::Exited(code);
+        }
+        if let Some(&Killed(signal)) = payload.downcast_ref::<Killed>() {
+            return RunOutcome::Signaled(signal);
+        }
+        if let Some(&interrupted) = payload.downcast_ref::<Interrupted>() {
+            return interrupted.into();
+        }
+
+        RunOutcome::Trapped {
+            message: trap_message(&**payload),
+            backtrace: Vec::new(),
+        }
+    }
+}
+
+/// Describes a trap or a panic of the WASI host with its payload.
+pub(super) fn trap_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(code) = payload.downcast_ref::<ExceptionCode>() {
+        code.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else {
+        "unknown error".to_string()
+    }
+}
+
+impl RunOutcome {
+    /// Reports a trap of instrumented WASM code that was interrupted as the reason.
+    pub(super) fn interrupted_by(self, interrupted: Option<Interrupted>) -> Self {
+        match (self, interrupted) {
+            (RunOutcome::Trapped { .. }, Some(interrupted)) => interrupted.into(),
+            (outcome, _) => outcome,
+        }
+    }
+}
+
+/// Runs an instantiated WASI binary by calling its `_start` function.
+pub(super) fn start(instance: &Instance) -> Result<RunOutcome, RunError> {
+    let start: Func<'_, ()> = instance
+        .func("_start")
+        .map_err(|_| RunError::MissingStart)?;
+
+    Ok(match start.call() {
+        Ok(()) => RunOutcome::Exited(0),
+        Err(e) => RunOutcome::from_runtime_error(e),
+    })
+}
+
+impl From<Interrupted> for RunOutcome {
+    fn from(interrupted: Interrupted) -> Self {
+        match interrupted {
+            Interrupted::Cancelled => RunOutcome::Cancelled,
+            Interrupted::TimedOut => RunOutcome::LimitExceeded(LimitKind::Time),
+        }
+    }
+}
+
+/// The limits of a WASI host that end a run when they are exceeded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum LimitKind {
+    /// The run took longer than the timeout of the WASI host.
+    Time,
+    /// The WASI binary requires more memory than
+    /// [`ResourceLimits::max_memory_pages`](struct.ResourceLimits.html#structfield.max_memory_pages)
+    /// allows.
+    Memory,
+    /// The arguments and environment variables are larger than
+    /// [`ResourceLimits::max_args_environ_size`](struct.ResourceLimits.html#structfield.max_args_environ_size)
+    /// allows.
+    ArgsEnviron,
+}
+
+/// The reasons a WASI binary could not be run.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RunError {
+    /// The WASM file could not be read.
+    Load(io::Error),
+    /// The WASM binary is invalid or could not be compiled.
+    Compile(String),
+    /// The imports of the WASM binary could not be satisfied, with one message per import.
+    Link(Vec<String>),
+    /// The WASM binary could not be instantiated, for example because its start function
+    /// trapped.
+    Instantiate(String),
+    /// The WASM binary does not export a `_start` function without parameters and results.
+    MissingStart,
+    /// The memory of the compiled module can grow beyond the memory limit of the WASI host.
+    /// Such modules have to be compiled with the limit.
+    UnlimitedMemory,
+}
+
+impl RunError {
+    pub(super) fn from_instantiate_error(error: wasmer_error::Error) -> Self {
+        match error {
+            wasmer_error::Error::CompileError(error) => RunError::Compile(error.to_string()),
+            wasmer_error::Error::LinkError(errors) => {
+                RunError::Link(errors.iter().map(|error| error.to_string()).collect())
+            }
+            error => RunError::Instantiate(error.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::Load(error) => write!(f, "unable to read the WASM file: {}", error),
+            RunError::Compile(message) => {
+                write!(f, "unable to compile the WASM binary: {}", message)
+            }
+            RunError::Link(messages) => {
+                write!(f, "unable to link the WASM binary: {}", messages.join(", "))
+            }
+            RunError::Instantiate(message) => {
+                write!(f, "unable to instantiate the WASM binary: {}", message)
+            }
+            RunError::MissingStart => write!(f, "the WASM binary does not export `_start`"),
+            RunError::UnlimitedMemory => {
+                write!(
+                    f,
+                    "the memory of the module is not limited like the WASI host requires"
+                )
+            }
+        }
+    }
+}
+
+impl Error for RunError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RunError::Load(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RunError {
+    fn from(error: io::Error) -> Self {
+        RunError::Load(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasi_snapshot_preview1::{
+        test_binaries::{computing, module, returning},
+        WasiHostBuilder,
+    };
+
+    fn outcome(payload: impl Any + Send) -> RunOutcome {
+        RunOutcome::from_runtime_error(RuntimeError(Box::new(payload)))
+    }
+
+    fn trapped(message: &str) -> RunOutcome {
+        RunOutcome::Trapped {
+            message: message.to_string(),
+            backtrace: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn outcomes_follow_the_payload() {
+        let code: native::exitcode = 3;
+
+        assert_eq!(outcome(code), RunOutcome::Exited(3));
+        assert_eq!(
+            outcome(Killed(Signal::Int)),
+            RunOutcome::Signaled(Signal::Int)
+        );
+        assert_eq!(outcome(Interrupted::Cancelled), RunOutcome::Cancelled);
+        assert_eq!(
+            outcome(Interrupted::TimedOut),
+            RunOutcome::LimitExceeded(LimitKind::Time)
+        );
+        assert_eq!(outcome("boom".to_string()), trapped("boom"));
+        assert_eq!(outcome("boom"), trapped("boom"));
+        assert_eq!(outcome(42u8), trapped("unknown error"));
+    }
+
+    #[test]
+    fn only_traps_are_attributed_to_interruptions() {
+        assert_eq!(
+            trapped("unreachable").interrupted_by(Some(Interrupted::Cancelled)),
+            RunOutcome::Cancelled
+        );
+        assert_eq!(
+            trapped("unreachable").interrupted_by(None),
+            trapped("unreachable")
+        );
+        assert_eq!(
+            RunOutcome::Exited(0).interrupted_by(Some(Interrupted::TimedOut)),
+            RunOutcome::Exited(0)
+        );
+    }
+
+    #[test]
+    fn runs_start_functions() {
+        let host = WasiHostBuilder::<String>::new().build().unwrap();
+
+        assert_eq!(
+            host.clone().run_binary(&returning()).unwrap(),
+            RunOutcome::Exited(0)
+        );
+        // unreachable
+        match host.clone().run_binary(&computing(&[0x00])).unwrap() {
+            RunOutcome::Trapped { message, backtrace } => {
+                assert!(!message.is_empty());
+                assert!(backtrace.is_empty());
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+        assert!(matches!(
+            host.clone().run_binary(&module(&[
+                (1, &[1, 0x60, 0, 0]),
+                (3, &[1, 0]),
+                (7, b"\x01\x04main\x00\x00"),
+                (10, &[1, 2, 0, 0x0b]),
+            ])),
+            Err(RunError::MissingStart)
+        ));
+        assert!(matches!(
+            host.run_binary(b"not wasm"),
+            Err(RunError::Compile(_))
+        ));
+    }
+
+    #[test]
+    fn errors_describe_the_failure() {
+        assert_eq!(
+            RunError::Link(vec!["a".to_string(), "b".to_string()]).to_string(),
+            "unable to link the WASM binary: a, b"
+        );
+        assert_eq!(
+            RunError::MissingStart.to_string(),
+            "the WASM binary does not export `_start`"
+        );
+
+        let error = RunError::from(io::Error::new(io::ErrorKind::NotFound, "missing"));
+        assert_eq!(error.to_string(), "unable to read the WASM file: missing");
+        assert!(error.source().is_some());
+    }
+}
@@ -0,0 +1,152 @@
+use std::{collections::HashMap, fmt};
+use wasihost_core::wasi_snapshot_preview1::Signal;
+
+/// What happens when the WASI binary raises a signal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SignalAction {
+    /// The run ends with [`RunOutcome::Signaled`](enum.RunOutcome.html#variant.Signaled).
+    Terminate,
+    /// `proc_raise` returns successfully and the WASI binary continues.
+    Ignore,
+}
+
+impl SignalAction {
+    /// Returns the default POSIX disposition of `signal`.
+    ///
+    /// Most signals terminate. `Chld`, `Urg` and `Winch` are ignored, and since a WASI binary
+    /// can not be stopped, `Stop`, `Tstp`, `Ttin`, `Ttou` and `Cont` are ignored as well.
+    pub fn default_for(signal: Signal) -> Self {
+        match signal {
+            Signal::None
+            | Signal::Chld
+            | Signal::Urg
+            | Signal::Winch
+            | Signal::Stop
+            | Signal::Tstp
+            | Signal::Ttin
+            | Signal::Ttou
+            | Signal::Cont => SignalAction::Ignore,
+            _ => SignalAction::Terminate,
+        }
+    }
+}
+
+/// Traps the WASI binary when a signal terminates it.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Killed(pub(super) Signal);
+
+type Handler = Box<dyn Fn(Signal) -> SignalAction + Send + Sync>;
+
+/// The handlers registered for signals, all other signals take their default action.
+#[derive(Default)]
+pub(super) struct SignalHandlers {
+    handlers: HashMap<Signal, Handler>,
+}
+
+impl SignalHandlers {
+    pub(super) fn set(&mut self, signal: Signal, handler: Handler) {
+        self.handlers.insert(signal, handler);
+    }
+
+    pub(super) fn remove(&mut self, signal: Signal) {
+        self.handlers.remove(&signal);
+    }
+
+    pub(super) fn action(&self, signal: Signal) -> SignalAction {
+        match self.handlers.get(&signal) {
+            Some(handler) => handler(signal),
+            None => SignalAction::default_for(signal),
+        }
+    }
+}
+
+impl fmt::Debug for SignalHandlers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.handlers.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasi_snapshot_preview1::{test_binaries::calling_with, RunOutcome, WasiHostBuilder};
+
+    fn raising(signal: Signal) -> Vec<u8> {
+        calling_with("proc_raise", &[signal as i32], &[])
+    }
+
+    #[test]
+    fn default_actions_follow_posix() {
+        assert_eq!(
+            SignalAction::default_for(Signal::Term),
+            SignalAction::Terminate
+        );
+        assert_eq!(
+            SignalAction::default_for(Signal::Kill),
+            SignalAction::Terminate
+        );
+        assert_eq!(
+            SignalAction::default_for(Signal::Chld),
+            SignalAction::Ignore
+        );
+        assert_eq!(
+            SignalAction::default_for(Signal::Stop),
+            SignalAction::Ignore
+        );
+        assert_eq!(
+            SignalAction::default_for(Signal::None),
+            SignalAction::Ignore
+        );
+    }
+
+    #[test]
+    fn handlers_replace_the_default_action() {
+        let mut handlers = SignalHandlers::default();
+
+        handlers.set(Signal::Term, Box::new(|_| SignalAction::Ignore));
+        handlers.set(
+            Signal::Winch,
+            Box::new(|signal| {
+                assert_eq!(signal, Signal::Winch);
+                SignalAction::Terminate
+            }),
+        );
+        assert_eq!(handlers.action(Signal::Term), SignalAction::Ignore);
+        assert_eq!(handlers.action(Signal::Winch), SignalAction::Terminate);
+        assert_eq!(handlers.action(Signal::Int), SignalAction::Terminate);
+
+        handlers.remove(Signal::Term);
+        assert_eq!(handlers.action(Signal::Term), SignalAction::Terminate);
+    }
+
+    #[test]
+    fn terminating_signals_end_the_run() {
+        let host = WasiHostBuilder::<String>::new().build().unwrap();
+
+        assert_eq!(
+            host.run_binary(&raising(Signal::Term)).unwrap(),
+            RunOutcome::Signaled(Signal::Term)
+        );
+    }
+
+    #[test]
+    fn ignored_signals_return_from_proc_raise() {
+        let host = WasiHostBuilder::<String>::new().build().unwrap();
+
+        host.set_signal_handler(Signal::Term, |_| SignalAction::Ignore);
+        assert_eq!(
+            host.clone().run_binary(&raising(Signal::Term)).unwrap(),
+            RunOutcome::Exited(0)
+        );
+        assert_eq!(
+            host.clone().run_binary(&raising(Signal::Chld)).unwrap(),
+            RunOutcome::Exited(0)
+        );
+
+        host.reset_signal_handler(Signal::Term);
+        assert_eq!(
+            host.run_binary(&raising(Signal::Term)).unwrap(),
+            RunOutcome::Signaled(Signal::Term)
+        );
+    }
+}
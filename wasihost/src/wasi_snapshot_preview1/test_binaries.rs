@@ -0,0 +1,120 @@
+//! Small WASM binaries assembled by hand for the tests.
+
+use super::leb128;
+
+/// Assembles a binary from its sections, given by id and content.
+pub(super) fn module(sections: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut binary = b"\0asm\x01\0\0\0".to_vec();
+
+    for &(id, content) in sections {
+        binary.push(id);
+        leb128::write_u32(&mut binary, content.len() as u32);
+        binary.extend_from_slice(content);
+    }
+    binary
+}
+
+fn code_section(code: &[u8]) -> Vec<u8> {
+    // One function without locals.
+    let mut body = vec![0];
+    body.extend_from_slice(code);
+    body.push(0x0b);
+
+    let mut section = vec![1];
+    leb128::write_u32(&mut section, body.len() as u32);
+    section.extend_from_slice(&body);
+    section
+}
+
+/// A binary that runs `code` in `_start` without importing anything.
+pub(super) fn computing(code: &[u8]) -> Vec<u8> {
+    module(&[
+        (1, &[1, 0x60, 0, 0]),
+        (3, &[1, 0]),
+        (7, b"\x01\x06_start\x00\x00"),
+        (10, &code_section(code)),
+    ])
+}
+
+/// A binary that returns from `_start` right away.
+pub(super) fn returning() -> Vec<u8> {
+    computing(&[])
+}
+
+/// A binary that loops forever without making system calls.
+pub(super) fn looping() -> Vec<u8> {
+    // loop, br 0, end
+    computing(&[0x03, 0x40, 0x0c, 0, 0x0b])
+}
+
+/// A binary that imports the WASI function `function`, which takes `params` 32 bit integers,
+/// and runs `code` in `_start`. Its page of memory starts with `data`.
+pub(super) fn calling(function: &str, params: u8, code: &[u8], data: &[u8]) -> Vec<u8> {
+    importing(function, params, &[0x7f], code, data)
+}
+
+fn importing(function: &str, params: u8, results: &[u8], code: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut types = vec![2, 0x60, params];
+    types.extend((0..params).map(|_| 0x7f));
+    types.push(results.len() as u8);
+    types.extend_from_slice(results);
+    types.extend_from_slice(&[0x60, 0, 0]);
+
+    let mut import = vec![1, 22];
+    import.extend_from_slice(b"wasi_snapshot_preview1");
+    leb128::write_u32(&mut import, function.len() as u32);
+    import.extend_from_slice(function.as_bytes());
+    import.extend_from_slice(&[0, 0]);
+
+    // An active segment at address 0.
+    let mut segment = vec![1, 0, 0x41, 0, 0x0b];
+    leb128::write_u32(&mut segment, data.len() as u32);
+    segment.extend_from_slice(data);
+
+    module(&[
+        (1, &types),
+        (2, &import),
+        (3, &[1, 1]),
+        (5, &[1, 0, 1]),
+        (7, b"\x02\x06memory\x02\x00\x06_start\x00\x01"),
+        (10, &code_section(code)),
+        (11, &segment),
+    ])
+}
+
+/// A binary that calls `function` with the `arguments` and stores the error it returns at
+/// address 0.
+pub(super) fn calling_with(function: &str, arguments: &[i32], data: &[u8]) -> Vec<u8> {
+    let mut code = vec![0x41, 0];
+    for &argument in arguments {
+        code.push(0x41);
+        write_sleb128(&mut code, argument);
+    }
+    // call 0, i32.store
+    code.extend_from_slice(&[0x10, 0, 0x36, 2, 0]);
+
+    calling(function, arguments.len() as u8, &code, data)
+}
+
+/// A binary that exits with `code` by calling `proc_exit`.
+pub(super) fn exiting(code: i32) -> Vec<u8> {
+    let mut instructions = vec![0x41];
+    write_sleb128(&mut instructions, code);
+    // call 0
+    instructions.extend_from_slice(&[0x10, 0]);
+
+    importing("proc_exit", 1, &[], &instructions, &[])
+}
+
+fn write_sleb128(buffer: &mut Vec<u8>, mut value: i32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            buffer.push(byte);
+            return;
+        }
+        buffer.push(byte | 0x80);
+    }
+}
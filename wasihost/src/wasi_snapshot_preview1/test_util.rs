@@ -0,0 +1,26 @@
+//! Helpers shared by the tests.
+
+use super::wasi_fd;
+use parking_lot::Mutex;
+use std::{future::Future, io, sync::Arc};
+
+/// A writer whose output can still be read once it has been handed to a WASI host.
+#[derive(Clone, Default)]
+pub(super) struct SharedBuffer(pub(super) Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Polls `future` until it completes, parking the current thread in between.
+pub(super) fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+
+    wasi_fd::block_on(|cx| future.as_mut().poll(cx))
+}
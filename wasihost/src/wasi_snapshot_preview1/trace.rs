@@ -0,0 +1,353 @@
+use super::observe::{Argument, CallObserver, ObservedImports, Outcome, Returned};
+use parking_lot::Mutex;
+use std::{
+    fmt,
+    io::{IoSlice, IoSliceMut, Write},
+    sync::Arc,
+    time::Duration,
+};
+use wasihost_core::{
+    wasi_snapshot_preview1::{Errno, WasiImports},
+    StringRepresentation,
+};
+
+/// The number of bytes of written data and strings shown in a trace.
+const MAX_SHOWN_BYTES: usize = 32;
+
+/// A system call made by a WASI binary, as reported to a [`TraceSink`](trait.TraceSink.html).
+///
+/// It is displayed like `fd_write(fd: Fd(1), bufs: "hello\n") -> Ok(Size(6)) (14.2µs)`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct TraceEvent<'a> {
+    /// The name of the WASI function.
+    pub function: &'static str,
+    /// The names and decoded values of the arguments.
+    pub arguments: &'a [(&'static str, String)],
+    /// The decoded result, `Stopped` if the call ended the run instead of returning.
+    pub result: &'a str,
+    /// The error the call failed with.
+    pub errno: Option<Errno>,
+    /// How long the call took.
+    pub duration: Duration,
+}
+
+impl fmt::Display for TraceEvent<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", self.function)?;
+
+        for (i, (name, value)) in self.arguments.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}: {}", name, value)?;
+        }
+
+        write!(f, ") -> {} ({:?})", self.result, self.duration)
+    }
+}
+
+/// Receives the system calls traced by [`TracingImports`](type.TracingImports.html).
+pub trait TraceSink: Send + Sync + 'static {
+    /// Records a system call after it returned.
+    fn record(&self, event: &TraceEvent<'_>);
+}
+
+impl<F: Fn(&TraceEvent<'_>) + Send + Sync + 'static> TraceSink for F {
+    fn record(&self, event: &TraceEvent<'_>) {
+        self(event)
+    }
+}
+
+/// A trace sink that writes one line per system call, for example to standard error or a file.
+pub struct TraceWriter<W> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send + 'static> TraceWriter<W> {
+    /// Writes the trace to `writer`.
+    pub fn new(writer: W) -> Self {
+        TraceWriter {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> TraceSink for TraceWriter<W> {
+    fn record(&self, event: &TraceEvent<'_>) {
+        // Tracing must not change the behavior of the WASI binary, so errors are ignored.
+        let _ = writeln!(self.writer.lock(), "{}", event);
+    }
+}
+
+impl<W> fmt::Debug for TraceWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TraceWriter").finish()
+    }
+}
+
+/// Reports the system calls of a WASI host or of
+/// [`TracingImports`](type.TracingImports.html) to a [`TraceSink`](trait.TraceSink.html).
+#[derive(Clone)]
+pub struct Tracer(pub(super) Arc<dyn TraceSink>);
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer").finish()
+    }
+}
+
+impl CallObserver for Tracer {
+    type Call = Vec<(&'static str, String)>;
+
+    fn start(
+        &self,
+        _function: &'static str,
+        arguments: &[(&'static str, Argument<'_>)],
+    ) -> Self::Call {
+        arguments
+            .iter()
+            .map(|(name, argument)| (*name, self::argument(argument)))
+            .collect()
+    }
+
+    fn finish<S: StringRepresentation>(
+        &self,
+        function: &'static str,
+        arguments: Self::Call,
+        outcome: &Outcome<'_, S>,
+        duration: Duration,
+    ) {
+        let (result, errno) = match outcome {
+            Outcome::Returned(returned) => (format!("Ok({})", self::returned(returned)), None),
+            Outcome::Failed(errno) => (format!("Err({:?})", errno), Some(*errno)),
+            Outcome::Exited(code) => (format!("Exit({:?})", code), None),
+            Outcome::Stopped(_) => ("Stopped".to_string(), None),
+        };
+
+        self.0.record(&TraceEvent {
+            function,
+            arguments: &arguments,
+            result: &result,
+            errno,
+            duration,
+        });
+    }
+}
+
+fn bytes<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> String {
+    let bytes: Vec<u8> = bytes
+        .into_iter()
+        .copied()
+        .take(MAX_SHOWN_BYTES + 1)
+        .collect();
+
+    if bytes.len() > MAX_SHOWN_BYTES {
+        format!(
+            "{:?}...",
+            String::from_utf8_lossy(&bytes[..MAX_SHOWN_BYTES])
+        )
+    } else {
+        format!("{:?}", String::from_utf8_lossy(&bytes))
+    }
+}
+
+fn bufs(bufs: &[IoSlice<'_>]) -> String {
+    bytes(bufs.iter().flat_map(|buf| buf.iter()))
+}
+
+fn iovs(iovs: &[IoSliceMut<'_>]) -> String {
+    let len: usize = iovs.iter().map(|iov| iov.len()).sum();
+    format!("[{} bytes]", len)
+}
+
+fn strings<S: StringRepresentation>(strings: &[S]) -> String {
+    let strings: Vec<_> = strings
+        .iter()
+        .map(|string| bytes(string.as_bytes()))
+        .collect();
+    format!("[{}]", strings.join(", "))
+}
+
+/// Decodes an argument into flags, rights and strings. Written data and strings are shortened,
+/// read buffers are shown by size.
+fn argument(argument: &Argument<'_>) -> String {
+    match argument {
+        Argument::Value(value) => format!("{:?}", value),
+        Argument::Data(data) => bufs(data),
+        Argument::Buffers(buffers) => iovs(buffers),
+        Argument::Buffer(len) => format!("[{} bytes]", len),
+        Argument::String(string) => bytes(*string),
+        Argument::Subscriptions(subscriptions) => format!("{:?}", subscriptions),
+    }
+}
+
+fn returned<S: StringRepresentation>(returned: &Returned<'_, S>) -> String {
+    match returned {
+        Returned::Unit | Returned::Random(_) => "()".to_string(),
+        Returned::Value(value) => format!("{:?}", value),
+        Returned::Strings(strings) => self::strings(strings),
+        Returned::String(string) => bytes(string.as_bytes()),
+        Returned::Read {
+            size,
+            flags: Some(flags),
+            ..
+        } => format!("({:?}, {:?})", size, flags),
+        Returned::Read { size, .. } | Returned::Written { size, .. } => format!("{:?}", size),
+        Returned::Dirent(Some((dirent, name))) => {
+            format!("Some(({:?}, {}))", dirent, bytes(name.as_bytes()))
+        }
+        Returned::Dirent(None) => "None".to_string(),
+        Returned::Events(events) => format!("{:?}", events),
+    }
+}
+
+/// Wraps WASI imports and reports every system call to a [`TraceSink`](trait.TraceSink.html),
+/// like `strace`.
+///
+/// Arguments are decoded into flags, rights and strings. Written data and strings are
+/// shortened, read buffers are shown by size.
+pub type TracingImports<I> = ObservedImports<I, Tracer>;
+
+impl<I: WasiImports> ObservedImports<I, Tracer> {
+    /// Traces the system calls handled by `inner` to `sink`.
+    pub fn new(inner: Arc<I>, sink: impl TraceSink) -> Self {
+        Self::with_observer(inner, Tracer(Arc::new(sink)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasi_snapshot_preview1::{
+        test_binaries::{calling_with, exiting},
+        test_util::SharedBuffer,
+        RunOutcome, WasiHostBuilder,
+    };
+    use wasihost_core::wasi_snapshot_preview1::Signal;
+
+    type Events = Arc<Mutex<Vec<(String, Option<Errno>)>>>;
+
+    /// A trace sink that keeps the traced calls and their errors.
+    fn collecting() -> (Events, impl TraceSink) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = {
+            let events = events.clone();
+            move |event: &TraceEvent<'_>| {
+                events.lock().push((event.to_string(), event.errno));
+            }
+        };
+        (events, sink)
+    }
+
+    fn without_duration(line: &str) -> &str {
+        &line[..line.rfind(" (").unwrap()]
+    }
+
+    #[test]
+    fn events_are_displayed_like_strace() {
+        let arguments = vec![("fd", "Fd(1)".to_string()), ("bufs", bytes(b"hello\n"))];
+        let event = TraceEvent {
+            function: "fd_write",
+            arguments: &arguments,
+            result: "Ok(Size(6))",
+            errno: None,
+            duration: Duration::from_micros(14),
+        };
+
+        assert_eq!(
+            event.to_string(),
+            r#"fd_write(fd: Fd(1), bufs: "hello\n") -> Ok(Size(6)) (14µs)"#
+        );
+    }
+
+    #[test]
+    fn long_data_is_shortened() {
+        let data = [b'a'; MAX_SHOWN_BYTES + 1];
+
+        assert_eq!(
+            bytes(&data[..MAX_SHOWN_BYTES]),
+            format!("{:?}", "a".repeat(32))
+        );
+        assert_eq!(bytes(&data[..]), format!("{:?}...", "a".repeat(32)));
+        assert_eq!(
+            iovs(&[IoSliceMut::new(&mut [0; 3]), IoSliceMut::new(&mut [0; 4])]),
+            "[7 bytes]"
+        );
+    }
+
+    #[test]
+    fn writers_get_one_line_per_call() {
+        let buffer = SharedBuffer::default();
+        let host = WasiHostBuilder::<String>::new()
+            .trace_sink(TraceWriter::new(buffer.clone()))
+            .build()
+            .unwrap();
+
+        host.clone()
+            .run_binary(&calling_with("fd_close", &[99], &[]))
+            .unwrap();
+        host.clone()
+            .run_binary(&calling_with("sched_yield", &[], &[]))
+            .unwrap();
+
+        let output = String::from_utf8(buffer.0.lock().clone()).unwrap();
+        let lines: Vec<_> = output.lines().map(without_duration).collect();
+        assert_eq!(
+            lines,
+            [
+                "fd_close(fd: Fd(99)) -> Err(Badf)",
+                "sched_yield() -> Ok(())"
+            ]
+        );
+    }
+
+    #[test]
+    fn calls_ending_the_run_are_traced() {
+        let (events, sink) = collecting();
+        let host = WasiHostBuilder::<String>::new()
+            .trace_sink(sink)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            host.clone().run_binary(&exiting(3)).unwrap(),
+            RunOutcome::Exited(3)
+        );
+        assert_eq!(
+            host.clone()
+                .run_binary(&calling_with("proc_raise", &[Signal::Term as i32], &[]))
+                .unwrap(),
+            RunOutcome::Signaled(Signal::Term)
+        );
+
+        let events = events.lock();
+        let lines: Vec<_> = events
+            .iter()
+            .map(|(line, errno)| (without_duration(line), *errno))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                ("proc_exit(rval: Exitcode(3)) -> Exit(Exitcode(3))", None),
+                ("proc_raise(sig: Term) -> Stopped", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn trace_sinks_can_be_replaced() {
+        let host = WasiHostBuilder::<String>::new().build().unwrap();
+        let binary = calling_with("fd_close", &[99], &[]);
+        let (first, sink) = collecting();
+
+        host.clone().run_binary(&binary).unwrap();
+        host.set_trace_sink(sink);
+        host.clone().run_binary(&binary).unwrap();
+        host.remove_trace_sink();
+        host.clone().run_binary(&binary).unwrap();
+
+        assert_eq!(first.lock().len(), 1);
+        assert_eq!(first.lock()[0].1, Some(Errno::Badf));
+    }
+}
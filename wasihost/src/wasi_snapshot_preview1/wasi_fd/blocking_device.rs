@@ -0,0 +1,254 @@
+use super::{super::cancel, pollable, CharacterDevice, Pollable};
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    io::{IoSlice, IoSliceMut},
+    ptr,
+    sync::Arc,
+    task::{Context, Poll, RawWaker, RawWakerVTable, Wake, Waker},
+    thread::{self, Thread},
+};
+use wasihost_core::wasi_snapshot_preview1::{
+    EventFdReadwrite, Eventrwflags, Filesize, Size, WasiResult,
+};
+
+/// The size of the buffer filled when `poll_oneoff` checks whether a polled device is
+/// readable.
+const POLL_BUFFER_SIZE: usize = 4096;
+
+/// Describes a character device that is polled for reads and writes, for example a connection
+/// or channel of an async runtime.
+///
+/// It is installed as a file descriptor by wrapping it in a
+/// [`BlockingDevice`](struct.BlockingDevice.html), which blocks the WASI binary until the device
+/// is ready.
+pub trait PollingCharacterDevice: Debug + Send + Sync + 'static {
+    /// Attempts to read data into `buf`. Returns `Poll::Pending` and wakes the task of `cx` once
+    /// data is available. Reading 0 bytes into a non-empty buffer signals end of file.
+    fn poll_read(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<WasiResult<Size>>;
+
+    /// Attempts to write data from `buf`. Returns `Poll::Pending` and wakes the task of `cx`
+    /// once data can be written.
+    fn poll_write(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<WasiResult<Size>>;
+}
+
+/// Presents a [`PollingCharacterDevice`](trait.PollingCharacterDevice.html) as a blocking
+/// character device.
+///
+/// Reads and writes of the WASI binary park the thread it runs on until the device is ready,
+/// so runs using it are best handed to an [`Executor`](trait.Executor.html) that may block, with
+/// [`WasiHost::run_module_on`](struct.WasiHost.html#method.run_module_on). `poll_oneoff` does
+/// not wait, it checks readability by reading ahead into a buffer.
+#[derive(Debug)]
+pub struct BlockingDevice<D> {
+    device: D,
+    read_ahead: Mutex<VecDeque<u8>>,
+}
+
+impl<D: PollingCharacterDevice> BlockingDevice<D> {
+    /// Wraps `device`.
+    pub fn new(device: D) -> Self {
+        BlockingDevice {
+            device,
+            read_ahead: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Returns the wrapped device.
+    pub fn get_ref(&self) -> &D {
+        &self.device
+    }
+}
+
+impl<D: PollingCharacterDevice> CharacterDevice for BlockingDevice<D> {
+    fn read(&self, iovs: &mut [IoSliceMut<'_>]) -> WasiResult<Size> {
+        {
+            let mut read_ahead = self.read_ahead.lock();
+
+            if !read_ahead.is_empty() {
+                let mut total = 0;
+
+                for iov in iovs.iter_mut() {
+                    let len = iov.len().min(read_ahead.len());
+
+                    for (byte, buffered) in iov.iter_mut().zip(read_ahead.drain(..len)) {
+                        *byte = buffered;
+                    }
+                    total += len;
+                }
+
+                return Ok(Size(total as u32));
+            }
+        }
+
+        match iovs.iter_mut().find(|iov| !iov.is_empty()) {
+            Some(iov) => block_on(|cx| self.device.poll_read(cx, iov)),
+            None => Ok(Size(0)),
+        }
+    }
+
+    fn write(&self, bufs: &[IoSlice<'_>]) -> WasiResult<Size> {
+        match bufs.iter().find(|buf| !buf.is_empty()) {
+            Some(buf) => block_on(|cx| self.device.poll_write(cx, buf)),
+            None => Ok(Size(0)),
+        }
+    }
+}
+
+impl<D: PollingCharacterDevice> Pollable for BlockingDevice<D> {
+    fn poll_read(&self) -> WasiResult<Option<EventFdReadwrite>> {
+        let mut read_ahead = self.read_ahead.lock();
+
+        if !read_ahead.is_empty() {
+            return Ok(Some(pollable::ready(read_ahead.len() as u64)));
+        }
+
+        // `poll_oneoff` checks again on its own, so the device does not need to wake anyone.
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut buf = [0; POLL_BUFFER_SIZE];
+
+        match self.device.poll_read(&mut cx, &mut buf) {
+            Poll::Ready(Ok(Size(0))) => Ok(Some(EventFdReadwrite {
+                nbytes: Filesize(0),
+                flags: Eventrwflags::FD_READWRITE_HANGUP,
+            })),
+            Poll::Ready(Ok(Size(len))) => {
+                read_ahead.extend(&buf[..len as usize]);
+                Ok(Some(pollable::ready(u64::from(len))))
+            }
+            Poll::Ready(Err(errno)) => Err(errno),
+            Poll::Pending => Ok(None),
+        }
+    }
+}
+
+/// Returns a waker that does nothing, like `Waker::noop`, which requires Rust 1.85.
+fn noop_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(ptr::null(), &VTABLE)
+    }
+    fn noop(_: *const ()) {}
+    const VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+    // The functions of the vtable ignore the data pointer, so any pointer is valid.
+    unsafe { Waker::from_raw(RawWaker::new(ptr::null(), &VTABLE)) }
+}
+
+/// Wakes a thread that waits in [`block_on`](fn.block_on.html).
+struct Unparker(Thread);
+
+impl Wake for Unparker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Polls until `poll` is ready, parking the current thread in between. Stops the run of the
+/// thread if it is interrupted meanwhile.
+pub(crate) fn block_on<T>(mut poll: impl FnMut(&mut Context<'_>) -> Poll<T>) -> T {
+    let waker = Waker::from(Arc::new(Unparker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(value) = poll(&mut cx) {
+            return value;
+        }
+        cancel::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// A device that becomes readable once `data` is filled from another thread, and accepts
+    /// at most two bytes per write.
+    #[derive(Debug, Default)]
+    struct Channel {
+        data: Mutex<Vec<u8>>,
+        waker: Mutex<Option<Waker>>,
+    }
+
+    impl Channel {
+        fn send(&self, data: &[u8]) {
+            self.data.lock().extend_from_slice(data);
+            if let Some(waker) = self.waker.lock().take() {
+                waker.wake();
+            }
+        }
+    }
+
+    impl PollingCharacterDevice for Channel {
+        fn poll_read(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<WasiResult<Size>> {
+            let mut data = self.data.lock();
+
+            if data.is_empty() {
+                *self.waker.lock() = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+
+            let len = buf.len().min(data.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            data.drain(..len);
+            Poll::Ready(Ok(Size(len as u32)))
+        }
+
+        fn poll_write(&self, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<WasiResult<Size>> {
+            Poll::Ready(Ok(Size(buf.len().min(2) as u32)))
+        }
+    }
+
+    #[test]
+    fn reads_wait_for_the_device() {
+        let device = Arc::new(BlockingDevice::new(Channel::default()));
+        let sender = {
+            let device = device.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                device.get_ref().send(b"hello");
+            })
+        };
+        let mut buf = [0; 8];
+
+        assert_eq!(
+            device.read(&mut [IoSliceMut::new(&mut buf)]).unwrap(),
+            Size(5)
+        );
+        assert_eq!(&buf[..5], b"hello");
+        sender.join().unwrap();
+
+        assert_eq!(
+            device
+                .write(&[IoSlice::new(b""), IoSlice::new(b"abc")])
+                .unwrap(),
+            Size(2)
+        );
+    }
+
+    #[test]
+    fn polling_reads_ahead() {
+        let device = BlockingDevice::new(Channel::default());
+
+        assert!(Pollable::poll_read(&device).unwrap().is_none());
+
+        device.get_ref().send(b"abc");
+        let ready = Pollable::poll_read(&device).unwrap().unwrap();
+        assert_eq!(ready.nbytes, Filesize(3));
+        // The data read ahead is not lost.
+        assert!(device.get_ref().data.lock().is_empty());
+
+        let mut buf = [0; 2];
+        assert_eq!(
+            device.read(&mut [IoSliceMut::new(&mut buf)]).unwrap(),
+            Size(2)
+        );
+        assert_eq!(&buf, b"ab");
+        assert_eq!(
+            Pollable::poll_read(&device).unwrap().unwrap().nbytes,
+            Filesize(1)
+        );
+    }
+}
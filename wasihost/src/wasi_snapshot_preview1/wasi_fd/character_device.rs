@@ -1,11 +1,16 @@
+use super::{super::cancel, Pollable};
+use crate::os;
+use parking_lot::Mutex;
 use std::{
-    fmt::Debug,
-    io::{stderr, stdin, stdout, IoSlice, IoSliceMut, Read, Write},
+    fmt::{self, Debug},
+    io::{stderr, stdin, stdout, Cursor, IoSlice, IoSliceMut, Read, Write},
+    sync::Arc,
 };
-use wasihost_core::wasi_snapshot_preview1::{Size, WasiResult};
+use wasihost_core::wasi_snapshot_preview1::{EventFdReadwrite, Size, WasiResult};
 
-/// Describes a character device.
-pub trait CharacterDevice: Debug + Send + Sync + 'static {
+/// Describes a character device. `poll_oneoff` checks its readiness through
+/// [`Pollable`](trait.Pollable.html).
+pub trait CharacterDevice: Pollable + Debug + Send + Sync + 'static {
     /// Reads data from the character device into `iovs`.
     fn read(&self, iovs: &mut [IoSliceMut<'_>]) -> WasiResult<Size>;
     /// Writes data from `bufs` to the character device.
@@ -18,7 +23,7 @@ pub struct Stdin;
 
 impl CharacterDevice for Stdin {
     fn read(&self, iovs: &mut [IoSliceMut<'_>]) -> WasiResult<Size> {
-        Ok(stdin().lock().read_vectored(iovs).map(|s| Size(s as u32))?)
+        os::read_stdin(iovs, cancel::interruption_point).map(|s| Size(s as u32))
     }
 
     fn write(&self, _bufs: &[IoSlice<'_>]) -> WasiResult<Size> {
@@ -26,6 +31,12 @@ impl CharacterDevice for Stdin {
     }
 }
 
+impl Pollable for Stdin {
+    fn poll_read(&self) -> WasiResult<Option<EventFdReadwrite>> {
+        os::read_readiness(&stdin())
+    }
+}
+
 /// The host's standard output.
 #[derive(Debug)]
 pub struct Stdout;
@@ -43,6 +54,8 @@ impl CharacterDevice for Stdout {
     }
 }
 
+impl Pollable for Stdout {}
+
 /// The host's standard error.
 #[derive(Debug)]
 pub struct Stderr;
@@ -59,3 +72,131 @@ impl CharacterDevice for Stderr {
             .map(|s| Size(s as u32))?)
     }
 }
+
+impl Pollable for Stderr {}
+
+/// A character device that reads from an in-memory buffer or any other reader, for example to
+/// provide standard input to a WASI binary.
+pub struct ReaderDevice {
+    reader: Mutex<Box<dyn Read + Send>>,
+}
+
+impl ReaderDevice {
+    /// Creates a character device that reads from `reader`.
+    pub fn new(reader: impl Read + Send + 'static) -> Self {
+        ReaderDevice {
+            reader: Mutex::new(Box::new(reader)),
+        }
+    }
+
+    /// Creates a character device that reads `bytes` and then reports end of file.
+    pub fn from_bytes(bytes: impl Into<Vec<u8>>) -> Self {
+        Self::new(Cursor::new(bytes.into()))
+    }
+}
+
+impl Debug for ReaderDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReaderDevice").finish()
+    }
+}
+
+impl CharacterDevice for ReaderDevice {
+    fn read(&self, iovs: &mut [IoSliceMut<'_>]) -> WasiResult<Size> {
+        Ok(Size(self.reader.lock().read_vectored(iovs)? as u32))
+    }
+
+    fn write(&self, _bufs: &[IoSlice<'_>]) -> WasiResult<Size> {
+        Ok(Size(0))
+    }
+}
+
+impl Pollable for ReaderDevice {}
+
+/// A character device that captures everything written to it in a shared buffer, for example
+/// to inspect the standard output of a WASI binary after it has finished.
+///
+/// Clones share the same buffer, so a clone can be kept to read the captured data.
+#[derive(Clone, Debug, Default)]
+pub struct CaptureDevice {
+    buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl CaptureDevice {
+    /// Creates a character device with an empty buffer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of the data captured so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.buffer.lock().clone()
+    }
+
+    /// Returns the data captured so far and empties the buffer.
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.buffer.lock())
+    }
+}
+
+impl CharacterDevice for CaptureDevice {
+    fn read(&self, _iovs: &mut [IoSliceMut<'_>]) -> WasiResult<Size> {
+        Ok(Size(0))
+    }
+
+    fn write(&self, bufs: &[IoSlice<'_>]) -> WasiResult<Size> {
+        let mut buffer = self.buffer.lock();
+        let len = buffer.len();
+
+        for buf in bufs {
+            buffer.extend_from_slice(buf);
+        }
+
+        Ok(Size((buffer.len() - len) as u32))
+    }
+}
+
+impl Pollable for CaptureDevice {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reader_devices_read_until_the_end() {
+        let device = ReaderDevice::from_bytes("hello world");
+        let (mut a, mut b) = ([0; 6], [0; 8]);
+
+        let read = device
+            .read(&mut [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)])
+            .unwrap();
+
+        assert_eq!(read, Size(11));
+        assert_eq!(&a, b"hello ");
+        assert_eq!(&b[..5], b"world");
+        assert_eq!(
+            device.read(&mut [IoSliceMut::new(&mut a)]).unwrap(),
+            Size(0)
+        );
+        assert_eq!(device.write(&[IoSlice::new(b"ignored")]).unwrap(), Size(0));
+    }
+
+    #[test]
+    fn capture_devices_share_their_buffer() {
+        let device = CaptureDevice::new();
+        let clone = device.clone();
+
+        let written = device
+            .write(&[IoSlice::new(b"hello "), IoSlice::new(b"world")])
+            .unwrap();
+
+        assert_eq!(written, Size(11));
+        assert_eq!(clone.contents(), b"hello world");
+        assert_eq!(clone.take(), b"hello world");
+        assert_eq!(device.contents(), b"");
+        assert_eq!(
+            device.read(&mut [IoSliceMut::new(&mut [0; 4])]).unwrap(),
+            Size(0)
+        );
+    }
+}
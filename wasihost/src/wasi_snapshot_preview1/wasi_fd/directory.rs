@@ -0,0 +1,596 @@
+use super::WasiFile;
+use crate::os;
+use parking_lot::Mutex;
+use std::{any::Any, fmt::Debug, io, path::Path, sync::Arc};
+use wasihost_core::wasi_snapshot_preview1::{
+    Dircookie, Dirent, Dirnamlen, Errno, Fdflags, Filestat, Filetype, Fstflags, Inode, Lookupflags,
+    Oflags, Rights, Timestamp, WasiResult,
+};
+
+/// Describes a directory. Paths are passed as raw bytes and are relative to the directory.
+/// Implementations are responsible for not resolving paths outside of the directory tree
+/// they expose.
+///
+/// Operations that are not required to browse and open files default to returning
+/// `Errno::Notsup`.
+pub trait WasiDirectory: Debug + Send + Sync + 'static {
+    /// Returns `self`, so that [`path_link`](#method.path_link) and
+    /// [`path_rename`](#method.path_rename) can recognize directories of their own type.
+    fn as_any(&self) -> &dyn Any;
+
+    /// Returns the attributes of the directory.
+    fn filestat_get(&self) -> WasiResult<Filestat>;
+
+    /// Sets the timestamps of the directory.
+    fn filestat_set_times(
+        &self,
+        _atim: Timestamp,
+        _mtim: Timestamp,
+        _fst_flags: Fstflags,
+    ) -> WasiResult<()> {
+        Err(Errno::Notsup)
+    }
+
+    /// Returns the directory entry at position `cookie` and its name, or `None` at the end of
+    /// the directory. The first two entries are `.` and `..`.
+    fn readdir(&self, cookie: Dircookie) -> WasiResult<Option<(Dirent, Vec<u8>)>>;
+
+    /// Creates the directory `path`.
+    fn path_create_directory(&self, _path: &[u8]) -> WasiResult<()> {
+        Err(Errno::Notsup)
+    }
+
+    /// Returns the attributes of `path`.
+    fn path_filestat_get(&self, flags: Lookupflags, path: &[u8]) -> WasiResult<Filestat>;
+
+    /// Sets the timestamps of `path`.
+    fn path_filestat_set_times(
+        &self,
+        _flags: Lookupflags,
+        _path: &[u8],
+        _atim: Timestamp,
+        _mtim: Timestamp,
+        _fst_flags: Fstflags,
+    ) -> WasiResult<()> {
+        Err(Errno::Notsup)
+    }
+
+    /// Creates a hard link to `old_path` at `new_path` in `new_dir`. Should fail with
+    /// `Errno::Xdev` if `new_dir` belongs to a different file system.
+    fn path_link(
+        &self,
+        _old_flags: Lookupflags,
+        _old_path: &[u8],
+        _new_dir: &dyn WasiDirectory,
+        _new_path: &[u8],
+    ) -> WasiResult<()> {
+        Err(Errno::Notsup)
+    }
+
+    /// Opens `path`. `rights` are the rights the new file descriptor is going to have and
+    /// can be used to decide how to open a file.
+    fn path_open(
+        &self,
+        dirflags: Lookupflags,
+        path: &[u8],
+        oflags: Oflags,
+        rights: Rights,
+        fdflags: Fdflags,
+    ) -> WasiResult<Opened>;
+
+    /// Returns the target of the symbolic link `path`.
+    fn path_readlink(&self, _path: &[u8]) -> WasiResult<Vec<u8>> {
+        Err(Errno::Notsup)
+    }
+
+    /// Removes the empty directory `path`.
+    fn path_remove_directory(&self, _path: &[u8]) -> WasiResult<()> {
+        Err(Errno::Notsup)
+    }
+
+    /// Moves `old_path` to `new_path` in `new_dir`. Should fail with `Errno::Xdev` if
+    /// `new_dir` belongs to a different file system.
+    fn path_rename(
+        &self,
+        _old_path: &[u8],
+        _new_dir: &dyn WasiDirectory,
+        _new_path: &[u8],
+    ) -> WasiResult<()> {
+        Err(Errno::Notsup)
+    }
+
+    /// Creates a symbolic link at `new_path` that points to `old_path`.
+    fn path_symlink(&self, _old_path: &[u8], _new_path: &[u8]) -> WasiResult<()> {
+        Err(Errno::Notsup)
+    }
+
+    /// Removes the file or symbolic link `path`.
+    fn path_unlink_file(&self, _path: &[u8]) -> WasiResult<()> {
+        Err(Errno::Notsup)
+    }
+}
+
+/// The result of opening a path relative to a [`WasiDirectory`](trait.WasiDirectory.html).
+#[derive(Debug)]
+pub enum Opened {
+    /// A directory was opened.
+    Directory(Box<dyn WasiDirectory>),
+    /// A file was opened.
+    File(Box<dyn WasiFile>),
+}
+
+/// A directory on the host file system. All paths are resolved relative to this
+/// directory and are not allowed to leave the preopened root directory.
+///
+/// Paths are resolved one component at a time, starting from directories that are held open,
+/// without following symbolic links on the host. Symbolic links are resolved by the directory
+/// itself, so moving directories or swapping in symbolic links while a path is resolved can not
+/// lead outside the root.
+#[derive(Debug)]
+pub(super) struct Directory {
+    /// The directories from the root down to the parent of this directory.
+    ancestors: Vec<Arc<os::DirHandle>>,
+    handle: Arc<os::DirHandle>,
+    entries: Mutex<Vec<DirectoryEntry>>,
+}
+
+#[derive(Debug)]
+struct DirectoryEntry {
+    name: Vec<u8>,
+    filetype: Filetype,
+    inode: Inode,
+}
+
+/// A path resolved relative to a [`Directory`](struct.Directory.html).
+struct Resolved {
+    ancestors: Vec<Arc<os::DirHandle>>,
+    directory: Arc<os::DirHandle>,
+    /// The last component of the path, or `None` if the path names `directory` itself.
+    name: Option<Vec<u8>>,
+}
+
+/// The number of symbolic links that are followed while resolving a single path.
+const MAX_SYMLINK_EXPANSIONS: usize = 40;
+
+/// Pushes the components of `path` onto `components` in reverse order, so that they can be
+/// popped in order.
+fn push_components(components: &mut Vec<Vec<u8>>, path: &[u8]) {
+    components.extend(
+        path.split(|&byte| byte == b'/')
+            .filter(|component| !component.is_empty())
+            .rev()
+            .map(<[u8]>::to_vec),
+    );
+}
+
+impl Directory {
+    /// Opens a host directory as a new root directory.
+    pub(super) fn open_root(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(Vec::new(), Arc::new(os::DirHandle::open(path)?)))
+    }
+
+    fn new(ancestors: Vec<Arc<os::DirHandle>>, handle: Arc<os::DirHandle>) -> Self {
+        Directory {
+            ancestors,
+            handle,
+            entries: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Resolves `path` relative to this directory. All components but the last one are
+    /// resolved and must not leave the root directory. The last component is only resolved if
+    /// it is a symbolic link and `follow_symlinks` is set.
+    fn resolve(&self, path: &[u8], follow_symlinks: bool) -> WasiResult<Resolved> {
+        if path.is_empty() {
+            return Err(Errno::Noent);
+        }
+        if path[0] == b'/' {
+            return Err(Errno::Notcapable);
+        }
+
+        let mut ancestors = self.ancestors.clone();
+        let mut directory = self.handle.clone();
+        let mut components = Vec::new();
+        let mut expansions = 0;
+        push_components(&mut components, path);
+
+        while let Some(component) = components.pop() {
+            let is_last = components.is_empty();
+
+            match component.as_slice() {
+                b"." => {}
+                b".." => directory = ancestors.pop().ok_or(Errno::Notcapable)?,
+                name => {
+                    let filetype = match directory.stat_at(name) {
+                        Ok(filestat) => filestat.filetype,
+                        Err(Errno::Noent) if is_last => Filetype::Unknown,
+                        Err(errno) => return Err(errno),
+                    };
+
+                    if filetype == Filetype::SymbolicLink && (!is_last || follow_symlinks) {
+                        expansions += 1;
+                        if expansions > MAX_SYMLINK_EXPANSIONS {
+                            return Err(Errno::Loop);
+                        }
+
+                        let target = directory.read_link_at(name)?;
+                        if target.first() == Some(&b'/') {
+                            return Err(Errno::Notcapable);
+                        }
+
+                        push_components(&mut components, &target);
+                    } else if is_last {
+                        return Ok(Resolved {
+                            ancestors,
+                            directory,
+                            name: Some(component),
+                        });
+                    } else {
+                        let child = Arc::new(directory.open_dir_at(name)?);
+                        ancestors.push(directory);
+                        directory = child;
+                    }
+                }
+            }
+        }
+
+        Ok(Resolved {
+            ancestors,
+            directory,
+            name: None,
+        })
+    }
+
+    fn read_entries(&self) -> WasiResult<Vec<DirectoryEntry>> {
+        fn special_entry(name: &[u8], directory: &os::DirHandle) -> WasiResult<DirectoryEntry> {
+            Ok(DirectoryEntry {
+                name: name.to_vec(),
+                filetype: Filetype::Directory,
+                inode: directory.filestat()?.ino,
+            })
+        }
+
+        let mut children: Vec<_> = self
+            .handle
+            .entries()?
+            .into_iter()
+            .map(|(name, filetype, inode)| DirectoryEntry {
+                name,
+                filetype,
+                inode,
+            })
+            .collect();
+        children.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let parent = self.ancestors.last().unwrap_or(&self.handle);
+
+        let mut entries = Vec::with_capacity(children.len() + 2);
+        entries.push(special_entry(b".", &self.handle)?);
+        entries.push(special_entry(b"..", parent)?);
+        entries.extend(children);
+
+        Ok(entries)
+    }
+}
+
+impl WasiDirectory for Directory {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn filestat_get(&self) -> WasiResult<Filestat> {
+        self.handle.filestat()
+    }
+
+    fn readdir(&self, cookie: Dircookie) -> WasiResult<Option<(Dirent, Vec<u8>)>> {
+        let mut entries = self.entries.lock();
+
+        // Only read the directory again when starting from the beginning, so the cookies
+        // stay stable while a listing is in progress.
+        if cookie.0 == 0 || entries.is_empty() {
+            *entries = self.read_entries()?;
+        }
+
+        Ok(entries.get(cookie.0 as usize).map(|entry| {
+            let dirent = Dirent {
+                d_next: Dircookie(cookie.0 + 1),
+                d_ino: entry.inode,
+                d_namlen: Dirnamlen(entry.name.len() as u32),
+                d_type: entry.filetype,
+            };
+
+            (dirent, entry.name.clone())
+        }))
+    }
+
+    fn path_create_directory(&self, path: &[u8]) -> WasiResult<()> {
+        let resolved = self.resolve(path, false)?;
+        let name = resolved.name.ok_or(Errno::Exist)?;
+
+        resolved.directory.create_dir_at(&name)
+    }
+
+    fn path_filestat_get(&self, flags: Lookupflags, path: &[u8]) -> WasiResult<Filestat> {
+        let resolved = self.resolve(path, flags.contains(Lookupflags::SYMLINK_FOLLOW))?;
+
+        match resolved.name {
+            Some(name) => resolved.directory.stat_at(&name),
+            None => resolved.directory.filestat(),
+        }
+    }
+
+    fn path_open(
+        &self,
+        dirflags: Lookupflags,
+        path: &[u8],
+        oflags: Oflags,
+        rights: Rights,
+        fdflags: Fdflags,
+    ) -> WasiResult<Opened> {
+        let Resolved {
+            mut ancestors,
+            directory,
+            name,
+        } = self.resolve(path, dirflags.contains(Lookupflags::SYMLINK_FOLLOW))?;
+
+        let filestat = match name {
+            Some(ref name) => match directory.stat_at(name) {
+                Ok(filestat) => Some(filestat),
+                Err(Errno::Noent) => None,
+                Err(errno) => return Err(errno),
+            },
+            None => Some(directory.filestat()?),
+        };
+
+        match filestat {
+            Some(ref filestat) => {
+                if filestat.filetype == Filetype::SymbolicLink {
+                    return Err(Errno::Loop);
+                }
+                if oflags.contains(Oflags::CREAT | Oflags::EXCL) {
+                    return Err(Errno::Exist);
+                }
+                if filestat.filetype == Filetype::Directory {
+                    if oflags.contains(Oflags::TRUNC) {
+                        return Err(Errno::Isdir);
+                    }
+
+                    let handle = match name {
+                        Some(ref name) => {
+                            let child = Arc::new(directory.open_dir_at(name)?);
+                            ancestors.push(directory);
+                            child
+                        }
+                        None => directory,
+                    };
+
+                    return Ok(Opened::Directory(Box::new(Directory::new(
+                        ancestors, handle,
+                    ))));
+                }
+                if oflags.contains(Oflags::DIRECTORY) {
+                    return Err(Errno::Notdir);
+                }
+            }
+            None => {
+                if !oflags.contains(Oflags::CREAT) {
+                    return Err(Errno::Noent);
+                }
+                if oflags.contains(Oflags::DIRECTORY) {
+                    return Err(Errno::Inval);
+                }
+            }
+        }
+
+        // Only directories are named by a path without a last component.
+        let name = name.ok_or(Errno::Isdir)?;
+        let create = filestat.is_none();
+        let write = rights.contains(Rights::FD_WRITE) || oflags.contains(Oflags::TRUNC) || create;
+
+        let file = directory.open_file_at(
+            &name,
+            &os::FileOptions {
+                read: rights.contains(Rights::FD_READ) || !write,
+                write,
+                append: write && fdflags.contains(Fdflags::APPEND),
+                create_new: create,
+                truncate: oflags.contains(Oflags::TRUNC) && !create,
+            },
+        )?;
+
+        Ok(Opened::File(Box::new(file)))
+    }
+
+    fn path_remove_directory(&self, path: &[u8]) -> WasiResult<()> {
+        let resolved = self.resolve(path, false)?;
+        // A path without a last component names a directory that is in use.
+        let name = resolved.name.ok_or(Errno::Busy)?;
+
+        if resolved.directory.stat_at(&name)?.filetype != Filetype::Directory {
+            return Err(Errno::Notdir);
+        }
+
+        resolved.directory.remove_dir_at(&name)
+    }
+
+    fn path_unlink_file(&self, path: &[u8]) -> WasiResult<()> {
+        let resolved = self.resolve(path, false)?;
+        let name = resolved.name.ok_or(Errno::Isdir)?;
+
+        if resolved.directory.stat_at(&name)?.filetype == Filetype::Directory {
+            return Err(Errno::Isdir);
+        }
+
+        resolved.directory.unlink_at(&name)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::{fs, io::IoSliceMut, os::unix::fs::symlink};
+    use tempfile::TempDir;
+
+    fn sandbox() -> (TempDir, Directory) {
+        let outside = TempDir::new().unwrap();
+        let root = outside.path().join("root");
+
+        fs::create_dir(&root).unwrap();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("sub/inside.txt"), "inside").unwrap();
+        fs::write(outside.path().join("secret.txt"), "secret").unwrap();
+
+        let directory = Directory::open_root(&root).unwrap();
+        (outside, directory)
+    }
+
+    fn open(
+        directory: &dyn WasiDirectory,
+        dirflags: Lookupflags,
+        path: &str,
+    ) -> WasiResult<Opened> {
+        directory.path_open(
+            dirflags,
+            path.as_bytes(),
+            Oflags::empty(),
+            Rights::FD_READ,
+            Fdflags::empty(),
+        )
+    }
+
+    fn read_to_string(opened: Opened) -> String {
+        let file = match opened {
+            Opened::File(file) => file,
+            Opened::Directory(_) => panic!("opened a directory"),
+        };
+        let mut buf = [0; 64];
+        let size = file.read(&mut [IoSliceMut::new(&mut buf)]).unwrap();
+
+        String::from_utf8(buf[..size.0 as usize].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn opens_files_below_the_root() {
+        let (_outside, directory) = sandbox();
+
+        let opened = open(&directory, Lookupflags::empty(), "sub/../sub/./inside.txt").unwrap();
+        assert_eq!(read_to_string(opened), "inside");
+    }
+
+    #[test]
+    fn rejects_paths_leaving_the_root() {
+        let (_outside, directory) = sandbox();
+
+        for path in &["../secret.txt", "sub/../../secret.txt", "/etc/passwd", ".."] {
+            assert_eq!(
+                open(&directory, Lookupflags::empty(), path).unwrap_err(),
+                Errno::Notcapable,
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_symlinks_leaving_the_root() {
+        let (outside, directory) = sandbox();
+        let root = outside.path().join("root");
+
+        symlink(outside.path().join("secret.txt"), root.join("link")).unwrap();
+        symlink(outside.path(), root.join("dirlink")).unwrap();
+
+        assert_eq!(
+            open(&directory, Lookupflags::SYMLINK_FOLLOW, "link").unwrap_err(),
+            Errno::Notcapable
+        );
+        assert_eq!(
+            open(&directory, Lookupflags::empty(), "link").unwrap_err(),
+            Errno::Loop
+        );
+        assert_eq!(
+            open(&directory, Lookupflags::empty(), "dirlink/secret.txt").unwrap_err(),
+            Errno::Notcapable
+        );
+    }
+
+    #[test]
+    fn follows_symlinks_within_the_root() {
+        let (outside, directory) = sandbox();
+        let root = outside.path().join("root");
+
+        symlink("sub/inside.txt", root.join("link")).unwrap();
+
+        let opened = open(&directory, Lookupflags::SYMLINK_FOLLOW, "link").unwrap();
+        assert_eq!(read_to_string(opened), "inside");
+    }
+
+    #[test]
+    fn does_not_create_files_through_symlinks() {
+        let (outside, directory) = sandbox();
+        let root = outside.path().join("root");
+        let target = outside.path().join("created.txt");
+
+        symlink(&target, root.join("dangling")).unwrap();
+
+        let result = directory.path_open(
+            Lookupflags::empty(),
+            b"dangling",
+            Oflags::CREAT,
+            Rights::FD_WRITE,
+            Fdflags::empty(),
+        );
+        assert_eq!(result.unwrap_err(), Errno::Loop);
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn opened_directories_do_not_follow_a_swapped_symlink() {
+        let (outside, directory) = sandbox();
+        let root = outside.path().join("root");
+
+        let sub = match open(&directory, Lookupflags::empty(), "sub").unwrap() {
+            Opened::Directory(sub) => sub,
+            Opened::File(_) => panic!("opened a file"),
+        };
+
+        fs::rename(root.join("sub"), root.join("moved")).unwrap();
+        symlink(outside.path(), root.join("sub")).unwrap();
+
+        let opened = open(&*sub, Lookupflags::empty(), "inside.txt").unwrap();
+        assert_eq!(read_to_string(opened), "inside");
+        assert_eq!(
+            open(&*sub, Lookupflags::empty(), "../sub/secret.txt").unwrap_err(),
+            Errno::Notcapable
+        );
+        assert_eq!(
+            open(&*sub, Lookupflags::empty(), "../../secret.txt").unwrap_err(),
+            Errno::Notcapable
+        );
+    }
+
+    #[test]
+    fn removes_files_and_directories() {
+        let (outside, directory) = sandbox();
+        let root = outside.path().join("root");
+
+        assert_eq!(
+            directory.path_remove_directory(b"sub").unwrap_err(),
+            Errno::Notempty
+        );
+        assert_eq!(
+            directory.path_unlink_file(b"sub").unwrap_err(),
+            Errno::Isdir
+        );
+        assert_eq!(
+            directory.path_remove_directory(b".").unwrap_err(),
+            Errno::Busy
+        );
+
+        directory.path_unlink_file(b"sub/inside.txt").unwrap();
+        directory.path_remove_directory(b"sub").unwrap();
+        directory.path_create_directory(b"new").unwrap();
+
+        assert!(!root.join("sub").exists());
+        assert!(root.join("new").is_dir());
+    }
+}
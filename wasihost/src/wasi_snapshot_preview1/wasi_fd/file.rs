@@ -0,0 +1,313 @@
+use super::{pollable, Pollable};
+use crate::os;
+use std::{
+    convert::TryFrom,
+    fmt::Debug,
+    fs::File,
+    io::{IoSlice, IoSliceMut, Read, Seek, SeekFrom, Write},
+};
+use wasihost_core::wasi_snapshot_preview1::{
+    Advice, Errno, EventFdReadwrite, Fdflags, Filedelta, Filesize, Filestat, Fstflags, Size,
+    Timestamp, WasiResult, Whence,
+};
+
+/// Describes a regular file.
+///
+/// Operations that are not required to read a file default to returning `Errno::Notsup`,
+/// hints and synchronization default to doing nothing.
+pub trait WasiFile: Debug + Send + Sync + 'static {
+    /// Provides advice about the expected access pattern.
+    fn advise(&self, _offset: Filesize, _len: Filesize, _advice: Advice) -> WasiResult<()> {
+        Ok(())
+    }
+
+    /// Makes sure that the file is at least `offset + len` bytes long.
+    fn allocate(&self, _offset: Filesize, _len: Filesize) -> WasiResult<()> {
+        Err(Errno::Notsup)
+    }
+
+    /// Synchronizes the data of the file to the underlying storage.
+    fn datasync(&self) -> WasiResult<()> {
+        Ok(())
+    }
+
+    /// Applies the flags set with `fd_fdstat_set_flags`. [`write`](#method.write) is told
+    /// whether to append on every call, so this is only needed to let the host append on its
+    /// own.
+    fn fdstat_set_flags(&self, _flags: Fdflags) -> WasiResult<()> {
+        Ok(())
+    }
+
+    /// Returns the attributes of the file.
+    fn filestat_get(&self) -> WasiResult<Filestat>;
+
+    /// Truncates or extends the file to `size` bytes.
+    fn filestat_set_size(&self, _size: Filesize) -> WasiResult<()> {
+        Err(Errno::Notsup)
+    }
+
+    /// Sets the timestamps of the file.
+    fn filestat_set_times(
+        &self,
+        _atim: Timestamp,
+        _mtim: Timestamp,
+        _fst_flags: Fstflags,
+    ) -> WasiResult<()> {
+        Err(Errno::Notsup)
+    }
+
+    /// Reads from the file at `offset` into `iovs` without changing the current position.
+    fn pread(&self, iovs: &mut [IoSliceMut<'_>], offset: Filesize) -> WasiResult<Size>;
+
+    /// Writes `bufs` to the file at `offset` without changing the current position.
+    fn pwrite(&self, _bufs: &[IoSlice<'_>], _offset: Filesize) -> WasiResult<Size> {
+        Err(Errno::Notsup)
+    }
+
+    /// Reads from the current position into `iovs`.
+    fn read(&self, iovs: &mut [IoSliceMut<'_>]) -> WasiResult<Size>;
+
+    /// Writes `bufs` at the current position, or at the end of the file if `append` is set.
+    fn write(&self, _bufs: &[IoSlice<'_>], _append: bool) -> WasiResult<Size> {
+        Err(Errno::Notsup)
+    }
+
+    /// Moves the current position.
+    fn seek(&self, offset: Filedelta, whence: Whence) -> WasiResult<Filesize>;
+
+    /// Synchronizes the data and metadata of the file to the underlying storage.
+    fn sync(&self) -> WasiResult<()> {
+        Ok(())
+    }
+
+    /// Returns the current position.
+    fn tell(&self) -> WasiResult<Filesize> {
+        self.seek(Filedelta(0), Whence::Cur)
+    }
+}
+
+/// Regular files are always ready, reading reports the bytes left to read.
+impl Pollable for dyn WasiFile {
+    fn poll_read(&self) -> WasiResult<Option<EventFdReadwrite>> {
+        let remaining = match (self.filestat_get(), self.tell()) {
+            (Ok(stat), Ok(position)) => stat.size.0.saturating_sub(position.0),
+            _ => 0,
+        };
+
+        Ok(Some(pollable::ready(remaining)))
+    }
+}
+
+impl WasiFile for File {
+    fn allocate(&self, offset: Filesize, len: Filesize) -> WasiResult<()> {
+        let end = offset.0.checked_add(len.0).ok_or(Errno::Fbig)?;
+
+        if end > self.metadata()?.len() {
+            self.set_len(end)?;
+        }
+
+        Ok(())
+    }
+
+    fn datasync(&self) -> WasiResult<()> {
+        Ok(self.sync_data()?)
+    }
+
+    fn fdstat_set_flags(&self, flags: Fdflags) -> WasiResult<()> {
+        os::set_append(self, flags.contains(Fdflags::APPEND))
+    }
+
+    fn filestat_get(&self) -> WasiResult<Filestat> {
+        Ok(os::filestat_from_metadata(&self.metadata()?))
+    }
+
+    fn filestat_set_size(&self, size: Filesize) -> WasiResult<()> {
+        Ok(self.set_len(size.0)?)
+    }
+
+    fn filestat_set_times(
+        &self,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    ) -> WasiResult<()> {
+        if fst_flags.contains(Fstflags::ATIM | Fstflags::ATIM_NOW)
+            || fst_flags.contains(Fstflags::MTIM | Fstflags::MTIM_NOW)
+        {
+            return Err(Errno::Inval);
+        }
+
+        os::set_file_times(self, atim, mtim, fst_flags)
+    }
+
+    fn pread(&self, iovs: &mut [IoSliceMut<'_>], offset: Filesize) -> WasiResult<Size> {
+        let mut offset = offset.0;
+        let mut total = 0;
+
+        for iov in iovs.iter_mut() {
+            let read = match os::read_at(self, iov, offset) {
+                Ok(read) => read,
+                // Report the data already read, the error recurs on the next call.
+                Err(_) if total > 0 => break,
+                Err(errno) => return Err(errno),
+            };
+
+            total += read;
+            offset += read as u64;
+
+            if read < iov.len() {
+                break;
+            }
+        }
+
+        Ok(Size(total as u32))
+    }
+
+    fn pwrite(&self, bufs: &[IoSlice<'_>], offset: Filesize) -> WasiResult<Size> {
+        let mut offset = offset.0;
+        let mut total = 0;
+
+        for buf in bufs {
+            let written = match os::write_at(self, buf, offset) {
+                Ok(written) => written,
+                Err(_) if total > 0 => break,
+                Err(errno) => return Err(errno),
+            };
+
+            total += written;
+            offset += written as u64;
+
+            if written < buf.len() {
+                break;
+            }
+        }
+
+        Ok(Size(total as u32))
+    }
+
+    fn read(&self, iovs: &mut [IoSliceMut<'_>]) -> WasiResult<Size> {
+        Ok(Size((&*self).read_vectored(iovs)? as u32))
+    }
+
+    fn write(&self, bufs: &[IoSlice<'_>], append: bool) -> WasiResult<Size> {
+        // Files append on their own if the flag can be set on them with
+        // `fd_fdstat_set_flags`. Otherwise others may write to the file between the seek and
+        // the write.
+        if append && !os::SETS_APPEND {
+            Seek::seek(&mut &*self, SeekFrom::End(0))?;
+        }
+
+        Ok(Size((&*self).write_vectored(bufs)? as u32))
+    }
+
+    fn seek(&self, offset: Filedelta, whence: Whence) -> WasiResult<Filesize> {
+        let position = match whence {
+            Whence::Set => SeekFrom::Start(u64::try_from(offset.0).map_err(|_| Errno::Inval)?),
+            Whence::Cur => SeekFrom::Current(offset.0),
+            Whence::End => SeekFrom::End(offset.0),
+            _ => return Err(Errno::Inval),
+        };
+
+        Ok(Filesize(Seek::seek(&mut &*self, position)?))
+    }
+
+    fn sync(&self) -> WasiResult<()> {
+        Ok(self.sync_all()?)
+    }
+
+    fn tell(&self) -> WasiResult<Filesize> {
+        Ok(Filesize((&*self).stream_position()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_with(contents: &[u8]) -> File {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(contents).unwrap();
+        Seek::seek(&mut file, SeekFrom::Start(0)).unwrap();
+        file
+    }
+
+    fn contents(file: &File) -> Vec<u8> {
+        let mut contents = Vec::new();
+        let mut buf = [0; 64];
+        let mut offset = 0;
+
+        loop {
+            let read = WasiFile::pread(file, &mut [IoSliceMut::new(&mut buf)], Filesize(offset))
+                .unwrap()
+                .0 as usize;
+            if read == 0 {
+                return contents;
+            }
+            contents.extend_from_slice(&buf[..read]);
+            offset += read as u64;
+        }
+    }
+
+    #[test]
+    fn pread_fills_buffers_without_moving_the_cursor() {
+        let file = file_with(b"hello world");
+        let (mut a, mut b) = ([0; 4], [0; 16]);
+
+        let read = WasiFile::pread(
+            &file,
+            &mut [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)],
+            Filesize(2),
+        )
+        .unwrap();
+
+        assert_eq!(read, Size(9));
+        assert_eq!(&a, b"llo ");
+        assert_eq!(&b[..5], b"world");
+        assert_eq!(WasiFile::tell(&file).unwrap(), Filesize(0));
+    }
+
+    #[test]
+    fn pwrite_writes_at_the_offset_without_moving_the_cursor() {
+        let file = file_with(b"hello world");
+
+        let written = WasiFile::pwrite(
+            &file,
+            &[IoSlice::new(b"W"), IoSlice::new(b"ORLD")],
+            Filesize(6),
+        )
+        .unwrap();
+
+        assert_eq!(written, Size(5));
+        assert_eq!(contents(&file), b"hello WORLD");
+        assert_eq!(WasiFile::tell(&file).unwrap(), Filesize(0));
+    }
+
+    #[test]
+    fn write_appends_when_the_flag_is_set_later() {
+        let file = file_with(b"hello");
+
+        WasiFile::write(&file, &[IoSlice::new(b"J")], false).unwrap();
+        WasiFile::fdstat_set_flags(&file, Fdflags::APPEND).unwrap();
+        WasiFile::seek(&file, Filedelta(0), Whence::Set).unwrap();
+        WasiFile::write(&file, &[IoSlice::new(b" world")], true).unwrap();
+
+        assert_eq!(contents(&file), b"Jello world");
+
+        WasiFile::fdstat_set_flags(&file, Fdflags::empty()).unwrap();
+        WasiFile::seek(&file, Filedelta(0), Whence::Set).unwrap();
+        WasiFile::write(&file, &[IoSlice::new(b"Y")], false).unwrap();
+
+        assert_eq!(contents(&file), b"Yello world");
+    }
+
+    #[test]
+    fn allocate_only_grows_the_file() {
+        let file = file_with(b"hello");
+
+        WasiFile::allocate(&file, Filesize(2), Filesize(1)).unwrap();
+        assert_eq!(WasiFile::filestat_get(&file).unwrap().size, Filesize(5));
+
+        WasiFile::allocate(&file, Filesize(6), Filesize(4)).unwrap();
+        assert_eq!(WasiFile::filestat_get(&file).unwrap().size, Filesize(10));
+    }
+}
@@ -0,0 +1,1378 @@
+use super::{
+    super::clock::{SharedClock, SystemClock},
+    Opened, WasiDirectory, WasiFile,
+};
+use parking_lot::{Mutex, RwLock};
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    io::{IoSlice, IoSliceMut},
+    sync::Arc,
+};
+use wasihost_core::wasi_snapshot_preview1::{
+    Clockid, Device, Dircookie, Dirent, Dirnamlen, Errno, Fdflags, Filedelta, Filesize, Filestat,
+    Filetype, Fstflags, Inode, Linkcount, Lookupflags, Oflags, Rights, Size, Timestamp, WasiResult,
+    Whence,
+};
+
+const ROOT_INODE: u64 = 1;
+
+/// The maximum number of symbolic links that are followed while resolving a single path.
+const MAX_SYMLINKS: u32 = 32;
+
+/// The default maximum size of a single file, 256 MiB.
+const DEFAULT_MAX_FILE_SIZE: u64 = 256 << 20;
+
+/// The default maximum size of all files together, 256 MiB.
+const DEFAULT_MAX_SIZE: u64 = 256 << 20;
+
+/// A file system that only lives in memory.
+///
+/// Cloning a `MemoryFs` yields another handle to the same file system, so it can be populated
+/// before it is preopened and inspected after the WASI binary has finished. Paths passed to
+/// the methods of this type are relative to the root of the file system, leading slashes are
+/// ignored and symbolic links are followed.
+///
+/// Timestamps are taken from the realtime clock of the host the file system is preopened in,
+/// so they are virtualized along with it, and from the system clock before that.
+///
+/// The contents of the files are limited to
+/// [`max_file_size`](#method.max_file_size) bytes per file and
+/// [`max_size`](#method.max_size) bytes in total, so a WASI binary can not exhaust the
+/// memory of the host. Growing a file beyond these limits fails with `Errno::Fbig` and
+/// `Errno::Nospc` respectively.
+#[derive(Clone, Debug)]
+pub struct MemoryFs {
+    tree: Arc<Mutex<Tree>>,
+}
+
+#[derive(Debug)]
+struct Tree {
+    /// The clocks that timestamps are taken from.
+    clock: SharedClock,
+    nodes: HashMap<u64, Node>,
+    next_inode: u64,
+    /// The size of the contents of all files, including removed files that are still open.
+    size: u64,
+    max_file_size: u64,
+    max_size: u64,
+}
+
+#[derive(Debug)]
+struct Node {
+    data: NodeData,
+    /// The number of directory entries referring to this node.
+    nlink: u64,
+    /// The number of file descriptors referring to this node.
+    open: u64,
+    atim: Timestamp,
+    mtim: Timestamp,
+    ctim: Timestamp,
+}
+
+#[derive(Debug)]
+enum NodeData {
+    File(Vec<u8>),
+    Directory {
+        parent: u64,
+        entries: BTreeMap<Vec<u8>, u64>,
+    },
+    Symlink(Vec<u8>),
+}
+
+/// A directory of a [`MemoryFs`](struct.MemoryFs.html) that is referred to by a file descriptor.
+#[derive(Debug)]
+pub(super) struct MemoryDirectory {
+    fs: MemoryFs,
+    inode: u64,
+}
+
+/// A regular file of a [`MemoryFs`](struct.MemoryFs.html) that is referred to by a file
+/// descriptor.
+#[derive(Debug)]
+pub(super) struct MemoryFile {
+    fs: MemoryFs,
+    inode: u64,
+    position: Mutex<u64>,
+}
+
+fn current_time(clock: &SharedClock) -> Timestamp {
+    // Reading recursively, as the host may poll file descriptors while reading the clocks.
+    clock
+        .read_recursive()
+        .time_get(Clockid::Realtime, Timestamp(0))
+        .unwrap_or(Timestamp(0))
+}
+
+fn components(path: &[u8]) -> impl DoubleEndedIterator<Item = &[u8]> {
+    path.split(|&b| b == b'/').filter(|c| !c.is_empty())
+}
+
+/// Strips leading slashes from a path passed in by the embedder.
+fn relative(path: &[u8]) -> &[u8] {
+    let start = path.iter().position(|&b| b != b'/').unwrap_or(path.len());
+
+    &path[start..]
+}
+
+fn is_dot_or_dot_dot(name: &[u8]) -> bool {
+    name == b"." || name == b".."
+}
+
+fn read_at(contents: &[u8], iovs: &mut [IoSliceMut<'_>], offset: u64) -> usize {
+    let start = usize::try_from(offset)
+        .unwrap_or(contents.len())
+        .min(contents.len());
+    let mut position = start;
+
+    for iov in iovs.iter_mut() {
+        let len = iov.len().min(contents.len() - position);
+
+        iov[..len].copy_from_slice(&contents[position..position + len]);
+        position += len;
+    }
+
+    position - start
+}
+
+impl Node {
+    fn new(data: NodeData, now: Timestamp) -> Self {
+        Node {
+            data,
+            nlink: 0,
+            open: 0,
+            atim: now,
+            mtim: now,
+            ctim: now,
+        }
+    }
+
+    fn filetype(&self) -> Filetype {
+        match self.data {
+            NodeData::File(_) => Filetype::RegularFile,
+            NodeData::Directory { .. } => Filetype::Directory,
+            NodeData::Symlink(_) => Filetype::SymbolicLink,
+        }
+    }
+
+    fn filestat(&self, inode: u64) -> Filestat {
+        let size = match self.data {
+            NodeData::File(ref contents) => contents.len(),
+            NodeData::Directory { ref entries, .. } => entries.len(),
+            NodeData::Symlink(ref target) => target.len(),
+        };
+
+        Filestat {
+            dev: Device(0),
+            ino: Inode(inode),
+            filetype: self.filetype(),
+            nlink: Linkcount(self.nlink),
+            size: Filesize(size as u64),
+            atim: self.atim,
+            mtim: self.mtim,
+            ctim: self.ctim,
+        }
+    }
+
+    fn contents_mut(&mut self) -> &mut Vec<u8> {
+        match self.data {
+            NodeData::File(ref mut contents) => contents,
+            _ => unreachable!("memory files always refer to regular files"),
+        }
+    }
+
+    /// Marks the node as modified at `now`.
+    fn touch(&mut self, now: Timestamp) {
+        self.mtim = now;
+        self.ctim = now;
+    }
+
+    fn set_times(
+        &mut self,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+        now: Timestamp,
+    ) -> WasiResult<()> {
+        if fst_flags.contains(Fstflags::ATIM | Fstflags::ATIM_NOW)
+            || fst_flags.contains(Fstflags::MTIM | Fstflags::MTIM_NOW)
+        {
+            return Err(Errno::Inval);
+        }
+
+        if fst_flags.contains(Fstflags::ATIM_NOW) {
+            self.atim = now;
+        } else if fst_flags.contains(Fstflags::ATIM) {
+            self.atim = atim;
+        }
+        if fst_flags.contains(Fstflags::MTIM_NOW) {
+            self.mtim = now;
+        } else if fst_flags.contains(Fstflags::MTIM) {
+            self.mtim = mtim;
+        }
+        self.ctim = now;
+
+        Ok(())
+    }
+}
+
+impl Tree {
+    fn new() -> Self {
+        let clock: SharedClock = Arc::new(RwLock::new(Box::new(SystemClock::new())));
+        let mut root = Node::new(
+            NodeData::Directory {
+                parent: ROOT_INODE,
+                entries: BTreeMap::new(),
+            },
+            current_time(&clock),
+        );
+        root.nlink = 1;
+
+        let mut nodes = HashMap::new();
+        nodes.insert(ROOT_INODE, root);
+
+        Tree {
+            clock,
+            nodes,
+            next_inode: ROOT_INODE + 1,
+            size: 0,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+
+    fn now(&self) -> Timestamp {
+        current_time(&self.clock)
+    }
+
+    /// Marks the node `inode` as modified.
+    fn touch(&mut self, inode: u64) {
+        let now = self.now();
+        self.node_mut(inode).touch(now);
+    }
+
+    fn set_times(
+        &mut self,
+        inode: u64,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    ) -> WasiResult<()> {
+        let now = self.now();
+        self.node_mut(inode).set_times(atim, mtim, fst_flags, now)
+    }
+
+    fn node(&self, inode: u64) -> &Node {
+        &self.nodes[&inode]
+    }
+
+    fn node_mut(&mut self, inode: u64) -> &mut Node {
+        self.nodes.get_mut(&inode).expect("unknown inode")
+    }
+
+    fn file_size(&self, inode: u64) -> u64 {
+        match self.node(inode).data {
+            NodeData::File(ref contents) => contents.len() as u64,
+            _ => 0,
+        }
+    }
+
+    /// Checks that a file may change its size from `old` to `new` bytes.
+    fn check_size(&self, old: u64, new: u64) -> WasiResult<()> {
+        if new > old {
+            if new > self.max_file_size {
+                return Err(Errno::Fbig);
+            }
+            if new - old > self.max_size.saturating_sub(self.size) {
+                return Err(Errno::Nospc);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Truncates or zero-extends the regular file `inode` to `size` bytes.
+    fn resize(&mut self, inode: u64, size: u64) -> WasiResult<()> {
+        let old = self.file_size(inode);
+        self.check_size(old, size)?;
+
+        let len = usize::try_from(size).map_err(|_| Errno::Fbig)?;
+        let contents = self.node_mut(inode).contents_mut();
+        if len > contents.len() {
+            contents
+                .try_reserve(len - contents.len())
+                .map_err(|_| Errno::Nospc)?;
+        }
+        contents.resize(len, 0);
+
+        self.size = self.size - old + size;
+
+        Ok(())
+    }
+
+    /// Replaces the contents of the regular file `inode`.
+    fn replace(&mut self, inode: u64, contents: Vec<u8>) -> WasiResult<()> {
+        let old = self.file_size(inode);
+        let new = contents.len() as u64;
+        self.check_size(old, new)?;
+
+        *self.node_mut(inode).contents_mut() = contents;
+        self.size = self.size - old + new;
+
+        Ok(())
+    }
+
+    /// Writes `bufs` to the regular file `inode` at `offset`, extending it as needed.
+    fn write_at(&mut self, inode: u64, bufs: &[IoSlice<'_>], offset: u64) -> WasiResult<usize> {
+        let len = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        let end = offset.checked_add(len as u64).ok_or(Errno::Fbig)?;
+
+        if len == 0 {
+            return Ok(0);
+        }
+        if self.file_size(inode) < end {
+            self.resize(inode, end)?;
+        }
+
+        // The file is at least `end` bytes long now, so `offset` fits in a `usize`.
+        let contents = self.node_mut(inode).contents_mut();
+        let mut position = offset as usize;
+        for buf in bufs {
+            contents[position..position + buf.len()].copy_from_slice(buf);
+            position += buf.len();
+        }
+
+        Ok(len)
+    }
+
+    fn entries(&self, dir: u64) -> WasiResult<&BTreeMap<Vec<u8>, u64>> {
+        match self.node(dir).data {
+            NodeData::Directory { ref entries, .. } => Ok(entries),
+            _ => Err(Errno::Notdir),
+        }
+    }
+
+    fn entries_mut(&mut self, dir: u64) -> WasiResult<&mut BTreeMap<Vec<u8>, u64>> {
+        match self.node_mut(dir).data {
+            NodeData::Directory {
+                ref mut entries, ..
+            } => Ok(entries),
+            _ => Err(Errno::Notdir),
+        }
+    }
+
+    /// Returns the inode of the parent of `dir`. The root directory and directories that have
+    /// been removed are their own parent.
+    fn parent(&self, dir: u64) -> u64 {
+        match self.node(dir).data {
+            NodeData::Directory { parent, .. } if self.node(dir).nlink > 0 => parent,
+            _ => dir,
+        }
+    }
+
+    /// Looks up `name` in the directory `dir` without following symbolic links.
+    fn child(&self, dir: u64, name: &[u8]) -> WasiResult<Option<u64>> {
+        let entries = self.entries(dir)?;
+
+        match name {
+            b"." => Ok(Some(dir)),
+            b".." if dir == ROOT_INODE => Err(Errno::Notcapable),
+            b".." if self.node(dir).nlink == 0 => Err(Errno::Noent),
+            b".." => Ok(Some(self.parent(dir))),
+            _ => Ok(entries.get(name).copied()),
+        }
+    }
+
+    /// Resolves `inode` if it is a symbolic link that was found in the directory `dir`.
+    fn follow(&self, dir: u64, inode: u64, depth: &mut u32) -> WasiResult<u64> {
+        match self.node(inode).data {
+            NodeData::Symlink(ref target) => {
+                *depth += 1;
+                if *depth > MAX_SYMLINKS {
+                    return Err(Errno::Loop);
+                }
+
+                let (parent, name) = self.resolve_parent(dir, target, depth)?;
+                self.step(parent, name, true, depth)
+            }
+            _ => Ok(inode),
+        }
+    }
+
+    fn step(&self, dir: u64, name: &[u8], follow: bool, depth: &mut u32) -> WasiResult<u64> {
+        let inode = self.child(dir, name)?.ok_or(Errno::Noent)?;
+
+        if follow {
+            self.follow(dir, inode, depth)
+        } else {
+            Ok(inode)
+        }
+    }
+
+    /// Resolves all but the last component of `path` relative to the directory `dir`. Returns
+    /// the directory containing the last component and the last component itself. Absolute
+    /// paths are rejected, as they would escape the directory.
+    fn resolve_parent<'a>(
+        &self,
+        dir: u64,
+        path: &'a [u8],
+        depth: &mut u32,
+    ) -> WasiResult<(u64, &'a [u8])> {
+        if path.starts_with(b"/") {
+            return Err(Errno::Notcapable);
+        }
+
+        let mut components = components(path);
+        let last = components.next_back().ok_or(Errno::Noent)?;
+
+        let mut dir = dir;
+        for component in components {
+            dir = self.step(dir, component, true, depth)?;
+        }
+        self.entries(dir)?;
+
+        Ok((dir, last))
+    }
+
+    fn lookup(&self, dir: u64, path: &[u8], follow: bool) -> WasiResult<u64> {
+        let mut depth = 0;
+        let (parent, name) = self.resolve_parent(dir, path, &mut depth)?;
+
+        self.step(parent, name, follow, &mut depth)
+    }
+
+    /// Looks up a path passed in by the embedder, which may be absolute.
+    fn lookup_from_root(&self, path: &[u8], follow: bool) -> WasiResult<u64> {
+        let path = relative(path);
+
+        if path.is_empty() {
+            Ok(ROOT_INODE)
+        } else {
+            self.lookup(ROOT_INODE, path, follow)
+        }
+    }
+
+    fn check_vacant(&self, dir: u64, name: &[u8]) -> WasiResult<()> {
+        if self.child(dir, name)?.is_some() {
+            return Err(Errno::Exist);
+        }
+        if self.node(dir).nlink == 0 {
+            return Err(Errno::Noent);
+        }
+
+        Ok(())
+    }
+
+    /// Adds an entry for the node `inode` to the directory `dir`.
+    fn link(&mut self, dir: u64, name: &[u8], inode: u64) -> WasiResult<()> {
+        self.entries_mut(dir)?.insert(name.to_vec(), inode);
+        self.touch(dir);
+
+        let now = self.now();
+        let node = self.node_mut(inode);
+        node.nlink += 1;
+        node.ctim = now;
+
+        Ok(())
+    }
+
+    /// Removes the entry `name` from the directory `dir`.
+    fn unlink(&mut self, dir: u64, name: &[u8]) -> WasiResult<()> {
+        let inode = self.entries_mut(dir)?.remove(name).ok_or(Errno::Noent)?;
+        self.touch(dir);
+
+        let now = self.now();
+        let node = self.node_mut(inode);
+        node.nlink -= 1;
+        node.ctim = now;
+        self.collect(inode);
+
+        Ok(())
+    }
+
+    fn insert(&mut self, dir: u64, name: &[u8], data: NodeData) -> WasiResult<u64> {
+        self.check_vacant(dir, name)?;
+
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        let now = self.now();
+        self.nodes.insert(inode, Node::new(data, now));
+        self.link(dir, name, inode)?;
+
+        Ok(inode)
+    }
+
+    fn acquire(&mut self, inode: u64) {
+        self.node_mut(inode).open += 1;
+    }
+
+    fn release(&mut self, inode: u64) {
+        self.node_mut(inode).open -= 1;
+        self.collect(inode);
+    }
+
+    /// Removes the node `inode` once neither directory entries nor file descriptors refer
+    /// to it anymore.
+    fn collect(&mut self, inode: u64) {
+        let node = self.node(inode);
+
+        if node.nlink == 0 && node.open == 0 {
+            self.size -= self.file_size(inode);
+            self.nodes.remove(&inode);
+        }
+    }
+
+    fn create_dir(&mut self, dir: u64, path: &[u8]) -> WasiResult<u64> {
+        let (dir, name) = self.resolve_parent(dir, path, &mut 0)?;
+        let data = NodeData::Directory {
+            parent: dir,
+            entries: BTreeMap::new(),
+        };
+
+        self.insert(dir, name, data)
+    }
+
+    fn hard_link(
+        &mut self,
+        old_dir: u64,
+        old_path: &[u8],
+        follow: bool,
+        new_dir: u64,
+        new_path: &[u8],
+    ) -> WasiResult<()> {
+        let inode = self.lookup(old_dir, old_path, follow)?;
+        if let NodeData::Directory { .. } = self.node(inode).data {
+            return Err(Errno::Perm);
+        }
+
+        let (new_dir, new_name) = self.resolve_parent(new_dir, new_path, &mut 0)?;
+        self.check_vacant(new_dir, new_name)?;
+
+        self.link(new_dir, new_name, inode)
+    }
+
+    fn symlink(&mut self, target: &[u8], dir: u64, path: &[u8]) -> WasiResult<()> {
+        let (dir, name) = self.resolve_parent(dir, path, &mut 0)?;
+
+        self.insert(dir, name, NodeData::Symlink(target.to_vec()))
+            .map(|_| ())
+    }
+
+    fn read_link(&self, inode: u64) -> WasiResult<Vec<u8>> {
+        match self.node(inode).data {
+            NodeData::Symlink(ref target) => Ok(target.clone()),
+            _ => Err(Errno::Inval),
+        }
+    }
+
+    fn remove_dir(&mut self, dir: u64, path: &[u8]) -> WasiResult<()> {
+        let (dir, name) = self.resolve_parent(dir, path, &mut 0)?;
+
+        match name {
+            b"." => return Err(Errno::Inval),
+            b".." => return Err(Errno::Notempty),
+            _ => {}
+        }
+
+        let inode = self.child(dir, name)?.ok_or(Errno::Noent)?;
+        match self.node(inode).data {
+            NodeData::Directory { ref entries, .. } if !entries.is_empty() => {
+                return Err(Errno::Notempty)
+            }
+            NodeData::Directory { .. } => {}
+            _ => return Err(Errno::Notdir),
+        }
+
+        self.unlink(dir, name)
+    }
+
+    fn remove_file(&mut self, dir: u64, path: &[u8]) -> WasiResult<()> {
+        let (dir, name) = self.resolve_parent(dir, path, &mut 0)?;
+
+        let inode = self.child(dir, name)?.ok_or(Errno::Noent)?;
+        if let NodeData::Directory { .. } = self.node(inode).data {
+            return Err(Errno::Isdir);
+        }
+
+        self.unlink(dir, name)
+    }
+
+    fn rename(
+        &mut self,
+        old_dir: u64,
+        old_path: &[u8],
+        new_dir: u64,
+        new_path: &[u8],
+    ) -> WasiResult<()> {
+        let (old_dir, old_name) = self.resolve_parent(old_dir, old_path, &mut 0)?;
+        let (new_dir, new_name) = self.resolve_parent(new_dir, new_path, &mut 0)?;
+
+        if is_dot_or_dot_dot(old_name) || is_dot_or_dot_dot(new_name) {
+            return Err(Errno::Inval);
+        }
+
+        let inode = self.child(old_dir, old_name)?.ok_or(Errno::Noent)?;
+        let existing = self.child(new_dir, new_name)?;
+
+        if existing == Some(inode) {
+            return Ok(());
+        }
+        if self.node(new_dir).nlink == 0 {
+            return Err(Errno::Noent);
+        }
+
+        if let NodeData::Directory { .. } = self.node(inode).data {
+            // A directory can not become a subdirectory of itself.
+            let mut ancestor = new_dir;
+            while ancestor != ROOT_INODE {
+                if ancestor == inode {
+                    return Err(Errno::Inval);
+                }
+                ancestor = self.parent(ancestor);
+            }
+
+            if let Some(existing) = existing {
+                match self.node(existing).data {
+                    NodeData::Directory { ref entries, .. } if !entries.is_empty() => {
+                        return Err(Errno::Notempty)
+                    }
+                    NodeData::Directory { .. } => {}
+                    _ => return Err(Errno::Notdir),
+                }
+            }
+        } else if let Some(existing) = existing {
+            if let NodeData::Directory { .. } = self.node(existing).data {
+                return Err(Errno::Isdir);
+            }
+        }
+
+        if existing.is_some() {
+            self.unlink(new_dir, new_name)?;
+        }
+
+        self.entries_mut(old_dir)?.remove(old_name);
+        self.entries_mut(new_dir)?.insert(new_name.to_vec(), inode);
+        self.touch(old_dir);
+        self.touch(new_dir);
+
+        let now = self.now();
+        let node = self.node_mut(inode);
+        if let NodeData::Directory { ref mut parent, .. } = node.data {
+            *parent = new_dir;
+        }
+        node.ctim = now;
+
+        Ok(())
+    }
+}
+
+impl MemoryFs {
+    /// Creates an empty file system.
+    pub fn new() -> Self {
+        MemoryFs {
+            tree: Arc::new(Mutex::new(Tree::new())),
+        }
+    }
+
+    /// Limits the size of a single file to `bytes`. Files that are already larger keep their
+    /// contents, but can not grow any further.
+    pub fn max_file_size(self, bytes: u64) -> Self {
+        self.tree.lock().max_file_size = bytes;
+        self
+    }
+
+    /// Limits the size of all files together to `bytes`, which includes files that have been
+    /// removed but are still open.
+    pub fn max_size(self, bytes: u64) -> Self {
+        self.tree.lock().max_size = bytes;
+        self
+    }
+
+    /// Takes timestamps from `clock` instead of the system clock.
+    pub(super) fn set_clock(&self, clock: SharedClock) {
+        self.tree.lock().clock = clock;
+    }
+
+    /// Returns the size of the contents of all files in bytes.
+    pub fn size(&self) -> u64 {
+        self.tree.lock().size
+    }
+
+    /// Creates the directory `path`. The parent directory must already exist.
+    pub fn create_dir(&self, path: impl AsRef<[u8]>) -> WasiResult<()> {
+        self.tree
+            .lock()
+            .create_dir(ROOT_INODE, relative(path.as_ref()))
+            .map(|_| ())
+    }
+
+    /// Creates the directory `path` and all missing parent directories.
+    pub fn create_dir_all(&self, path: impl AsRef<[u8]>) -> WasiResult<()> {
+        let mut tree = self.tree.lock();
+        let mut dir = ROOT_INODE;
+
+        for component in components(path.as_ref()) {
+            dir = match tree.child(dir, component)? {
+                Some(inode) => tree.follow(dir, inode, &mut 0)?,
+                None => {
+                    let data = NodeData::Directory {
+                        parent: dir,
+                        entries: BTreeMap::new(),
+                    };
+
+                    tree.insert(dir, component, data)?
+                }
+            };
+        }
+
+        tree.entries(dir).map(|_| ())
+    }
+
+    /// Writes `contents` to the regular file `path`, creating it if it does not exist.
+    pub fn write(&self, path: impl AsRef<[u8]>, contents: impl Into<Vec<u8>>) -> WasiResult<()> {
+        let contents = contents.into();
+        let mut tree = self.tree.lock();
+        let (dir, name) = tree.resolve_parent(ROOT_INODE, relative(path.as_ref()), &mut 0)?;
+
+        let inode = match tree.child(dir, name)? {
+            Some(inode) => {
+                let inode = tree.follow(dir, inode, &mut 0)?;
+                match tree.node(inode).data {
+                    NodeData::File(_) => inode,
+                    _ => return Err(Errno::Isdir),
+                }
+            }
+            None => {
+                tree.check_size(0, contents.len() as u64)?;
+                tree.insert(dir, name, NodeData::File(Vec::new()))?
+            }
+        };
+
+        tree.replace(inode, contents)?;
+        tree.touch(inode);
+
+        Ok(())
+    }
+
+    /// Creates a symbolic link at `path` that points to `target`.
+    pub fn symlink(&self, target: impl AsRef<[u8]>, path: impl AsRef<[u8]>) -> WasiResult<()> {
+        self.tree
+            .lock()
+            .symlink(target.as_ref(), ROOT_INODE, relative(path.as_ref()))
+    }
+
+    /// Returns the contents of the regular file `path`.
+    pub fn read(&self, path: impl AsRef<[u8]>) -> WasiResult<Vec<u8>> {
+        let tree = self.tree.lock();
+        let inode = tree.lookup_from_root(path.as_ref(), true)?;
+
+        match tree.node(inode).data {
+            NodeData::File(ref contents) => Ok(contents.clone()),
+            _ => Err(Errno::Isdir),
+        }
+    }
+
+    /// Returns the sorted names of the entries of the directory `path`, excluding `.` and `..`.
+    pub fn read_dir(&self, path: impl AsRef<[u8]>) -> WasiResult<Vec<Vec<u8>>> {
+        let tree = self.tree.lock();
+        let inode = tree.lookup_from_root(path.as_ref(), true)?;
+
+        Ok(tree.entries(inode)?.keys().cloned().collect())
+    }
+
+    /// Returns the target of the symbolic link `path`.
+    pub fn read_link(&self, path: impl AsRef<[u8]>) -> WasiResult<Vec<u8>> {
+        let tree = self.tree.lock();
+
+        tree.read_link(tree.lookup_from_root(path.as_ref(), false)?)
+    }
+
+    /// Returns the attributes of `path`.
+    pub fn metadata(&self, path: impl AsRef<[u8]>) -> WasiResult<Filestat> {
+        let tree = self.tree.lock();
+        let inode = tree.lookup_from_root(path.as_ref(), true)?;
+
+        Ok(tree.node(inode).filestat(inode))
+    }
+
+    /// Returns the attributes of `path` without following a symbolic link at the end.
+    pub fn symlink_metadata(&self, path: impl AsRef<[u8]>) -> WasiResult<Filestat> {
+        let tree = self.tree.lock();
+        let inode = tree.lookup_from_root(path.as_ref(), false)?;
+
+        Ok(tree.node(inode).filestat(inode))
+    }
+
+    /// Sets the access and modification times of `path`.
+    pub fn set_times(
+        &self,
+        path: impl AsRef<[u8]>,
+        atim: Timestamp,
+        mtim: Timestamp,
+    ) -> WasiResult<()> {
+        let mut tree = self.tree.lock();
+        let inode = tree.lookup_from_root(path.as_ref(), true)?;
+
+        tree.set_times(inode, atim, mtim, Fstflags::ATIM | Fstflags::MTIM)
+    }
+
+    /// Removes the file or symbolic link `path`.
+    pub fn remove_file(&self, path: impl AsRef<[u8]>) -> WasiResult<()> {
+        self.tree
+            .lock()
+            .remove_file(ROOT_INODE, relative(path.as_ref()))
+    }
+
+    /// Removes the empty directory `path`.
+    pub fn remove_dir(&self, path: impl AsRef<[u8]>) -> WasiResult<()> {
+        self.tree
+            .lock()
+            .remove_dir(ROOT_INODE, relative(path.as_ref()))
+    }
+}
+
+impl Default for MemoryFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryDirectory {
+    /// Opens the root directory of `fs`.
+    pub(super) fn root(fs: MemoryFs) -> Self {
+        let mut tree = fs.tree.lock();
+
+        Self::new(&fs, &mut tree, ROOT_INODE)
+    }
+
+    fn new(fs: &MemoryFs, tree: &mut Tree, inode: u64) -> Self {
+        tree.acquire(inode);
+
+        MemoryDirectory {
+            fs: fs.clone(),
+            inode,
+        }
+    }
+
+    /// Returns the file system the directory belongs to.
+    pub(super) fn fs(&self) -> &MemoryFs {
+        &self.fs
+    }
+
+    /// Returns `dir` if it is a directory of the same file system.
+    fn same_fs<'a>(&self, dir: &'a dyn WasiDirectory) -> WasiResult<&'a MemoryDirectory> {
+        match dir.as_any().downcast_ref::<MemoryDirectory>() {
+            Some(dir) if Arc::ptr_eq(&self.fs.tree, &dir.fs.tree) => Ok(dir),
+            _ => Err(Errno::Xdev),
+        }
+    }
+}
+
+impl WasiDirectory for MemoryDirectory {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn filestat_get(&self) -> WasiResult<Filestat> {
+        Ok(self.fs.tree.lock().node(self.inode).filestat(self.inode))
+    }
+
+    fn filestat_set_times(
+        &self,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    ) -> WasiResult<()> {
+        self.fs
+            .tree
+            .lock()
+            .set_times(self.inode, atim, mtim, fst_flags)
+    }
+
+    fn readdir(&self, cookie: Dircookie) -> WasiResult<Option<(Dirent, Vec<u8>)>> {
+        let tree = self.fs.tree.lock();
+        let special = [
+            (&b"."[..], self.inode),
+            (&b".."[..], tree.parent(self.inode)),
+        ];
+
+        let entry = special
+            .iter()
+            .copied()
+            .chain(
+                tree.entries(self.inode)?
+                    .iter()
+                    .map(|(name, &inode)| (&name[..], inode)),
+            )
+            .nth(cookie.0 as usize);
+
+        Ok(entry.map(|(name, inode)| {
+            let dirent = Dirent {
+                d_next: Dircookie(cookie.0 + 1),
+                d_ino: Inode(inode),
+                d_namlen: Dirnamlen(name.len() as u32),
+                d_type: tree.node(inode).filetype(),
+            };
+
+            (dirent, name.to_vec())
+        }))
+    }
+
+    fn path_create_directory(&self, path: &[u8]) -> WasiResult<()> {
+        self.fs.tree.lock().create_dir(self.inode, path).map(|_| ())
+    }
+
+    fn path_filestat_get(&self, flags: Lookupflags, path: &[u8]) -> WasiResult<Filestat> {
+        let tree = self.fs.tree.lock();
+        let inode = tree.lookup(
+            self.inode,
+            path,
+            flags.contains(Lookupflags::SYMLINK_FOLLOW),
+        )?;
+
+        Ok(tree.node(inode).filestat(inode))
+    }
+
+    fn path_filestat_set_times(
+        &self,
+        flags: Lookupflags,
+        path: &[u8],
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    ) -> WasiResult<()> {
+        let mut tree = self.fs.tree.lock();
+        let inode = tree.lookup(
+            self.inode,
+            path,
+            flags.contains(Lookupflags::SYMLINK_FOLLOW),
+        )?;
+
+        tree.set_times(inode, atim, mtim, fst_flags)
+    }
+
+    fn path_link(
+        &self,
+        old_flags: Lookupflags,
+        old_path: &[u8],
+        new_dir: &dyn WasiDirectory,
+        new_path: &[u8],
+    ) -> WasiResult<()> {
+        let new_dir = self.same_fs(new_dir)?;
+
+        self.fs.tree.lock().hard_link(
+            self.inode,
+            old_path,
+            old_flags.contains(Lookupflags::SYMLINK_FOLLOW),
+            new_dir.inode,
+            new_path,
+        )
+    }
+
+    fn path_open(
+        &self,
+        dirflags: Lookupflags,
+        path: &[u8],
+        oflags: Oflags,
+        _rights: Rights,
+        _fdflags: Fdflags,
+    ) -> WasiResult<Opened> {
+        let mut tree = self.fs.tree.lock();
+        let (dir, name) = tree.resolve_parent(self.inode, path, &mut 0)?;
+
+        let inode = match tree.child(dir, name)? {
+            Some(inode) => {
+                let inode = if dirflags.contains(Lookupflags::SYMLINK_FOLLOW) {
+                    tree.follow(dir, inode, &mut 0)?
+                } else {
+                    inode
+                };
+
+                if oflags.contains(Oflags::CREAT | Oflags::EXCL) {
+                    return Err(Errno::Exist);
+                }
+
+                match tree.node(inode).data {
+                    NodeData::Symlink(_) => return Err(Errno::Loop),
+                    NodeData::Directory { .. } => {
+                        if oflags.contains(Oflags::TRUNC) {
+                            return Err(Errno::Isdir);
+                        }
+
+                        return Ok(Opened::Directory(Box::new(MemoryDirectory::new(
+                            &self.fs, &mut tree, inode,
+                        ))));
+                    }
+                    NodeData::File(_) => {
+                        if oflags.contains(Oflags::DIRECTORY) {
+                            return Err(Errno::Notdir);
+                        }
+                        if oflags.contains(Oflags::TRUNC) {
+                            tree.resize(inode, 0)?;
+                            tree.touch(inode);
+                        }
+                    }
+                }
+
+                inode
+            }
+            None => {
+                if !oflags.contains(Oflags::CREAT) {
+                    return Err(Errno::Noent);
+                }
+                if oflags.contains(Oflags::DIRECTORY) {
+                    return Err(Errno::Inval);
+                }
+
+                tree.insert(dir, name, NodeData::File(Vec::new()))?
+            }
+        };
+
+        tree.acquire(inode);
+
+        Ok(Opened::File(Box::new(MemoryFile {
+            fs: self.fs.clone(),
+            inode,
+            position: Mutex::new(0),
+        })))
+    }
+
+    fn path_readlink(&self, path: &[u8]) -> WasiResult<Vec<u8>> {
+        let tree = self.fs.tree.lock();
+
+        tree.read_link(tree.lookup(self.inode, path, false)?)
+    }
+
+    fn path_remove_directory(&self, path: &[u8]) -> WasiResult<()> {
+        self.fs.tree.lock().remove_dir(self.inode, path)
+    }
+
+    fn path_rename(
+        &self,
+        old_path: &[u8],
+        new_dir: &dyn WasiDirectory,
+        new_path: &[u8],
+    ) -> WasiResult<()> {
+        let new_dir = self.same_fs(new_dir)?;
+
+        self.fs
+            .tree
+            .lock()
+            .rename(self.inode, old_path, new_dir.inode, new_path)
+    }
+
+    fn path_symlink(&self, old_path: &[u8], new_path: &[u8]) -> WasiResult<()> {
+        self.fs.tree.lock().symlink(old_path, self.inode, new_path)
+    }
+
+    fn path_unlink_file(&self, path: &[u8]) -> WasiResult<()> {
+        self.fs.tree.lock().remove_file(self.inode, path)
+    }
+}
+
+impl Drop for MemoryDirectory {
+    fn drop(&mut self) {
+        self.fs.tree.lock().release(self.inode);
+    }
+}
+
+impl WasiFile for MemoryFile {
+    fn allocate(&self, offset: Filesize, len: Filesize) -> WasiResult<()> {
+        let end = offset.0.checked_add(len.0).ok_or(Errno::Fbig)?;
+        let mut tree = self.fs.tree.lock();
+
+        if tree.file_size(self.inode) < end {
+            tree.resize(self.inode, end)?;
+            tree.touch(self.inode);
+        }
+
+        Ok(())
+    }
+
+    fn filestat_get(&self) -> WasiResult<Filestat> {
+        Ok(self.fs.tree.lock().node(self.inode).filestat(self.inode))
+    }
+
+    fn filestat_set_size(&self, size: Filesize) -> WasiResult<()> {
+        let mut tree = self.fs.tree.lock();
+
+        tree.resize(self.inode, size.0)?;
+        tree.touch(self.inode);
+
+        Ok(())
+    }
+
+    fn filestat_set_times(
+        &self,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    ) -> WasiResult<()> {
+        self.fs
+            .tree
+            .lock()
+            .set_times(self.inode, atim, mtim, fst_flags)
+    }
+
+    fn pread(&self, iovs: &mut [IoSliceMut<'_>], offset: Filesize) -> WasiResult<Size> {
+        let mut tree = self.fs.tree.lock();
+        let node = tree.node_mut(self.inode);
+
+        Ok(Size(read_at(node.contents_mut(), iovs, offset.0) as u32))
+    }
+
+    fn pwrite(&self, bufs: &[IoSlice<'_>], offset: Filesize) -> WasiResult<Size> {
+        let mut tree = self.fs.tree.lock();
+        let written = tree.write_at(self.inode, bufs, offset.0)?;
+        tree.touch(self.inode);
+
+        Ok(Size(written as u32))
+    }
+
+    fn read(&self, iovs: &mut [IoSliceMut<'_>]) -> WasiResult<Size> {
+        let mut position = self.position.lock();
+        let mut tree = self.fs.tree.lock();
+        let node = tree.node_mut(self.inode);
+        let read = read_at(node.contents_mut(), iovs, *position);
+        *position += read as u64;
+
+        Ok(Size(read as u32))
+    }
+
+    fn write(&self, bufs: &[IoSlice<'_>], append: bool) -> WasiResult<Size> {
+        let mut position = self.position.lock();
+        let mut tree = self.fs.tree.lock();
+
+        if append {
+            *position = tree.file_size(self.inode);
+        }
+
+        let written = tree.write_at(self.inode, bufs, *position)?;
+        *position += written as u64;
+        tree.touch(self.inode);
+
+        Ok(Size(written as u32))
+    }
+
+    fn seek(&self, offset: Filedelta, whence: Whence) -> WasiResult<Filesize> {
+        let mut position = self.position.lock();
+        let base = match whence {
+            Whence::Set => 0,
+            Whence::Cur => *position,
+            Whence::End => self
+                .fs
+                .tree
+                .lock()
+                .node_mut(self.inode)
+                .contents_mut()
+                .len() as u64,
+            _ => return Err(Errno::Inval),
+        };
+
+        let new_position = if offset.0 < 0 {
+            base.checked_sub(offset.0.wrapping_neg() as u64)
+        } else {
+            base.checked_add(offset.0 as u64)
+        };
+        *position = new_position.ok_or(Errno::Inval)?;
+
+        Ok(Filesize(*position))
+    }
+
+    fn tell(&self) -> WasiResult<Filesize> {
+        Ok(Filesize(*self.position.lock()))
+    }
+}
+
+impl Drop for MemoryFile {
+    fn drop(&mut self) {
+        self.fs.tree.lock().release(self.inode);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(dir: &dyn WasiDirectory, path: &str, oflags: Oflags) -> WasiResult<Opened> {
+        dir.path_open(
+            Lookupflags::SYMLINK_FOLLOW,
+            path.as_bytes(),
+            oflags,
+            Rights::all(),
+            Fdflags::empty(),
+        )
+    }
+
+    fn open_file(dir: &dyn WasiDirectory, path: &str) -> Box<dyn WasiFile> {
+        match open(dir, path, Oflags::CREAT).unwrap() {
+            Opened::File(file) => file,
+            Opened::Directory(_) => panic!("opened a directory"),
+        }
+    }
+
+    fn open_dir(dir: &dyn WasiDirectory, path: &str) -> WasiResult<Box<dyn WasiDirectory>> {
+        match open(dir, path, Oflags::DIRECTORY)? {
+            Opened::Directory(dir) => Ok(dir),
+            Opened::File(_) => panic!("opened a file"),
+        }
+    }
+
+    fn read(file: &dyn WasiFile) -> Vec<u8> {
+        let mut buf = [0; 64];
+        let read = file
+            .pread(&mut [IoSliceMut::new(&mut buf)], Filesize(0))
+            .unwrap();
+
+        buf[..read.0 as usize].to_vec()
+    }
+
+    #[test]
+    fn hard_links_share_their_contents() {
+        let fs = MemoryFs::new();
+        let root = MemoryDirectory::root(fs.clone());
+        fs.write("a", "hello").unwrap();
+
+        root.path_link(Lookupflags::empty(), b"a", &root, b"b")
+            .unwrap();
+        assert_eq!(fs.metadata("b").unwrap().nlink, Linkcount(2));
+
+        fs.write("b", "world").unwrap();
+        assert_eq!(fs.read("a").unwrap(), b"world");
+
+        root.path_unlink_file(b"a").unwrap();
+        assert_eq!(fs.read("a").unwrap_err(), Errno::Noent);
+        assert_eq!(fs.read("b").unwrap(), b"world");
+        assert_eq!(fs.metadata("b").unwrap().nlink, Linkcount(1));
+        assert_eq!(
+            root.path_link(Lookupflags::empty(), b"b", &root, b"b")
+                .unwrap_err(),
+            Errno::Exist
+        );
+    }
+
+    #[test]
+    fn rename_moves_and_replaces_entries() {
+        let fs = MemoryFs::new();
+        let root = MemoryDirectory::root(fs.clone());
+        fs.create_dir_all("dir/sub").unwrap();
+        fs.write("a", "a").unwrap();
+        fs.write("dir/b", "b").unwrap();
+
+        root.path_rename(b"a", &root, b"dir/b").unwrap();
+        assert_eq!(fs.read_dir("").unwrap(), vec![b"dir".to_vec()]);
+        assert_eq!(fs.read("dir/b").unwrap(), b"a");
+        assert_eq!(fs.size(), 1);
+
+        assert_eq!(
+            root.path_rename(b"dir", &root, b"dir/sub/dir").unwrap_err(),
+            Errno::Inval
+        );
+        assert_eq!(
+            root.path_rename(b"dir/b", &root, b"dir/sub").unwrap_err(),
+            Errno::Isdir
+        );
+
+        root.path_rename(b"dir/sub", &root, b"sub").unwrap();
+        let sub = open_dir(&root, "sub").unwrap();
+        assert_eq!(
+            sub.path_filestat_get(Lookupflags::empty(), b"../dir/b")
+                .unwrap()
+                .size,
+            Filesize(1)
+        );
+    }
+
+    #[test]
+    fn unlinked_files_stay_readable_while_open() {
+        let fs = MemoryFs::new();
+        let root = MemoryDirectory::root(fs.clone());
+        fs.write("file", "contents").unwrap();
+
+        let file = open_file(&root, "file");
+        root.path_unlink_file(b"file").unwrap();
+
+        assert_eq!(fs.read("file").unwrap_err(), Errno::Noent);
+        assert_eq!(read(&*file), b"contents");
+        assert_eq!(file.filestat_get().unwrap().nlink, Linkcount(0));
+        assert_eq!(fs.size(), 8);
+
+        drop(file);
+        assert_eq!(fs.size(), 0);
+    }
+
+    #[test]
+    fn removed_directories_have_no_parent() {
+        let fs = MemoryFs::new();
+        let root = MemoryDirectory::root(fs.clone());
+        fs.create_dir("dir").unwrap();
+
+        let dir = open_dir(&root, "dir").unwrap();
+        root.path_remove_directory(b"dir").unwrap();
+
+        assert_eq!(open_dir(&*dir, "..").unwrap_err(), Errno::Noent);
+        assert_eq!(dir.path_create_directory(b"sub").unwrap_err(), Errno::Noent);
+        assert_eq!(
+            open(&*dir, "file", Oflags::CREAT).unwrap_err(),
+            Errno::Noent
+        );
+        assert_eq!(open_dir(&root, "..").unwrap_err(), Errno::Notcapable);
+    }
+
+    #[test]
+    fn files_can_not_grow_beyond_the_file_size_limit() {
+        let fs = MemoryFs::new().max_file_size(8);
+        let root = MemoryDirectory::root(fs.clone());
+        let file = open_file(&root, "file");
+
+        file.pwrite(&[IoSlice::new(b"12345678")], Filesize(0))
+            .unwrap();
+        assert_eq!(
+            file.pwrite(&[IoSlice::new(b"9")], Filesize(8)).unwrap_err(),
+            Errno::Fbig
+        );
+        assert_eq!(
+            file.pwrite(&[IoSlice::new(b"9")], Filesize(u64::MAX))
+                .unwrap_err(),
+            Errno::Fbig
+        );
+        assert_eq!(
+            file.allocate(Filesize(1 << 40), Filesize(1)).unwrap_err(),
+            Errno::Fbig
+        );
+        assert_eq!(
+            file.filestat_set_size(Filesize(u64::MAX)).unwrap_err(),
+            Errno::Fbig
+        );
+        assert_eq!(fs.write("other", "123456789").unwrap_err(), Errno::Fbig);
+        assert_eq!(read(&*file), b"12345678");
+
+        file.filestat_set_size(Filesize(2)).unwrap();
+        assert_eq!(read(&*file), b"12");
+        assert_eq!(fs.size(), 2);
+    }
+
+    #[test]
+    fn files_can_not_grow_beyond_the_total_size_limit() {
+        let fs = MemoryFs::new().max_size(10);
+        let root = MemoryDirectory::root(fs.clone());
+        fs.write("a", "123456").unwrap();
+        let file = open_file(&root, "b");
+
+        file.write(&[IoSlice::new(b"1234")], false).unwrap();
+        assert_eq!(
+            file.write(&[IoSlice::new(b"5")], false).unwrap_err(),
+            Errno::Nospc
+        );
+        assert_eq!(
+            file.allocate(Filesize(0), Filesize(5)).unwrap_err(),
+            Errno::Nospc
+        );
+
+        open(&root, "a", Oflags::TRUNC).unwrap();
+        file.allocate(Filesize(0), Filesize(10)).unwrap();
+        assert_eq!(fs.size(), 10);
+        assert_eq!(fs.write("a", "1").unwrap_err(), Errno::Nospc);
+    }
+}
@@ -1,28 +1,58 @@
-#![allow(unused_variables)] // Remove when everything is implemented.
-
+mod blocking_device;
 mod character_device;
-
-use super::atomic::{AtomicFdflags, AtomicRights};
+mod directory;
+mod file;
+mod memory_fs;
+mod pipe;
+mod pollable;
+mod socket;
+
+use self::memory_fs::MemoryDirectory;
+use super::{
+    atomic::{AtomicFdflags, AtomicRights},
+    clock::SharedClock,
+};
 use std::{
-    io::{IoSlice, IoSliceMut},
+    io::{self, IoSlice, IoSliceMut},
     marker::PhantomData,
     ops::Deref,
+    path::Path,
+    sync::atomic::AtomicU64,
 };
 use wasihost_core::{
     wasi_snapshot_preview1::{
-        Advice, Dircookie, Dirent, Errno, Fdflags, Fdstat, Filedelta, Filesize, Filestat, Filetype,
-        Fstflags, Lookupflags, Oflags, Prestat, Riflags, Rights, Roflags, Sdflags, Siflags, Size,
-        Timestamp, WasiResult, Whence,
+        Advice, Dircookie, Dirent, Errno, EventFdReadwrite, Eventtype, Fdflags, Fdstat, Filedelta,
+        Filesize, Filestat, Filetype, Fstflags, Lookupflags, Oflags, Prestat, PrestatDir, Riflags,
+        Rights, Roflags, Sdflags, Siflags, Size, Timestamp, WasiResult, Whence,
     },
     StringRepresentation,
 };
 
+#[cfg(test)]
+pub(crate) use blocking_device::block_on;
+#[allow(unreachable_pub)] // false positive
+pub use blocking_device::{BlockingDevice, PollingCharacterDevice};
+#[allow(unreachable_pub)] // false positive
+pub use character_device::{CaptureDevice, CharacterDevice, ReaderDevice, Stderr, Stdin, Stdout};
+#[allow(unreachable_pub)] // false positive
+pub use directory::{Opened, WasiDirectory};
+#[allow(unreachable_pub)] // false positive
+pub use file::WasiFile;
+#[allow(unreachable_pub)] // false positive
+pub use memory_fs::MemoryFs;
+#[allow(unreachable_pub)] // false positive
+pub use pipe::{pipe, PipeReader, PipeWriter};
+#[allow(unreachable_pub)] // false positive
+pub use pollable::Pollable;
 #[allow(unreachable_pub)] // false positive
-pub use character_device::{CharacterDevice, Stderr, Stdin, Stdout};
+pub use socket::WasiSocket;
 
 #[derive(Debug)]
 enum WasiFdInner {
     CharacterDevice(Box<dyn CharacterDevice>),
+    Directory(Box<dyn WasiDirectory>),
+    File(Box<dyn WasiFile>),
+    Socket(Box<dyn WasiSocket>),
 }
 
 /// A WASI file descriptor.
@@ -32,10 +62,24 @@ pub struct WasiFd<S> {
     flags: AtomicFdflags,
     rights: AtomicRights,
     rights_inheriting: AtomicRights,
+    preopen_name: Option<Vec<u8>>,
+    bytes_written: AtomicU64,
     _phantom: PhantomData<fn(S) -> S>,
 }
 
 impl<S: StringRepresentation> WasiFd<S> {
+    fn new(inner: WasiFdInner, flags: Fdflags, rights: Rights, rights_inheriting: Rights) -> Self {
+        WasiFd {
+            inner,
+            flags: AtomicFdflags::new(flags),
+            rights: AtomicRights::new(rights),
+            rights_inheriting: AtomicRights::new(rights_inheriting),
+            preopen_name: None,
+            bytes_written: AtomicU64::new(0),
+            _phantom: PhantomData,
+        }
+    }
+
     /// Creates a WASI file descriptor from a character device.
     pub fn from_character_device<C: CharacterDevice>(
         character_device: C,
@@ -43,17 +87,67 @@ impl<S: StringRepresentation> WasiFd<S> {
         rights: Rights,
     ) -> Self {
         let inner = WasiFdInner::CharacterDevice(Box::new(character_device));
-        let flags = AtomicFdflags::new(flags);
-        let rights = AtomicRights::new(rights);
-        let rights_inheriting = AtomicRights::new(Rights::empty());
+
+        Self::new(inner, flags, rights, Rights::empty())
+    }
+
+    /// Creates a WASI file descriptor from a regular file.
+    pub fn from_file<F: WasiFile>(file: F, flags: Fdflags, rights: Rights) -> Self {
+        let inner = WasiFdInner::File(Box::new(file));
+
+        Self::new(inner, flags, rights, Rights::empty())
+    }
+
+    /// Creates a WASI file descriptor from a socket.
+    pub fn from_socket<T: WasiSocket>(socket: T, flags: Fdflags, rights: Rights) -> Self {
+        let inner = WasiFdInner::Socket(Box::new(socket));
+
+        Self::new(inner, flags, rights, Rights::empty())
+    }
+
+    /// Creates a WASI file descriptor for a directory that is preopened under the name `name`.
+    pub fn from_directory<D: WasiDirectory>(
+        directory: D,
+        name: impl Into<Vec<u8>>,
+        rights: Rights,
+        rights_inheriting: Rights,
+    ) -> Self {
+        let inner = WasiFdInner::Directory(Box::new(directory));
 
         WasiFd {
-            inner,
-            flags,
+            preopen_name: Some(name.into()),
+            ..Self::new(inner, Fdflags::empty(), rights, rights_inheriting)
+        }
+    }
+
+    /// Creates a WASI file descriptor for a directory on the host that is preopened under
+    /// the name `name`. Paths resolved relative to this file descriptor or any file descriptor
+    /// opened from it can not leave the directory.
+    pub fn from_host_directory(
+        path: impl AsRef<Path>,
+        name: impl Into<Vec<u8>>,
+        rights: Rights,
+        rights_inheriting: Rights,
+    ) -> io::Result<Self> {
+        let directory = directory::Directory::open_root(path.as_ref())?;
+
+        Ok(Self::from_directory(
+            directory,
+            name,
             rights,
             rights_inheriting,
-            _phantom: PhantomData,
-        }
+        ))
+    }
+
+    /// Creates a WASI file descriptor for the root directory of an in-memory file system that
+    /// is preopened under the name `name`.
+    pub fn from_memory_fs(
+        fs: MemoryFs,
+        name: impl Into<Vec<u8>>,
+        rights: Rights,
+        rights_inheriting: Rights,
+    ) -> Self {
+        Self::from_directory(MemoryDirectory::root(fs), name, rights, rights_inheriting)
     }
 
     fn check_rights(&self, required: Rights) -> WasiResult<()> {
@@ -68,9 +162,45 @@ impl<S: StringRepresentation> WasiFd<S> {
         }
     }
 
+    /// Counts the bytes written to this file descriptor, to enforce resource limits.
+    pub(super) fn bytes_written(&self) -> &AtomicU64 {
+        &self.bytes_written
+    }
+
+    /// Returns the size of the regular file, or `None` for other file descriptors.
+    pub(super) fn file_size(&self) -> Option<u64> {
+        match self.inner {
+            WasiFdInner::File(ref f) => f.filestat_get().ok().map(|stat| stat.size.0),
+            _ => None,
+        }
+    }
+
+    /// Returns the position `write` writes to in a regular file, or `None` if it appends or
+    /// this is not a regular file.
+    pub(super) fn write_position(&self) -> Option<u64> {
+        match self.inner {
+            WasiFdInner::File(ref f) if !self.flags.get().contains(Fdflags::APPEND) => {
+                f.tell().ok().map(|position| position.0)
+            }
+            _ => None,
+        }
+    }
+
     fn get_filetype(&self) -> Filetype {
         match self.inner {
             WasiFdInner::CharacterDevice(_) => Filetype::CharacterDevice,
+            WasiFdInner::Directory(_) => Filetype::Directory,
+            WasiFdInner::File(_) => Filetype::RegularFile,
+            WasiFdInner::Socket(ref s) => s.filetype(),
+        }
+    }
+
+    fn get_directory(&self) -> WasiResult<&dyn WasiDirectory> {
+        match self.inner {
+            WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::Directory(ref d) => Ok(&**d),
+            WasiFdInner::File(_) => Err(Errno::Notdir),
+            WasiFdInner::Socket(_) => Err(Errno::Notdir),
         }
     }
 
@@ -79,6 +209,9 @@ impl<S: StringRepresentation> WasiFd<S> {
 
         match self.inner {
             WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::Directory(_) => Err(Errno::Isdir),
+            WasiFdInner::File(ref f) => f.advise(offset, len, advice),
+            WasiFdInner::Socket(_) => Err(Errno::Spipe),
         }
     }
 
@@ -87,6 +220,9 @@ impl<S: StringRepresentation> WasiFd<S> {
 
         match self.inner {
             WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::Directory(_) => Err(Errno::Isdir),
+            WasiFdInner::File(ref f) => f.allocate(offset, len),
+            WasiFdInner::Socket(_) => Err(Errno::Notsup),
         }
     }
 
@@ -95,6 +231,9 @@ impl<S: StringRepresentation> WasiFd<S> {
 
         match self.inner {
             WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::Directory(_) => Err(Errno::Notsup),
+            WasiFdInner::File(ref f) => f.datasync(),
+            WasiFdInner::Socket(_) => Err(Errno::Notsup),
         }
     }
 
@@ -110,6 +249,10 @@ impl<S: StringRepresentation> WasiFd<S> {
     pub(super) fn fdstat_set_flags(&self, flags: Fdflags) -> WasiResult<()> {
         self.check_rights(Rights::FD_FDSTAT_SET_FLAGS)?;
 
+        if let WasiFdInner::File(ref f) = self.inner {
+            f.fdstat_set_flags(flags)?;
+        }
+
         self.flags.set(flags);
         Ok(())
     }
@@ -142,6 +285,9 @@ impl<S: StringRepresentation> WasiFd<S> {
 
         match self.inner {
             WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::Directory(ref d) => d.filestat_get(),
+            WasiFdInner::File(ref f) => f.filestat_get(),
+            WasiFdInner::Socket(_) => Err(Errno::Notsup),
         }
     }
 
@@ -150,6 +296,9 @@ impl<S: StringRepresentation> WasiFd<S> {
 
         match self.inner {
             WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::Directory(_) => Err(Errno::Isdir),
+            WasiFdInner::File(ref f) => f.filestat_set_size(size),
+            WasiFdInner::Socket(_) => Err(Errno::Notsup),
         }
     }
 
@@ -163,6 +312,9 @@ impl<S: StringRepresentation> WasiFd<S> {
 
         match self.inner {
             WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::Directory(ref d) => d.filestat_set_times(atim, mtim, fst_flags),
+            WasiFdInner::File(ref f) => f.filestat_set_times(atim, mtim, fst_flags),
+            WasiFdInner::Socket(_) => Err(Errno::Notsup),
         }
     }
 
@@ -171,18 +323,35 @@ impl<S: StringRepresentation> WasiFd<S> {
 
         match self.inner {
             WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::Directory(_) => Err(Errno::Isdir),
+            WasiFdInner::File(ref f) => f.pread(iovs, offset),
+            WasiFdInner::Socket(_) => Err(Errno::Spipe),
         }
     }
 
     pub(super) fn prestat_get(&self) -> WasiResult<Prestat> {
         match self.inner {
             WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::Directory(_) => match self.preopen_name {
+                Some(ref name) => Ok(Prestat::Dir(PrestatDir {
+                    pr_name_len: Size(name.len() as u32),
+                })),
+                None => Err(Errno::Badf),
+            },
+            WasiFdInner::File(_) => Err(Errno::Badf),
+            WasiFdInner::Socket(_) => Err(Errno::Badf),
         }
     }
 
     pub(super) fn prestat_dir_name(&self) -> WasiResult<S> {
         match self.inner {
             WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::Directory(_) => match self.preopen_name {
+                Some(ref name) => S::from_bytes(name.clone()).map_err(|_| Errno::Inval),
+                None => Err(Errno::Badf),
+            },
+            WasiFdInner::File(_) => Err(Errno::Badf),
+            WasiFdInner::Socket(_) => Err(Errno::Badf),
         }
     }
 
@@ -191,6 +360,9 @@ impl<S: StringRepresentation> WasiFd<S> {
 
         match self.inner {
             WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::Directory(_) => Err(Errno::Isdir),
+            WasiFdInner::File(ref f) => f.pwrite(bufs, offset),
+            WasiFdInner::Socket(_) => Err(Errno::Spipe),
         }
     }
 
@@ -199,15 +371,23 @@ impl<S: StringRepresentation> WasiFd<S> {
 
         match self.inner {
             WasiFdInner::CharacterDevice(ref d) => d.read(iovs),
+            WasiFdInner::Directory(_) => Err(Errno::Isdir),
+            WasiFdInner::File(ref f) => f.read(iovs),
+            WasiFdInner::Socket(ref s) => s.recv(iovs, Riflags::empty()).map(|(size, _)| size),
         }
     }
 
     pub(super) fn readdir(&self, cookie: Dircookie) -> WasiResult<Option<(Dirent, S)>> {
         self.check_rights(Rights::FD_READDIR)?;
 
-        match self.inner {
-            WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
-        }
+        self.get_directory()?
+            .readdir(cookie)?
+            .map(|(dirent, name)| {
+                S::from_bytes(name)
+                    .map(|name| (dirent, name))
+                    .map_err(|_| Errno::Inval)
+            })
+            .transpose()
     }
 
     pub(super) fn seek(&self, offset: Filedelta, whence: Whence) -> WasiResult<Filesize> {
@@ -225,6 +405,9 @@ impl<S: StringRepresentation> WasiFd<S> {
 
         match self.inner {
             WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::Directory(_) => Err(Errno::Isdir),
+            WasiFdInner::File(ref f) => f.seek(offset, whence),
+            WasiFdInner::Socket(_) => Err(Errno::Spipe),
         }
     }
 
@@ -233,6 +416,9 @@ impl<S: StringRepresentation> WasiFd<S> {
 
         match self.inner {
             WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::Directory(_) => Err(Errno::Notsup),
+            WasiFdInner::File(ref f) => f.sync(),
+            WasiFdInner::Socket(_) => Err(Errno::Notsup),
         }
     }
 
@@ -247,6 +433,9 @@ impl<S: StringRepresentation> WasiFd<S> {
 
         match self.inner {
             WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::Directory(_) => Err(Errno::Isdir),
+            WasiFdInner::File(ref f) => f.tell(),
+            WasiFdInner::Socket(_) => Err(Errno::Spipe),
         }
     }
 
@@ -255,15 +444,17 @@ impl<S: StringRepresentation> WasiFd<S> {
 
         match self.inner {
             WasiFdInner::CharacterDevice(ref d) => d.write(bufs),
+            WasiFdInner::Directory(_) => Err(Errno::Isdir),
+            WasiFdInner::File(ref f) => f.write(bufs, self.flags.get().contains(Fdflags::APPEND)),
+            WasiFdInner::Socket(ref s) => s.send(bufs, Siflags(0)),
         }
     }
 
     pub(super) fn path_create_directory(&self, path: &<S as Deref>::Target) -> WasiResult<()> {
         self.check_rights(Rights::PATH_CREATE_DIRECTORY)?;
 
-        match self.inner {
-            WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
-        }
+        self.get_directory()?
+            .path_create_directory(S::target_as_bytes(path))
     }
 
     pub(super) fn path_filestat_get(
@@ -273,9 +464,8 @@ impl<S: StringRepresentation> WasiFd<S> {
     ) -> WasiResult<Filestat> {
         self.check_rights(Rights::PATH_FILESTAT_GET)?;
 
-        match self.inner {
-            WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
-        }
+        self.get_directory()?
+            .path_filestat_get(flags, S::target_as_bytes(path))
     }
 
     pub(super) fn path_filestat_set_times(
@@ -288,9 +478,13 @@ impl<S: StringRepresentation> WasiFd<S> {
     ) -> WasiResult<()> {
         self.check_rights(Rights::PATH_FILESTAT_SET_TIMES)?;
 
-        match self.inner {
-            WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
-        }
+        self.get_directory()?.path_filestat_set_times(
+            flags,
+            S::target_as_bytes(path),
+            atim,
+            mtim,
+            fst_flags,
+        )
     }
 
     pub(super) fn path_open(
@@ -329,9 +523,24 @@ impl<S: StringRepresentation> WasiFd<S> {
             Self::check_rights_with(rights_inheriting, fs_rights_inheriting)?;
         }
 
-        match self.inner {
-            WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
-        }
+        let opened = self.get_directory()?.path_open(
+            dirflags,
+            S::target_as_bytes(path),
+            oflags,
+            fs_rights_base,
+            fdflags,
+        )?;
+        let inner = match opened {
+            Opened::Directory(directory) => WasiFdInner::Directory(directory),
+            Opened::File(file) => WasiFdInner::File(file),
+        };
+
+        Ok(Self::new(
+            inner,
+            fdflags,
+            fs_rights_base,
+            fs_rights_inheriting,
+        ))
     }
 
     pub(super) fn path_link(
@@ -344,25 +553,29 @@ impl<S: StringRepresentation> WasiFd<S> {
         self.check_rights(Rights::PATH_LINK_SOURCE)?;
         new_fd.check_rights(Rights::PATH_LINK_TARGET)?;
 
-        match self.inner {
-            WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
-        }
+        self.get_directory()?.path_link(
+            old_flags,
+            S::target_as_bytes(old_path),
+            new_fd.get_directory()?,
+            S::target_as_bytes(new_path),
+        )
     }
 
     pub(super) fn path_readlink(&self, path: &<S as Deref>::Target) -> WasiResult<S> {
         self.check_rights(Rights::PATH_READLINK)?;
 
-        match self.inner {
-            WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
-        }
+        let target = self
+            .get_directory()?
+            .path_readlink(S::target_as_bytes(path))?;
+
+        S::from_bytes(target).map_err(|_| Errno::Inval)
     }
 
     pub(super) fn path_remove_directory(&self, path: &<S as Deref>::Target) -> WasiResult<()> {
         self.check_rights(Rights::PATH_REMOVE_DIRECTORY)?;
 
-        match self.inner {
-            WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
-        }
+        self.get_directory()?
+            .path_remove_directory(S::target_as_bytes(path))
     }
 
     pub(super) fn path_rename(
@@ -374,9 +587,11 @@ impl<S: StringRepresentation> WasiFd<S> {
         self.check_rights(Rights::PATH_RENAME_SOURCE)?;
         new_fd.check_rights(Rights::PATH_RENAME_TARGET)?;
 
-        match self.inner {
-            WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
-        }
+        self.get_directory()?.path_rename(
+            S::target_as_bytes(old_path),
+            new_fd.get_directory()?,
+            S::target_as_bytes(new_path),
+        )
     }
 
     pub(super) fn path_symlink(
@@ -386,16 +601,41 @@ impl<S: StringRepresentation> WasiFd<S> {
     ) -> WasiResult<()> {
         self.check_rights(Rights::PATH_SYMLINK)?;
 
-        match self.inner {
-            WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
-        }
+        self.get_directory()?
+            .path_symlink(S::target_as_bytes(old_path), S::target_as_bytes(new_path))
     }
 
     pub(super) fn path_unlink_file(&self, path: &<S as Deref>::Target) -> WasiResult<()> {
         self.check_rights(Rights::PATH_UNLINK_FILE)?;
 
+        self.get_directory()?
+            .path_unlink_file(S::target_as_bytes(path))
+    }
+
+    /// Lets the in-memory file system this file descriptor is a directory of take timestamps
+    /// from `clock`.
+    pub(super) fn share_clock(&self, clock: &SharedClock) {
+        if let WasiFdInner::Directory(ref d) = self.inner {
+            if let Some(d) = d.as_any().downcast_ref::<MemoryDirectory>() {
+                d.fs().set_clock(clock.clone());
+            }
+        }
+    }
+
+    /// Checks whether the file descriptor is ready for the event type `r#type`, returning
+    /// `None` if it is not.
+    pub(super) fn poll(&self, r#type: Eventtype) -> WasiResult<Option<EventFdReadwrite>> {
+        match r#type {
+            Eventtype::FdRead => self.check_rights(Rights::FD_READ)?,
+            Eventtype::FdWrite => self.check_rights(Rights::FD_WRITE)?,
+            _ => return Err(Errno::Inval),
+        }
+
         match self.inner {
-            WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::CharacterDevice(ref d) => pollable::poll(&**d, r#type),
+            WasiFdInner::Directory(_) => Err(Errno::Isdir),
+            WasiFdInner::File(ref f) => pollable::poll(&**f, r#type),
+            WasiFdInner::Socket(ref s) => pollable::poll(&**s, r#type),
         }
     }
 
@@ -408,6 +648,9 @@ impl<S: StringRepresentation> WasiFd<S> {
 
         match self.inner {
             WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::Directory(_) => Err(Errno::Notsock),
+            WasiFdInner::File(_) => Err(Errno::Notsock),
+            WasiFdInner::Socket(ref s) => s.recv(ri_data, ri_flags),
         }
     }
 
@@ -416,6 +659,9 @@ impl<S: StringRepresentation> WasiFd<S> {
 
         match self.inner {
             WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::Directory(_) => Err(Errno::Notsock),
+            WasiFdInner::File(_) => Err(Errno::Notsock),
+            WasiFdInner::Socket(ref s) => s.send(si_data, si_flags),
         }
     }
 
@@ -424,6 +670,296 @@ impl<S: StringRepresentation> WasiFd<S> {
 
         match self.inner {
             WasiFdInner::CharacterDevice(_) => Err(Errno::Notsup),
+            WasiFdInner::Directory(_) => Err(Errno::Notsock),
+            WasiFdInner::File(_) => Err(Errno::Notsock),
+            WasiFdInner::Socket(ref s) => s.shutdown(how),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use std::any::Any;
+    use wasihost_core::wasi_snapshot_preview1::{Device, Inode, Linkcount};
+
+    fn filestat(filetype: Filetype, size: usize) -> Filestat {
+        Filestat {
+            dev: Device(0),
+            ino: Inode(0),
+            filetype,
+            nlink: Linkcount(1),
+            size: Filesize(size as u64),
+            atim: Timestamp(0),
+            mtim: Timestamp(0),
+            ctim: Timestamp(0),
+        }
+    }
+
+    /// A read-only file that only implements the required methods.
+    #[derive(Debug)]
+    struct StaticFile {
+        contents: &'static [u8],
+        position: Mutex<usize>,
+    }
+
+    impl WasiFile for StaticFile {
+        fn filestat_get(&self) -> WasiResult<Filestat> {
+            Ok(filestat(Filetype::RegularFile, self.contents.len()))
+        }
+
+        fn pread(&self, iovs: &mut [IoSliceMut<'_>], offset: Filesize) -> WasiResult<Size> {
+            let start = (offset.0 as usize).min(self.contents.len());
+            let len = iovs[0].len().min(self.contents.len() - start);
+            iovs[0][..len].copy_from_slice(&self.contents[start..start + len]);
+
+            Ok(Size(len as u32))
+        }
+
+        fn read(&self, iovs: &mut [IoSliceMut<'_>]) -> WasiResult<Size> {
+            let mut position = self.position.lock();
+            let read = self.pread(iovs, Filesize(*position as u64))?;
+            *position += read.0 as usize;
+
+            Ok(read)
         }
+
+        fn seek(&self, offset: Filedelta, whence: Whence) -> WasiResult<Filesize> {
+            let mut position = self.position.lock();
+            match whence {
+                Whence::Set => *position = offset.0 as usize,
+                Whence::Cur => *position = (*position as i64 + offset.0) as usize,
+                _ => return Err(Errno::Inval),
+            }
+
+            Ok(Filesize(*position as u64))
+        }
+    }
+
+    /// A read-only directory that only implements the required methods.
+    #[derive(Debug)]
+    struct StaticDirectory;
+
+    impl WasiDirectory for StaticDirectory {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn filestat_get(&self) -> WasiResult<Filestat> {
+            Ok(filestat(Filetype::Directory, 1))
+        }
+
+        fn readdir(&self, _cookie: Dircookie) -> WasiResult<Option<(Dirent, Vec<u8>)>> {
+            Ok(None)
+        }
+
+        fn path_filestat_get(&self, _flags: Lookupflags, path: &[u8]) -> WasiResult<Filestat> {
+            match path {
+                b"hello" => Ok(filestat(Filetype::RegularFile, 5)),
+                _ => Err(Errno::Noent),
+            }
+        }
+
+        fn path_open(
+            &self,
+            _dirflags: Lookupflags,
+            path: &[u8],
+            _oflags: Oflags,
+            _rights: Rights,
+            _fdflags: Fdflags,
+        ) -> WasiResult<Opened> {
+            match path {
+                b"hello" => Ok(Opened::File(Box::new(StaticFile {
+                    contents: b"hello",
+                    position: Mutex::new(0),
+                }))),
+                _ => Err(Errno::Noent),
+            }
+        }
+    }
+
+    /// A character device that only implements the required methods.
+    #[derive(Debug)]
+    struct NullDevice;
+
+    impl CharacterDevice for NullDevice {
+        fn read(&self, _iovs: &mut [IoSliceMut<'_>]) -> WasiResult<Size> {
+            Ok(Size(0))
+        }
+
+        fn write(&self, bufs: &[IoSlice<'_>]) -> WasiResult<Size> {
+            Ok(Size(bufs.iter().map(|buf| buf.len() as u32).sum()))
+        }
+    }
+
+    impl Pollable for NullDevice {}
+
+    /// A character device that never has data to read.
+    #[derive(Debug)]
+    struct EmptyDevice;
+
+    impl CharacterDevice for EmptyDevice {
+        fn read(&self, _iovs: &mut [IoSliceMut<'_>]) -> WasiResult<Size> {
+            Err(Errno::Again)
+        }
+
+        fn write(&self, _bufs: &[IoSlice<'_>]) -> WasiResult<Size> {
+            Err(Errno::Badf)
+        }
+    }
+
+    impl Pollable for EmptyDevice {
+        fn poll_read(&self) -> WasiResult<Option<EventFdReadwrite>> {
+            Ok(None)
+        }
+    }
+
+    fn preopen(rights_inheriting: Rights) -> WasiFd<String> {
+        WasiFd::from_directory(StaticDirectory, "/static", Rights::all(), rights_inheriting)
+    }
+
+    fn open(dir: &WasiFd<String>, path: &str, rights: Rights) -> WasiResult<WasiFd<String>> {
+        dir.path_open(
+            Lookupflags::empty(),
+            path,
+            Oflags::empty(),
+            rights,
+            Rights::empty(),
+            Fdflags::empty(),
+        )
+    }
+
+    #[test]
+    fn opens_files_of_custom_directories() {
+        let dir = preopen(Rights::all());
+        let file = open(&dir, "hello", Rights::FD_READ | Rights::FD_SEEK).unwrap();
+        let mut buf = [0; 8];
+
+        assert_eq!(
+            file.fdstat_get().unwrap().fs_filetype,
+            Filetype::RegularFile
+        );
+        assert_eq!(
+            file.read(&mut [IoSliceMut::new(&mut buf)]).unwrap(),
+            Size(5)
+        );
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(file.seek(Filedelta(-2), Whence::Cur).unwrap(), Filesize(3));
+        assert_eq!(
+            dir.path_filestat_get(Lookupflags::empty(), "hello")
+                .unwrap()
+                .size,
+            Filesize(5)
+        );
+        assert_eq!(
+            open(&dir, "missing", Rights::FD_READ).unwrap_err(),
+            Errno::Noent
+        );
+    }
+
+    #[test]
+    fn reports_where_writes_grow_files() {
+        let dir = preopen(Rights::all());
+        let file = open(&dir, "hello", Rights::FD_SEEK | Rights::FD_FDSTAT_SET_FLAGS).unwrap();
+
+        assert_eq!(file.file_size(), Some(5));
+        file.seek(Filedelta(7), Whence::Set).unwrap();
+        assert_eq!(file.write_position(), Some(7));
+        assert_eq!(dir.file_size(), None);
+        assert_eq!(dir.write_position(), None);
+
+        file.fdstat_set_flags(Fdflags::APPEND).unwrap();
+        assert_eq!(file.write_position(), None);
+    }
+
+    #[test]
+    fn optional_operations_are_not_supported_by_default() {
+        let dir = preopen(Rights::all());
+        let file = open(&dir, "hello", Rights::all()).unwrap();
+
+        assert_eq!(
+            file.write(&[IoSlice::new(b"x")]).unwrap_err(),
+            Errno::Notsup
+        );
+        assert_eq!(
+            file.pwrite(&[IoSlice::new(b"x")], Filesize(0)).unwrap_err(),
+            Errno::Notsup
+        );
+        assert_eq!(
+            file.filestat_set_size(Filesize(0)).unwrap_err(),
+            Errno::Notsup
+        );
+        assert_eq!(file.sync(), Ok(()));
+        assert_eq!(file.tell(), Ok(Filesize(0)));
+        assert_eq!(dir.path_create_directory("sub").unwrap_err(), Errno::Notsup);
+        assert_eq!(dir.path_unlink_file("hello").unwrap_err(), Errno::Notsup);
+        assert_eq!(dir.path_readlink("hello").unwrap_err(), Errno::Notsup);
+    }
+
+    #[test]
+    fn opened_files_are_limited_by_the_rights_of_the_directory() {
+        let dir = preopen(Rights::FD_READ);
+        let file = open(&dir, "hello", Rights::FD_READ).unwrap();
+
+        assert_eq!(
+            open(&dir, "hello", Rights::FD_WRITE).unwrap_err(),
+            Errno::Notcapable
+        );
+        assert_eq!(
+            file.write(&[IoSlice::new(b"x")]).unwrap_err(),
+            Errno::Notcapable
+        );
+        assert_eq!(file.tell().unwrap_err(), Errno::Notcapable);
+    }
+
+    #[test]
+    fn character_devices_are_ready_by_default() {
+        let fd =
+            WasiFd::<String>::from_character_device(NullDevice, Fdflags::empty(), Rights::all());
+
+        assert_eq!(
+            fd.poll(Eventtype::FdRead)
+                .unwrap()
+                .map(|event| event.nbytes),
+            Some(Filesize(0))
+        );
+        assert_eq!(
+            fd.poll(Eventtype::FdWrite)
+                .unwrap()
+                .map(|event| event.nbytes),
+            Some(Filesize(0))
+        );
+    }
+
+    #[test]
+    fn polls_character_devices() {
+        let fd =
+            WasiFd::<String>::from_character_device(EmptyDevice, Fdflags::empty(), Rights::FD_READ);
+
+        assert!(fd.poll(Eventtype::FdRead).unwrap().is_none());
+        assert_eq!(fd.poll(Eventtype::FdWrite).unwrap_err(), Errno::Notcapable);
+        assert_eq!(fd.poll(Eventtype::Clock).unwrap_err(), Errno::Inval);
+    }
+
+    #[test]
+    fn polls_files_and_directories() {
+        let dir = preopen(Rights::all());
+        let file = open(&dir, "hello", Rights::FD_READ | Rights::FD_WRITE).unwrap();
+
+        file.read(&mut [IoSliceMut::new(&mut [0; 2])]).unwrap();
+        assert_eq!(
+            file.poll(Eventtype::FdRead)
+                .unwrap()
+                .map(|event| event.nbytes),
+            Some(Filesize(3))
+        );
+        assert_eq!(
+            file.poll(Eventtype::FdWrite)
+                .unwrap()
+                .map(|event| event.nbytes),
+            Some(Filesize(0))
+        );
+        assert_eq!(dir.poll(Eventtype::FdRead).unwrap_err(), Errno::Isdir);
     }
 }
@@ -0,0 +1,299 @@
+use super::{super::cancel, pollable, CharacterDevice, Pollable};
+use parking_lot::{Condvar, Mutex};
+use std::{
+    collections::VecDeque,
+    io::{IoSlice, IoSliceMut},
+    sync::Arc,
+};
+use wasihost_core::wasi_snapshot_preview1::{
+    Errno, EventFdReadwrite, Eventrwflags, Filesize, Size, WasiResult,
+};
+
+#[derive(Debug)]
+struct State {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    reader_open: bool,
+    writer_open: bool,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    /// Notified when data was written or the writer was dropped.
+    readable: Condvar,
+    /// Notified when data was read or the reader was dropped.
+    writable: Condvar,
+}
+
+/// Creates an in-process pipe that buffers up to `capacity` bytes.
+///
+/// Reading from the [`PipeReader`](struct.PipeReader.html) blocks until data is available and
+/// returns end of file once the [`PipeWriter`](struct.PipeWriter.html) has been dropped and the
+/// buffer is empty. Writing blocks while the buffer is full and fails with `Errno::Pipe` once
+/// the reader has been dropped.
+///
+/// The ends are usually installed as standard input and output of two WASI hosts that run on
+/// separate threads.
+pub fn pipe(capacity: usize) -> (PipeReader, PipeWriter) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            buffer: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            reader_open: true,
+            writer_open: true,
+        }),
+        readable: Condvar::new(),
+        writable: Condvar::new(),
+    });
+
+    (
+        PipeReader {
+            shared: shared.clone(),
+        },
+        PipeWriter { shared },
+    )
+}
+
+/// The reading end of a pipe created by [`pipe`](fn.pipe.html).
+#[derive(Debug)]
+pub struct PipeReader {
+    shared: Arc<Shared>,
+}
+
+impl CharacterDevice for PipeReader {
+    fn read(&self, iovs: &mut [IoSliceMut<'_>]) -> WasiResult<Size> {
+        let mut state = self.shared.state.lock();
+
+        while state.buffer.is_empty() && state.writer_open {
+            cancel::wait(&self.shared.readable, &mut state);
+        }
+
+        let mut total = 0;
+
+        for iov in iovs.iter_mut() {
+            let (front, back) = state.buffer.as_slices();
+            let mut read = 0;
+
+            for slice in [front, back].iter() {
+                let len = slice.len().min(iov.len() - read);
+                iov[read..read + len].copy_from_slice(&slice[..len]);
+                read += len;
+            }
+
+            state.buffer.drain(..read);
+            total += read;
+
+            if read < iov.len() {
+                break;
+            }
+        }
+
+        if total > 0 {
+            self.shared.writable.notify_all();
+        }
+
+        Ok(Size(total as u32))
+    }
+
+    fn write(&self, _bufs: &[IoSlice<'_>]) -> WasiResult<Size> {
+        Err(Errno::Badf)
+    }
+}
+
+impl Pollable for PipeReader {
+    fn poll_read(&self) -> WasiResult<Option<EventFdReadwrite>> {
+        let state = self.shared.state.lock();
+
+        if !state.writer_open {
+            Ok(Some(EventFdReadwrite {
+                nbytes: Filesize(state.buffer.len() as u64),
+                flags: Eventrwflags::FD_READWRITE_HANGUP,
+            }))
+        } else if !state.buffer.is_empty() {
+            Ok(Some(pollable::ready(state.buffer.len() as u64)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn poll_write(&self) -> WasiResult<Option<EventFdReadwrite>> {
+        Err(Errno::Badf)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+
+        state.reader_open = false;
+        state.buffer.clear();
+        self.shared.writable.notify_all();
+    }
+}
+
+/// The writing end of a pipe created by [`pipe`](fn.pipe.html).
+#[derive(Debug)]
+pub struct PipeWriter {
+    shared: Arc<Shared>,
+}
+
+impl CharacterDevice for PipeWriter {
+    fn read(&self, _iovs: &mut [IoSliceMut<'_>]) -> WasiResult<Size> {
+        Err(Errno::Badf)
+    }
+
+    fn write(&self, bufs: &[IoSlice<'_>]) -> WasiResult<Size> {
+        let mut state = self.shared.state.lock();
+        let mut total = 0;
+
+        for buf in bufs {
+            let mut buf = &buf[..];
+
+            while !buf.is_empty() {
+                while state.reader_open && state.buffer.len() == state.capacity {
+                    cancel::wait(&self.shared.writable, &mut state);
+                }
+
+                if !state.reader_open {
+                    // Report the data written so far, the next write fails.
+                    return if total > 0 {
+                        Ok(Size(total as u32))
+                    } else {
+                        Err(Errno::Pipe)
+                    };
+                }
+
+                let len = buf.len().min(state.capacity - state.buffer.len());
+                state.buffer.extend(&buf[..len]);
+                self.shared.readable.notify_all();
+
+                buf = &buf[len..];
+                total += len;
+            }
+        }
+
+        Ok(Size(total as u32))
+    }
+}
+
+impl Pollable for PipeWriter {
+    fn poll_read(&self) -> WasiResult<Option<EventFdReadwrite>> {
+        Err(Errno::Badf)
+    }
+
+    fn poll_write(&self) -> WasiResult<Option<EventFdReadwrite>> {
+        let state = self.shared.state.lock();
+
+        if !state.reader_open {
+            Ok(Some(EventFdReadwrite {
+                nbytes: Filesize(0),
+                flags: Eventrwflags::FD_READWRITE_HANGUP,
+            }))
+        } else if state.buffer.len() < state.capacity {
+            Ok(Some(pollable::ready(
+                (state.capacity - state.buffer.len()) as u64,
+            )))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.shared.state.lock().writer_open = false;
+        self.shared.readable.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn read(reader: &PipeReader, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        let read = reader.read(&mut [IoSliceMut::new(&mut buf)]).unwrap();
+        buf.truncate(read.0 as usize);
+
+        buf
+    }
+
+    #[test]
+    fn transfers_data_in_order() {
+        let (reader, writer) = pipe(16);
+
+        writer
+            .write(&[IoSlice::new(b"hello "), IoSlice::new(b"world")])
+            .unwrap();
+        assert_eq!(read(&reader, 4), b"hell");
+        assert_eq!(read(&reader, 16), b"o world");
+    }
+
+    #[test]
+    fn reports_end_of_file_after_the_writer_is_dropped() {
+        let (reader, writer) = pipe(16);
+
+        writer.write(&[IoSlice::new(b"rest")]).unwrap();
+        drop(writer);
+
+        assert_eq!(reader.poll_read().unwrap().unwrap().nbytes, Filesize(4));
+        assert_eq!(read(&reader, 16), b"rest");
+        assert_eq!(read(&reader, 16), b"");
+        assert_eq!(
+            reader.poll_read().unwrap().unwrap().flags,
+            Eventrwflags::FD_READWRITE_HANGUP
+        );
+    }
+
+    #[test]
+    fn fails_to_write_after_the_reader_is_dropped() {
+        let (reader, writer) = pipe(16);
+        drop(reader);
+
+        assert_eq!(
+            writer.write(&[IoSlice::new(b"lost")]).unwrap_err(),
+            Errno::Pipe
+        );
+        assert_eq!(
+            writer.poll_write().unwrap().unwrap().flags,
+            Eventrwflags::FD_READWRITE_HANGUP
+        );
+    }
+
+    #[test]
+    fn reports_readiness_by_buffered_data() {
+        let (reader, writer) = pipe(4);
+
+        assert!(reader.poll_read().unwrap().is_none());
+        assert_eq!(writer.poll_write().unwrap().unwrap().nbytes, Filesize(4));
+
+        writer.write(&[IoSlice::new(b"full")]).unwrap();
+        assert_eq!(reader.poll_read().unwrap().unwrap().nbytes, Filesize(4));
+        assert!(writer.poll_write().unwrap().is_none());
+        assert_eq!(reader.poll_write().unwrap_err(), Errno::Badf);
+        assert_eq!(writer.poll_read().unwrap_err(), Errno::Badf);
+    }
+
+    #[test]
+    fn blocks_writers_until_the_buffer_is_drained() {
+        let (reader, writer) = pipe(4);
+        let data = (0..64).collect::<Vec<u8>>();
+        let expected = data.clone();
+
+        let thread = thread::spawn(move || writer.write(&[IoSlice::new(&data)]).unwrap());
+
+        let mut received = Vec::new();
+        loop {
+            let chunk = read(&reader, 3);
+            if chunk.is_empty() {
+                break;
+            }
+            received.extend(chunk);
+        }
+
+        assert_eq!(thread.join().unwrap(), Size(64));
+        assert_eq!(received, expected);
+    }
+}
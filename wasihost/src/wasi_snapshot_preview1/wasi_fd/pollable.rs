@@ -0,0 +1,41 @@
+use wasihost_core::wasi_snapshot_preview1::{
+    Errno, EventFdReadwrite, Eventrwflags, Eventtype, Filesize, WasiResult,
+};
+
+/// Describes an object whose readiness can be checked by `poll_oneoff`, like a
+/// [`WasiSocket`](trait.WasiSocket.html).
+///
+/// Both checks must not block. They return `None` if the object is not ready yet and default
+/// to always being ready.
+pub trait Pollable {
+    /// Checks whether reading would not block, returning the number of bytes available.
+    fn poll_read(&self) -> WasiResult<Option<EventFdReadwrite>> {
+        Ok(Some(ready(0)))
+    }
+
+    /// Checks whether writing would not block, returning the number of bytes that can be
+    /// written.
+    fn poll_write(&self) -> WasiResult<Option<EventFdReadwrite>> {
+        Ok(Some(ready(0)))
+    }
+}
+
+/// Checks whether `pollable` is ready for the event type `r#type`.
+pub(super) fn poll<P: Pollable + ?Sized>(
+    pollable: &P,
+    r#type: Eventtype,
+) -> WasiResult<Option<EventFdReadwrite>> {
+    match r#type {
+        Eventtype::FdRead => pollable.poll_read(),
+        Eventtype::FdWrite => pollable.poll_write(),
+        _ => Err(Errno::Inval),
+    }
+}
+
+/// Creates a readiness event with `nbytes` bytes available.
+pub(super) fn ready(nbytes: u64) -> EventFdReadwrite {
+    EventFdReadwrite {
+        nbytes: Filesize(nbytes),
+        flags: Eventrwflags::empty(),
+    }
+}
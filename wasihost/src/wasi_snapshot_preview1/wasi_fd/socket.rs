@@ -0,0 +1,186 @@
+use super::Pollable;
+use crate::os;
+use std::{
+    fmt::Debug,
+    io::{IoSlice, IoSliceMut, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+};
+use wasihost_core::wasi_snapshot_preview1::{
+    Errno, EventFdReadwrite, Filetype, Riflags, Roflags, Sdflags, Siflags, Size, WasiResult,
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+/// Describes a socket.
+///
+/// WASI does not provide a way to create or accept connections, so sockets are handed to the
+/// WASI binary by the embedder.
+pub trait WasiSocket: Pollable + Debug + Send + Sync + 'static {
+    /// Returns the type of the socket.
+    fn filetype(&self) -> Filetype {
+        Filetype::SocketStream
+    }
+
+    /// Receives data into `iovs`.
+    fn recv(&self, iovs: &mut [IoSliceMut<'_>], flags: Riflags) -> WasiResult<(Size, Roflags)>;
+
+    /// Sends data from `bufs`.
+    fn send(&self, bufs: &[IoSlice<'_>], flags: Siflags) -> WasiResult<Size>;
+
+    /// Shuts down the receiving and/or sending side of the socket.
+    fn shutdown(&self, how: Sdflags) -> WasiResult<()>;
+}
+
+fn shutdown_mode(how: Sdflags) -> WasiResult<Shutdown> {
+    if how == Sdflags::RD | Sdflags::WR {
+        Ok(Shutdown::Both)
+    } else if how == Sdflags::RD {
+        Ok(Shutdown::Read)
+    } else if how == Sdflags::WR {
+        Ok(Shutdown::Write)
+    } else {
+        Err(Errno::Inval)
+    }
+}
+
+impl WasiSocket for TcpStream {
+    fn recv(&self, iovs: &mut [IoSliceMut<'_>], flags: Riflags) -> WasiResult<(Size, Roflags)> {
+        if flags.is_empty() {
+            Ok((Size((&*self).read_vectored(iovs)? as u32), Roflags::empty()))
+        } else {
+            os::sock_recv(self, iovs, flags)
+        }
+    }
+
+    fn send(&self, bufs: &[IoSlice<'_>], _flags: Siflags) -> WasiResult<Size> {
+        Ok(Size((&*self).write_vectored(bufs)? as u32))
+    }
+
+    fn shutdown(&self, how: Sdflags) -> WasiResult<()> {
+        Ok(TcpStream::shutdown(self, shutdown_mode(how)?)?)
+    }
+}
+
+impl Pollable for TcpStream {
+    fn poll_read(&self) -> WasiResult<Option<EventFdReadwrite>> {
+        os::read_readiness(self)
+    }
+
+    fn poll_write(&self) -> WasiResult<Option<EventFdReadwrite>> {
+        os::write_readiness(self)
+    }
+}
+
+/// A listening socket can only be polled for incoming connections, as WASI can not accept
+/// them.
+impl WasiSocket for TcpListener {
+    fn recv(&self, _iovs: &mut [IoSliceMut<'_>], _flags: Riflags) -> WasiResult<(Size, Roflags)> {
+        Err(Errno::Notconn)
+    }
+
+    fn send(&self, _bufs: &[IoSlice<'_>], _flags: Siflags) -> WasiResult<Size> {
+        Err(Errno::Notconn)
+    }
+
+    fn shutdown(&self, _how: Sdflags) -> WasiResult<()> {
+        Err(Errno::Notconn)
+    }
+}
+
+impl Pollable for TcpListener {
+    fn poll_read(&self) -> WasiResult<Option<EventFdReadwrite>> {
+        os::read_readiness(self)
+    }
+
+    fn poll_write(&self) -> WasiResult<Option<EventFdReadwrite>> {
+        Err(Errno::Notconn)
+    }
+}
+
+#[cfg(unix)]
+impl WasiSocket for UnixStream {
+    fn recv(&self, iovs: &mut [IoSliceMut<'_>], flags: Riflags) -> WasiResult<(Size, Roflags)> {
+        if flags.is_empty() {
+            Ok((Size((&*self).read_vectored(iovs)? as u32), Roflags::empty()))
+        } else {
+            os::sock_recv(self, iovs, flags)
+        }
+    }
+
+    fn send(&self, bufs: &[IoSlice<'_>], _flags: Siflags) -> WasiResult<Size> {
+        Ok(Size((&*self).write_vectored(bufs)? as u32))
+    }
+
+    fn shutdown(&self, how: Sdflags) -> WasiResult<()> {
+        Ok(UnixStream::shutdown(self, shutdown_mode(how)?)?)
+    }
+}
+
+#[cfg(unix)]
+impl Pollable for UnixStream {
+    fn poll_read(&self) -> WasiResult<Option<EventFdReadwrite>> {
+        os::read_readiness(self)
+    }
+
+    fn poll_write(&self) -> WasiResult<Option<EventFdReadwrite>> {
+        os::write_readiness(self)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn recv(socket: &dyn WasiSocket, flags: Riflags) -> Vec<u8> {
+        let mut buf = [0; 16];
+        let (size, _) = socket
+            .recv(&mut [IoSliceMut::new(&mut buf)], flags)
+            .unwrap();
+
+        buf[..size.0 as usize].to_vec()
+    }
+
+    #[test]
+    fn sends_and_peeks_at_data() {
+        let (a, b) = UnixStream::pair().unwrap();
+
+        assert!(b.poll_read().unwrap().is_none());
+        a.send(&[IoSlice::new(b"hello")], Siflags(0)).unwrap();
+
+        assert_eq!(b.poll_read().unwrap().unwrap().nbytes.0, 5);
+        assert_eq!(recv(&b, Riflags::RECV_PEEK), b"hello");
+        assert_eq!(recv(&b, Riflags::empty()), b"hello");
+        assert!(b.poll_read().unwrap().is_none());
+        assert!(b.poll_write().unwrap().is_some());
+    }
+
+    #[test]
+    fn shuts_down_the_sending_side() {
+        let (a, b) = UnixStream::pair().unwrap();
+
+        WasiSocket::shutdown(&a, Sdflags::WR).unwrap();
+
+        assert_eq!(recv(&b, Riflags::empty()), b"");
+        assert_eq!(
+            WasiSocket::shutdown(&a, Sdflags::empty()).unwrap_err(),
+            Errno::Inval
+        );
+    }
+
+    #[test]
+    fn listeners_can_not_transfer_data() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        assert_eq!(
+            listener
+                .recv(&mut [IoSliceMut::new(&mut [0; 4])], Riflags::empty())
+                .unwrap_err(),
+            Errno::Notconn
+        );
+        assert!(listener.poll_read().unwrap().is_none());
+
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        assert!(listener.poll_read().unwrap().is_some());
+    }
+}
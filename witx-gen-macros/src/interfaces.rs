@@ -2,7 +2,7 @@ use super::{to_ident::ToIdent, type_ref_ext::TypeRefExt, StringExt};
 use proc_macro2::TokenStream;
 use quote::{quote, ToTokens};
 use syn::Index;
-use witx::{Document, Id};
+use witx::{Document, Id, InterfaceFunc};
 
 pub(crate) fn generate_interfaces(document: &Document, version: &str) -> TokenStream {
     let wasi_snapshot_id = Id::new(&version);
@@ -22,38 +22,8 @@ pub(crate) fn generate_interfaces(document: &Document, version: &str) -> TokenSt
             .iter()
             .map(|p| format!("* {}", p.docs).as_docs());
         let ident = func.name.to_ident_native(None);
-
-        let params = func.params.iter().map(|p| {
-            let ident = p.name.to_ident_native(None);
-
-            if p.tref.is_string() {
-                let len_ident = format!("{}_len", p.name.as_str()).to_ident_native(None);
-
-                return quote! { #ident: witx_gen::WasmSlicePtr<u8>, #len_ident: size };
-            }
-
-            if let Some(inner) = p.tref.as_array() {
-                let inner_type = inner.name.to_ident_native(None);
-                let len_ident = format!("{}_len", p.name.as_str()).to_ident_native(None);
-
-                return quote! { #ident: witx_gen::WasmSlicePtr<#inner_type>, #len_ident: size };
-            }
-
-            let tp = p.tref.to_type();
-            quote! { #ident: #tp }
-        });
-
-        let results = if func.results.len() == 0 && func.name.as_str() == "proc_exit" {
-            quote! { std::result::Result<std::convert::Infallible, exitcode> }
-        } else {
-            let results = func.results.iter().map(|p| {
-                let typename = p.tref.to_type();
-
-                quote! { #typename }
-            });
-
-            quote! { ( #( #results ),* ) }
-        };
+        let params = native_params(&func);
+        let results = native_return_type(&func);
 
         quote! {
             #docs
@@ -68,56 +38,9 @@ pub(crate) fn generate_interfaces(document: &Document, version: &str) -> TokenSt
 
     let wasi_trait_impls_arc = wasi_module.funcs().map(|func| {
         let ident = func.name.to_ident_native(None);
-
-        let params = func.params.iter().map(|p| {
-            let ident = p.name.to_ident_native(None);
-
-            if p.tref.is_string() {
-                let len_ident = format!("{}_len", p.name.as_str()).to_ident_native(None);
-
-                return quote! { #ident: witx_gen::WasmSlicePtr<u8>, #len_ident: size };
-            }
-
-            if let Some(inner) = p.tref.as_array() {
-                let inner_type = inner.name.to_ident_native(None);
-                let len_ident = format!("{}_len", p.name.as_str()).to_ident_native(None);
-
-                return quote! { #ident: witx_gen::WasmSlicePtr<#inner_type>, #len_ident: size };
-            }
-
-            let tp = p.tref.to_type();
-            quote! { #ident: #tp }
-        });
-
-        let param_names = func.params.iter().map(|p| {
-            let ident = p.name.to_ident_native(None);
-
-            if p.tref.is_string() {
-                let len_ident = format!("{}_len", p.name.as_str()).to_ident_native(None);
-
-                return quote! { #ident, #len_ident };
-            }
-
-            if let Some(_) = p.tref.as_array() {
-                let len_ident = format!("{}_len", p.name.as_str()).to_ident_native(None);
-
-                return quote! { #ident, #len_ident };
-            }
-
-            quote! { #ident }
-        });
-
-        let results = if func.results.len() == 0 && func.name.as_str() == "proc_exit" {
-            quote! { std::result::Result<std::convert::Infallible, exitcode> }
-        } else {
-            let results = func.results.iter().map(|p| {
-                let typename = p.tref.to_type();
-
-                quote! { #typename }
-            });
-
-            quote! { ( #( #results ),* ) }
-        };
+        let params = native_params(&func);
+        let param_names = native_param_names(&func);
+        let results = native_return_type(&func);
 
         quote! {
             fn #ident(&self, ctx: &mut witx_gen::reexports::Ctx, #( #params ),*) -> #results {
@@ -216,6 +139,113 @@ pub(crate) fn generate_interfaces(document: &Document, version: &str) -> TokenSt
         }
     });
 
+    let call_variants = wasi_module.funcs().map(|func| {
+        let name = func.name.as_str();
+        let variant = name.to_ident();
+        let params = native_params(&func);
+
+        quote! {
+            #[doc = "A call of `"]
+            #[doc = #name]
+            #[doc = "`."]
+            #variant { #( #params ),* }
+        }
+    });
+
+    let call_names = wasi_module.funcs().map(|func| {
+        let name = func.name.as_str();
+        let variant = name.to_ident();
+
+        quote! { NativeCall::#variant { .. } => #name }
+    });
+
+    let call_results_variants = wasi_module.funcs().map(|func| {
+        let name = func.name.as_str();
+        let variant = name.to_ident();
+        let results = native_result_types(&func);
+
+        quote! {
+            #[doc = "The results of `"]
+            #[doc = #name]
+            #[doc = "`."]
+            #variant( #( #results ),* )
+        }
+    });
+
+    let call_results_errnos = wasi_module.funcs().map(|func| {
+        let variant = func.name.as_str().to_ident();
+
+        if is_proc_exit(&func) {
+            quote! { NativeCallResults::#variant(_) => None }
+        } else {
+            let rest = (1..func.results.len()).map(|_| quote! { _ });
+
+            quote! { NativeCallResults::#variant(errno, #( #rest ),*) => Some(*errno) }
+        }
+    });
+
+    let wasi_trait_impls_layered = wasi_module.funcs().map(|func| {
+        let ident = func.name.to_ident_native(None);
+        let variant = func.name.as_str().to_ident();
+        let params = native_params(&func);
+        let param_names = native_param_names(&func);
+
+        if is_proc_exit(&func) {
+            return quote! {
+                fn #ident(
+                    &self,
+                    ctx: &mut witx_gen::reexports::Ctx,
+                    #( #params ),*
+                ) -> std::result::Result<std::convert::Infallible, exitcode> {
+                    let call = NativeCall::#variant { #( #param_names ),* };
+
+                    // Exiting can not be denied.
+                    let _ = self.layer.before(ctx, &call);
+                    let result = self.inner.#ident(ctx, #( #param_names ),*);
+
+                    let code = match result {
+                        Ok(never) => match never {},
+                        Err(code) => code,
+                    };
+                    self.layer.after(ctx, &call, &NativeCallResults::#variant(code));
+
+                    result
+                }
+            };
+        }
+
+        let result_types = native_result_types(&func);
+        let (denied, results) = if func.results.len() == 1 {
+            (quote! { errno }, quote! { results })
+        } else {
+            let defaults = (1..func.results.len()).map(|_| quote! { Default::default() });
+            let indices = (0..func.results.len()).map(Index::from);
+
+            (
+                quote! { (errno, #( #defaults ),*) },
+                quote! { #( results.#indices ),* },
+            )
+        };
+
+        quote! {
+            fn #ident(
+                &self,
+                ctx: &mut witx_gen::reexports::Ctx,
+                #( #params ),*
+            ) -> ( #( #result_types ),* ) {
+                let call = NativeCall::#variant { #( #param_names ),* };
+
+                let results = match self.layer.before(ctx, &call) {
+                    Ok(()) => self.inner.#ident(ctx, #( #param_names ),*),
+                    Err(errno) => #denied,
+                };
+                self.layer.after(ctx, &call, &NativeCallResults::#variant(#results));
+
+                results
+            }
+        }
+    });
+
     quote! {
         /// Functions necessary to satisfy the WASI specification.
         pub trait NativeWasiImports: Send + Sync + 'static {
@@ -232,6 +262,94 @@ pub(crate) fn generate_interfaces(document: &Document, version: &str) -> TokenSt
             #( #wasi_trait_impls_arc )*
         }
 
+        /// A call of a WASI function with its native parameters, as passed to a
+        /// [`NativeWasiImportsLayer`](trait.NativeWasiImportsLayer.html).
+        #[derive(Debug, Copy, Clone)]
+        pub enum NativeCall {
+            #( #call_variants, )*
+        }
+
+        impl NativeCall {
+            /// Returns the name of the called WASI function.
+            pub fn name(&self) -> &'static str {
+                match self {
+                    #( #call_names, )*
+                }
+            }
+        }
+
+        /// The native results of a call of a WASI function, as passed to a
+        /// [`NativeWasiImportsLayer`](trait.NativeWasiImportsLayer.html). Results that are
+        /// returned through pointers are not written to the WASM memory yet when the layer sees
+        /// them.
+        #[derive(Debug, Copy, Clone)]
+        pub enum NativeCallResults {
+            #( #call_results_variants, )*
+        }
+
+        impl NativeCallResults {
+            /// Returns the error code of the call, which is `None` for `proc_exit`.
+            pub fn errno(&self) -> Option<errno> {
+                match self {
+                    #( #call_results_errnos, )*
+                }
+            }
+        }
+
+        /// Hooks that observe every call of native WASI imports wrapped in
+        /// [`NativeLayered`](struct.NativeLayered.html), for tracing, policies or metrics.
+        pub trait NativeWasiImportsLayer: Send + Sync + 'static {
+            /// Called before a call is forwarded. Returning an error denies the call, which then
+            /// fails with that error without being forwarded. `proc_exit` can not be denied.
+            fn before(
+                &self,
+                _ctx: &mut witx_gen::reexports::Ctx,
+                _call: &NativeCall,
+            ) -> std::result::Result<(), errno> {
+                Ok(())
+            }
+
+            /// Called after a call has returned or was denied.
+            fn after(
+                &self,
+                _ctx: &mut witx_gen::reexports::Ctx,
+                _call: &NativeCall,
+                _results: &NativeCallResults,
+            ) {
+            }
+        }
+
+        /// Native WASI imports that forward every call to `inner` between the hooks of a
+        /// [`NativeWasiImportsLayer`](trait.NativeWasiImportsLayer.html).
+        #[derive(Debug)]
+        pub struct NativeLayered<L, I> {
+            layer: std::sync::Arc<L>,
+            inner: I,
+        }
+
+        impl<L, I> NativeLayered<L, I> {
+            /// Wraps `inner` in `layer`.
+            pub fn new(layer: L, inner: I) -> Self {
+                NativeLayered {
+                    layer: std::sync::Arc::new(layer),
+                    inner,
+                }
+            }
+        }
+
+        impl<L, I: Clone> Clone for NativeLayered<L, I> {
+            fn clone(&self) -> Self {
+                NativeLayered {
+                    layer: self.layer.clone(),
+                    inner: self.inner.clone(),
+                }
+            }
+        }
+
+        impl<L: NativeWasiImportsLayer, I: NativeWasiImports> NativeWasiImports for NativeLayered<L, I> {
+            #( #wasi_trait_impls_layered )*
+        }
+
         impl<T: NativeWasiImports + Clone> NativeWasiImportsExt for T {
             fn into_imports(self) -> witx_gen::reexports::ImportObject {
                 witx_gen::reexports::imports! {
@@ -243,3 +361,73 @@ pub(crate) fn generate_interfaces(document: &Document, version: &str) -> TokenSt
         }
     }
 }
+
+fn is_proc_exit(func: &InterfaceFunc) -> bool {
+    func.results.is_empty() && func.name.as_str() == "proc_exit"
+}
+
+fn native_params(func: &InterfaceFunc) -> Vec<TokenStream> {
+    func.params
+        .iter()
+        .map(|p| {
+            let ident = p.name.to_ident_native(None);
+
+            if p.tref.is_string() {
+                let len_ident = format!("{}_len", p.name.as_str()).to_ident_native(None);
+
+                return quote! { #ident: witx_gen::WasmSlicePtr<u8>, #len_ident: size };
+            }
+
+            if let Some(inner) = p.tref.as_array() {
+                let inner_type = inner.name.to_ident_native(None);
+                let len_ident = format!("{}_len", p.name.as_str()).to_ident_native(None);
+
+                return quote! { #ident: witx_gen::WasmSlicePtr<#inner_type>, #len_ident: size };
+            }
+
+            let tp = p.tref.to_type();
+            quote! { #ident: #tp }
+        })
+        .collect()
+}
+
+fn native_param_names(func: &InterfaceFunc) -> Vec<TokenStream> {
+    func.params
+        .iter()
+        .map(|p| {
+            let ident = p.name.to_ident_native(None);
+
+            if p.tref.is_string() || p.tref.as_array().is_some() {
+                let len_ident = format!("{}_len", p.name.as_str()).to_ident_native(None);
+
+                return quote! { #ident, #len_ident };
+            }
+
+            quote! { #ident }
+        })
+        .collect()
+}
+
+fn native_return_type(func: &InterfaceFunc) -> TokenStream {
+    if is_proc_exit(func) {
+        return quote! { std::result::Result<std::convert::Infallible, exitcode> };
+    }
+
+    let results = native_result_types(func);
+    quote! { ( #( #results ),* ) }
+}
+
+fn native_result_types(func: &InterfaceFunc) -> Vec<TokenStream> {
+    if is_proc_exit(func) {
+        return vec![quote! { exitcode }];
+    }
+
+    func.results
+        .iter()
+        .map(|p| {
+            let typename = p.tref.to_type();
+
+            quote! { #typename }
+        })
+        .collect()
+}